@@ -0,0 +1,84 @@
+extern crate bkchainsaw;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::time::Instant;
+use structopt::StructOpt;
+
+use bkchainsaw::bk;
+use bkchainsaw::bknode::BkNode;
+use bkchainsaw::bktree::{BkTree, BkTreeAdd};
+use bkchainsaw::keys;
+use bkchainsaw::metric::levenshtein::LevenshteinMetric;
+use bkchainsaw::nodeallocator::NodeAllocator;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bench_children",
+    about = "Compare dense and sparse BkInRam child tables on a word list"
+)]
+struct CommandLineArgs {
+    /// One key per line.
+    #[structopt(parse(from_os_str))]
+    input_filename: PathBuf,
+
+    #[structopt(
+        long = "queries",
+        default_value = "1000",
+        help = "Search for this many of the input lines"
+    )]
+    queries: usize,
+
+    #[structopt(long = "tolerance", default_value = "2")]
+    tolerance: usize,
+}
+
+fn bench<'a, A>(name: &str, words: &[String], opts: &CommandLineArgs, alloc: &'a A)
+where
+    A: NodeAllocator<'a, Key = String, Node = bk::BkInRam<String>>,
+{
+    let mut tree: bk::BkInRamTree<keys::StringKey, LevenshteinMetric, A> =
+        bk::BkInRamTree::new(LevenshteinMetric, alloc);
+    let start = Instant::now();
+    for word in words {
+        tree.add(word).unwrap();
+    }
+    let build = start.elapsed();
+
+    let mut child_bytes = 0;
+    let mut stack: Vec<&bk::BkInRam<String>> = tree.root.iter().collect();
+    while let Some(node) = stack.pop() {
+        child_bytes += node.children_bytes();
+        node.each_child(|_, child| stack.push(child));
+    }
+
+    let start = Instant::now();
+    let mut found = 0;
+    for word in words.iter().take(opts.queries) {
+        tree.find_each(word, opts.tolerance, |_, _| found += 1);
+    }
+    let search = start.elapsed();
+
+    println!(
+        "{}: {} nodes, {} child table bytes, built in {:?}, {} searches in {:?} finding {}",
+        name,
+        tree.node_count,
+        child_bytes,
+        build,
+        words.len().min(opts.queries),
+        search,
+        found
+    );
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let opts = CommandLineArgs::from_args();
+    let words: Vec<String> = BufReader::new(File::open(&opts.input_filename)?)
+        .lines()
+        .collect::<Result<_, _>>()?;
+    bench("dense", &words, &opts, &bk::STRING_ALLOC);
+    bench("sparse", &words, &opts, &bk::SPARSE_STRING_ALLOC);
+    Ok(())
+}
@@ -0,0 +1,34 @@
+extern crate bkchainsaw;
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use bkchainsaw::delta::Overlay;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bkcompact",
+    about = "Merge a bkfile and its delta files into a new bkfile"
+)]
+struct CommandLineArgs {
+    #[structopt(parse(from_os_str))]
+    base_filename: PathBuf,
+
+    /// Delta files of the base, as `Overlay::write_delta` writes them.
+    #[structopt(parse(from_os_str))]
+    delta_filenames: Vec<PathBuf>,
+
+    #[structopt(long = "output", short = "o", parse(from_os_str))]
+    output_filename: PathBuf,
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let opts = CommandLineArgs::from_args();
+    let overlay = Overlay::open(&opts.base_filename, &opts.delta_filenames)?;
+    let descr = overlay.compact(&opts.output_filename)?;
+    println!("nodes: {}", descr.node_count);
+    println!("keys bytes: {} ({})", descr.key_bytes, descr.key_format);
+    Ok(())
+}
@@ -2,40 +2,31 @@ extern crate bkchainsaw;
 extern crate chrono;
 
 use std::boxed::Box;
-use std::cell::RefCell;
 use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::env;
 use std::error::Error;
+use std::fs;
 use std::fs::File;
-use std::io;
-use std::io::Result as IoResult;
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use bkchainsaw::array_storage::F64BNode8;
-use bkchainsaw::array_storage::{InStorageNode, InStorageNodeMut};
 use bkchainsaw::bk;
 use bkchainsaw::bkfile;
-use bkchainsaw::bknode::BkNode;
-use bkchainsaw::bktree;
+use bkchainsaw::bkfile_tree::BkFile;
 use bkchainsaw::bktree::BkTreeAdd;
-use bkchainsaw::bktreemut;
+use bkchainsaw::codec;
+use bkchainsaw::external::ExternalBuilder;
 use bkchainsaw::keys;
+use bkchainsaw::metric::plugin::PluginMetric;
+use bkchainsaw::metric::Metric;
+use bkchainsaw::Dist;
 use bkchainsaw::HammingMetric;
 
-use bkchainsaw::extensible_mmap::ExtensibleMmapMut;
-
-#[macro_use]
-extern crate structopt;
-
-use chrono::{DateTime, Utc};
 use sha2::{Digest, Sha256};
 use structopt::StructOpt;
-use tempfile;
-
-use memmap::MmapMut;
-use memmap::MmapOptions;
 
 #[derive(Debug, Default, StructOpt)]
 #[structopt(name = "bkfile_from_ints", about = "Build a bkfile")]
@@ -46,62 +37,309 @@ struct CommandLineArgs {
     #[structopt(parse(from_os_str))]
     output_filename: PathBuf,
 
+    // Accepted for old scripts' sake: builds write nothing but the output and checkpoints.
+    #[allow(dead_code)]
     #[structopt(
         name = "preserve_intermediates",
         help = "Keep the intermediate files around for debugging"
     )]
     preserve_intermediates: bool,
+
+    #[structopt(
+        long = "shards",
+        default_value = "1",
+        help = "Split the keys round robin across this many trees, stored as a forest"
+    )]
+    shards: usize,
+
+    #[structopt(
+        long = "assume-sorted-unique",
+        help = "Input is sorted and deduplicated, so skip duplicate checks while inserting"
+    )]
+    assume_sorted_unique: bool,
+
+    #[structopt(
+        long = "key-codec",
+        help = "How to encode the key section, e.g. \"fixed 64 bits\" or \"varint\". By default \
+                keys take the fewest whole bytes the largest needs"
+    )]
+    key_codec: Option<String>,
+
+    #[structopt(
+        long = "checkpoint",
+        parse(from_os_str),
+        help = "Save progress here while building, and resume from it if it exists"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[structopt(
+        long = "checkpoint-every",
+        default_value = "1000000",
+        help = "Input lines before the first checkpoint. Each checkpoint rewrites everything \
+                built so far, so later ones wait as many lines as came before them, keeping \
+                the total written in proportion to the input"
+    )]
+    checkpoint_every: u64,
+
+    #[structopt(
+        long = "metric-plugin",
+        parse(from_os_str),
+        help = "Measure keys with the distance function in this shared library instead of \
+                hamming. Needs the plugins feature"
+    )]
+    metric_plugin: Option<PathBuf>,
+
+    #[structopt(
+        long = "tree-keys",
+        help = "Hold at most this many keys in RAM, writing out a tree of them at a time, for \
+                inputs too big to build in RAM. The file holds a forest of those trees"
+    )]
+    tree_keys: Option<usize>,
+
+    #[structopt(
+        long = "reserve",
+        default_value = "0",
+        help = "Leave room for this many more keys to be added to the file in place"
+    )]
+    reserve: usize,
+
+    #[structopt(
+        long = "checksum",
+        default_value = "SHA256",
+        help = "How to checksum the file: SHA256, or with the fast-checksums feature BLAKE3 or XXH3"
+    )]
+    checksum: String,
+
+    #[structopt(
+        long = "signing-key",
+        parse(from_os_str),
+        help = "Sign the file with the 32 byte ed25519 secret key in this file. Needs the \
+                signatures feature"
+    )]
+    signing_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "sibling-deltas",
+        help = "Store each node's children in key order, their keys as deltas: much smaller \
+                files of dense integer keys. Not with --key-codec or --reserve"
+    )]
+    sibling_deltas: bool,
+}
+
+/// Hamming distance, unless the command line names a plugin.
+#[derive(Debug, Clone)]
+enum KeyMetric {
+    Hamming(HammingMetric<u64>),
+    Plugin(Rc<PluginMetric>),
+}
+
+impl Metric<u64> for KeyMetric {
+    fn distance(&self, k1: &u64, k2: &u64) -> Dist {
+        match self {
+            KeyMetric::Hamming(metric) => metric.distance(k1, k2),
+            KeyMetric::Plugin(metric) => metric.distance(k1, k2),
+        }
+    }
+
+    fn lower_bound(&self, k1: &u64, k2: &u64) -> Dist {
+        match self {
+            KeyMetric::Hamming(metric) => metric.lower_bound(k1, k2),
+            KeyMetric::Plugin(metric) => metric.lower_bound(k1, k2),
+        }
+    }
+
+    /// Recorded in the header, so searches can tell they need the plugin too.
+    fn registered_name(&self) -> Option<&str> {
+        match self {
+            KeyMetric::Hamming(metric) => metric.registered_name(),
+            KeyMetric::Plugin(metric) => Metric::<u64>::registered_name(&**metric),
+        }
+    }
 }
 
-// TODO: handle more file types than fixed u64 keys with <256 distances and children
-// F64BNode8 uses 8 bytes per node
-const NODE_SIZE: u64 = 8;
+type Tree = bk::BkInRamTree<'static, keys::U64Key, KeyMetric, bk::BkInRamAllocator<'static, u64>>;
 
-// F64BNode8 uses 8 bytes per key
-const KEY_SIZE: u64 = 8;
+/// How far through the input a build has got.
+#[derive(Debug, Default)]
+struct Progress {
+    lines: u64,
+    /// Byte offset of the next line.
+    offset: u64,
+    /// Last key added, for --assume-sorted-unique.
+    previous: Option<u64>,
+    /// SHA-256 of the input so far, so a resumed build can tell it's reading the same input.
+    input: Sha256,
+}
 
-struct InFileAllocator {
-    nodes: ExtensibleMmapMut,
-    keys: ExtensibleMmapMut,
+impl Progress {
+    fn input_hash(&self) -> String {
+        format!("{:064x}", self.input.clone().result())
+    }
 }
 
-fn walk(
-    alloc: &mut InFileAllocator,
-    offset: usize,
-    dist: usize,
-    node: &bk::BkInRam<u64>,
+/// Write `trees` out as a bkfile, one root per nonempty tree, with `values`.
+fn write_bkfile(
+    trees: &[Tree],
+    values: &HashMap<u64, Vec<u8>>,
+    options: bkfile::WriteOptions,
+    output_filename: &Path,
+) -> Result<bkfile::FileDescrHeader, Box<dyn Error>> {
+    let options = bkfile::WriteOptions {
+        values: Some(values),
+        ..options
+    };
+    bkfile::write_forest(trees, output_filename, &options)
+}
+
+/// Save the trees built so far, and how much input they cover, as a bkfile at `path`. Written
+/// beside it, synced, then renamed into place, so a crash mid-write leaves the previous
+/// checkpoint.
+fn checkpoint(
+    path: &Path,
+    trees: &[Tree],
+    values: &HashMap<u64, Vec<u8>>,
+    codec: Option<&dyn codec::KeyCodec<Key = u64>>,
+    checksum: bkfile::ChecksumKind,
+    progress: &Progress,
 ) -> Result<(), Box<dyn Error>> {
-    let children = node.children_vector();
-    // A BkFile is a pre-order representation. We have to allocate space for all of this
-    // node's children contiguously and earlier in the file than any of the grandchildren.
-    let (child_offset, _) = alloc
-        .nodes
-        .alloc_bytes(NODE_SIZE as usize * children.len())?;
-    let (ko, _) = alloc.keys.alloc_bytes(KEY_SIZE as usize * children.len())?;
-    // F64Node8 can compute where to put its key.
-    // Future work: for variable sized keys, the key offset calculated here needs to be
-    // passed forward.
-    // this block should be a  fn render_at ... , but that requires building the children vector twice.
-    {
-        // This should be safe because the space for this node was allocated in the previous
-        // call.
-        let kbuflen = alloc.keys.len();
-        let knodelen = alloc.nodes.len();
-        let mut mirror = F64BNode8 {
-            offset,
-            key_buffer: RefCell::new(alloc.keys.ram_mut()),
-            node_buffer: RefCell::new(alloc.nodes.ram_mut()),
+    let mut metadata = BTreeMap::new();
+    metadata.insert("Checkpoint-Lines".to_string(), progress.lines.to_string());
+    metadata.insert("Checkpoint-Offset".to_string(), progress.offset.to_string());
+    metadata.insert("Checkpoint-Input".to_string(), progress.input_hash());
+    metadata.insert("Checkpoint-Shards".to_string(), trees.len().to_string());
+    if let Some(previous) = progress.previous {
+        metadata.insert("Checkpoint-Previous".to_string(), previous.to_string());
+    }
+    let partial = path.with_extension("partial");
+    let options = bkfile::WriteOptions {
+        codec,
+        metadata,
+        checksum,
+        ..Default::default()
+    };
+    write_bkfile(trees, values, options, &partial)?;
+    File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, path)?;
+    // And the rename itself.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+type Checkpoint = (Vec<Tree>, HashMap<u64, Vec<u8>>, Progress);
+
+/// The trees, values and progress saved by `checkpoint`, after checking `input` starts with the
+/// input they were built from, and reading past it.
+fn resume(
+    path: &Path,
+    input: &mut impl BufRead,
+    shards: usize,
+    metric: &KeyMetric,
+) -> Result<Checkpoint, Box<dyn Error>> {
+    let file = BkFile::open(path, true)?;
+    let metadata = file.descr().metadata.clone();
+    let field = |name: &str| -> Result<&String, Box<dyn Error>> {
+        metadata
+            .get(name)
+            .ok_or_else(|| format!("{:?} is not a checkpoint: no {}", path, name).into())
+    };
+    if field("Checkpoint-Shards")?.parse::<usize>()? != shards {
+        return Err(format!(
+            "{:?} was checkpointed with {} shards, not {}",
+            path,
+            field("Checkpoint-Shards")?,
+            shards
+        )
+        .into());
+    }
+    if file.descr().metric != metric.registered_name().unwrap_or_default() {
+        return Err(format!("{:?} was checkpointed with another metric", path).into());
+    }
+    let mut progress = Progress {
+        lines: field("Checkpoint-Lines")?.parse()?,
+        offset: field("Checkpoint-Offset")?.parse()?,
+        previous: match metadata.get("Checkpoint-Previous") {
+            Some(previous) => Some(previous.parse()?),
+            None => None,
+        },
+        input: Sha256::new(),
+    };
+    let mut prefix = input.take(progress.offset);
+    let mut buffer = [0; 1 << 16];
+    loop {
+        match prefix.read(&mut buffer)? {
+            0 => break,
+            read => progress.input.input(&buffer[..read]),
+        }
+    }
+    if &progress.input_hash() != field("Checkpoint-Input")? {
+        return Err(format!("{:?} was checkpointed from other input", path).into());
+    }
+
+    let mut trees: Vec<Tree> = (0..shards)
+        .map(|_| bk::BkInRamTree::new(metric.clone(), &bk::U64_ALLOC))
+        .collect();
+    let mut values = HashMap::new();
+    for file_tree in file.trees()? {
+        // Empty shards have no root in the file, so go by the metadata rather than position.
+        let shard: usize = match file_tree.metadata.get("Shard") {
+            Some(shard) => shard.split('/').next().unwrap_or("").parse()?,
+            None => 0,
         };
-        mirror.set_key(node.key)?;
-        mirror.set_dist(dist)?;
-        mirror.set_num_children(children.len())?;
-        mirror.set_child_offset(child_offset)?;
+        if shard >= shards {
+            return Err(format!("{:?} has a tree for shard {}", path, shard).into());
+        }
+        let (tree, tree_values) = file_tree.into_parts()?;
+        trees[shard] = tree.with_metric(metric.clone());
+        values.extend(tree_values);
     }
+    Ok((trees, values, progress))
+}
+
+/// Each line is a key, optionally followed by its flags, then its value: the rest of the line,
+/// spaces and all.
+fn parse_line(line: &str) -> Result<(u64, u8, Option<&str>), Box<dyn Error>> {
+    let mut fields = line.trim().splitn(3, [' ', '\t']);
+    let num: u64 = fields.next().ok_or("blank line")?.parse()?;
+    let flags: u8 = match fields.next() {
+        Some(flags) => flags.parse()?,
+        None => 0,
+    };
+    Ok((num, flags, fields.next()))
+}
 
-    for (i, (dist, child)) in children.iter().rev().enumerate() {
-        let offset = child_offset + NODE_SIZE as usize * i;
-        walk(alloc, offset, *dist, child)?;
+/// Build the output `tree_keys` keys at a time, never holding more of them in RAM.
+fn build_external(
+    opts: &CommandLineArgs,
+    tree_keys: usize,
+    codec: Option<&dyn codec::KeyCodec<Key = u64>>,
+    checksum: bkfile::ChecksumKind,
+    signing_key: Option<[u8; 32]>,
+    metric: KeyMetric,
+) -> Result<(), Box<dyn Error>> {
+    if opts.checkpoint.is_some() || opts.shards > 1 {
+        return Err("--tree-keys can't be combined with --checkpoint or --shards".into());
     }
+    if opts.sibling_deltas {
+        return Err("--tree-keys can't be combined with --sibling-deltas".into());
+    }
+    // Keys are written before the largest is known, so they can't be sized to fit.
+    let codec = codec.unwrap_or(&codec::FixedU64);
+    let mut builder = ExternalBuilder::new(metric, tree_keys, codec)?;
+    builder.set_checksum(checksum);
+    if let Some(key) = signing_key {
+        builder.set_signing_key(key);
+    }
+    for line in BufReader::new(File::open(&opts.input_filename)?).lines() {
+        let line = line?;
+        let (num, flags, value) = parse_line(&line)?;
+        builder.add(&num, flags, value.map(str::as_bytes))?;
+    }
+    let descr = builder.finish(&opts.output_filename, BTreeMap::new())?;
+    println!("trees: {}", descr.roots.len());
+    println!("nodes bytes: {}", descr.node_bytes);
+    println!("keys bytes: {} ({})", descr.key_bytes, descr.key_format);
     Ok(())
 }
 
@@ -109,84 +347,117 @@ fn main() -> Result<(), Box<dyn Error + 'static>> {
     let opts = CommandLineArgs::from_args();
     let args: Vec<String> = env::args().collect();
     println!("args: {:?}", args);
+    if opts.sibling_deltas && (opts.key_codec.is_some() || opts.reserve > 0) {
+        return Err("--sibling-deltas can't be combined with --key-codec or --reserve".into());
+    }
+    let codec = match opts.key_codec {
+        Some(ref name) => {
+            Some(codec::u64_codec(name).ok_or_else(|| format!("Unknown key codec {:?}", name))?)
+        }
+        None => None,
+    };
+    let checksum = bkfile::ChecksumKind::named(&opts.checksum)
+        .ok_or_else(|| format!("Unknown checksum {:?}", opts.checksum))?;
+    // Before building anything, in case this build can't compute it.
+    checksum.hasher()?;
+    let signing_key = match opts.signing_key {
+        Some(ref path) => Some(
+            <[u8; 32]>::try_from(fs::read(path)?.as_slice())
+                .map_err(|_| format!("{:?} doesn't hold a 32 byte key", path))?,
+        ),
+        None => None,
+    };
+    let metric = match opts.metric_plugin {
+        Some(ref path) => KeyMetric::Plugin(Rc::new(unsafe { PluginMetric::load(path) }?)),
+        None => KeyMetric::Hamming(HammingMetric::default()),
+    };
 
-    // Step 1: build the tree in RAM
-    let mut tree: bk::BkInRamTree<
-        '_,
-        keys::U64Key,
-        HammingMetric<u64>,
-        bk::BkInRamAllocator<'_, u64>,
-    > = bk::BkInRamTree::new(HammingMetric::default(), &bk::U64_ALLOC);
-    let numbers = BufReader::new(File::open(args[1].clone())?).lines();
-    for numstr in numbers {
-        let num: u64 = numstr?.parse()?;
-        tree.add(num)?;
-    }
-
-    // Step 2: Render the ndoes into bytes.
-    // TODO preserve temps for debugging;
-    let nodestemp = tempfile::tempfile()?;
-    let keystemp = tempfile::tempfile()?;
-    nodestemp.set_len(tree.node_count * NODE_SIZE)?;
-    keystemp.set_len(tree.node_count * KEY_SIZE)?;
-
-    let mut alloc = InFileAllocator {
-        nodes: ExtensibleMmapMut::on(nodestemp)?,
-        keys: ExtensibleMmapMut::on(keystemp)?,
+    if let Some(tree_keys) = opts.tree_keys {
+        return build_external(
+            &opts,
+            tree_keys,
+            codec.as_deref(),
+            checksum,
+            signing_key,
+            metric,
+        );
+    }
+
+    // Step 1: build the trees in RAM
+    let shards = max(opts.shards, 1);
+    let mut input = BufReader::new(File::open(&opts.input_filename)?);
+    let (mut trees, mut values, mut progress) = match opts.checkpoint {
+        Some(ref path) if path.exists() => {
+            let resumed = resume(path, &mut input, shards, &metric)?;
+            println!("resuming from {:?} after {} lines", path, resumed.2.lines);
+            resumed
+        }
+        _ => (
+            (0..shards)
+                .map(|_| bk::BkInRamTree::new(metric.clone(), &bk::U64_ALLOC))
+                .collect(),
+            HashMap::new(),
+            Progress::default(),
+        ),
     };
-    if let Some(ref node) = tree.root {
-        alloc.nodes.alloc_bytes(NODE_SIZE as usize)?;
-        alloc.keys.alloc_bytes(KEY_SIZE as usize)?;
-        walk(&mut alloc, 0, 0, &node)?;
-    }
-
-    println!(
-        "nodes bytes: {} / {}",
-        alloc.nodes.len(),
-        alloc.nodes.capacity()
-    );
-    println!(
-        "keys bytes: {} / {}",
-        alloc.keys.len(),
-        alloc.keys.capacity()
-    );
-
-    // Step 3: build the header (key offset = nodes.lengths
-    let mut descr: bkfile::FileDescrHeader = Default::default();
-    descr.created_on = Utc::now().to_rfc3339();
-    descr.node_format = "8 bits distance, 8 bits child".to_string();
-    descr.node_bytes = alloc.nodes.len() as u64;
-    descr.node_offset = 0;
-    descr.node_count = tree.node_count;
-    descr.key_format = "fixed 64 bits".to_string();
-    descr.key_offset = alloc.nodes.len() as u64;
-    descr.key_bytes = alloc.keys.len() as u64;
-    let header = descr.encode(bkfile::PREFIX_SIZE);
-    println!("{:#?}", descr);
+    let mut next_checkpoint = progress.lines + max(opts.checkpoint_every, progress.lines);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = input.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        let (num, flags, value) = parse_line(&line)?;
+        if let Some(value) = value {
+            values.insert(num, value.as_bytes().to_vec());
+        }
+        let tree = &mut trees[progress.lines as usize % shards];
+        if opts.assume_sorted_unique {
+            // Cheaper than the per-level checks it replaces, and catches mislabelled input.
+            if progress.previous.is_some_and(|p| p >= num) {
+                return Err(format!("Input is not sorted and unique at {}", num).into());
+            }
+            progress.previous = Some(num);
+            tree.add_unique(&num)?;
+        } else {
+            tree.add(&num)?;
+        }
+        if flags != 0 {
+            tree.set_flags(&num, flags);
+        }
 
-    // Step 4: Checksum: header + nodes + keys
-    let mut hasher = Sha256::new();
-    hasher.write(&header)?;
-    hasher.write(&alloc.nodes.ram_mut())?;
-    hasher.write(&alloc.keys.ram_mut())?;
-
-    // Step 5: write it out
-    let mut out = BufWriter::new(File::create(opts.output_filename)?);
-    write!(&mut out, "{}\n", bkfile::MAGIC_VERSION)?;
-    write!(
-        &mut out,
-        "{}: {:064x}\n",
-        bkfile::HASH_HEADER_NAME,
-        hasher.result()
-    )?;
-    assert_eq!(
-        bkfile::PREFIX_SIZE,
-        out.seek(SeekFrom::Current(0))? as usize
-    );
-    io::copy(&mut header.as_slice(), &mut out)?;
-    io::copy(&mut alloc.nodes.ram(), &mut out)?;
-    io::copy(&mut alloc.keys.ram(), &mut out)?;
-    out.flush()?;
+        progress.lines += 1;
+        progress.offset += read as u64;
+        progress.input.input(line.as_bytes());
+        if let Some(ref path) = opts.checkpoint {
+            if opts.checkpoint_every > 0 && progress.lines == next_checkpoint {
+                checkpoint(path, &trees, &values, codec.as_deref(), checksum, &progress)?;
+                println!("checkpointed after {} lines", progress.lines);
+                next_checkpoint += max(opts.checkpoint_every, progress.lines);
+            }
+        }
+    }
+
+    // Steps 2 to 5: render the nodes into bytes and write them out.
+    let options = bkfile::WriteOptions {
+        codec: codec.as_deref(),
+        reserve_nodes: opts.reserve,
+        checksum,
+        signing_key,
+        sibling_deltas: opts.sibling_deltas,
+        ..Default::default()
+    };
+    let descr = write_bkfile(&trees, &values, options, &opts.output_filename)?;
+    println!("nodes bytes: {}", descr.node_bytes);
+    println!("keys bytes: {} ({})", descr.key_bytes, descr.key_format);
+    println!("{:#?}", descr);
+    if let Some(ref path) = opts.checkpoint {
+        // The output covers everything the checkpoint did.
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,72 @@
+extern crate bkchainsaw;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use bkchainsaw::bk;
+use bkchainsaw::bkfile;
+use bkchainsaw::bktree::BkTreeAdd;
+use bkchainsaw::build::BuildOrder;
+use bkchainsaw::keys;
+use bkchainsaw::metric::levenshtein::LevenshteinMetric;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bkfile_from_strings",
+    about = "Build a bkfile of strings, by edit distance"
+)]
+struct CommandLineArgs {
+    /// A string per line, optionally followed by a tab and its flags.
+    #[structopt(parse(from_os_str))]
+    input_filename: PathBuf,
+
+    #[structopt(parse(from_os_str))]
+    output_filename: PathBuf,
+
+    #[structopt(
+        long = "shuffle",
+        help = "Add the strings in a shuffled order, for sorted input that would build a deep tree"
+    )]
+    shuffle: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let opts = CommandLineArgs::from_args();
+    let mut tree: bk::BkInRamTree<
+        keys::StringKey,
+        LevenshteinMetric,
+        bk::BkInRamAllocator<'_, String>,
+    > = bk::BkInRamTree::new(LevenshteinMetric, &bk::STRING_ALLOC);
+
+    let mut words = Vec::new();
+    let mut flagged = Vec::new();
+    for line in BufReader::new(File::open(&opts.input_filename)?).lines() {
+        let line = line?;
+        let mut fields = line.splitn(2, '\t');
+        let word = fields.next().unwrap_or("").to_string();
+        if let Some(flags) = fields.next() {
+            flagged.push((word.clone(), flags.trim().parse::<u8>()?));
+        }
+        words.push(word);
+    }
+    let order = if opts.shuffle {
+        BuildOrder::Shuffled(words.len() as u64)
+    } else {
+        BuildOrder::AsGiven
+    };
+    let report = tree.bulk_build(words, order)?;
+    for (word, flags) in flagged {
+        tree.set_flags(&word, flags);
+    }
+
+    let descr = bkfile::write_string_tree(&tree, &opts.output_filename, &Default::default())?;
+    println!(
+        "{} strings, {} deep: {} node bytes, {} key bytes",
+        report.added, report.max_depth, descr.node_bytes, descr.key_bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,171 @@
+extern crate bkchainsaw;
+
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+
+use bkchainsaw::bk::BkFind;
+use bkchainsaw::bkfile;
+use bkchainsaw::bkfile_tree::{self, BkFile};
+use bkchainsaw::bknode::BkNode;
+use bkchainsaw::bktree::BkTree;
+use bkchainsaw::keys::U64Key;
+use bkchainsaw::metric;
+use bkchainsaw::metric::plugin::PluginMetric;
+use bkchainsaw::Dist;
+
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "bkfind", about = "Search a bkfile for keys near a needle")]
+struct CommandLineArgs {
+    #[structopt(parse(from_os_str))]
+    tree_filename: PathBuf,
+
+    /// A number, or for files of strings, a string.
+    needle: String,
+
+    #[structopt(default_value = "0")]
+    tolerance: usize,
+
+    #[structopt(long = "no-verify", help = "Skip checking the file checksum")]
+    no_verify: bool,
+
+    #[structopt(
+        long = "from-node",
+        help = "Only search the subtree at this byte offset into the node array"
+    )]
+    from_node: Option<u64>,
+
+    #[structopt(
+        long = "allow-truncated",
+        help = "Search whatever survived of a truncated file, skipping the checksum"
+    )]
+    allow_truncated: bool,
+
+    #[structopt(
+        long = "metric-plugin",
+        parse(from_os_str),
+        help = "Measure keys with the distance function in this shared library, as the file was \
+                built. Needs the plugins feature"
+    )]
+    metric_plugin: Option<PathBuf>,
+
+    #[structopt(
+        long = "sorted",
+        help = "List each tree's matches by distance then key, the same for any layout of the keys"
+    )]
+    sorted: bool,
+}
+
+/// Search a file of strings by the metric it was built with, or edit distance for files from
+/// before metrics were recorded, as bkfile_from_strings builds them.
+fn find_strings(opts: &CommandLineArgs, metric: &str) -> Result<(), Box<dyn Error + 'static>> {
+    // String files are opened whole, checksum checked, and searched by the metric they record.
+    let unsupported = [
+        (opts.no_verify, "--no-verify"),
+        (opts.allow_truncated, "--allow-truncated"),
+        (opts.from_node.is_some(), "--from-node"),
+        (opts.metric_plugin.is_some(), "--metric-plugin"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+        return Err(format!("{} isn't supported for files of variable length keys", flag).into());
+    }
+    let metric = metric::by_name(if metric.is_empty() {
+        "levenshtein"
+    } else {
+        metric
+    })?;
+    let tree = bkfile_tree::open_bytes_tree(&opts.tree_filename, metric)?;
+    let mut found: Vec<(Dist, String, u8)> = Vec::new();
+    tree.find_each_flagged(
+        opts.needle.as_bytes(),
+        opts.tolerance,
+        |dist, key, flags| found.push((dist, String::from_utf8_lossy(key).into_owned(), flags)),
+    );
+    if opts.sorted {
+        found.sort();
+    }
+    for (dist, key, flags) in found {
+        println!("0\t{}\t{}\t{}", dist, key, flags)
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let opts = CommandLineArgs::from_args();
+    let header = bkfile::Header::read(&mut File::open(&opts.tree_filename)?, false)?;
+    if header.descr().node_format == bkfile::VARIABLE_KEY_NODE_FORMAT {
+        return find_strings(&opts, &header.descr().metric);
+    }
+    let needle: u64 = opts.needle.parse()?;
+    let file = if opts.allow_truncated {
+        let file = BkFile::open_truncated(&opts.tree_filename)?;
+        let recovery = file.recovery()?;
+        eprintln!(
+            "{} of {} nodes present, {} reachable",
+            recovery.present, recovery.expected, recovery.reachable
+        );
+        file
+    } else {
+        BkFile::open(&opts.tree_filename, !opts.no_verify)?
+    };
+    let plugin = match opts.metric_plugin {
+        Some(ref path) => Some(unsafe { PluginMetric::load(path) }?),
+        None => None,
+    };
+    match (&plugin, file.descr().metric.as_str()) {
+        (None, "") | (None, "hamming") | (None, "hamming-u64") => {}
+        (None, name) => {
+            return Err(format!(
+                "{:?} was built with the metric {:?}, which bkfind can't search u64 keys by \
+                 unless it's a plugin passed with --metric-plugin",
+                opts.tree_filename, name
+            )
+            .into())
+        }
+        (Some(plugin), name) if name != plugin.name() => {
+            return Err(format!(
+                "{:?} was built with the metric {:?}, not the plugin {}",
+                opts.tree_filename,
+                name,
+                plugin.name()
+            )
+            .into())
+        }
+        (Some(_), _) => {}
+    }
+    let trees = match opts.from_node {
+        Some(offset) => vec![file.tree_at(offset)?],
+        None => file.trees()?,
+    };
+    for (root_index, tree) in trees.iter().enumerate() {
+        let mut found: Vec<(Dist, u64, u8)> = Vec::new();
+        match plugin {
+            Some(ref plugin) => {
+                BkFind::new(0, tree.root(), opts.tolerance, &needle)
+                    .each_node_while::<U64Key, PluginMetric, _>(plugin, |dist, node| {
+                        found.push((dist, *node.key(), node.flags()));
+                        true
+                    });
+            }
+            None => tree.find_each_flagged(&needle, opts.tolerance, |dist, key, flags| {
+                found.push((dist, *key, flags))
+            }),
+        }
+        if opts.sorted {
+            // As BkTree::find_sorted.
+            found.sort_by_key(|(dist, key, _)| (*dist, *key));
+        }
+        for (dist, key, flags) in found {
+            // Keys with values get them as a fifth column.
+            let value = match tree.value(&key) {
+                Some(value) => format!("\t{}", String::from_utf8_lossy(value)),
+                None => String::new(),
+            };
+            println!("{}\t{}\t{}\t{}{}", root_index, dist, key, flags, value)
+        }
+    }
+
+    Ok(())
+}
@@ -1,21 +1,19 @@
 extern crate bkchainsaw;
 
-use serde_json;
 use std::env;
 use std::error::Error;
 use std::fs::File;
+use std::io::Seek;
 use std::io::Write as IOWrite;
 use std::io::{BufReader, BufWriter};
-use std::io::{Seek, SeekFrom};
 
 use bkchainsaw::bkfile;
 
-#[macro_use]
-extern crate structopt;
-
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+// Parsed for --help and to check the arguments are there; main reads them positionally.
+#[allow(dead_code)]
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bktree_from_json", about = "Build a bktree file from parts")]
 struct CommandLineArgs {
@@ -38,7 +36,7 @@ struct CommandLineArgs {
 }
 
 fn main() -> Result<(), Box<dyn Error + 'static>> {
-    let opts = CommandLineArgs::from_args();
+    CommandLineArgs::from_args();
     let args: Vec<String> = env::args().collect();
     // 1: input descr json file
     // 2: checksum value
@@ -52,7 +50,7 @@ fn main() -> Result<(), Box<dyn Error + 'static>> {
     writeln!(out, "{}", bkfile::MAGIC_VERSION)?;
     writeln!(out, "{}: {}", bkfile::HASH_HEADER_NAME, args[2])?;
 
-    let pos = out.seek(SeekFrom::Current(0))?;
+    let pos = out.stream_position()?;
     let descr_bytes = descr.encode(pos as usize);
     out.write_all(&descr_bytes[..])?;
     out.flush()?;
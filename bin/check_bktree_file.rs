@@ -1,23 +1,55 @@
 extern crate bkchainsaw;
 
-use memmap::Mmap;
-use memmap::MmapOptions;
-use sha2::{Digest, Sha256};
 use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io;
-use std::io::Result as IOResult;
-use std::io::{BufRead, BufReader};
-use std::io::{Seek, SeekFrom};
 
 use bkchainsaw::bkfile;
+use bkchainsaw::bkfile_tree::BkFile;
+use bkchainsaw::bktree::BkTree;
 
 fn main() -> Result<(), Box<dyn Error + 'static>> {
     let args: Vec<String> = env::args().collect();
     println!("args: {:?}", args);
     let mut treefile = File::open(args[1].clone())?;
-    bkfile::Header::read(&mut treefile, true)?;
+    let header = bkfile::Header::read(&mut treefile, true)?;
+    // The stats come from the same mapping as the signature is checked over.
+    let file = if args.iter().skip(2).any(|a| a == "--stats") {
+        Some(BkFile::open(&args[1], true)?)
+    } else {
+        None
+    };
+
+    if let Some(i) = args.iter().position(|a| a == "--public-key") {
+        let hex = args
+            .get(i + 1)
+            .ok_or("--public-key needs a hex ed25519 key")?;
+        let key = parse_key(hex)?;
+        match file {
+            Some(ref file) => file.verify_signature(&key)?,
+            None => header.verify_signature(&key)?,
+        }
+        println!("signature ok");
+    }
+
+    if let Some(file) = file {
+        for (i, tree) in file.trees()?.iter().enumerate() {
+            println!("tree {}", i);
+            print!("{}", tree.stats());
+        }
+    }
 
     Ok(())
 }
+
+/// 32 bytes, as 64 hex digits.
+fn parse_key(hex: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("Expected 64 hex digits, not {:?}", hex).into());
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(key)
+}
@@ -1,12 +1,11 @@
 extern crate bkchainsaw;
 
-use serde_json;
 use std::env;
 use std::error::Error;
 use std::fs::File;
+use std::io::Seek;
 use std::io::Write as IOWrite;
 use std::io::{BufReader, BufWriter};
-use std::io::{Seek, SeekFrom};
 
 use bkchainsaw::bkfile;
 
@@ -24,7 +23,7 @@ fn main() -> Result<(), Box<dyn Error + 'static>> {
     writeln!(out, "{}", bkfile::MAGIC_VERSION)?;
     writeln!(out, "{}: {}", bkfile::HASH_HEADER_NAME, args[2])?;
 
-    let pos = out.seek(SeekFrom::Current(0))?;
+    let pos = out.stream_position()?;
     let descr_bytes = descr.encode(pos as usize);
     out.write_all(&descr_bytes[..])?;
     out.flush()?;
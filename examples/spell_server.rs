@@ -0,0 +1,293 @@
+/*
+ * A spelling suggestion server, wiring most of the crate together: a Levenshtein `BkInRamMap`
+ * from words to their frequencies, a `QueryCache` in front of it, similarity scores, and the
+ * `observe` counters.
+ *
+ *   cargo run --example spell_server -- words.tsv --save words.bk
+ *   cargo run --example spell_server -- words.bk --http 127.0.0.1:8080
+ *
+ * The word list has a word per line, optionally followed by a tab and how often it's used.
+ * `--save` writes the map to a string bkfile (`bkfile::write_string_map`), frequencies as the
+ * words' values, and a list ending in ".bk" is read back from one. With no `--http`, words are
+ * read from stdin and answered on stdout, a line each. Over HTTP, ask `GET /suggest?q=wrod` and
+ * get back a JSON array; `GET /stats` reports the counters.
+ */
+extern crate bkchainsaw;
+#[macro_use]
+extern crate serde_derive;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use structopt::StructOpt;
+
+use bkchainsaw::bkfile;
+use bkchainsaw::bkfile_tree;
+use bkchainsaw::bkmap::BkInRamMap;
+use bkchainsaw::bktree::BkTree;
+use bkchainsaw::cache::QueryCache;
+use bkchainsaw::keys::StringKey;
+use bkchainsaw::metric::levenshtein::LevenshteinMetric;
+use bkchainsaw::observe::{self, Counters};
+use bkchainsaw::score::{LengthNormalizer, Normalizer};
+use bkchainsaw::Dist;
+
+type Words = QueryCache<BkInRamMap<StringKey, LevenshteinMetric, u64>, str, String>;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "spell_server", about = "Suggest spellings from a word list")]
+struct CommandLineArgs {
+    /// A word per line, with an optional tab separated frequency, or a bkfile saved by --save.
+    #[structopt(parse(from_os_str))]
+    words_filename: PathBuf,
+
+    #[structopt(
+        long = "save",
+        parse(from_os_str),
+        help = "Write the words and their frequencies to this bkfile"
+    )]
+    save: Option<PathBuf>,
+
+    #[structopt(long = "tolerance", default_value = "2")]
+    tolerance: Dist,
+
+    #[structopt(long = "suggestions", default_value = "5")]
+    suggestions: usize,
+
+    #[structopt(long = "cache", default_value = "10000", help = "Searches to remember")]
+    cache: usize,
+
+    #[structopt(
+        long = "http",
+        help = "Serve HTTP on this address instead of reading stdin"
+    )]
+    http: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Suggestion {
+    word: String,
+    distance: Dist,
+    similarity: f64,
+    frequency: u64,
+}
+
+type Map = BkInRamMap<StringKey, LevenshteinMetric, u64>;
+
+/// A word list, or a bkfile `save` wrote.
+fn load(path: &Path) -> Result<Map, Box<dyn Error>> {
+    let mut words = BkInRamMap::new(LevenshteinMetric);
+    if path.extension().is_some_and(|extension| extension == "bk") {
+        let (tree, frequencies) = bkfile_tree::open_string_map(path, LevenshteinMetric)?;
+        // Parents before children, so the map is the same shape as the tree saved.
+        let mut bad = None;
+        tree.preorder_each(|_, _, word| match frequencies.get(word) {
+            Some(frequency) if frequency.len() == 8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(frequency);
+                words.add(word, u64::from_le_bytes(bytes));
+            }
+            _ => bad = Some(word.clone()),
+        });
+        return match bad {
+            Some(word) => Err(format!("{:?} has no frequency for {:?}", path, word).into()),
+            None => Ok(words),
+        };
+    }
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let mut fields = line.splitn(2, '\t');
+        let word = fields.next().unwrap_or("").trim();
+        if word.is_empty() {
+            continue;
+        }
+        let frequency = match fields.next() {
+            Some(count) => count
+                .trim()
+                .parse()
+                .map_err(|e| format!("Bad frequency on line {}: {}", number + 1, e))?,
+            None => 1,
+        };
+        words.add(word, frequency);
+    }
+    Ok(words)
+}
+
+/// Write `words` as a string bkfile, each word's total frequency as its value.
+fn save(words: &Map, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut frequencies = HashMap::new();
+    words.preorder_each(|_, _, word: &String| {
+        let frequency: u64 = words.get(word).iter().sum();
+        frequencies.insert(word.clone(), frequency.to_le_bytes().to_vec());
+    });
+    bkfile::write_string_map(words, path, &Default::default(), &frequencies)?;
+    Ok(())
+}
+
+/// The closest words, the most used first among those equally close.
+fn suggest(words: &Words, opts: &CommandLineArgs, needle: &str) -> Vec<Suggestion> {
+    let normalizer = LengthNormalizer::default();
+    let mut found: Vec<Suggestion> = words
+        .find(needle, opts.tolerance)
+        .iter()
+        .map(|(distance, word)| Suggestion {
+            word: word.clone(),
+            distance: *distance,
+            similarity: normalizer.similarity(*distance, word.as_str(), needle),
+            frequency: words.tree().get(word).iter().sum(),
+        })
+        .collect();
+    found.sort_by(|a, b| {
+        (a.distance, b.frequency, &a.word).cmp(&(b.distance, a.frequency, &b.word))
+    });
+    found.truncate(opts.suggestions);
+    found
+}
+
+/// `%XX` escapes and `+` for spaces, as browsers send query strings.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.bytes();
+    while let Some(b) = rest.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = rest.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(decoded) => bytes.push(decoded),
+                    None => bytes.extend_from_slice(&hex),
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    words: &Words,
+    counters: &Counters,
+    opts: &CommandLineArgs,
+) -> Result<(), Box<dyn Error>> {
+    let mut request_line = String::new();
+    BufReader::new(&*stream).read_line(&mut request_line)?;
+    let target = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if target == "/stats" {
+        let snapshot = counters.snapshot();
+        let stats = serde_json::json!({
+            "queries": snapshot.queries,
+            "nodes_visited": snapshot.nodes_visited,
+            "cache_hits": words.stats().hits,
+            "cache_misses": words.stats().misses,
+        });
+        ("200 OK", stats.to_string())
+    } else if let Some(query) = target.strip_prefix("/suggest?q=") {
+        let needle = percent_decode(query.split('&').next().unwrap_or(""));
+        let found = suggest(words, opts, &needle);
+        ("200 OK", serde_json::to_string(&found)?)
+    } else {
+        ("404 Not Found", "{}".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+    let opts = CommandLineArgs::from_args();
+    let counters = Arc::new(Counters::new());
+    observe::install(counters.clone())?;
+    let map = load(&opts.words_filename)?;
+    eprintln!("{} words from {} entries", map.node_count, map.value_count);
+    if let Some(ref path) = opts.save {
+        save(&map, path)?;
+    }
+    let words: Words = QueryCache::new(map, opts.cache);
+
+    match opts.http {
+        Some(ref address) => {
+            let listener = TcpListener::bind(address)?;
+            eprintln!("Serving on http://{}", listener.local_addr()?);
+            for stream in listener.incoming() {
+                let mut stream = stream?;
+                if let Err(e) = respond(&mut stream, &words, &counters, &opts) {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        None => {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let needle = line?;
+                let found = suggest(&words, &opts, needle.trim());
+                let listed: Vec<String> = found
+                    .iter()
+                    .map(|s| format!("{}:{}:{}", s.word, s.distance, s.frequency))
+                    .collect();
+                println!("{}\t{}", needle.trim(), listed.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggested(map: Map, needle: &str) -> Vec<(String, Dist, u64)> {
+        let opts = CommandLineArgs {
+            words_filename: PathBuf::new(),
+            save: None,
+            tolerance: 2,
+            suggestions: 5,
+            cache: 10,
+            http: None,
+        };
+        let words: Words = QueryCache::new(map, opts.cache);
+        suggest(&words, &opts, needle)
+            .into_iter()
+            .map(|s| (s.word, s.distance, s.frequency))
+            .collect()
+    }
+
+    #[test]
+    fn suggests_from_lists_and_saved_bkfiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("words.tsv");
+        std::fs::write(
+            &list,
+            "word\t10\nwork\t30\nworld\t5\nsword\nword\t2\n\nzebra\t7\n",
+        )
+        .unwrap();
+        let expected = vec![
+            ("work".to_string(), 1, 30),
+            ("word".to_string(), 1, 12),
+            ("world".to_string(), 2, 5),
+            ("sword".to_string(), 2, 1),
+        ];
+        assert_eq!(expected, suggested(load(&list).unwrap(), "wor"));
+
+        let saved = dir.path().join("words.bk");
+        save(&load(&list).unwrap(), &saved).unwrap();
+        let reloaded = load(&saved).unwrap();
+        assert_eq!(5, reloaded.node_count);
+        assert_eq!(expected, suggested(reloaded, "wor"));
+
+        assert_eq!("wr d%", percent_decode("wr+d%25"));
+    }
+}
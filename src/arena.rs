@@ -0,0 +1,386 @@
+/*
+ * Arena allocated trees, for building millions of nodes without a trip to the global allocator
+ * for each.
+ *
+ * Nodes live in a `BkArenaAllocator` that outlives the tree, and are freed all together when it
+ * is dropped. Each node links to its first child and its next sibling rather than owning a table
+ * of children, so a node costs one bump of the arena and nothing more.
+ *
+ *   let arena = BkArenaAllocator::new();
+ *   let mut tree: BkArenaTree<U64Key, HammingMetric<u64>> = BkArenaTree::new(metric, &arena);
+ *   tree.add(&key)?;
+ *
+ * Arena memory is only reclaimed with the arena: removing keys leaves tombstones as usual, and
+ * their nodes stay put. Suited to trees built once and then searched, like the ones written out
+ * to bkfiles.
+ */
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use typed_arena::Arena;
+
+use crate::bk::BkFind;
+use crate::bknode::{BkNode, BkNodeMut};
+use crate::bktree::{BkTree, BkTreeRootMut};
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::nodeallocator::NodeAllocator;
+use crate::observe;
+use crate::Dist;
+
+struct ArenaNode<'a, K> {
+    key: K,
+    /// Distance from the parent: the child slot this node fills.
+    dist: Dist,
+    /// Children are a list, furthest first, threaded through `next_sibling`. Nothing in a node
+    /// needs dropping or a heap of its own.
+    first_child: Option<ArenaRef<'a, K>>,
+    next_sibling: Option<ArenaRef<'a, K>>,
+    tombstone: bool,
+    flags: u8,
+}
+
+/// A node in a `BkArenaAllocator`. What `BkArenaTree`s are made of.
+pub struct ArenaRef<'a, K>(&'a mut ArenaNode<'a, K>);
+
+impl<'a, K> ArenaRef<'a, K> {
+    fn children(&self) -> Children<'_, 'a, K> {
+        Children(self.0.first_child.as_ref())
+    }
+}
+
+struct Children<'s, 'a, K>(Option<&'s ArenaRef<'a, K>>);
+
+impl<'s, 'a, K> Iterator for Children<'s, 'a, K> {
+    type Item = &'s ArenaRef<'a, K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let child = self.0?;
+        self.0 = child.0.next_sibling.as_ref();
+        Some(child)
+    }
+}
+
+impl<'a, K> BkNode for ArenaRef<'a, K> {
+    type Key = K;
+
+    fn key(&self) -> &K {
+        &self.0.key
+    }
+
+    fn has_child_at(&self, dist: Dist) -> bool {
+        self.child_at(dist).is_some()
+    }
+
+    fn child_at(&self, dist: Dist) -> Option<&Self> {
+        self.children()
+            .take_while(|child| child.0.dist >= dist)
+            .find(|child| child.0.dist == dist)
+    }
+
+    fn children_vector(&self) -> Vec<(Dist, &Self)> {
+        self.children().map(|child| (child.0.dist, child)).collect()
+    }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        for child in self.children() {
+            f(child.0.dist, child);
+        }
+    }
+
+    fn max_child_dist(&self) -> Option<Dist> {
+        self.0.first_child.as_ref().map(|child| child.0.dist)
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.0.tombstone
+    }
+
+    fn flags(&self) -> u8 {
+        self.0.flags
+    }
+}
+
+impl<'a, K> BkNodeMut for ArenaRef<'a, K> {
+    fn set_child_node(&mut self, dist: Dist, node: Self) {
+        let mut cursor = &mut self.0.first_child;
+        while cursor.as_ref().is_some_and(|child| child.0.dist > dist) {
+            cursor = &mut cursor.as_mut().unwrap().0.next_sibling;
+        }
+        assert!(cursor.as_ref().is_none_or(|child| child.0.dist != dist));
+        node.0.dist = dist;
+        node.0.next_sibling = cursor.take();
+        *cursor = Some(node);
+    }
+
+    fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self> {
+        let mut cursor = self.0.first_child.as_mut();
+        while let Some(child) = cursor {
+            if child.0.dist <= dist {
+                return if child.0.dist == dist {
+                    Some(child)
+                } else {
+                    None
+                };
+            }
+            cursor = child.0.next_sibling.as_mut();
+        }
+        None
+    }
+
+    fn set_tombstone(&mut self, tombstone: bool) {
+        self.0.tombstone = tombstone;
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.0.flags = flags;
+    }
+}
+
+impl<'a, K: Debug> Debug for ArenaRef<'a, K> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let children = self.children_vector();
+        f.debug_map().entry(&self.0.key, &children).finish()
+    }
+}
+
+/// Hands out nodes from an arena. Unlike `U64_ALLOC` and friends it holds the nodes, so each
+/// tree (or set of trees dropped together) needs its own.
+pub struct BkArenaAllocator<'a, K> {
+    arena: Arena<ArenaNode<'a, K>>,
+}
+
+impl<'a, K> BkArenaAllocator<'a, K> {
+    pub fn new() -> Self {
+        BkArenaAllocator {
+            arena: Arena::new(),
+        }
+    }
+
+    /// Room for `nodes` nodes before the arena grows.
+    pub fn with_capacity(nodes: usize) -> Self {
+        BkArenaAllocator {
+            arena: Arena::with_capacity(nodes),
+        }
+    }
+
+    /// Nodes handed out so far.
+    pub fn len(&self) -> usize {
+        self.arena.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arena.len() == 0
+    }
+
+    fn alloc(&'a self, key: K) -> ArenaRef<'a, K> {
+        ArenaRef(self.arena.alloc(ArenaNode {
+            key,
+            dist: 0,
+            first_child: None,
+            next_sibling: None,
+            tombstone: false,
+            flags: 0,
+        }))
+    }
+}
+
+impl<'a, K> Default for BkArenaAllocator<'a, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, K> Debug for BkArenaAllocator<'a, K> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BkArenaAllocator")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<'a, K: Clone> NodeAllocator<'a> for BkArenaAllocator<'a, K> {
+    type Key = K;
+    type Node = ArenaRef<'a, K>;
+
+    fn new_root(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(self.alloc(key))
+    }
+
+    fn new_child(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(self.alloc(key))
+    }
+}
+
+/// A BK tree whose nodes live in a `BkArenaAllocator`. Add, remove and search it through the
+/// usual traits.
+pub struct BkArenaTree<'a, KQ: KeyQuery, M> {
+    pub root: Option<ArenaRef<'a, KQ::Key>>,
+    /// Depth of the deepest node, the root's being 0: the same as `TreeStats::max_depth`.
+    pub max_depth: usize,
+    pub node_count: u64,
+    /// Number of nodes in node_count whose keys have been removed.
+    pub tombstone_count: u64,
+    metric: M,
+    node_allocator: &'a BkArenaAllocator<'a, KQ::Key>,
+    observe_id: observe::TreeId,
+}
+
+impl<'a, KQ: KeyQuery, M> BkArenaTree<'a, KQ, M> {
+    pub fn new(metric: M, alloc: &'a BkArenaAllocator<'a, KQ::Key>) -> Self {
+        BkArenaTree {
+            root: None,
+            max_depth: 0,
+            node_count: 0,
+            tombstone_count: 0,
+            metric,
+            node_allocator: alloc,
+            observe_id: observe::TreeId::next(),
+        }
+    }
+}
+
+impl<'a, KQ: KeyQuery, M> Drop for BkArenaTree<'a, KQ, M> {
+    fn drop(&mut self) {
+        observe::tree_dropped(self.observe_id);
+    }
+}
+
+impl<'a, KQ: KeyQuery, M> Debug for BkArenaTree<'a, KQ, M>
+where
+    KQ::Key: Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BkArenaTree")
+            .field("node_count", &self.node_count)
+            .field("tombstone_count", &self.tombstone_count)
+            .field("max_depth", &self.max_depth)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl<'a, K, Q, KQ, M> BkTree<K> for BkArenaTree<'a, KQ, M>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    type KQ = KQ;
+    type Metric = M;
+    type Node = ArenaRef<'a, K>;
+
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &M {
+        &self.metric
+    }
+
+    fn len(&self) -> usize {
+        (self.node_count - self.tombstone_count) as usize
+    }
+
+    fn recorded_counts(&self) -> Option<(u64, usize)> {
+        Some((self.node_count, self.max_depth))
+    }
+
+    fn find_each<'b, F>(&'b self, needle: &'b Q, tolerance: Dist, callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        if let Some(ref root) = self.root {
+            let finder = BkFind::new(self.max_depth, Some(root), tolerance, needle);
+            let visited = finder.each::<KQ, M, F>(&self.metric, callback);
+            observe::query_served(visited as u64);
+        }
+    }
+}
+
+impl<'a, K, Q, KQ, M> BkTreeRootMut<'a, K> for BkArenaTree<'a, KQ, M>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    type Alloc = BkArenaAllocator<'a, K>;
+
+    fn node_allocator(&mut self) -> &'a Self::Alloc {
+        self.node_allocator
+    }
+
+    fn root_mut(&mut self) -> &mut Option<Self::Node> {
+        &mut self.root
+    }
+
+    fn max_depth_mut(&mut self) -> &mut usize {
+        &mut self.max_depth
+    }
+
+    fn incr_node_count(&mut self) {
+        self.node_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn incr_tombstone_count(&mut self) {
+        self.tombstone_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn decr_tombstone_count(&mut self) {
+        self.tombstone_count -= 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    /// The nodes stay in the arena until it's dropped.
+    fn clear(&mut self) {
+        self.root = None;
+        self.max_depth = 0;
+        self.node_count = 0;
+        self.tombstone_count = 0;
+        observe::tree_size(self.observe_id, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+    use crate::bktree::{BkTreeAdd, BkTreeRemove};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn arena_trees_match_in_ram_trees() {
+        let arena = BkArenaAllocator::with_capacity(1000);
+        let mut tree: BkArenaTree<U64Key, HammingMetric<u64>> =
+            BkArenaTree::new(Default::default(), &arena);
+        let (keys, in_ram) = hashed_tree(1000);
+        for key in &keys {
+            tree.add(key).unwrap();
+        }
+        tree.add(&0).unwrap();
+        assert_eq!((1000, 1000), (tree.node_count, arena.len() as u64));
+        assert!(tree.same_keys(&in_ram));
+
+        assert!(tree.remove(&keys[1]));
+        assert_eq!(1, tree.tombstone_count);
+        let needle = keys[1] ^ 0b11;
+        assert_eq!(Vec::<(Dist, u64)>::new(), tree.find_knn(&needle, 1, 2));
+        let mut found = Vec::new();
+        tree.find_each(&needle, 24, |d, k| found.push((d, *k)));
+        let mut expected = Vec::new();
+        in_ram.find_each(&needle, 24, |d, k| expected.push((d, *k)));
+        expected.retain(|(_, k)| *k != keys[1]);
+        found.sort();
+        expected.sort();
+        assert_eq!(expected, found);
+    }
+}
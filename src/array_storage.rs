@@ -4,7 +4,6 @@
  *
  * All multi byte entities are stored little endian.
 */
-use std::cell::RefCell;
 use std::error::Error;
 
 use byteorder::{ByteOrder, LittleEndian};
@@ -18,14 +17,13 @@ pub trait InStorageNode {
     fn children_offset(&self) -> Option<usize>;
     fn key_offset(&self) -> Option<usize>;
     fn key_length(&self) -> Option<usize>;
-    // fn key_bytes(&self) -> Option<&'a [u8]>;
 }
 
 type NodeMutationResult = Result<(), Box<dyn Error>>;
 
 pub trait InStorageNodeMut: InStorageNode {
-    type Key;
-    fn set_key(&mut self, key: Self::Key) -> NodeMutationResult;
+    type Key: ?Sized;
+    fn set_key(&mut self, key: &Self::Key) -> NodeMutationResult;
     fn set_dist(&mut self, dist: Dist) -> NodeMutationResult;
     fn set_num_children(&mut self, n: usize) -> NodeMutationResult;
     fn set_child_offset(&mut self, child_offset: usize) -> NodeMutationResult;
@@ -49,32 +47,37 @@ pub trait InStorageNodeMut: InStorageNode {
  * in the same order as VBNode16 instances.
 */
 #[derive(Clone)]
-struct VBNode16<'a> {
-    pub node_buffer: &'a [u8],
-    pub key_buffer: &'a [u8],
+pub struct VBNode16<B> {
+    /// Must end at the last node, which is how the last node finds the end of its key.
+    pub node_buffer: B,
+    pub key_buffer: B,
     pub offset: usize,
 }
 
-/*
-impl<'a> VBNode16<'a> {
+impl<B: AsRef<[u8]>> VBNode16<B> {
     fn get(&self, offset: usize, len: usize) -> Option<&[u8]> {
-        get_slice(self.node_buffer, self.offset, offset, len)
+        get_slice(self.node_buffer.as_ref(), self.offset, offset, len)
     }
 
     fn key_end(&self) -> Option<usize> {
         // Read the key offset of the next VBNode16 to figure out where our key ends.
         // This is the V part of the name.
-        let next = VBNode16 {
-            offset: self.offset + self.encoding_size(),
-            ..*self
-        };
-        return next.key_offset();
+        let next = self.offset + self.encoding_size();
+        match get_slice(self.node_buffer.as_ref(), next, 4, 4) {
+            Some(offset) => Some(LittleEndian::read_u32(offset) as usize),
+            // Last node in the array.
+            None => Some(self.key_buffer.as_ref().len()),
+        }
+    }
+
+    pub fn key_bytes(&self) -> Option<&[u8]> {
+        self.key_buffer
+            .as_ref()
+            .get(self.key_offset()?..self.key_end()?)
     }
 }
-*/
 
-/*
-impl<'a> InStorageNode<'a> for VBNode16<'a> {
+impl<B: AsRef<[u8]>> InStorageNode for VBNode16<B> {
     fn encoding_size(&self) -> usize {
         12
     }
@@ -83,27 +86,84 @@ impl<'a> InStorageNode<'a> for VBNode16<'a> {
         Some(LittleEndian::read_u16(self.get(0, 2)?) as Dist)
     }
     fn child_count(&self) -> Option<usize> {
-        Some(LittleEndian::read_u16(self.get(2, 2)?) as Dist)
+        Some(LittleEndian::read_u16(self.get(2, 2)?) as usize)
     }
     fn key_offset(&self) -> Option<usize> {
-        Some(LittleEndian::read_u16(self.get(4, 4)?) as Dist)
+        Some(LittleEndian::read_u32(self.get(4, 4)?) as usize)
     }
     fn children_offset(&self) -> Option<usize> {
-        Some(LittleEndian::read_u16(self.get(8, 4)?) as Dist)
+        let offset = LittleEndian::read_u32(self.get(8, 4)?) as usize;
+        if offset > 0 {
+            Some(offset)
+        } else {
+            None
+        }
     }
     fn key_length(&self) -> Option<usize> {
-        Some(self.key_end()? - self.key_offset()?)
+        self.key_end()?.checked_sub(self.key_offset()?)
     }
-    fn key_bytes(&self) -> Option<&'a [u8]> {
-        let start = self.key_offset()?;
-        Some(match self.key_end() {
-            Some(end) => &self.key_buffer[start..end],
-            // Last node in the file.
-            None => &self.key_buffer[start..],
-        })
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> VBNode16<B> {
+    /// Where `set_key` puts the key. Keys must be laid out in node order, each starting where the
+    /// previous node's key ends, as that's how key lengths are recovered.
+    pub fn set_key_offset(&mut self, key_offset: usize) -> NodeMutationResult {
+        if key_offset > u32::MAX as usize {
+            return Err("key offset too large for VBNode16".into());
+        }
+        LittleEndian::write_u32(
+            get_slice_mut(self.node_buffer.as_mut(), self.offset, 4, 4)
+                .ok_or("out of space for key offset")?,
+            key_offset as u32,
+        );
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> InStorageNodeMut for VBNode16<B> {
+    type Key = [u8];
+
+    fn set_key(&mut self, key: &[u8]) -> NodeMutationResult {
+        let start = self.key_offset().ok_or("no key offset")?;
+        get_slice_mut(self.key_buffer.as_mut(), start, 0, key.len())
+            .ok_or("out of space for key")?
+            .copy_from_slice(key);
+        Ok(())
+    }
+    fn set_dist(&mut self, dist: Dist) -> NodeMutationResult {
+        if dist > u16::MAX as Dist {
+            return Err("distance too large for VBNode16".into());
+        }
+        LittleEndian::write_u16(
+            get_slice_mut(self.node_buffer.as_mut(), self.offset, 0, 2)
+                .ok_or("out of space for dist")?,
+            dist as u16,
+        );
+        Ok(())
+    }
+    fn set_num_children(&mut self, n: usize) -> NodeMutationResult {
+        if n > u16::MAX as usize {
+            return Err("too many children for VBNode16".into());
+        }
+        LittleEndian::write_u16(
+            get_slice_mut(self.node_buffer.as_mut(), self.offset, 2, 2)
+                .ok_or("out of space for child count")?,
+            n as u16,
+        );
+        Ok(())
+    }
+    fn set_child_offset(&mut self, offset: usize) -> NodeMutationResult {
+        if offset > u32::MAX as usize {
+            return Err("child offset too large for VBNode16".into());
+        }
+        LittleEndian::write_u32(
+            get_slice_mut(self.node_buffer.as_mut(), self.offset, 8, 4)
+                .ok_or("out of space for child offset")?,
+            offset as u32,
+        );
+        Ok(())
     }
 }
-*/
 
 /**
  * 64 bit keys, 8 bit child counters and distances.
@@ -116,74 +176,55 @@ impl<'a> InStorageNode<'a> for VBNode16<'a> {
  *   * num children: 1 bytes
  *   * padding: 2 bytes (must be 0)
  *   * children offset: 4 bytes
+ *
  *  Total: 8 bytes
  *
  * F64BNode8 key array: adjacent keys at fixed offsets.
 */
 
-pub struct F64BNode8<'a> {
-    pub node_buffer: RefCell<&'a mut [u8]>,
-    pub key_buffer: RefCell<&'a mut [u8]>,
+#[derive(Clone)]
+pub struct F64BNode8<B> {
+    pub node_buffer: B,
+    pub key_buffer: B,
     pub offset: usize,
 }
 
-impl<'a> F64BNode8<'a> {
+impl<B: AsRef<[u8]>> F64BNode8<B> {
     fn key_end(&self) -> Option<usize> {
         let end = self.key_offset()? + self.key_length()?;
-        if end <= self.key_buffer.borrow().len() {
+        if end <= self.key_buffer.as_ref().len() {
             return Some(self.key_offset()? + 8);
         }
-        return None;
+        None
     }
-    fn key(&self) -> Option<u64> {
+    pub fn key(&self) -> Option<u64> {
         let start = self.key_offset()?;
         match self.key_end() {
             Some(end) => Some(LittleEndian::read_u64(
-                &self.key_buffer.borrow()[start..end],
+                &self.key_buffer.as_ref()[start..end],
             )),
             // Last node in the file. Shouldn't happen for this type, unless the key buffer was truncated.
             None => panic!("Key buffer appears to have been truncated!"),
         }
     }
-
-    fn next_node(self) -> F64BNode8<'a> {
-        F64BNode8 {
-            offset: self.offset + self.encoding_size(),
-            ..self
-        }
-    }
-
-    fn prev_node(self) -> F64BNode8<'a> {
-        F64BNode8 {
-            offset: self.offset - self.encoding_size(),
-            ..self
-        }
-    }
-
-    fn first_child(self) -> Option<F64BNode8<'a>> {
-        Some(F64BNode8 {
-            offset: self.children_offset()?,
-            ..self
-        })
-    }
 }
 
-impl<'a> InStorageNode for F64BNode8<'a> {
+impl<B: AsRef<[u8]>> InStorageNode for F64BNode8<B> {
     fn encoding_size(&self) -> usize {
         8
     }
 
     fn dist(&self) -> Option<Dist> {
         //Some(LittleEndian::read_u8(self.get(0, 1)?) as Dist)
-        Some(get_slice(&self.node_buffer.borrow(), self.offset, 0, 1)?[0] as Dist)
+        Some(get_slice(self.node_buffer.as_ref(), self.offset, 0, 1)?[0] as Dist)
     }
     fn child_count(&self) -> Option<usize> {
         //Some(LittleEndian::read_u8(self.get(1, 1)?) as Dist)
-        Some(get_slice(&self.node_buffer.borrow(), self.offset, 1, 1)?[0] as Dist)
+        Some(get_slice(self.node_buffer.as_ref(), self.offset, 1, 1)?[0] as Dist)
     }
     fn children_offset(&self) -> Option<usize> {
         let offset =
-            LittleEndian::read_u16(get_slice(&self.node_buffer.borrow(), self.offset, 4, 4)?)
+            LittleEndian::read_u32(get_slice(self.node_buffer.as_ref(), self.offset, 4, 4)?)
                 as Dist;
         if offset > 0 {
             Some(offset)
@@ -200,37 +241,35 @@ impl<'a> InStorageNode for F64BNode8<'a> {
     }
 }
 
-impl<'a> InStorageNodeMut for F64BNode8<'a> {
+impl<B: AsRef<[u8]> + AsMut<[u8]>> InStorageNodeMut for F64BNode8<B> {
     type Key = u64;
 
-    fn set_key(&mut self, key: u64) -> NodeMutationResult {
+    fn set_key(&mut self, key: &u64) -> NodeMutationResult {
+        let start = self.key_offset().ok_or("no key offset")?;
         LittleEndian::write_u64(
-            get_slice_mut(
-                &mut self.key_buffer.borrow_mut(),
-                self.key_offset().ok_or("no key offset")?,
-                0,
-                8,
-            )
-            .ok_or("out of space for key")?,
-            key,
+            get_slice_mut(self.key_buffer.as_mut(), start, 0, 8).ok_or("out of space for key")?,
+            *key,
         );
         Ok(())
     }
     fn set_dist(&mut self, dist: Dist) -> NodeMutationResult {
-        get_slice_mut(&mut self.node_buffer.borrow_mut(), self.offset, 0, 1)
+        get_slice_mut(self.node_buffer.as_mut(), self.offset, 0, 1)
             .ok_or("out of space for dist")?[0] = dist as u8;
         Ok(())
     }
     fn set_num_children(&mut self, n: usize) -> NodeMutationResult {
-        get_slice_mut(&mut self.node_buffer.borrow_mut(), self.offset, 1, 1)
+        get_slice_mut(self.node_buffer.as_mut(), self.offset, 1, 1)
             .ok_or("out of space for child count")?[0] = n as u8;
         Ok(())
     }
     fn set_child_offset(&mut self, offset: usize) -> NodeMutationResult {
-        LittleEndian::write_u16(
-            get_slice_mut(&mut self.node_buffer.borrow_mut(), self.offset, 4, 4)
+        if offset > u32::MAX as usize {
+            return Err("child offset too large for F64BNode8".into());
+        }
+        LittleEndian::write_u32(
+            get_slice_mut(self.node_buffer.as_mut(), self.offset, 4, 4)
                 .ok_or("out of space for child offset")?,
-            offset as u16,
+            offset as u32,
         );
         Ok(())
     }
@@ -247,7 +286,7 @@ fn get_slice<'a: 'b, 'b>(
     if buf.len() >= end {
         return Some(&buf[start..end]);
     }
-    return None;
+    None
 }
 
 fn get_slice_mut<'a: 'b, 'b>(
@@ -261,18 +300,17 @@ fn get_slice_mut<'a: 'b, 'b>(
     if buf.len() >= end {
         return Some(&mut buf[start..end]);
     }
-    return None;
+    None
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    /*
     #[test]
     fn single_vbnode16() {
-        let nodes = &[8, 0, 5, 0, 1, 0, 0, 0, 7, 0, 0, 0];
-        let keys = &[0, 1, 2, 3, 4, 5, 6, 7];
+        let nodes: &[u8] = &[8, 0, 5, 0, 1, 0, 0, 0, 7, 0, 0, 0];
+        let keys: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7];
         let node = VBNode16 {
             offset: 0,
             node_buffer: nodes,
@@ -287,10 +325,10 @@ mod test {
 
     #[test]
     fn two_vbnode16() {
-        let nodes = &[
+        let nodes: &[u8] = &[
             8, 0, 5, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0,
         ];
-        let keys = &[0, 1, 2, 3, 4, 5, 6, 7];
+        let keys: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7];
         {
             let node = VBNode16 {
                 offset: 0,
@@ -309,7 +347,47 @@ mod test {
             assert_eq!(Some(&keys[4..8]), node.key_bytes());
         }
     }
-    */
+
+    #[test]
+    fn write_vbnode16() {
+        let mut nodes = [0u8; 36];
+        let mut keys = [0u8; 13];
+        let words: &[&[u8]] = &[b"kitten", b"", b"sitting"];
+        let mut key_offset = 0;
+        for (i, word) in words.iter().enumerate() {
+            let mut node = VBNode16 {
+                offset: 12 * i,
+                node_buffer: &mut nodes[..],
+                key_buffer: &mut keys[..],
+            };
+            node.set_dist(300 * i).unwrap();
+            node.set_num_children(if i == 0 { 2 } else { 0 }).unwrap();
+            node.set_child_offset(if i == 0 { 12 } else { 0 }).unwrap();
+            node.set_key_offset(key_offset).unwrap();
+            node.set_key(word).unwrap();
+            key_offset += word.len();
+        }
+
+        for (i, word) in words.iter().enumerate() {
+            let node = VBNode16 {
+                offset: 12 * i,
+                node_buffer: &nodes[..],
+                key_buffer: &keys[..],
+            };
+            assert_eq!(Some(300 * i), node.dist());
+            assert_eq!(Some(word.len()), node.key_length());
+            assert_eq!(Some(*word), node.key_bytes());
+        }
+        let mut node = VBNode16 {
+            offset: 0,
+            node_buffer: &mut nodes[..],
+            key_buffer: &mut keys[..],
+        };
+        assert_eq!(Some(12), node.children_offset());
+        assert!(node.set_dist(1 << 16).is_err());
+        node.set_key_offset(10).unwrap();
+        assert!(node.set_key(b"kitten").is_err());
+    }
 
     #[test]
     fn single_f64bnode8() {
@@ -317,14 +395,14 @@ mod test {
         let keys = &[0, 1, 2, 3, 4, 5, 6, 7];
         let node = F64BNode8 {
             offset: 0,
-            node_buffer: RefCell::new(nodes),
-            key_buffer: RefCell::new(keys),
+            node_buffer: &nodes[..],
+            key_buffer: &keys[..],
         };
         assert_eq!(Some(8), node.dist());
         assert_eq!(Some(5), node.child_count());
         assert_eq!(Some(0), node.key_offset());
         assert_eq!(Some(1), node.children_offset());
-        assert_eq!(Some(&keys[0..8]), node.key_bytes());
+        assert_eq!(Some(0x0706050403020100), node.key());
     }
 
     #[test]
@@ -334,26 +412,26 @@ mod test {
         {
             let node = F64BNode8 {
                 offset: 0,
-                node_buffer: RefCell::new(nodes),
-                key_buffer: RefCell::new(keys),
+                node_buffer: &nodes[..],
+                key_buffer: &keys[..],
             };
             assert_eq!(Some(8), node.dist());
             assert_eq!(Some(5), node.child_count());
             assert_eq!(Some(0), node.key_offset());
             assert_eq!(Some(1), node.children_offset());
-            assert_eq!(Some(&keys[0..8]), node.key_bytes());
+            assert_eq!(Some(0x0706050403020100), node.key());
         }
         {
             let node = F64BNode8 {
                 offset: 8,
-                node_buffer: RefCell::new(nodes),
-                key_buffer: RefCell::new(keys),
+                node_buffer: &nodes[..],
+                key_buffer: &keys[..],
             };
             assert_eq!(Some(4), node.dist());
             assert_eq!(Some(3), node.child_count());
             assert_eq!(Some(8), node.key_offset());
             assert_eq!(None, node.children_offset());
-            assert_eq!(Some(&keys[8..16]), node.key_bytes());
+            assert_eq!(Some(0x0f0e0d0c0b0a0908), node.key());
         }
     }
 }
@@ -2,47 +2,185 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Formatter;
+use std::iter::FromIterator;
 use std::marker::PhantomData;
+use std::mem;
 use std::option::Option;
 use std::vec::Vec;
 
+use smallvec::SmallVec;
+
 use crate::bknode::{BkNode, BkNodeMut};
-use crate::bktree::{BkTree, BkTreeAdd, BkTreeRootMut};
+use crate::bktree::{self, BkTree, BkTreeAdd, BkTreeRootMut};
+use crate::build;
+use crate::build::BuildReport;
 use crate::keyquery::KeyQuery;
+use crate::keys::HeapBytes;
 use crate::metric::Metric;
 
 use crate::nodeallocator::NodeAllocator;
+use crate::observe;
+use crate::stats::{CompactionReport, Fragmentation, QueryStats, TreeStats};
 use crate::Dist;
 
+/// How a `BkInRam` holds its children.
+enum Children<K> {
+    /// Indexed by distance. Fast, but a child at distance d costs d slots.
+    Dense(Vec<Option<BkInRam<K>>>),
+    /// Sorted by distance. Pays a binary search per lookup to store only the children present.
+    Sparse(Vec<(Dist, BkInRam<K>)>),
+}
+
 /// BK tree node optimised for small distances.
-/// TODO: consider feature(const_generics) to drop the vec overhead, once that's stable.
-/// (https://github.com/rust-lang/rust/issues/44580)
+/// `fixed::BkInRamFixed` drops the vec overhead, for metrics with a known `max_distance`.
+///
+/// Metrics with large distances (Levenshtein over long strings) leave most of a dense child table
+/// empty; `new_sparse` nodes store only the children they have. Pick one for a whole tree through
+/// its allocator: `BkInRamAllocator` or `BkSparseInRamAllocator`.
 pub struct BkInRam<K> {
     pub key: K,
-    children: Vec<Option<Self>>,
+    children: Children<K>,
+    tombstone: bool,
+    flags: u8,
+}
+
+/// A node's children, furthest first.
+pub enum ChildrenIter<'a, K> {
+    Dense(std::iter::Rev<std::iter::Enumerate<std::slice::Iter<'a, Option<BkInRam<K>>>>>),
+    Sparse(std::iter::Rev<std::slice::Iter<'a, (Dist, BkInRam<K>)>>),
+}
+
+impl<'a, K> Iterator for ChildrenIter<'a, K> {
+    type Item = (Dist, &'a BkInRam<K>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            ChildrenIter::Dense(children) => {
+                for (dist, child) in children {
+                    if let Some(child) = child {
+                        return Some((dist, child));
+                    }
+                }
+                None
+            }
+            ChildrenIter::Sparse(children) => children.next().map(|(dist, child)| (*dist, child)),
+        }
+    }
 }
 
 impl<K> BkInRam<K> {
     pub fn new(key: K) -> BkInRam<K> {
         BkInRam {
-            key: key,
-            children: Vec::with_capacity(16),
+            key,
+            children: Children::Dense(Vec::with_capacity(16)),
+            tombstone: false,
+            flags: 0,
+        }
+    }
+
+    /// A node storing only the children it has. See `BkSparseInRamAllocator`.
+    pub fn new_sparse(key: K) -> BkInRam<K> {
+        BkInRam {
+            key,
+            children: Children::Sparse(Vec::new()),
+            tombstone: false,
+            flags: 0,
+        }
+    }
+
+    pub fn children_iter(&self) -> ChildrenIter<'_, K> {
+        // Find here looks at the last child first, and things play nicer if the closest is first.
+        match self.children {
+            // The dense representation stores the distance to the child implicitly as the index
+            // into the child vector.
+            Children::Dense(ref children) => ChildrenIter::Dense(children.iter().enumerate().rev()),
+            Children::Sparse(ref children) => ChildrenIter::Sparse(children.iter().rev()),
+        }
+    }
+
+    /// Heap bytes taken by this node's child table, not counting the children's own.
+    pub fn children_bytes(&self) -> usize {
+        match self.children {
+            Children::Dense(ref children) => {
+                children.capacity() * std::mem::size_of::<Option<Self>>()
+            }
+            Children::Sparse(ref children) => {
+                children.capacity() * std::mem::size_of::<(Dist, Self)>()
+            }
+        }
+    }
+
+    /// Detach the child at `dist`, e.g. to move its subtree elsewhere.
+    pub fn take_child(&mut self, dist: Dist) -> Option<Self> {
+        match self.children {
+            Children::Dense(ref mut children) => {
+                let child = children.get_mut(dist)?.take();
+                // Keep the last slot filled, as max_child_dist expects.
+                while let Some(None) = children.last() {
+                    children.pop();
+                }
+                child
+            }
+            Children::Sparse(ref mut children) => {
+                match children.binary_search_by_key(&dist, |(d, _)| *d) {
+                    Ok(i) => Some(children.remove(i).1),
+                    Err(_) => None,
+                }
+            }
+        }
+    }
+
+    /// Give back child table slots the node has no children for.
+    fn shrink_children(&mut self) {
+        match self.children {
+            Children::Dense(ref mut children) => children.shrink_to_fit(),
+            Children::Sparse(ref mut children) => children.shrink_to_fit(),
+        }
+    }
+
+    /// Add this node's child table to `frag`.
+    fn measure_children(&self, frag: &mut Fragmentation) {
+        // (slots allocated, slots filled, slots holding children, bytes per slot)
+        let (capacity, len, used, slot_bytes) = match self.children {
+            Children::Dense(ref children) => (
+                children.capacity(),
+                children.len(),
+                children.iter().filter(|c| c.is_some()).count(),
+                mem::size_of::<Option<Self>>(),
+            ),
+            Children::Sparse(ref children) => (
+                children.capacity(),
+                children.len(),
+                children.len(),
+                mem::size_of::<(Dist, Self)>(),
+            ),
+        };
+        let tombstones = self.children_iter().filter(|(_, c)| c.tombstone).count();
+        frag.dead_slots += (len - used) as u64;
+        frag.spare_slots += (capacity - len) as u64;
+        frag.bytes += capacity * slot_bytes;
+        frag.wasted_bytes += (capacity - used + tombstones) * slot_bytes;
+    }
+
+    /// Drop the node and everything under it without recursing. See `BkInRamTree`'s `Drop`.
+    fn drop_subtree(self) {
+        let mut stack = vec![self];
+        while let Some(mut node) = stack.pop() {
+            node.take_children(&mut stack);
         }
     }
 
-    pub fn children_iter(&self) -> impl Iterator<Item = (Dist, &Self)> {
-        self.children
-            .iter()
-            // This implementation stores the distance to the child implicitly as the index into
-            // the child vector.
-            .enumerate()
-            .filter(|(_, child)| child.is_some())
-            .map(|(dist, child)| (dist.into(), child.as_ref().unwrap()))
-            .rev() // Find here looks at the last child first, and things play nicer if the closest is first.
+    /// Move the children onto `out`, leaving the node childless. For tearing trees down without
+    /// recursing.
+    fn take_children(&mut self, out: &mut Vec<Self>) {
+        match self.children {
+            Children::Dense(ref mut children) => out.extend(children.drain(..).flatten()),
+            Children::Sparse(ref mut children) => out.extend(children.drain(..).map(|(_, c)| c)),
+        }
     }
 }
 
-impl<'a, K> BkNode for BkInRam<K> {
+impl<K> BkNode for BkInRam<K> {
     type Key = K;
 
     fn key(&self) -> &Self::Key {
@@ -50,39 +188,101 @@ impl<'a, K> BkNode for BkInRam<K> {
     }
 
     fn has_child_at(&self, dist: Dist) -> bool {
-        let child: Option<&Option<Self>> = self.children.get(dist);
-        match child {
-            None | Some(None) => false,
-            Some(_) => true,
-        }
+        self.child_at(dist).is_some()
     }
 
     fn child_at(&self, dist: Dist) -> Option<&Self> {
-        match self.children.get(dist) {
-            None | Some(None) => None,
-            Some(child @ Some(_)) => child.as_ref(),
+        match self.children {
+            Children::Dense(ref children) => match children.get(dist) {
+                None | Some(None) => None,
+                Some(child @ Some(_)) => child.as_ref(),
+            },
+            Children::Sparse(ref children) => children
+                .binary_search_by_key(&dist, |(d, _)| *d)
+                .ok()
+                .map(|i| &children[i].1),
         }
     }
 
     fn children_vector(&self) -> Vec<(Dist, &Self)> {
         self.children_iter().collect()
     }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        for (dist, child) in self.children_iter() {
+            f(dist, child);
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    fn max_child_dist(&self) -> Option<Dist> {
+        match self.children {
+            // The vector only grows to fit a new child, and take_child trims empty slots.
+            Children::Dense(ref children) => children.len().checked_sub(1),
+            Children::Sparse(ref children) => children.last().map(|(dist, _)| *dist),
+        }
+    }
+
+    /// Dense tables end with a child, as `max_child_dist` relies on; sparse ones are sorted by
+    /// slot, with no slot twice, as `child_at`'s binary search relies on.
+    fn children_well_formed(&self) -> bool {
+        match self.children {
+            Children::Dense(ref children) => !matches!(children.last(), Some(None)),
+            Children::Sparse(ref children) => children.windows(2).all(|w| w[0].0 < w[1].0),
+        }
+    }
 }
 
-impl<'a, K> BkNodeMut for BkInRam<K> {
+impl<K> BkNodeMut for BkInRam<K> {
     fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self> {
-        match self.children.get_mut(dist) {
-            None | Some(None) => None,
-            Some(child @ Some(_)) => child.as_mut(),
+        match self.children {
+            Children::Dense(ref mut children) => match children.get_mut(dist) {
+                None | Some(None) => None,
+                Some(child @ Some(_)) => child.as_mut(),
+            },
+            Children::Sparse(ref mut children) => {
+                match children.binary_search_by_key(&dist, |(d, _)| *d) {
+                    Ok(i) => Some(&mut children[i].1),
+                    Err(_) => None,
+                }
+            }
         }
     }
 
     fn set_child_node(&mut self, dist: Dist, node: Self) {
-        if self.children.len() <= dist {
-            self.children.resize_with(dist + 1, || None);
+        match self.children {
+            Children::Dense(ref mut children) => {
+                if children.len() <= dist {
+                    children.resize_with(dist + 1, || None);
+                }
+                assert!(children[dist].is_none());
+                children[dist] = Some(node);
+            }
+            Children::Sparse(ref mut children) => {
+                match children.binary_search_by_key(&dist, |(d, _)| *d) {
+                    Ok(_) => panic!("a child is already at distance {}", dist),
+                    Err(i) => children.insert(i, (dist, node)),
+                }
+            }
         }
-        assert!(!self.has_child_at(dist));
-        self.children[dist] = Some(node);
+    }
+
+    fn set_tombstone(&mut self, tombstone: bool) {
+        self.tombstone = tombstone;
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
     }
 }
 
@@ -91,12 +291,8 @@ where
     K: Debug,
 {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let children: Vec<_> = self
-            .children
-            .iter()
-            .enumerate()
-            .filter(|(_, x)| x.is_some())
-            .collect();
+        let mut children = self.children_vector();
+        children.reverse();
         f.debug_map().entry(&self.key, &children).finish()
     }
 }
@@ -107,6 +303,22 @@ pub struct BkInRamAllocator<'a, K>(#[derivative(Debug = "ignore")] PhantomData<&
 // The PhantomData above is misrepresenting 'a. It's the lifetime of the nodes, not the lifetime
 // of the keys of the nodes.
 
+impl<'a, K> BkInRamAllocator<'a, K> {
+    /// For key types without a ready-made allocator constant, e.g. `ArrayKey<N>`.
+    pub const fn new() -> Self {
+        BkInRamAllocator(PhantomData)
+    }
+
+    /// An allocator to borrow for 'static, for any key type.
+    pub(crate) const SHARED: Self = Self::new();
+}
+
+impl<'a, K> Default for BkInRamAllocator<'a, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a, K: Clone> NodeAllocator<'a> for BkInRamAllocator<'a, K> {
     type Key = K;
     type Node = BkInRam<K>;
@@ -126,6 +338,69 @@ impl<'a, K: Clone> NodeAllocator<'a> for BkInRamAllocator<'a, K> {
 pub const U64_ALLOC: BkInRamAllocator<'static, u64> = BkInRamAllocator(PhantomData);
 pub const STRING_ALLOC: BkInRamAllocator<'static, String> = BkInRamAllocator(PhantomData);
 
+/// `n` keys spread over all 64 bits, and a Hamming tree they were added to in order, for the
+/// tests of u64 trees.
+#[cfg(test)]
+pub(crate) fn hashed_tree(
+    n: u64,
+) -> (
+    Vec<u64>,
+    BkInRamTree<
+        'static,
+        crate::keys::U64Key,
+        crate::metric::hamming::HammingMetric<u64>,
+        BkInRamAllocator<'static, u64>,
+    >,
+) {
+    let keys: Vec<u64> = (0..n).map(|i| i.wrapping_mul(0x9e3779b97f4a7c15)).collect();
+    let mut tree = BkInRamTree::new(Default::default(), &U64_ALLOC);
+    for key in &keys {
+        tree.add(key).unwrap();
+    }
+    (keys, tree)
+}
+
+/// Allocates sparse `BkInRam` nodes, which store only the children they have. Measured with
+/// `bench_children` on 50,000 random words (release build, tolerance 2 searches): child tables
+/// were 11x smaller than dense ones for 10 to 60 letter words, with searches as fast, and 6.5x
+/// smaller for 3 to 9 letter words, with searches about 10% slower.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct BkSparseInRamAllocator<'a, K>(#[derivative(Debug = "ignore")] PhantomData<&'a K>);
+
+impl<'a, K> BkSparseInRamAllocator<'a, K> {
+    pub const fn new() -> Self {
+        BkSparseInRamAllocator(PhantomData)
+    }
+}
+
+impl<'a, K> Default for BkSparseInRamAllocator<'a, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, K: Clone> NodeAllocator<'a> for BkSparseInRamAllocator<'a, K> {
+    type Key = K;
+    type Node = BkInRam<K>;
+
+    fn new_root(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRam::new_sparse(key))
+    }
+
+    fn new_child(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRam::new_sparse(key))
+    }
+}
+
+pub const SPARSE_STRING_ALLOC: BkSparseInRamAllocator<'static, String> =
+    BkSparseInRamAllocator(PhantomData);
+
+/// Holds its nodes, so there's no constant for it: see `arena`.
+pub use crate::arena::BkArenaAllocator;
+
+type Admission<'a, Q> = Box<dyn 'a + Fn(&Q) -> bool + Send + Sync>;
+
 pub struct BkInRamTree<'nodes, KQ, M, A>
 where
     KQ: KeyQuery,
@@ -133,11 +408,19 @@ where
     A: 'nodes + NodeAllocator<'nodes, Node = BkInRam<<KQ as KeyQuery>::Key>>,
 {
     pub root: Option<A::Node>,
+    /// Depth of the deepest node, the root's being 0: the same as `TreeStats::max_depth`.
     pub max_depth: usize,
     pub node_count: u64,
+    /// Number of nodes in node_count whose keys have been removed.
+    pub tombstone_count: u64,
+    /// Number of keys the admission policy has turned away.
+    pub rejected_count: u64,
     metric: M,
     node_allocator: &'nodes A,
-    kq: KQ,
+    kq: PhantomData<KQ>,
+    stats: TreeStats,
+    admission: Option<Admission<'nodes, KQ::Query>>,
+    observe_id: observe::TreeId,
 }
 
 impl<'nodes, K, KQ, M, A> Debug for BkInRamTree<'nodes, KQ, M, A>
@@ -150,27 +433,255 @@ where
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("BkInRamTree")
             .field("node_count", &self.node_count)
+            .field("tombstone_count", &self.tombstone_count)
+            .field("rejected_count", &self.rejected_count)
             .field("max_depth", &self.max_depth)
             .field("root", &self.root)
             .finish()
     }
 }
 
+/// Trees are equal when they hold the same live keys, regardless of insertion order or layout.
+impl<'nodes, K, KQ, M, A> PartialEq for BkInRamTree<'nodes, KQ, M, A>
+where
+    K: Clone,
+    KQ: KeyQuery<Key = K>,
+    M: Metric<<KQ as KeyQuery>::Query>,
+    A: 'nodes + NodeAllocator<'nodes, Node = BkInRam<K>>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.node_count - self.tombstone_count == other.node_count - other.tombstone_count
+            && self.same_keys(other)
+    }
+}
+
+impl<'nodes, KQ, M, A> Drop for BkInRamTree<'nodes, KQ, M, A>
+where
+    KQ: KeyQuery,
+    M: Metric<<KQ as KeyQuery>::Query>,
+    A: 'nodes + NodeAllocator<'nodes, Node = BkInRam<<KQ as KeyQuery>::Key>>,
+{
+    /// Tear the tree down with an explicit stack. The default drop glue recurses once per level,
+    /// which overflows the stack on degenerate (e.g. chain shaped) trees.
+    fn drop(&mut self) {
+        if let Some(root) = self.root.take() {
+            root.drop_subtree();
+        }
+        observe::tree_dropped(self.observe_id);
+    }
+}
+
 impl<'nodes, K, KQ, M, Alloc> BkInRamTree<'nodes, KQ, M, Alloc>
 where
     K: Clone,
     KQ: KeyQuery<Key = K> + Default,
     M: Metric<<KQ as KeyQuery>::Query>,
-    Alloc: 'nodes + NodeAllocator<'nodes, Node = BkInRam<K>>,
+    Alloc: 'nodes + NodeAllocator<'nodes, Key = K, Node = BkInRam<K>>,
 {
     pub fn new(metric: M, alloc: &'nodes Alloc) -> Self {
         BkInRamTree {
             root: None,
             max_depth: 0,
             node_count: 0,
-            metric: metric,
+            tombstone_count: 0,
+            rejected_count: 0,
+            metric,
             node_allocator: alloc,
             kq: Default::default(),
+            stats: TreeStats::new(),
+            admission: None,
+            observe_id: observe::TreeId::next(),
+        }
+    }
+
+    /// Only add keys for which `admission` returns true, e.g. to keep blocklisted values or
+    /// malformed hashes out of the index. Rejected keys are counted in `rejected_count`.
+    pub fn set_admission<F>(&mut self, admission: F)
+    where
+        F: 'nodes + Fn(&KQ::Query) -> bool + Send + Sync,
+    {
+        self.admission = Some(Box::new(admission));
+    }
+
+    /// Space held but not used: tombstones, and empty or spare child table slots. A full
+    /// traversal.
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut frag = Fragmentation::default();
+        let mut stack: Vec<&BkInRam<K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            frag.nodes += 1;
+            if node.tombstone {
+                frag.tombstones += 1;
+            }
+            node.measure_children(&mut frag);
+            stack.extend(node.children_iter().map(|(_, child)| child));
+        }
+        frag
+    }
+
+    /// Roughly how much heap the tree takes: every node's child table, slack included, and what
+    /// the keys own. Allocator overhead isn't counted.
+    pub fn approx_heap_bytes(&self) -> usize
+    where
+        K: HeapBytes,
+    {
+        let mut bytes = 0;
+        let mut stack: Vec<&BkInRam<K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            bytes += node.children_bytes() + node.key.heap_bytes();
+            stack.extend(node.children_iter().map(|(_, child)| child));
+        }
+        bytes
+    }
+
+    /// Trim every child table to the children it has. Dense tables start with room for 16, so
+    /// after a build most of that is slack; adding to the tree afterwards grows them again.
+    pub fn shrink_to_fit(&mut self) {
+        let mut stack: Vec<&mut BkInRam<K>> = self.root.iter_mut().collect();
+        while let Some(node) = stack.pop() {
+            node.shrink_children();
+            match node.children {
+                Children::Dense(ref mut children) => stack.extend(children.iter_mut().flatten()),
+                Children::Sparse(ref mut children) => {
+                    stack.extend(children.iter_mut().map(|(_, c)| c))
+                }
+            }
+        }
+    }
+
+    /// Rebuild the tree from its live keys, purging the tombstones left behind by
+    /// `BkTreeRemove::remove`, and trim every child table to the children it has. Flags are
+    /// kept.
+    pub fn compact(&mut self) -> Result<CompactionReport, Box<dyn Error>> {
+        let before = self.fragmentation();
+        let keys = self.take_live_keys();
+        self.add_flagged(&keys)?;
+        self.shrink_to_fit();
+        Ok(CompactionReport {
+            before,
+            after: self.fragmentation(),
+        })
+    }
+
+    /// Rebuild the tree from its live keys in a shuffled order, to undo the depth that adding
+    /// sorted or clustered keys one at a time builds up. Tombstones are purged and flags kept,
+    /// as by `compact`. The shuffle is seeded by the key count, so rebuilds are repeatable; see
+    /// `BkTreeAdd::bulk_build` for other orders.
+    ///
+    /// BK trees are fairly robust to insertion order: on 20k 64 bit keys, sorted and clustered
+    /// batches came out a level or two deeper than shuffled ones, no more.
+    pub fn rebuild(&mut self) -> Result<BuildReport, Box<dyn Error>> {
+        let mut keys = self.take_live_keys();
+        let seed = keys.len() as u64;
+        build::shuffle(&mut keys, seed);
+        self.add_flagged(&keys)?;
+        Ok(BuildReport {
+            added: keys.len() as u64,
+            max_depth: self.max_depth,
+        })
+    }
+
+    /// Empty the tree, returning its live keys and their flags.
+    fn take_live_keys(&mut self) -> Vec<(K, u8)> {
+        let mut keys = Vec::with_capacity((self.node_count - self.tombstone_count) as usize);
+        let mut stack: Vec<BkInRam<K>> = self.root.take().into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            node.take_children(&mut stack);
+            if !node.tombstone {
+                keys.push((node.key, node.flags));
+            }
+        }
+        self.max_depth = 0;
+        self.node_count = 0;
+        self.tombstone_count = 0;
+        self.stats = TreeStats::new();
+        keys
+    }
+
+    /// Re-add keys taken out by `take_live_keys`. They were admitted once, so they skip the
+    /// admission policy, which may have changed since.
+    fn add_flagged(&mut self, keys: &[(K, u8)]) -> Result<(), Box<dyn Error>> {
+        for (key, flags) in keys.iter() {
+            let query = KQ::to_query_static(key);
+            bktree::insert(self, query, false)?;
+            if *flags != 0 {
+                self.set_flags(query, *flags);
+            }
+        }
+        Ok(())
+    }
+
+    /// `BkTreeAdd::merge`, taking `other`'s nodes as they are when this tree is empty rather
+    /// than adding its keys one by one.
+    pub fn absorb(&mut self, mut other: Self) -> Result<(), Box<dyn Error>> {
+        if self.root.is_some() {
+            return self.merge(&other);
+        }
+        self.root = other.root.take();
+        self.max_depth = other.max_depth;
+        self.node_count = other.node_count;
+        self.tombstone_count = other.tombstone_count;
+        self.stats = mem::replace(&mut other.stats, TreeStats::new());
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+        Ok(())
+    }
+
+    /// Recount the shape statistics from scratch, for trees assembled through `root` rather
+    /// than by adding keys.
+    pub fn recount_stats(&mut self) {
+        self.stats = TreeStats::from_tree(&*self);
+    }
+
+    /// The same nodes, measured by `metric` from now on. It must agree with the old metric on
+    /// every key already in the tree, e.g. a plugin the tree was built with, now loaded again.
+    pub fn with_metric<M2>(mut self, metric: M2) -> BkInRamTree<'nodes, KQ, M2, Alloc>
+    where
+        M2: Metric<<KQ as KeyQuery>::Query>,
+    {
+        let tree = BkInRamTree {
+            root: self.root.take(),
+            max_depth: self.max_depth,
+            node_count: self.node_count,
+            tombstone_count: self.tombstone_count,
+            rejected_count: self.rejected_count,
+            metric,
+            node_allocator: self.node_allocator,
+            kq: Default::default(),
+            stats: mem::replace(&mut self.stats, TreeStats::new()),
+            admission: self.admission.take(),
+            observe_id: observe::TreeId::next(),
+        };
+        observe::tree_size(tree.observe_id, tree.node_count, tree.tombstone_count);
+        tree
+    }
+
+    /// Shape statistics, kept up to date as keys are added and removed. Cheap enough to poll.
+    pub fn quick_stats(&self) -> TreeStats {
+        let mut stats = self.stats.clone();
+        stats.tombstones = self.tombstone_count;
+        stats
+    }
+
+    /// Attach `flags` to `key`, replacing any it had. They're passed to
+    /// `BkTree::find_each_flagged` callbacks. Returns false if the key isn't in the tree.
+    pub fn set_flags(&mut self, key: &KQ::Query, flags: u8) -> bool {
+        let mut cur = match self.root.as_mut() {
+            Some(root) => root,
+            None => return false,
+        };
+        loop {
+            if KQ::eq_static(&cur.key, key) {
+                if cur.tombstone {
+                    return false;
+                }
+                cur.flags = flags;
+                return true;
+            }
+            let dist = self.metric.distance(KQ::to_query_static(&cur.key), key);
+            cur = match cur.child_at_mut(dist) {
+                Some(child) => child,
+                None => return false,
+            };
         }
     }
 }
@@ -178,7 +689,7 @@ where
 impl<'nodes, Q, K, KQ, M, Alloc> BkTreeRootMut<'nodes, K> for BkInRamTree<'nodes, KQ, M, Alloc>
 where
     K: Clone,
-    Q: Sized,
+    Q: ?Sized,
     KQ: KeyQuery<Key = K, Query = Q> + Default,
     M: Metric<<KQ as KeyQuery>::Query>,
     Alloc: 'nodes + NodeAllocator<'nodes, Node = BkInRam<K>>,
@@ -186,7 +697,7 @@ where
     type Alloc = Alloc;
 
     fn node_allocator(&mut self) -> &'nodes Self::Alloc {
-        &self.node_allocator
+        self.node_allocator
     }
 
     fn root_mut(&mut self) -> &mut Option<<Self as BkTree<K>>::Node> {
@@ -199,13 +710,55 @@ where
 
     fn incr_node_count(&mut self) {
         self.node_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn incr_tombstone_count(&mut self) {
+        self.tombstone_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn decr_tombstone_count(&mut self) {
+        self.tombstone_count -= 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn clear(&mut self) {
+        if let Some(root) = self.root.take() {
+            root.drop_subtree();
+        }
+        self.max_depth = 0;
+        self.node_count = 0;
+        self.tombstone_count = 0;
+        self.rejected_count = 0;
+        self.stats = TreeStats::new();
+        observe::tree_size(self.observe_id, 0, 0);
+    }
+
+    fn record_insert(&mut self, depth: usize, parent: Option<&BkInRam<K>>) {
+        let parent_children = parent.map(|parent| {
+            let mut children = 0;
+            parent.each_child(|_, _| children += 1);
+            children
+        });
+        self.stats.record_insert(depth, parent_children);
+    }
+
+    fn admit(&mut self, query: &Q) -> bool {
+        match self.admission {
+            Some(ref admission) if !admission(query) => {
+                self.rejected_count += 1;
+                false
+            }
+            _ => true,
+        }
     }
 }
 
 impl<'nodes, K, KQ, M, A, Q> BkTree<K> for BkInRamTree<'nodes, KQ, M, A>
 where
     K: Clone,
-    Q: Sized,
+    Q: ?Sized,
     KQ: KeyQuery<Key = K, Query = Q>,
     M: Metric<Q>,
     A: 'nodes + NodeAllocator<'nodes, Node = BkInRam<K>>,
@@ -214,6 +767,22 @@ where
     type Metric = M;
     type Node = <A as NodeAllocator<'nodes>>::Node;
 
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &Self::Metric {
+        &self.metric
+    }
+
+    fn len(&self) -> usize {
+        (self.node_count - self.tombstone_count) as usize
+    }
+
+    fn recorded_counts(&self) -> Option<(u64, usize)> {
+        Some((self.node_count, self.max_depth))
+    }
+
     fn find_each<'a, F>(
         &'a self,
         needle: &'a <Self::KQ as KeyQuery>::Query,
@@ -224,80 +793,502 @@ where
     {
         if let Some(ref root) = self.root {
             let finder = BkFind::new(self.max_depth, Some(root), tolerance, needle);
-            finder.each::<KQ, M, F>(callback);
+            let visited = finder.each::<KQ, M, F>(&self.metric, callback);
+            observe::query_served(visited as u64);
         }
     }
 }
 
+/// Add every key, as `add` would. Panics if one can't be added, which only happens with
+/// allocators that can fail; `BkInRamAllocator` and `BkSparseInRamAllocator` can't.
+impl<'nodes, K, KQ, M, Alloc> Extend<K> for BkInRamTree<'nodes, KQ, M, Alloc>
+where
+    K: Clone,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<<KQ as KeyQuery>::Query>,
+    Alloc: 'nodes + NodeAllocator<'nodes, Key = K, Node = BkInRam<K>>,
+{
+    fn extend<I: IntoIterator<Item = K>>(&mut self, keys: I) {
+        for key in keys {
+            self.add(KQ::to_query_static(&key))
+                .expect("can't add a key to the tree");
+        }
+    }
+}
+
+/// Collect keys into a tree of dense nodes, measured by the metric's default.
+///
+///   let tree: BkInRamTree<U64Key, HammingMetric<u64>, _> = hashes.into_iter().collect();
+impl<K, KQ, M> FromIterator<K> for BkInRamTree<'static, KQ, M, BkInRamAllocator<'static, K>>
+where
+    K: 'static + Clone,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<<KQ as KeyQuery>::Query> + Default,
+{
+    fn from_iter<I: IntoIterator<Item = K>>(keys: I) -> Self {
+        let mut tree = BkInRamTree::new(M::default(), &BkInRamAllocator::SHARED);
+        tree.extend(keys);
+        tree
+    }
+}
+
+/// The parts of a tree that get serialized: its nodes and counts. The metric, allocator and
+/// admission policy are the program's to supply.
+///
+/// Nodes are a flat list in pre-order, each with its slot and how many children follow it, so
+/// neither saving nor loading recurses: trees of any depth fit any format's recursion limit.
+#[cfg(feature = "serialize")]
+#[derive(Serialize, Deserialize)]
+struct SerialTree<N> {
+    max_depth: usize,
+    node_count: u64,
+    tombstone_count: u64,
+    nodes: N,
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Serialize, Deserialize)]
+struct SerialNode<K> {
+    key: K,
+    /// Distance from the parent; 0 for the root.
+    slot: Dist,
+    children: usize,
+    tombstone: bool,
+    flags: u8,
+}
+
+/// A tree's nodes, serialized as a sequence as `preorder_each` would visit them, tombstones
+/// included.
+#[cfg(feature = "serialize")]
+struct SerialNodes<'t, K>(Option<&'t BkInRam<K>>);
+
+#[cfg(feature = "serialize")]
+impl<'t, K: serde::Serialize> serde::Serialize for SerialNodes<'t, K> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+
+        let mut seq = serializer.serialize_seq(None)?;
+        let mut stack: Vec<(Dist, &BkInRam<K>)> = self.0.into_iter().map(|r| (0, r)).collect();
+        while let Some((slot, node)) = stack.pop() {
+            let before = stack.len();
+            node.each_child(|slot, child| stack.push((slot, child)));
+            seq.serialize_element(&SerialNode {
+                key: &node.key,
+                slot,
+                children: stack.len() - before,
+                tombstone: node.tombstone,
+                flags: node.flags,
+            })?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl<'nodes, K, KQ, M, A> serde::Serialize for BkInRamTree<'nodes, KQ, M, A>
+where
+    K: serde::Serialize,
+    KQ: KeyQuery<Key = K>,
+    M: Metric<<KQ as KeyQuery>::Query>,
+    A: 'nodes + NodeAllocator<'nodes, Node = BkInRam<K>>,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerialTree {
+            max_depth: self.max_depth,
+            node_count: self.node_count,
+            tombstone_count: self.tombstone_count,
+            nodes: SerialNodes(self.root.as_ref()),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Reassemble serialized nodes into a tree's root, without recursing.
+#[cfg(feature = "serialize")]
+fn assemble<K>(nodes: Vec<SerialNode<K>>) -> Result<Option<BkInRam<K>>, String> {
+    let mut root = None;
+    // Nodes whose children are still to come: each with its slot and how many are left.
+    let mut pending: Vec<(BkInRam<K>, Dist, usize)> = Vec::new();
+    for saved in nodes {
+        if root.is_some() {
+            return Err("Nodes follow the root's last descendant".to_string());
+        }
+        let mut node = BkInRam::new(saved.key);
+        node.tombstone = saved.tombstone;
+        node.flags = saved.flags;
+        pending.push((node, saved.slot, saved.children));
+        // Attach every node that has all its children now.
+        while let Some(&(_, _, 0)) = pending.last() {
+            let (node, slot, _) = pending.pop().unwrap();
+            match pending.last_mut() {
+                Some((parent, _, left)) => {
+                    if parent.has_child_at(slot) {
+                        return Err(format!("Two children share slot {}", slot));
+                    }
+                    parent.set_child_node(slot, node);
+                    *left -= 1;
+                }
+                None => root = Some(node),
+            }
+        }
+    }
+    if !pending.is_empty() {
+        return Err("Nodes end before the last one's children".to_string());
+    }
+    Ok(root)
+}
+
+/// A tree of dense nodes, measured by the metric's default, as for `FromIterator`. Trees are
+/// validated as they load, so one saved with a different metric is an error rather than a tree
+/// that misses matches.
+#[cfg(feature = "serialize")]
+impl<'de, K, KQ, M> serde::Deserialize<'de>
+    for BkInRamTree<'static, KQ, M, BkInRamAllocator<'static, K>>
+where
+    K: 'static + Clone + serde::Deserialize<'de>,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<<KQ as KeyQuery>::Query> + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved: SerialTree<Vec<SerialNode<K>>> = SerialTree::deserialize(deserializer)?;
+        let mut tree = BkInRamTree::new(M::default(), &BkInRamAllocator::SHARED);
+        tree.root = assemble(saved.nodes).map_err(serde::de::Error::custom)?;
+        tree.max_depth = saved.max_depth;
+        tree.node_count = saved.node_count;
+        tree.tombstone_count = saved.tombstone_count;
+        tree.validate().map_err(serde::de::Error::custom)?;
+        tree.stats = TreeStats::from_tree(&tree);
+        if tree.stats.tombstones != tree.tombstone_count {
+            return Err(serde::de::Error::custom(format!(
+                "Tree records {} tombstones but has {}",
+                tree.tombstone_count, tree.stats.tombstones
+            )));
+        }
+        observe::tree_size(tree.observe_id, tree.node_count, tree.tombstone_count);
+        Ok(tree)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct BkFindEntry<'n, N: 'n + BkNode> {
     dist: Dist,
     node: &'n N,
+    /// From the search's `FanOut`; lowest is searched first among siblings.
+    priority: Dist,
 }
 
-pub struct BkFind<'q, 'n, Q: 'q, N: 'n>
+/// Orders a node's children for searching. Called with the node's distance from the needle and
+/// a child's slot, and the child with the lowest result is searched first. Only early exit
+/// searches and `find_knn` gain from a good order: full searches visit every candidate anyway.
+pub type FanOut = fn(Dist, Dist) -> Dist;
+
+/// Children whose slot is closest to the node's distance from the needle first: those with the
+/// lowest bound on how far their keys can be.
+pub fn closest_slot_first(node_dist: Dist, slot: Dist) -> Dist {
+    slot.abs_diff(node_dist)
+}
+
+pub struct BkFind<'q, 'n, Q: 'q + ?Sized, N: 'n>
 where
     N: 'n + BkNode,
 {
     tolerance: Dist,
+    /// Matches closer than this aren't reported.
+    min_dist: Dist,
+    /// None to search children in the order the nodes store them.
+    fan_out: Option<FanOut>,
+    /// Most nodes to visit.
+    budget: usize,
+    /// Added to every triangle inequality bound, for distances that break it by up to this.
+    slack: Dist,
     needle: &'q Q,
     root: Option<&'n N>,
-    stack: Vec<BkFindEntry<'n, N>>,
+    // Inline, so narrow queries needn't allocate.
+    stack: SmallVec<[BkFindEntry<'n, N>; 32]>,
 }
 
-impl<'q, 'n, Q: 'q, N: 'n> BkFind<'q, 'n, Q, N>
+impl<'q, 'n, Q: 'q + ?Sized, N: 'n> BkFind<'q, 'n, Q, N>
 where
     N: 'n + BkNode,
 {
     pub fn new(max_depth_hint: usize, root: Option<&'n N>, tolerance: Dist, needle: &'q Q) -> Self {
-        let stack = Vec::with_capacity(max_depth_hint);
+        let stack = SmallVec::with_capacity(max_depth_hint);
         BkFind {
             tolerance,
+            min_dist: 0,
+            fan_out: None,
+            budget: usize::MAX,
+            slack: 0,
             needle,
             root,
             stack,
         }
     }
+
+    /// Only report keys at least `min_dist` from the needle, skipping subtrees where every key
+    /// is closer.
+    pub fn at_least(mut self, min_dist: Dist) -> Self {
+        self.min_dist = min_dist;
+        self
+    }
+
+    /// Search each node's children in the order `fan_out` gives them.
+    pub fn ordered_by(mut self, fan_out: FanOut) -> Self {
+        self.fan_out = Some(fan_out);
+        self
+    }
+
+    /// Widen every bound the search prunes by by `slack`, for distances that aren't quite
+    /// metrics: where d(a, c) may exceed d(a, b) + d(b, c) by up to `slack`. The same matches are
+    /// reported, and ones a strict search would miss aren't, at the cost of visiting more nodes.
+    pub fn relaxed_by(mut self, slack: Dist) -> Self {
+        self.slack = slack;
+        self
+    }
+
+    /// Visit no more than `budget` nodes, which bounds the distances computed: one per node
+    /// visited. Subtrees past the budget go unsearched, so some matches may be missed;
+    /// `each_within` tells whether any were.
+    pub fn within(mut self, budget: usize) -> Self {
+        self.budget = budget;
+        self
+    }
+}
+
+/// What a search cost, and whether it saw everything it should have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Searched {
+    pub stats: QueryStats,
+    /// False if the budget ran out with nodes still to visit.
+    pub exhaustive: bool,
 }
 
-impl<'q, 'n, Q: 'q, N: 'n, K: 'n + Clone> BkFind<'q, 'n, Q, N>
+impl<'q, 'n, Q: 'q + ?Sized, N: 'n, K: 'n + Clone> BkFind<'q, 'n, Q, N>
 where
     N: 'n + BkNode<Key = K>,
 {
-    pub fn each<KQ, M, F>(mut self, mut callback: F)
+    /// Returns the number of nodes visited.
+    pub fn each<KQ, M, F>(self, metric: &M, mut callback: F) -> usize
     where
         KQ: KeyQuery<Key = <N as BkNode>::Key, Query = Q>,
         M: Metric<Q>,
         F: FnMut(Dist, &'n <KQ as KeyQuery>::Key),
     {
+        self.each_while::<KQ, M, _>(metric, |dist, key| {
+            callback(dist, key);
+            true
+        })
+    }
+
+    /// `each`, stopping as soon as the callback returns false. Returns the number of nodes
+    /// visited.
+    pub fn each_while<KQ, M, F>(self, metric: &M, mut callback: F) -> usize
+    where
+        KQ: KeyQuery<Key = <N as BkNode>::Key, Query = Q>,
+        M: Metric<Q>,
+        F: FnMut(Dist, &'n <KQ as KeyQuery>::Key) -> bool,
+    {
+        self.each_node_while::<KQ, M, _>(metric, |dist, node| callback(dist, node.key()))
+    }
+
+    /// `each_while`, passing the callback the matching nodes rather than their keys.
+    pub fn each_node_while<KQ, M, F>(self, metric: &M, callback: F) -> usize
+    where
+        KQ: KeyQuery<Key = <N as BkNode>::Key, Query = Q>,
+        M: Metric<Q>,
+        F: FnMut(Dist, &'n N) -> bool,
+    {
+        self.search::<KQ, M, _>(metric, callback)
+            .stats
+            .distance_computations
+    }
+
+    /// `each`, also telling what the search cost and whether the budget given to `within` cut it
+    /// short.
+    pub fn each_within<KQ, M, F>(self, metric: &M, mut callback: F) -> Searched
+    where
+        KQ: KeyQuery<Key = <N as BkNode>::Key, Query = Q>,
+        M: Metric<Q>,
+        F: FnMut(Dist, &'n <KQ as KeyQuery>::Key),
+    {
+        self.search::<KQ, M, _>(metric, |dist, node| {
+            callback(dist, node.key());
+            true
+        })
+    }
+
+    fn search<KQ, M, F>(mut self, metric: &M, mut callback: F) -> Searched
+    where
+        KQ: KeyQuery<Key = <N as BkNode>::Key, Query = Q>,
+        M: Metric<Q>,
+        F: FnMut(Dist, &'n N) -> bool,
+    {
+        let mut stats = QueryStats::default();
+        let mut exhaustive = true;
+        let budget = self.budget;
         if let Some(root) = self.root.take() {
-            let dist = M::distance_static(KQ::to_query_static(root.key()), self.needle);
+            if budget == 0 {
+                return Searched {
+                    stats,
+                    exhaustive: false,
+                };
+            }
+            stats.distance_computations += 1;
+            let dist = metric.distance(KQ::to_query_static(root.key()), self.needle);
             self.stack.push(BkFindEntry {
-                dist: dist,
+                dist,
                 node: root,
+                priority: 0,
             })
         }
 
+        let needle = self.needle;
+        let tolerance = self.tolerance;
+        let min_dist = self.min_dist;
+        let fan_out = self.fan_out;
+        // Subtrees are pruned by these, widened by the slack; matches are still only reported
+        // within the tolerance and min_dist.
+        let prune_tolerance = tolerance.saturating_add(self.slack);
+        let prune_min_dist = min_dist.saturating_sub(self.slack);
         while let Some(candidate) = self.stack.pop() {
+            stats.nodes_visited += 1;
             // Enqueue the children.
-            let min: Dist = candidate.dist.saturating_sub(self.tolerance);
-            let max: Dist = candidate.dist.saturating_add(self.tolerance);
-            let children = candidate.node.children_vector();
-            for (dist, child) in children.iter() {
-                if min <= *dist && *dist <= max {
-                    let child_dist =
-                        M::distance_static(KQ::to_query_static(child.key()), self.needle);
-                    self.stack.push(BkFindEntry {
+            let min: Dist = candidate.dist.saturating_sub(prune_tolerance);
+            let max: Dist = candidate.dist.saturating_add(prune_tolerance);
+            let stack = &mut self.stack;
+            let siblings = stack.len();
+            candidate.node.each_child(|dist, child| {
+                // Everything under a child is `dist` from this node, so no further than
+                // candidate.dist + dist from the needle.
+                if min <= dist && dist <= max && candidate.dist + dist >= prune_min_dist {
+                    // Skip children that can neither match nor have children that could, when
+                    // the metric can tell that cheaply.
+                    let child_query = KQ::to_query_static(child.key());
+                    let reach = child.max_child_dist().unwrap_or(0) + prune_tolerance;
+                    if metric.lower_bound(child_query, needle) > reach {
+                        return;
+                    }
+                    if stats.distance_computations == budget {
+                        exhaustive = false;
+                        return;
+                    }
+                    stats.distance_computations += 1;
+                    let child_dist = metric.distance(child_query, needle);
+                    stack.push(BkFindEntry {
                         dist: child_dist,
-                        node: *child,
+                        node: child,
+                        priority: fan_out.map_or(0, |f| f(candidate.dist, dist)),
                     })
                 }
+            });
+            if fan_out.is_some() {
+                // Popped from the end, so the first to search goes last.
+                stack[siblings..].sort_unstable_by_key(|entry| std::cmp::Reverse(entry.priority));
             }
+            // Counting the candidate, popped just before.
+            stats.max_stack_depth = stats.max_stack_depth.max(stack.len() + 1);
 
             // And maybe yield this node.
-            if candidate.dist <= self.tolerance {
-                callback(candidate.dist, candidate.node.key());
+            if candidate.dist <= tolerance
+                && candidate.dist >= min_dist
+                && !candidate.node.is_tombstone()
+                && !callback(candidate.dist, candidate.node)
+            {
+                break;
             }
         }
+        Searched { stats, exhaustive }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+    use crate::validate::InvariantError;
+
+    fn tree() -> BkInRamTree<'static, U64Key, HammingMetric<u64>, BkInRamAllocator<'static, u64>> {
+        let mut tree = BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for key in &[0u64, 0b1, 0b11, 0b111, 0b110] {
+            tree.add(key).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn validate_finds_malformed_child_tables() {
+        let mut dense = tree();
+        assert_eq!(Ok(()), dense.validate());
+        if let Children::Dense(ref mut children) = dense.root.as_mut().unwrap().children {
+            children.push(None);
+        }
+        assert_eq!(
+            Err(InvariantError::MalformedChildren { path: vec![] }),
+            dense.validate()
+        );
+
+        const SPARSE: BkSparseInRamAllocator<'static, u64> = BkSparseInRamAllocator::new();
+        let mut sparse: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &SPARSE);
+        for key in &[0u64, 0b1, 0b11, 0b111] {
+            sparse.add(key).unwrap();
+        }
+        assert_eq!(Ok(()), sparse.validate());
+        let root = sparse.root.as_mut().unwrap();
+        if let Children::Sparse(ref mut children) = root.children {
+            children.swap(0, 1);
+        }
+        assert_eq!(
+            Err(InvariantError::MalformedChildren { path: vec![] }),
+            sparse.validate()
+        );
+        let root = sparse.root.as_mut().unwrap();
+        if let Children::Sparse(ref mut children) = root.children {
+            children.swap(0, 1);
+            children[1].0 = children[0].0;
+        }
+        assert_eq!(
+            Err(InvariantError::MalformedChildren { path: vec![] }),
+            sparse.validate()
+        );
+    }
+
+    /// Every key a distance of 1 from every other: each one goes under the last.
+    #[cfg(feature = "serialize")]
+    #[derive(Default)]
+    struct Discrete;
+
+    #[cfg(feature = "serialize")]
+    impl Metric<u64> for Discrete {
+        fn distance(&self, k1: &u64, k2: &u64) -> Dist {
+            (k1 != k2) as Dist
+        }
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn deep_trees_round_trip_through_serde() {
+        use crate::bktree::BkTreeRemove;
+
+        let mut tree: BkInRamTree<U64Key, Discrete, _> = (0..1000u64).collect();
+        assert_eq!(999, tree.max_depth);
+        tree.remove(&500);
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: BkInRamTree<U64Key, Discrete, _> = serde_json::from_str(&json).unwrap();
+        assert!(loaded == tree);
+        assert_eq!((1000, 1), (loaded.node_count, loaded.tombstone_count));
+
+        // The root claims a second child, which the input ends without.
+        let mut saved: serde_json::Value = serde_json::from_str(&json).unwrap();
+        saved["nodes"][0]["children"] = 2.into();
+        let short: Result<BkInRamTree<U64Key, Discrete, _>, _> =
+            serde_json::from_value(saved.clone());
+        assert!(short.unwrap_err().to_string().contains("Nodes end"));
+        // And then one in the slot the rest of the chain is in.
+        let last = saved["nodes"][999].clone();
+        saved["nodes"].as_array_mut().unwrap().push(last);
+        let twice: Result<BkInRamTree<U64Key, Discrete, _>, _> = serde_json::from_value(saved);
+        assert!(twice.unwrap_err().to_string().contains("share slot 1"));
     }
 }
@@ -2,17 +2,65 @@
  * File format: (Version \n\0\0\1)
  *   Magic number:
  *     "BKTree: " + "0000\n"
+ *     Later versions only add header fields, which readers of earlier ones skip, so readers take
+ *     any version up to the latest they know of (`READABLE_VERSIONS`), and writers write the
+ *     earliest that holds what they write.
  *   Checksum: "SHA256: " + hex sha-256 of the remainder of the file following this newline + "\n---\n"
+ *     Or "BLAKE3: " + hex BLAKE3, or "XXH3: " + hex 128 bit XXH3 (see `ChecksumKind`)
  *   CBOR encoded header as a map:
  *       "Created-On":  ISO-8601 timestamp
- *       "Node-Format": "8 bits distance, 8 bits child"
+ *       "Node-Format": "8 bits distance, 8 bits child", or for variable length keys
+ *           "16 bits distance, 16 bits child, variable key" (see `array_storage::VBNode16`)
  *       "Node-Bytes": integer, node storage size
  *       "Node-Offset": integer, byte offset after the end of the header where nodes start
  *           Should be "0\n"
  *       "Node-Count": optional, integer, number of nodes
- *       "Key-Format": "fixed 64 bits" (future work: "variable length\n")
+ *       "Key-Format": the name of the codec keys are stored with (see `codec::KeyCodec`), e.g.
+ *           "fixed 64 bits", "fixed 40 bits" (or any other whole number of bytes), "varint", or
+ *           "pair(fixed 64 bits, fixed 32 bits)" for composite keys.
+ *           Variable length keys are "utf-8" strings (`codec::Utf8`), or "bytes": no codec, as
+ *           their nodes say where each starts.
+ *           "delta varint": each node's children are laid out in key order, and their keys
+ *           stored as zigzag varints of their difference from the previous child's key, or for
+ *           the first child, from its parent's. Roots and free nodes store their keys whole as
+ *           varints. (See `DELTA_KEY_FORMAT`.)
  *       "Key-Offset": integer, byte offset after header where keys start
- *       "Key-Bytes": integer, key storage size
+ *       "Key-Bytes": integer, key storage size: one encoded key per node, in node order
+ *       "Key-Type": optional, what the keys are, whatever the codec: "u64", "bytes" for
+ *           variable length keys, or "codec" for keys of the type the codec decodes, e.g. pairs.
+ *           Readers refuse key types they don't read.
+ *       "Format-Version": optional, integer, `FORMAT_VERSION`, left out while that's 0. Readers
+ *           refuse later versions.
+ *       "Endianness": optional, "little": the byte order of every integer in the sections.
+ *           Readers refuse others.
+ *       "Metric": optional, the name of the metric the trees were built with, as
+ *           `metric::dynamic::by_name` knows it, e.g. "hamming-u64" or "levenshtein", or a
+ *           metric plugin's file name
+ *       "Flags-Offset": optional, integer, byte offset after header where node flags start
+ *       "Flags-Bytes": optional, integer, flag storage size: one byte per node, indexed like the
+ *           node array
+ *       "Value-Index-Offset": optional, integer, byte offset after header where the value index
+ *           starts: one entry per node, indexed like the node array, of a 4 byte offset into the
+ *           value data and a 4 byte length. Length 0 means the key has no value.
+ *       "Value-Index-Bytes": optional, integer, value index size
+ *       "Value-Offset": optional, integer, byte offset after header where the value data starts
+ *       "Value-Bytes": optional, integer, value data size
+ *       "Free-Offset": optional, integer, byte offset in the node array of its first free node.
+ *           Nodes from there to the end of the array are zeroed, with zero keys, for adding keys
+ *           in place (see `bkfile_tree::BkFileTreeMut`). Only with fixed size key formats.
+ *       "Signature-Offset": optional, integer, byte offset after header where the signature
+ *           starts: an ed25519 signature of the SHA-512 of everything from the start of the header
+ *           to the signature, so of the header and every other section. Always the last section.
+ *       "Signature-Bytes": optional, integer, signature size: 64
+ *       "Metadata": optional, map of string to string about the file as a whole, e.g. how far
+ *           through its input a checkpoint got
+ *       "Roots": optional, array of maps, one per tree stored in the file (a forest):
+ *           "Node-Offset": integer, byte offset of the root node in the node array
+ *           "Node-Count": integer, number of nodes in this tree
+ *           "Metadata": optional, map of string to string, e.g. which shard the tree holds
+ *         Without it the file holds a single tree rooted at node offset 0, or none if
+ *         Node-Count is 0. Empty trees have no entry, so a file of nothing but empty trees has
+ *         zero length node and key arrays and no roots.
  *       "Padding:": optional if lucky, '.' repeated (0 to 63 times) until the byte after the end
  *           of header marker is 64-byte aligned from the start of the file.
  *
@@ -21,24 +69,37 @@
  *      * node array
  *      * 0 padding to next 64-byte-aligned position from the start of the file.
  *      * key array
+ *      * optional flags array
+ *      * optional value index, then value data: application payloads, e.g. file paths
+ *      * optional signature (see `Header::verify_signature`)
  */
 //use memmap::MmapOptions;
+use byteorder::{BigEndian, ByteOrder};
+use chrono::Utc;
 use memmap::Mmap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Result as IOResult;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::io::{Seek, SeekFrom};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
 //use std::error::Error;
-use sha2::{Digest, Sha256};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
 use std::error;
 use std::io;
 
-fn open_mmap(filename: &str, offset: usize, length: usize) -> IOResult<Mmap> {
-    let file = File::open(filename)?;
-    // let mmap = unsafe { MmapOptions::new().map(&file)? };
-    let mmap = unsafe { Mmap::map(&file)? };
-    Ok(mmap)
-}
+use crate::array_storage::{F64BNode8, InStorageNode, InStorageNodeMut, VBNode16};
+use crate::bkfile_tree::{self, BkFile};
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::codec::{self, FixedWidth, KeyCodec, Utf8, Varint};
+use crate::dyntree::DynBkTree;
+use crate::extensible_mmap::ExtensibleMmapMut;
+use crate::forest::BkForest;
+use crate::metric::{self, Metric};
+use crate::Dist;
 
 trait TrimStart {
     type Elt;
@@ -54,22 +115,30 @@ impl TrimStart for String {
                 return chars.collect::<String>();
             }
         }
-        return "".to_string();
+        "".to_string()
     }
 }
 
 impl TrimStart for Vec<u8> {
     type Elt = u8;
     fn trim_start_matches(&self, val: u8) -> Self {
-        let mut i = 0;
-        while self[i] == val {
-            i += 1;
-        }
-        return (self)[i..].to_vec();
+        let start = self.iter().position(|&b| b != val).unwrap_or(self.len());
+        self[start..].to_vec()
     }
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+/// One tree in a file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RootDescr {
+    #[serde(rename = "Node-Offset")]
+    pub node_offset: u64,
+    #[serde(rename = "Node-Count")]
+    pub node_count: u64,
+    #[serde(rename = "Metadata", default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct FileDescrHeader {
     #[serde(rename = "Created-On")]
     pub created_on: String,
@@ -90,34 +159,338 @@ pub struct FileDescrHeader {
     #[serde(rename = "Key-Bytes")]
     pub key_bytes: u64,
 
+    /// Empty in files from before key types were recorded.
+    #[serde(rename = "Key-Type", default, skip_serializing_if = "String::is_empty")]
+    pub key_type: String,
+    /// Left out while 0, so files from before it was recorded encode as they were written.
+    #[serde(rename = "Format-Version", default, skip_serializing_if = "is_zero")]
+    pub format_version: u64,
+    /// Empty in files from before byte order was recorded, which are all little endian.
+    #[serde(
+        rename = "Endianness",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub endianness: String,
+
+    /// Empty if the metric has no name (see `Metric::registered_name`).
+    #[serde(rename = "Metric", default, skip_serializing_if = "String::is_empty")]
+    pub metric: String,
+
+    #[serde(
+        rename = "Flags-Offset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub flags_offset: Option<u64>,
+    #[serde(
+        rename = "Flags-Bytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub flags_bytes: Option<u64>,
+
+    #[serde(
+        rename = "Value-Index-Offset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_index_offset: Option<u64>,
+    #[serde(
+        rename = "Value-Index-Bytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_index_bytes: Option<u64>,
+    #[serde(
+        rename = "Value-Offset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_offset: Option<u64>,
+    #[serde(
+        rename = "Value-Bytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_bytes: Option<u64>,
+
+    #[serde(
+        rename = "Free-Offset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub free_offset: Option<u64>,
+
+    #[serde(
+        rename = "Signature-Offset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub signature_offset: Option<u64>,
+    #[serde(
+        rename = "Signature-Bytes",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub signature_bytes: Option<u64>,
+
+    #[serde(
+        rename = "Metadata",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub metadata: BTreeMap<String, String>,
+
+    #[serde(rename = "Roots", default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<RootDescr>,
+
     #[serde(rename = "Padding", default)]
     padding: String,
+
+    /// Fields this library doesn't know, from files written by later ones. Kept, so rewriting
+    /// the header (see `bkfile_tree::BkFileTreeMut::sync`) doesn't lose them, but ignored.
+    #[serde(flatten, skip_serializing)]
+    pub unknown: BTreeMap<String, serde_cbor::Value>,
+}
+
+/// The number of entries in the CBOR map whose encoding starts `cbor`, and the length of its
+/// head. None unless it starts with a definite length map.
+fn map_head(cbor: &[u8]) -> Option<(usize, usize)> {
+    let (major, info) = (cbor.first()? >> 5, cbor.first()? & 0x1f);
+    match (major, info) {
+        (5, 0..=23) => Some((info as usize, 1)),
+        (5, 24) => Some((*cbor.get(1)? as usize, 2)),
+        (5, 25) => Some((BigEndian::read_u16(cbor.get(1..3)?) as usize, 3)),
+        (5, 26) => Some((BigEndian::read_u32(cbor.get(1..5)?) as usize, 5)),
+        _ => None,
+    }
+}
+
+fn write_map_head(count: usize, out: &mut Vec<u8>) {
+    match count {
+        0..=23 => out.push(0xa0 | count as u8),
+        24..=0xff => out.extend_from_slice(&[0xb8, count as u8]),
+        0x100..=0xffff => {
+            out.push(0xb9);
+            out.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xba);
+            out.extend_from_slice(&(count as u32).to_be_bytes());
+        }
+    }
 }
 
 impl FileDescrHeader {
+    /// The CBOR encoding, with any unknown fields after the known ones. Serde would encode those
+    /// with the map's length left open, so they're spliced on instead, to keep the encoding the
+    /// same size as the one read.
+    fn to_cbor(&self) -> Vec<u8> {
+        let known = serde_cbor::to_vec(self).unwrap();
+        if self.unknown.is_empty() {
+            return known;
+        }
+        let (count, head) = map_head(&known).expect("headers encode as maps");
+        let mut cbor = Vec::with_capacity(known.len() + 64 * self.unknown.len());
+        write_map_head(count + self.unknown.len(), &mut cbor);
+        cbor.extend_from_slice(&known[head..]);
+        for (name, value) in &self.unknown {
+            cbor.extend(serde_cbor::to_vec(name).unwrap());
+            cbor.extend(serde_cbor::to_vec(value).unwrap());
+        }
+        cbor
+    }
+
     pub fn encode(&mut self, offset: usize) -> Vec<u8> {
-        // Ensure 64 byte alignment
+        // Ensure 64 byte alignment. The padding string's length prefix grows a byte once it
+        // reaches 24 characters, so search rather than compute.
         const ALIGNMENT: usize = 64;
-        self.padding = "".to_string();
-        let mut buffer = serde_cbor::to_vec(&self).unwrap();
-        let padding = ALIGNMENT - (offset + buffer.len() + 1) % ALIGNMENT;
-        self.padding = ".".repeat(padding);
-        buffer = serde_cbor::to_vec(&self).unwrap();
-        assert_eq!(0, (offset + buffer.len()) % ALIGNMENT);
-        return buffer;
+        // Files with free nodes get a spare alignment's worth, so their counts can grow as keys
+        // are added without moving the data.
+        let spare = if self.free_offset.is_some() {
+            ALIGNMENT
+        } else {
+            0
+        };
+        for padding in spare..spare + 2 * ALIGNMENT {
+            self.padding = ".".repeat(padding);
+            let buffer = self.to_cbor();
+            if (offset + buffer.len()).is_multiple_of(ALIGNMENT) {
+                return buffer;
+            }
+        }
+        unreachable!("no padding aligns the header");
+    }
+
+    /// Encode to exactly `len` bytes, as a header rewritten in place must, if padding can make
+    /// it fit.
+    pub fn encode_exactly(&mut self, len: usize) -> Option<Vec<u8>> {
+        for padding in 0..len {
+            self.padding = ".".repeat(padding);
+            let buffer = self.to_cbor();
+            if buffer.len() >= len {
+                return Some(buffer).filter(|buffer| buffer.len() == len);
+            }
+        }
+        None
+    }
+
+    /// Whether the file's keys are `key_type` (e.g. `KEY_TYPE_U64`), or it's from before key
+    /// types were recorded.
+    pub fn check_key_type(&self, key_type: &str) -> Result<(), Box<dyn error::Error>> {
+        if !self.key_type.is_empty() && self.key_type != key_type {
+            return Err(format!("File holds {:?} keys, not {:?}", self.key_type, key_type).into());
+        }
+        Ok(())
+    }
+
+    /// The trees stored in the file, in file order.
+    pub fn roots(&self) -> Vec<RootDescr> {
+        if self.roots.is_empty() && self.node_count > 0 {
+            return vec![RootDescr {
+                node_offset: 0,
+                node_count: self.node_count,
+                metadata: BTreeMap::new(),
+            }];
+        }
+        self.roots.clone()
+    }
+}
+
+/// How a file's checksum line hashes the rest of it. BLAKE3 and 128 bit XXH3, with the
+/// `fast-checksums` feature, take a fraction of SHA-256's time, for files that only need checking
+/// for corruption. Files of any kind open without the feature if their checksums aren't checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumKind {
+    #[default]
+    Sha256,
+    Blake3,
+    Xxh3,
+}
+
+impl ChecksumKind {
+    /// The name the checksum line starts with.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumKind::Sha256 => HASH_HEADER_NAME,
+            ChecksumKind::Blake3 => "BLAKE3",
+            ChecksumKind::Xxh3 => "XXH3",
+        }
+    }
+
+    pub fn named(name: &str) -> Option<Self> {
+        [
+            ChecksumKind::Sha256,
+            ChecksumKind::Blake3,
+            ChecksumKind::Xxh3,
+        ]
+        .iter()
+        .cloned()
+        .find(|kind| kind.name() == name)
+    }
+
+    /// The length of the magic number and checksum lines, after which the CBOR header starts.
+    pub fn prefix_size(self) -> usize {
+        let hex_digits = match self {
+            ChecksumKind::Xxh3 => 32,
+            _ => 64,
+        };
+        MAGIC_VERSION.len() + 1 + self.name().len() + 2 + hex_digits + 1
+    }
+
+    /// A hash to compute this kind of checksum with, or an error if this build can't.
+    pub fn hasher(self) -> Result<Hasher, Box<dyn error::Error>> {
+        match self {
+            ChecksumKind::Sha256 => Ok(Hasher::Sha256(Sha256::new())),
+            #[cfg(feature = "fast-checksums")]
+            ChecksumKind::Blake3 => Ok(Hasher::Blake3(Box::new(blake3::Hasher::new()))),
+            #[cfg(feature = "fast-checksums")]
+            ChecksumKind::Xxh3 => Ok(Hasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new()))),
+            #[cfg(not(feature = "fast-checksums"))]
+            _ => Err(format!("{} checksums need the fast-checksums feature", self.name()).into()),
+        }
+    }
+}
+
+/// A `ChecksumKind`'s hash, part way through a file.
+pub enum Hasher {
+    Sha256(Sha256),
+    #[cfg(feature = "fast-checksums")]
+    Blake3(Box<blake3::Hasher>),
+    #[cfg(feature = "fast-checksums")]
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.input(data),
+            #[cfg(feature = "fast-checksums")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            #[cfg(feature = "fast-checksums")]
+            Hasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The hash, as the checksum line gives it.
+    pub fn hex(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:064x}", hasher.result()),
+            #[cfg(feature = "fast-checksums")]
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            #[cfg(feature = "fast-checksums")]
+            Hasher::Xxh3(hasher) => format!("{:032x}", hasher.digest128()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        Ok(())
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Header {
-    version: Vec<u8>,
+    version: u32,
+    checksum_kind: ChecksumKind,
     checksum: Vec<u8>,
     descr: FileDescrHeader,
+    /// Where the CBOR header starts, after the checksum line.
+    descr_offset: u64,
+    data_offset: u64,
+    signature: Option<Vec<u8>>,
+    /// The SHA-512 the signature signs, if the checksum was verified.
+    signed_digest: Option<Vec<u8>>,
 }
 
-pub const MAGIC_VERSION: &'static str = "BKTREE: 0000";
-pub const HASH_HEADER_NAME: &'static str = "SHA256";
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+pub const MAGIC_VERSION: &str = "BKTREE: 0000";
+/// The magic number without its version.
+pub const MAGIC_PREFIX: &str = "BKTREE: ";
+/// The magic versions `Header::read` takes: every one this library knows of.
+pub const READABLE_VERSIONS: RangeInclusive<u32> = 0..=1;
+/// The latest "Format-Version" this library reads and writes.
+pub const FORMAT_VERSION: u64 = 0;
+/// "Key-Type"s.
+pub const KEY_TYPE_U64: &str = "u64";
+pub const KEY_TYPE_BYTES: &str = "bytes";
+/// Whatever the "Key-Format" codec decodes, e.g. pairs (`codec::Pair`).
+pub const KEY_TYPE_CODEC: &str = "codec";
+pub const HASH_HEADER_NAME: &str = "SHA256";
 pub const PREFIX_SIZE: usize = 86;
+const SIGNATURE_SIZE: usize = 64;
 
 impl Header {
     pub fn read(
@@ -128,36 +501,1647 @@ impl Header {
         let mut reader = BufReader::new(file);
 
         // Check the magic number
-        reader.read_until('\n' as u8, &mut header.version)?;
-        if header.version != MAGIC_VERSION.as_bytes() {
-            return Err("Unknown file format (expected \"BKTREE: 0000\")".into());
-        }
+        let mut magic: Vec<u8> = Vec::new();
+        reader.read_until(b'\n', &mut magic)?;
+        header.version = magic
+            .strip_prefix(MAGIC_PREFIX.as_bytes())
+            .and_then(|version| version.strip_suffix(b"\n"))
+            .filter(|version| version.len() == 4)
+            .and_then(|version| std::str::from_utf8(version).ok()?.parse().ok())
+            .filter(|version| READABLE_VERSIONS.contains(version))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown file format (expected \"{}{:04}\" to \"{}{:04}\")",
+                    MAGIC_PREFIX,
+                    READABLE_VERSIONS.start(),
+                    MAGIC_PREFIX,
+                    READABLE_VERSIONS.end()
+                )
+            })?;
 
         // Read the checksum
         let mut checksum_type: Vec<u8> = Vec::new();
-        reader.read_until(':' as u8, &mut checksum_type)?;
-        if checksum_type != HASH_HEADER_NAME.as_bytes() {
-            return Err("Unknown checksum format (expected \"SHA256\")".into());
-        }
+        reader.read_until(b':', &mut checksum_type)?;
+        header.checksum_kind = checksum_type
+            .strip_suffix(b":")
+            .and_then(|name| ChecksumKind::named(std::str::from_utf8(name).ok()?))
+            .ok_or("Unknown checksum format (expected \"SHA256\", \"BLAKE3\" or \"XXH3\")")?;
         let mut checksum: Vec<u8> = Vec::new();
-        reader.read_until('\n' as u8, &mut checksum);
-        header.checksum = checksum.trim_start_matches(' ' as u8);
+        reader.read_until(b'\n', &mut checksum)?;
+        if checksum.pop() != Some(b'\n') {
+            return Err("Truncated checksum".into());
+        }
+        header.checksum = checksum.trim_start_matches(b' ');
+        if header.checksum.is_empty() {
+            return Err("Missing checksum".into());
+        }
 
-        let descr_start = reader.seek(SeekFrom::Current(0))?;
+        let descr_start = reader.stream_position()?;
+        header.descr_offset = descr_start;
         if verify_checksum {
-            let mut hasher = Sha256::new();
-            let n = io::copy(&mut reader, &mut hasher)?;
-            let found = format!("{:x}", hasher.result());
+            let mut hasher = header.checksum_kind.hasher()?;
+            io::copy(&mut reader, &mut hasher)?;
+            let found = hasher.hex();
             if found.as_bytes() != header.checksum.as_slice() {
                 return Err(format!(
                     "Checksum failure. Found {:?}, expected {:?}",
-                    found, header.checksum
+                    found,
+                    String::from_utf8_lossy(&header.checksum)
                 )
                 .into());
             }
         }
         reader.seek(SeekFrom::Start(descr_start))?;
 
-        return Ok(header);
+        // The node and key arrays follow the descr directly, so it's parsed without insisting on
+        // end of input.
+        let mut counted = CountingReader {
+            inner: &mut reader,
+            count: 0,
+        };
+        let mut deserializer = serde_cbor::Deserializer::from_reader(&mut counted);
+        header.descr = FileDescrHeader::deserialize(&mut deserializer)?;
+        if header.descr.format_version > FORMAT_VERSION {
+            return Err(format!(
+                "File is format version {}, newer than this library reads ({})",
+                header.descr.format_version, FORMAT_VERSION
+            )
+            .into());
+        }
+        if !["", "little"].contains(&header.descr.endianness.as_str()) {
+            return Err(format!("Unsupported endianness {:?}", header.descr.endianness).into());
+        }
+        // Counted rather than encoded again, as a later version's fields needn't encode the same.
+        header.data_offset = descr_start + counted.count;
+        for root in header.descr.roots.iter() {
+            if root.node_offset >= header.descr.node_bytes {
+                return Err(format!(
+                    "Root at node offset {} is past the end of the nodes ({} bytes)",
+                    root.node_offset, header.descr.node_bytes
+                )
+                .into());
+            }
+        }
+
+        if let (Some(offset), Some(bytes)) =
+            (header.descr.signature_offset, header.descr.signature_bytes)
+        {
+            let signed = header.data_offset - descr_start + offset;
+            if verify_checksum {
+                reader.seek(SeekFrom::Start(descr_start))?;
+                let mut hasher = Sha512::new();
+                io::copy(&mut (&mut reader).take(signed), &mut hasher)?;
+                header.signed_digest = Some(hasher.result().to_vec());
+            }
+            reader.seek(SeekFrom::Start(descr_start + signed))?;
+            let mut signature = Vec::new();
+            (&mut reader).take(bytes).read_to_end(&mut signature)?;
+            header.signature = Some(signature);
+        }
+
+        Ok(header)
+    }
+
+    /// Whether the file is signed by the ed25519 key `public_key`, checking the signature over
+    /// the header and sections. Needs the `signatures` feature, and the header read with its
+    /// checksum verified, as that reads the signed bytes.
+    pub fn verify_signature(&self, public_key: &[u8; 32]) -> Result<(), Box<dyn error::Error>> {
+        let signature = self.signature.as_ref().ok_or("File isn't signed")?;
+        let digest = self
+            .signed_digest
+            .as_ref()
+            .ok_or("Signatures are only checked in headers read with their checksums verified")?;
+        verify(public_key, digest, signature)
+    }
+
+    /// The bytes the signature signs, the header and sections, as offsets into the file.
+    pub(crate) fn signed_range(&self) -> Option<Range<u64>> {
+        let offset = self.descr.signature_offset?;
+        Some(self.descr_offset..self.data_offset + offset)
+    }
+
+    /// `verify_signature`, over `signed`: the file's `signed_range`, as the caller read it.
+    pub(crate) fn verify_signed_bytes(
+        &self,
+        public_key: &[u8; 32],
+        signed: &[u8],
+    ) -> Result<(), Box<dyn error::Error>> {
+        let signature = self.signature.as_ref().ok_or("File isn't signed")?;
+        let mut hasher = Sha512::new();
+        hasher.input(signed);
+        verify(public_key, &hasher.result(), signature)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    pub fn descr(&self) -> &FileDescrHeader {
+        &self.descr
+    }
+
+    /// The version in the file's magic number, one of `READABLE_VERSIONS`.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// What the file's builder recorded about it as a whole, e.g. its source dataset or the
+    /// tool version: the "Metadata" map, empty for files without one.
+    pub fn meta(&self) -> &BTreeMap<String, String> {
+        &self.descr.metadata
+    }
+
+    pub fn checksum_kind(&self) -> ChecksumKind {
+        self.checksum_kind
+    }
+
+    /// The file's checksum, as hex.
+    pub fn checksum(&self) -> String {
+        String::from_utf8_lossy(&self.checksum).into_owned()
+    }
+
+    /// Offset from the start of the file that the descr's node and key offsets count from.
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+}
+
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// The value index and value data for `values`, which are indexed like the node array.
+pub fn encode_values<V: AsRef<[u8]>>(
+    values: &[V],
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn error::Error + 'static>> {
+    let mut index = Vec::with_capacity(8 * values.len());
+    let mut data = Vec::new();
+    for value in values {
+        let value = value.as_ref();
+        if data.len() + value.len() > u32::MAX as usize {
+            return Err("Values too large for the value section".into());
+        }
+        index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        index.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(value);
+    }
+    Ok((index, data))
+}
+
+/// (offset, length) of each node's value in the value data. A trailing partial entry is ignored.
+pub fn decode_value_index(index: &[u8]) -> Vec<(usize, usize)> {
+    index
+        .chunks_exact(8)
+        .map(|entry| {
+            let mut offset = [0u8; 4];
+            let mut length = [0u8; 4];
+            offset.copy_from_slice(&entry[..4]);
+            length.copy_from_slice(&entry[4..]);
+            (
+                u32::from_le_bytes(offset) as usize,
+                u32::from_le_bytes(length) as usize,
+            )
+        })
+        .collect()
+}
+
+// F64BNode8 uses 8 bytes per node
+const NODE_SIZE: usize = 8;
+
+/// The widest field values a tree needs stored, measured before writing it so the file's fields
+/// can be sized to fit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FieldWidths {
+    /// Largest distance from a node to its parent.
+    pub max_dist: Dist,
+    /// Most children of any one node.
+    pub max_children: usize,
+    /// Largest key.
+    pub max_key: u64,
+}
+
+impl FieldWidths {
+    /// Measure every node of every tree in `trees`.
+    pub fn measure<T: BkTree<u64>>(trees: &[T]) -> Self {
+        let mut widths = Self::default();
+        for tree in trees {
+            tree.preorder_each(|dist, children, key| {
+                widths.max_dist = widths.max_dist.max(dist);
+                widths.max_children = widths.max_children.max(children);
+                widths.max_key = widths.max_key.max(*key);
+            });
+        }
+        widths
+    }
+
+    /// Whether the node array's 8 bit distances and child counts can hold these.
+    pub fn check_nodes(&self) -> Result<(), Box<dyn error::Error>> {
+        if self.max_dist > u8::MAX as Dist {
+            return Err(format!("Distance {} doesn't fit in 8 bits", self.max_dist).into());
+        }
+        if self.max_children > u8::MAX as usize {
+            return Err(format!("{} children don't fit in 8 bits", self.max_children).into());
+        }
+        Ok(())
+    }
+
+    /// The narrowest fixed size key codec that holds every key.
+    pub fn key_codec(&self) -> FixedWidth {
+        FixedWidth::fitting(self.max_key)
+    }
+}
+
+/// How `write_tree` and `write_forest` lay out a file. The writers of variable length keys
+/// (`write_bytes_tree` and the rest) take only the metadata, checksum and signing key.
+#[derive(Default)]
+pub struct WriteOptions<'a> {
+    /// Encodes the key section. Unless set, keys take the fewest whole bytes the largest needs,
+    /// or all 8 in files with room for more.
+    pub codec: Option<&'a dyn KeyCodec<Key = u64>>,
+    /// About the file as a whole.
+    pub metadata: BTreeMap<String, String>,
+    /// Payloads for the value section, by key.
+    pub values: Option<&'a HashMap<u64, Vec<u8>>>,
+    /// Free nodes to leave at the end of the node array, for `BkFileTreeMut` to add keys in.
+    /// Needs a fixed size key codec.
+    pub reserve_nodes: usize,
+    /// How the checksum line hashes the file.
+    pub checksum: ChecksumKind,
+    /// An ed25519 secret key to sign the file with. Needs the `signatures` feature.
+    pub signing_key: Option<[u8; 32]>,
+    /// Lay out each node's children in key order and store their keys as deltas, in the
+    /// "delta varint" key format, for much smaller key sections when keys are dense integers.
+    /// Has no codec of its own, and leaves no room for more keys.
+    pub sibling_deltas: bool,
+}
+
+impl WriteOptions<'_> {
+    /// Refuse the options only files of u64 keys have, for the variable length key writers.
+    fn check_variable_keys(&self) -> Result<(), Box<dyn error::Error>> {
+        if self.codec.is_some()
+            || self.values.is_some()
+            || self.reserve_nodes > 0
+            || self.sibling_deltas
+        {
+            return Err(
+                "Files of variable length keys take only metadata, a checksum and a \
+                        signing key"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The sections of a file being written: the node array, in a temporary file as it may be big,
+/// and the keys, flags and values, indexed like the nodes.
+struct Sections {
+    nodes: ExtensibleMmapMut,
+    /// The node index `keys`, `flags` and `values` start at.
+    base: usize,
+    keys: Vec<u64>,
+    flags: Vec<u8>,
+    values: Vec<Vec<u8>>,
+    /// Lay out siblings in key order, for `WriteOptions::sibling_deltas`.
+    sort_siblings: bool,
+}
+
+impl Sections {
+    /// Lay out the tree under `node`, which has a slot at `offset` `dist` from its parent, in
+    /// pre-order with each node's children side by side. Iterative, so degenerate deep trees
+    /// can't overflow the stack. Returns how many nodes it laid out.
+    fn walk<N: BkNode<Key = u64>>(
+        &mut self,
+        offset: usize,
+        dist: Dist,
+        node: &N,
+        values: Option<&HashMap<u64, Vec<u8>>>,
+    ) -> Result<u64, Box<dyn error::Error>> {
+        let mut count = 0;
+        let mut stack: Vec<(usize, Dist, &N)> = vec![(offset, dist, node)];
+        while let Some((offset, dist, node)) = stack.pop() {
+            if node.is_tombstone() {
+                return Err("Bkfiles can't hold removed keys: compact the tree first".into());
+            }
+            count += 1;
+            let mut children = node.children_vector();
+            if self.sort_siblings {
+                // Largest first, as they're laid out in reverse.
+                children.sort_by(|a, b| b.1.key().cmp(a.1.key()));
+            }
+            // All of this node's children go together, before any of the grandchildren.
+            let (child_offset, _) = self.nodes.alloc_bytes(NODE_SIZE * children.len())?;
+            {
+                let mut mirror = F64BNode8 {
+                    offset,
+                    // Keys go in self.keys, for the codec.
+                    key_buffer: &mut [][..],
+                    node_buffer: self.nodes.ram_mut(),
+                };
+                mirror.set_dist(dist)?;
+                mirror.set_num_children(children.len())?;
+                mirror.set_child_offset(child_offset)?;
+            }
+            let index = offset / NODE_SIZE - self.base;
+            if self.keys.len() <= index {
+                self.keys.resize(index + 1, 0);
+                self.flags.resize(index + 1, 0);
+            }
+            self.keys[index] = *node.key();
+            self.flags[index] = node.flags();
+            if let Some(value) = values.and_then(|values| values.get(node.key())) {
+                if self.values.len() <= index {
+                    self.values.resize(index + 1, Vec::new());
+                }
+                self.values[index] = value.clone();
+            }
+
+            // Laid out in reverse, closest first for in-RAM nodes, and pushed in reverse so the
+            // first laid out is walked first.
+            for (i, (dist, child)) in children.iter().rev().enumerate().rev() {
+                stack.push((child_offset + NODE_SIZE * i, *dist, *child));
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// The key format for `WriteOptions::sibling_deltas`.
+pub const DELTA_KEY_FORMAT: &str = "delta varint";
+
+/// For each of the first `slots` nodes in a node array, the node whose key the "delta varint"
+/// format stores its key relative to (see `codec::to_deltas`): its previous sibling, or its
+/// parent if it's a first child. None for roots and free nodes.
+pub(crate) fn delta_references(nodes: &[u8], slots: usize) -> Vec<Option<usize>> {
+    let mut references = vec![None; slots];
+    for parent in 0..slots.min(nodes.len() / NODE_SIZE) {
+        let node = F64BNode8 {
+            offset: parent * NODE_SIZE,
+            key_buffer: &[][..],
+            node_buffer: nodes,
+        };
+        if let (Some(children), Some(count)) = (node.children_offset(), node.child_count()) {
+            let first = children / NODE_SIZE;
+            for (i, reference) in references.iter_mut().skip(first).take(count).enumerate() {
+                *reference = Some(if i == 0 { parent } else { first + i - 1 });
+            }
+        }
+    }
+    references
+}
+
+/// Write `tree` to `path` as a bkfile, returning the header written. Distances and child counts
+/// must be under 256, checked before anything is written, and the tree must have no removed
+/// keys.
+///
+///   bkfile::write_tree(&tree, "hashes.bk", &Default::default())?;
+pub fn write_tree<T, P>(
+    tree: &T,
+    path: P,
+    options: &WriteOptions,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    T: BkTree<u64>,
+    P: AsRef<Path>,
+{
+    write_forest(std::slice::from_ref(tree), path, options)
+}
+
+/// The name the first of `trees` with a named metric records for them all.
+fn metric_name<K: Clone, T: BkTree<K>>(trees: &[T]) -> String {
+    trees
+        .iter()
+        .find_map(|tree| tree.metric().registered_name())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// `write_tree` for several trees in one file, a root each. Empty trees get no root. With more
+/// than one tree, each root's metadata gives its "Shard" as its index out of the count, e.g.
+/// "1/4".
+pub fn write_forest<T, P>(
+    trees: &[T],
+    path: P,
+    options: &WriteOptions,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    T: BkTree<u64>,
+    P: AsRef<Path>,
+{
+    let widths = FieldWidths::measure(trees);
+    widths.check_nodes()?;
+    // Keys to come may be bigger, so files with room for them get full size keys.
+    let fitted = if options.reserve_nodes > 0 {
+        FixedWidth::new(8)
+    } else {
+        widths.key_codec()
+    };
+    let codec = options.codec.unwrap_or(&fitted);
+    if options.sibling_deltas && options.codec.is_some() {
+        return Err("Sibling deltas are a key format of their own: leave the codec unset".into());
+    }
+    codec::check_registered(codec)?;
+
+    let mut sections = Sections {
+        nodes: ExtensibleMmapMut::on(tempfile::tempfile()?)?,
+        base: 0,
+        keys: Vec::new(),
+        flags: Vec::new(),
+        values: Vec::new(),
+        sort_siblings: options.sibling_deltas,
+    };
+    // The roots go first, side by side, as though they were children of a virtual root.
+    let nonempty: Vec<(usize, &T::Node)> = trees
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tree)| tree.root().map(|root| (i, root)))
+        .collect();
+    sections.nodes.alloc_bytes(NODE_SIZE * nonempty.len())?;
+    let mut roots: Vec<RootDescr> = Vec::new();
+    for (slot, (shard, node)) in nonempty.into_iter().enumerate() {
+        let offset = NODE_SIZE * slot;
+        let mut root = RootDescr {
+            node_offset: offset as u64,
+            node_count: sections.walk(offset, 0, node, options.values)?,
+            ..Default::default()
+        };
+        if trees.len() > 1 {
+            root.metadata
+                .insert("Shard".to_string(), format!("{}/{}", shard, trees.len()));
+        }
+        roots.push(root);
+    }
+    let node_count = sections.keys.len();
+    // Free nodes are zeroed, with zero keys, no flags and no values.
+    let free_offset = if options.reserve_nodes > 0 {
+        if FixedWidth::named(codec.name()).is_none() || options.sibling_deltas {
+            return Err("Only fixed size keys leave room for more".into());
+        }
+        let free_offset = sections.nodes.len();
+        sections
+            .nodes
+            .alloc_bytes(NODE_SIZE * options.reserve_nodes)?;
+        sections.keys.resize(node_count + options.reserve_nodes, 0);
+        sections.flags.resize(node_count + options.reserve_nodes, 0);
+        Some(free_offset as u64)
+    } else {
+        None
+    };
+    let slots = sections.keys.len();
+    let (keys, key_format) = if options.sibling_deltas {
+        let references = delta_references(sections.nodes.ram(), slots);
+        let deltas = codec::to_deltas(&sections.keys, &references);
+        (codec::encode_keys(&Varint, &deltas)?, DELTA_KEY_FORMAT)
+    } else {
+        (codec::encode_keys(codec, &sections.keys)?, codec.name())
+    };
+
+    let mut descr = FileDescrHeader {
+        created_on: Utc::now().to_rfc3339(),
+        node_format: "8 bits distance, 8 bits child".to_string(),
+        node_bytes: sections.nodes.len() as u64,
+        node_offset: 0,
+        node_count: node_count as u64,
+        key_format: key_format.to_string(),
+        key_offset: sections.nodes.len() as u64,
+        key_bytes: keys.len() as u64,
+        key_type: KEY_TYPE_U64.to_string(),
+        metric: metric_name(trees),
+        free_offset,
+        roots,
+        metadata: options.metadata.clone(),
+        ..Default::default()
+    };
+    // Only files with flagged keys carry the flags section.
+    let flags: &[u8] = if sections.flags.iter().any(|f| *f != 0) {
+        descr.flags_offset = Some(descr.key_offset + descr.key_bytes);
+        descr.flags_bytes = Some(sections.flags.len() as u64);
+        &sections.flags
+    } else {
+        &[]
+    };
+    // Likewise values. The index has an entry for every node.
+    let (value_index, value_data) = if sections.values.is_empty() {
+        (Vec::new(), Vec::new())
+    } else {
+        sections.values.resize(slots, Vec::new());
+        encode_values(&sections.values)?
+    };
+    if !value_index.is_empty() {
+        let index_offset = descr.key_offset + descr.key_bytes + flags.len() as u64;
+        descr.value_index_offset = Some(index_offset);
+        descr.value_index_bytes = Some(value_index.len() as u64);
+        descr.value_offset = Some(index_offset + value_index.len() as u64);
+        descr.value_bytes = Some(value_data.len() as u64);
+    }
+    write_file(
+        path.as_ref(),
+        &mut descr,
+        &[
+            sections.nodes.ram(),
+            &keys,
+            flags,
+            &value_index,
+            &value_data,
+        ],
+        options.checksum,
+        options.signing_key.as_ref(),
+    )?;
+    Ok(descr)
+}
+
+/// Writes a bkfile a tree at a time, for forests too big to hold in RAM all at once. Each tree's
+/// nodes go to a mapped temporary file as it's added, and its keys, flags and values to more
+/// temporary files, so only the tree being added needs to be in RAM:
+///
+///   let mut writer = ForestWriter::new(&FixedU64)?;
+///   for shard in shards { writer.add(&build(shard)?, None)?; }
+///   writer.finish("hashes.bk", metadata)?;
+///
+/// Each tree's root comes first in its nodes, rather than all the roots first as `write_forest`
+/// lays them out. Keys are encoded as each tree is added, so unlike `write_forest` it can't size
+/// them to fit: the codec is up to the caller.
+pub struct ForestWriter<'a> {
+    codec: &'a dyn KeyCodec<Key = u64>,
+    sections: Sections,
+    keys: BufWriter<File>,
+    flags: BufWriter<File>,
+    value_index: BufWriter<File>,
+    value_data: BufWriter<File>,
+    value_bytes: u64,
+    flagged: bool,
+    valued: bool,
+    roots: Vec<RootDescr>,
+    metric: String,
+    checksum: ChecksumKind,
+    signing_key: Option<[u8; 32]>,
+}
+
+impl<'a> ForestWriter<'a> {
+    pub fn new(codec: &'a dyn KeyCodec<Key = u64>) -> Result<Self, Box<dyn error::Error>> {
+        codec::check_registered(codec)?;
+        let temp = || -> io::Result<BufWriter<File>> { Ok(BufWriter::new(tempfile::tempfile()?)) };
+        Ok(ForestWriter {
+            codec,
+            sections: Sections {
+                nodes: ExtensibleMmapMut::on(tempfile::tempfile()?)?,
+                base: 0,
+                keys: Vec::new(),
+                flags: Vec::new(),
+                values: Vec::new(),
+                sort_siblings: false,
+            },
+            keys: temp()?,
+            flags: temp()?,
+            value_index: temp()?,
+            value_data: temp()?,
+            value_bytes: 0,
+            flagged: false,
+            valued: false,
+            roots: Vec::new(),
+            metric: String::new(),
+            checksum: ChecksumKind::default(),
+            signing_key: None,
+        })
+    }
+
+    /// How the checksum line hashes the file. SHA-256 unless set.
+    pub fn set_checksum(&mut self, checksum: ChecksumKind) {
+        self.checksum = checksum;
+    }
+
+    /// Sign the file with this ed25519 secret key. Needs the `signatures` feature.
+    pub fn set_signing_key(&mut self, signing_key: [u8; 32]) {
+        self.signing_key = Some(signing_key);
+    }
+
+    /// Nodes written so far.
+    pub fn node_count(&self) -> u64 {
+        (self.sections.nodes.len() / NODE_SIZE) as u64
+    }
+
+    /// Add `tree` as the next root, with `values` for any of its keys. Empty trees get no root.
+    /// Checks the tree fits the node format before writing any of it.
+    pub fn add<T: BkTree<u64>>(
+        &mut self,
+        tree: &T,
+        values: Option<&HashMap<u64, Vec<u8>>>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let root = match tree.root() {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        FieldWidths::measure(std::slice::from_ref(tree)).check_nodes()?;
+        if self.metric.is_empty() {
+            self.metric = metric_name(std::slice::from_ref(tree));
+        }
+        let (offset, _) = self.sections.nodes.alloc_bytes(NODE_SIZE)?;
+        let node_count = self.sections.walk(offset, 0, root, values)?;
+        self.roots.push(RootDescr {
+            node_offset: offset as u64,
+            node_count,
+            ..Default::default()
+        });
+
+        let sections = &mut self.sections;
+        self.keys
+            .write_all(&codec::encode_keys(self.codec, &sections.keys)?)?;
+        self.flags.write_all(&sections.flags)?;
+        self.flagged |= sections.flags.iter().any(|f| *f != 0);
+        sections.values.resize(sections.keys.len(), Vec::new());
+        for value in &sections.values {
+            if self.value_bytes + value.len() as u64 > u32::MAX as u64 {
+                return Err("Values too large for the value section".into());
+            }
+            self.value_index
+                .write_all(&(self.value_bytes as u32).to_le_bytes())?;
+            self.value_index
+                .write_all(&(value.len() as u32).to_le_bytes())?;
+            self.value_data.write_all(value)?;
+            self.value_bytes += value.len() as u64;
+            self.valued |= !value.is_empty();
+        }
+        sections.base = sections.nodes.len() / NODE_SIZE;
+        sections.keys.clear();
+        sections.flags.clear();
+        sections.values.clear();
+        Ok(())
+    }
+
+    /// Write the file to `path`, with `metadata` about it as a whole, returning its header.
+    /// With more than one tree, each root's metadata gives its "Shard" as in `write_forest`.
+    pub fn finish<P: AsRef<Path>>(
+        self,
+        path: P,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<FileDescrHeader, Box<dyn error::Error>> {
+        let mut roots = self.roots;
+        let shards = roots.len();
+        if shards > 1 {
+            for (i, root) in roots.iter_mut().enumerate() {
+                root.metadata
+                    .insert("Shard".to_string(), format!("{}/{}", i, shards));
+            }
+        }
+        let keys = mapped(self.keys)?;
+        let flags = if self.flagged {
+            mapped(self.flags)?
+        } else {
+            None
+        };
+        let (value_index, value_data) = if self.valued {
+            (mapped(self.value_index)?, mapped(self.value_data)?)
+        } else {
+            (None, None)
+        };
+        let bytes = |map: &Option<Mmap>| or_empty(map).len() as u64;
+
+        let nodes = self.sections.nodes.ram();
+        let mut descr = FileDescrHeader {
+            created_on: Utc::now().to_rfc3339(),
+            node_format: "8 bits distance, 8 bits child".to_string(),
+            node_bytes: nodes.len() as u64,
+            node_offset: 0,
+            node_count: (nodes.len() / NODE_SIZE) as u64,
+            key_format: self.codec.name().to_string(),
+            key_offset: nodes.len() as u64,
+            key_bytes: bytes(&keys),
+            key_type: KEY_TYPE_U64.to_string(),
+            metric: self.metric,
+            roots,
+            metadata,
+            ..Default::default()
+        };
+        let mut offset = descr.key_offset + descr.key_bytes;
+        if flags.is_some() {
+            descr.flags_offset = Some(offset);
+            descr.flags_bytes = Some(bytes(&flags));
+            offset += bytes(&flags);
+        }
+        if value_index.is_some() {
+            descr.value_index_offset = Some(offset);
+            descr.value_index_bytes = Some(bytes(&value_index));
+            descr.value_offset = Some(offset + bytes(&value_index));
+            descr.value_bytes = Some(bytes(&value_data));
+        }
+        write_file(
+            path.as_ref(),
+            &mut descr,
+            &[
+                nodes,
+                or_empty(&keys),
+                or_empty(&flags),
+                or_empty(&value_index),
+                or_empty(&value_data),
+            ],
+            self.checksum,
+            self.signing_key.as_ref(),
+        )?;
+        Ok(descr)
+    }
+}
+
+/// Map what's been written to `out`, or None if it's empty, as empty files can't be mapped.
+fn mapped(out: BufWriter<File>) -> Result<Option<Mmap>, Box<dyn error::Error>> {
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(unsafe { Mmap::map(&file)? }))
+}
+
+fn or_empty(map: &Option<Mmap>) -> &[u8] {
+    map.as_ref().map_or(&[], |map| &map[..])
+}
+
+/// Write `descr`, then the data sections one after another, then a signature of them all if
+/// there's a `signing_key`, with the checksum over everything.
+fn write_file(
+    path: &Path,
+    descr: &mut FileDescrHeader,
+    sections: &[&[u8]],
+    checksum: ChecksumKind,
+    signing_key: Option<&[u8; 32]>,
+) -> Result<(), Box<dyn error::Error>> {
+    descr.format_version = FORMAT_VERSION;
+    descr.endianness = "little".to_string();
+    if signing_key.is_some() {
+        descr.signature_offset = Some(sections.iter().map(|s| s.len() as u64).sum());
+        descr.signature_bytes = Some(SIGNATURE_SIZE as u64);
+    }
+    let header = descr.encode(checksum.prefix_size());
+    let mut hasher = checksum.hasher()?;
+    hasher.update(&header);
+    for section in sections {
+        hasher.update(section);
+    }
+    let signature = match signing_key {
+        Some(key) => {
+            let mut signed = Sha512::new();
+            signed.input(&header);
+            for section in sections {
+                signed.input(section);
+            }
+            sign(key, &signed.result())?
+        }
+        None => Vec::new(),
+    };
+    hasher.update(&signature);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(&mut out, "{}", MAGIC_VERSION)?;
+    writeln!(&mut out, "{}: {}", checksum.name(), hasher.hex())?;
+    out.write_all(&header)?;
+    for section in sections {
+        out.write_all(section)?;
+    }
+    out.write_all(&signature)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// The ed25519 public key that goes with `signing_key`, for consumers to check signatures with.
+#[cfg(feature = "signatures")]
+pub fn public_key(signing_key: &[u8; 32]) -> [u8; 32] {
+    ed25519_dalek::SigningKey::from_bytes(signing_key)
+        .verifying_key()
+        .to_bytes()
+}
+
+#[cfg(feature = "signatures")]
+fn sign(signing_key: &[u8; 32], digest: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    use ed25519_dalek::Signer;
+    let key = ed25519_dalek::SigningKey::from_bytes(signing_key);
+    Ok(key.sign(digest).to_bytes().to_vec())
+}
+
+#[cfg(not(feature = "signatures"))]
+fn sign(_signing_key: &[u8; 32], _digest: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    Err("Signing files needs the signatures feature".into())
+}
+
+#[cfg(feature = "signatures")]
+fn verify(
+    public_key: &[u8; 32],
+    digest: &[u8],
+    signature: &[u8],
+) -> Result<(), Box<dyn error::Error>> {
+    let key = ed25519_dalek::VerifyingKey::from_bytes(public_key)?;
+    let signature = ed25519_dalek::Signature::from_slice(signature)?;
+    key.verify_strict(digest, &signature)
+        .map_err(|_| "Signature doesn't match the key".into())
+}
+
+#[cfg(not(feature = "signatures"))]
+fn verify(
+    _public_key: &[u8; 32],
+    _digest: &[u8],
+    _signature: &[u8],
+) -> Result<(), Box<dyn error::Error>> {
+    Err("Checking signatures needs the signatures feature".into())
+}
+
+/// The node format for variable length keys: `array_storage::VBNode16`, 12 bytes per node.
+pub const VARIABLE_KEY_NODE_FORMAT: &str = "16 bits distance, 16 bits child, variable key";
+/// A key format that goes with it: each node's key bytes, back to back in node order, their
+/// lengths given by where the next node's key starts. String keys are `codec::Utf8` instead.
+pub const BYTES_KEY_FORMAT: &str = "bytes";
+const VARIABLE_NODE_SIZE: usize = 12;
+
+/// Write a tree of byte string keys to `path` as a bkfile, returning the header written.
+/// Distances and child counts must be under 2^16, the keys under 4GiB in all, and the tree must
+/// have no removed keys. Flags are kept; values only by `write_string_map`.
+///
+///   bkfile::write_bytes_tree(&blobs, "blobs.bk", &Default::default())?;
+///   let blobs = bkfile_tree::open_bytes_tree("blobs.bk", metric)?;
+pub fn write_bytes_tree<K, T, P>(
+    tree: &T,
+    path: P,
+    options: &WriteOptions,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    K: Clone + Eq + std::hash::Hash + AsRef<[u8]>,
+    T: BkTree<K>,
+    P: AsRef<Path>,
+{
+    let key_format = (KEY_TYPE_BYTES, BYTES_KEY_FORMAT);
+    write_variable_key_tree(
+        tree,
+        path.as_ref(),
+        options,
+        key_format,
+        None,
+        |key, out| {
+            out.extend_from_slice(key.as_ref());
+            Ok(())
+        },
+    )
+}
+
+/// `write_bytes_tree`, for string keys, which are stored with `codec::Utf8`.
+///
+///   bkfile::write_string_tree(&words, "words.bk", &Default::default())?;
+///   let words = bkfile_tree::open_string_tree("words.bk", LevenshteinMetric)?;
+pub fn write_string_tree<T, P>(
+    tree: &T,
+    path: P,
+    options: &WriteOptions,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    T: BkTree<String>,
+    P: AsRef<Path>,
+{
+    write_string_map(tree, path, options, &HashMap::new())
+}
+
+/// `write_string_tree`, storing each key's value from `values` with it, as `write_forest` does
+/// with `WriteOptions::values`. Read them back with `bkfile_tree::open_string_map`.
+pub fn write_string_map<T, P>(
+    tree: &T,
+    path: P,
+    options: &WriteOptions,
+    values: &HashMap<String, Vec<u8>>,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    T: BkTree<String>,
+    P: AsRef<Path>,
+{
+    let key_format = (KEY_TYPE_BYTES, Utf8.name());
+    write_variable_key_tree(
+        tree,
+        path.as_ref(),
+        options,
+        key_format,
+        Some(values),
+        |key, out| Utf8.encode(key, out),
+    )
+}
+
+/// `write_bytes_tree`, for keys of any type `codec` stores, e.g. pairs. Read the file back with
+/// `bkfile_tree::open_codec_tree` and the same codec.
+///
+///   let codec = Pair::new(FixedU64, FixedU32);
+///   bkfile::write_codec_tree(&tree, "pairs.bk", &Default::default(), &codec)?;
+pub fn write_codec_tree<K, T, C, P>(
+    tree: &T,
+    path: P,
+    options: &WriteOptions,
+    codec: &C,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    K: Clone + Eq + std::hash::Hash,
+    T: BkTree<K>,
+    C: KeyCodec<Key = K> + ?Sized,
+    P: AsRef<Path>,
+{
+    let key_format = (KEY_TYPE_CODEC, codec.name());
+    write_variable_key_tree(
+        tree,
+        path.as_ref(),
+        options,
+        key_format,
+        None,
+        |key, out| codec.encode(key, out),
+    )
+}
+
+/// Write a `VARIABLE_KEY_NODE_FORMAT` file, with keys stored by `encode` and described by
+/// `key_format`: the key type, then the key format. Keys in `values` get a value section entry.
+fn write_variable_key_tree<K, T, E>(
+    tree: &T,
+    path: &Path,
+    options: &WriteOptions,
+    key_format: (&str, &str),
+    values: Option<&HashMap<K, Vec<u8>>>,
+    encode: E,
+) -> Result<FileDescrHeader, Box<dyn error::Error>>
+where
+    K: Clone + Eq + std::hash::Hash,
+    T: BkTree<K>,
+    E: Fn(&K, &mut Vec<u8>) -> Result<(), Box<dyn error::Error>>,
+{
+    options.check_variable_keys()?;
+    let mut nodes: Vec<u8> = Vec::new();
+    // Indexed like the nodes.
+    let mut keys: Vec<&K> = Vec::new();
+    let mut flags: Vec<u8> = Vec::new();
+    // Laid out as `write_forest` does, with each node's children side by side after it.
+    let mut stack: Vec<(usize, Dist, &T::Node)> = Vec::new();
+    if let Some(root) = tree.root() {
+        nodes.resize(VARIABLE_NODE_SIZE, 0);
+        stack.push((0, 0, root));
+    }
+    while let Some((offset, dist, node)) = stack.pop() {
+        if node.is_tombstone() {
+            return Err("Bkfiles can't hold removed keys: compact the tree first".into());
+        }
+        let children = node.children_vector();
+        let child_offset = nodes.len();
+        nodes.resize(child_offset + VARIABLE_NODE_SIZE * children.len(), 0);
+        let mut mirror = VBNode16 {
+            offset,
+            node_buffer: &mut nodes[..],
+            key_buffer: &mut [][..],
+        };
+        mirror.set_dist(dist)?;
+        mirror.set_num_children(children.len())?;
+        if !children.is_empty() {
+            mirror.set_child_offset(child_offset)?;
+        }
+        let index = offset / VARIABLE_NODE_SIZE;
+        if keys.len() <= index {
+            keys.resize(index + 1, node.key());
+            flags.resize(index + 1, 0);
+        }
+        keys[index] = node.key();
+        flags[index] = node.flags();
+        for (i, (dist, child)) in children.iter().rev().enumerate().rev() {
+            stack.push((child_offset + VARIABLE_NODE_SIZE * i, *dist, *child));
+        }
+    }
+
+    // Now the keys' offsets are known.
+    let mut key_bytes: Vec<u8> = Vec::new();
+    for (index, key) in keys.iter().enumerate() {
+        VBNode16 {
+            offset: index * VARIABLE_NODE_SIZE,
+            node_buffer: &mut nodes[..],
+            key_buffer: &mut [][..],
+        }
+        .set_key_offset(key_bytes.len())?;
+        encode(key, &mut key_bytes)?;
+    }
+
+    let mut descr = FileDescrHeader {
+        created_on: Utc::now().to_rfc3339(),
+        node_format: VARIABLE_KEY_NODE_FORMAT.to_string(),
+        node_bytes: nodes.len() as u64,
+        node_offset: 0,
+        node_count: keys.len() as u64,
+        key_format: key_format.1.to_string(),
+        key_offset: nodes.len() as u64,
+        key_bytes: key_bytes.len() as u64,
+        key_type: key_format.0.to_string(),
+        metric: metric_name(std::slice::from_ref(tree)),
+        metadata: options.metadata.clone(),
+        ..Default::default()
+    };
+    let flags: &[u8] = if flags.iter().any(|f| *f != 0) {
+        descr.flags_offset = Some(descr.key_offset + descr.key_bytes);
+        descr.flags_bytes = Some(flags.len() as u64);
+        &flags
+    } else {
+        &[]
+    };
+    // As in write_forest, an index entry for every node, only if any key has a value.
+    let (value_index, value_data) = match values {
+        Some(values) if keys.iter().any(|key| values.contains_key(key)) => {
+            let empty = Vec::new();
+            let indexed: Vec<&Vec<u8>> = keys
+                .iter()
+                .map(|key| values.get(key).unwrap_or(&empty))
+                .collect();
+            encode_values(&indexed)?
+        }
+        _ => (Vec::new(), Vec::new()),
+    };
+    if !value_index.is_empty() {
+        let index_offset = descr.key_offset + descr.key_bytes + flags.len() as u64;
+        descr.value_index_offset = Some(index_offset);
+        descr.value_index_bytes = Some(value_index.len() as u64);
+        descr.value_offset = Some(index_offset + value_index.len() as u64);
+        descr.value_bytes = Some(value_data.len() as u64);
+    }
+    write_file(
+        path,
+        &mut descr,
+        &[&nodes, &key_bytes, flags, &value_index, &value_data],
+        options.checksum,
+        options.signing_key.as_ref(),
+    )?;
+    Ok(descr)
+}
+
+/// Open a bkfile of any format, checking its checksum, as a tree of whatever keys it holds, with
+/// the metric its header names (see `metric::dynamic::by_name`): u64s, which are searched by
+/// Hamming distance, or byte strings. Variable length keys in files from before metrics were
+/// recorded are taken to be text, by Levenshtein distance, as `bkfile_from_strings` builds them.
+/// Files of several trees open as a forest. Files of byte strings built with a metric plugin (see
+/// `metric::plugin`) need it registered under its name first.
+///
+///   let tree = bkfile::open_any("hashes.bk")?;
+///   println!("{} {} keys by {}", tree.len(), tree.key_type(), tree.metric_name());
+pub fn open_any<P: AsRef<Path>>(path: P) -> Result<Box<dyn DynBkTree>, Box<dyn error::Error>> {
+    let path = path.as_ref();
+    let header = Header::read(&mut File::open(path)?, false)?;
+    let descr = header.descr();
+    if descr.node_format == VARIABLE_KEY_NODE_FORMAT {
+        let metric = match descr.metric.as_str() {
+            "" => metric::dynamic::by_name("levenshtein")?,
+            name => metric::dynamic::by_name(name)?,
+        };
+        return Ok(Box::new(bkfile_tree::open_bytes_tree(path, metric)?));
+    }
+    if !["", "hamming", "hamming-u64"].contains(&descr.metric.as_str()) {
+        return Err(format!(
+            "{:?} was built with the metric {:?}, but only Hamming distance searches u64 keys",
+            path, descr.metric
+        )
+        .into());
+    }
+    let mut trees = BkFile::open(path, true)?.trees()?;
+    if trees.len() == 1 {
+        return Ok(Box::new(trees.pop().unwrap()));
+    }
+    Ok(Box::new(BkForest::new(trees)))
+}
+
+/// A bkfile holding `descr` and `data`, with a valid checksum, rewound to the start.
+#[cfg(test)]
+pub(crate) fn write_test_file(descr: &mut FileDescrHeader, data: &[u8]) -> File {
+    let encoded = descr.encode(PREFIX_SIZE);
+    let mut hasher = Sha256::new();
+    hasher.input(&encoded);
+    hasher.input(data);
+    let mut file = tempfile::tempfile().unwrap();
+    writeln!(&mut file, "{}", MAGIC_VERSION).unwrap();
+    writeln!(&mut file, "{}: {:064x}", HASH_HEADER_NAME, hasher.result()).unwrap();
+    file.write_all(&encoded).unwrap();
+    file.write_all(data).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamTree, STRING_ALLOC, U64_ALLOC};
+    use crate::bktree::{BkTreeAdd, BkTreeRemove};
+    use crate::keys::{StringKey, U64Key};
+    use crate::metric::combinators::ScaledMetric;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+    use crate::metric::strlen::StrLenMetric;
+    use crate::metric::Metric;
+
+    #[test]
+    fn writes_trees_that_read_back() {
+        let (keys, mut tree) = hashed_tree(500);
+        tree.set_flags(&keys[1], 4);
+        let mut values = HashMap::new();
+        values.insert(keys[1], b"one".to_vec());
+        let mut metadata = BTreeMap::new();
+        metadata.insert("Source".to_string(), "test".to_string());
+        let options = WriteOptions {
+            codec: Some(&codec::Varint),
+            metadata: metadata.clone(),
+            values: Some(&values),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        let descr = write_tree(&tree, &path, &options).unwrap();
+        assert_eq!(
+            (500, "varint", "u64", "little"),
+            (
+                descr.node_count,
+                descr.key_format.as_str(),
+                descr.key_type.as_str(),
+                descr.endianness.as_str()
+            )
+        );
+
+        let header = Header::read(&mut File::open(&path).unwrap(), false).unwrap();
+        assert_eq!(&metadata, header.meta());
+        let file = BkFile::open(&path, true).unwrap();
+        assert_eq!(metadata, file.descr().metadata);
+        let read = file.trees().unwrap().pop().unwrap();
+        for needle in &[0, keys[1] ^ 0b11] {
+            let mut expected = Vec::new();
+            tree.find_each_flagged(needle, 20, |d, k, f| expected.push((d, *k, f)));
+            let mut found = Vec::new();
+            read.find_each_flagged(needle, 20, |d, k, f| found.push((d, *k, f)));
+            expected.sort();
+            found.sort();
+            assert_eq!(expected, found);
+        }
+        assert_eq!(values, read.into_parts().unwrap().1);
+
+        tree.remove(&0);
+        assert!(write_tree(&tree, &path, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn sizes_fields_to_fit() {
+        #[derive(Clone, Copy, Default)]
+        struct Difference;
+        impl Metric<u64> for Difference {
+            fn distance(&self, k1: &u64, k2: &u64) -> Dist {
+                k1.abs_diff(*k2) as Dist
+            }
+        }
+        let mut tree: BkInRamTree<U64Key, Difference, _> = BkInRamTree::new(Difference, &U64_ALLOC);
+        for key in &[0u64, 100, 200, 300] {
+            tree.add(key).unwrap();
+        }
+        assert_eq!(
+            FieldWidths {
+                max_dist: 300,
+                max_children: 3,
+                max_key: 300
+            },
+            FieldWidths::measure(std::slice::from_ref(&tree))
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        assert!(write_tree(&tree, &path, &Default::default()).is_err());
+        assert!(!path.exists());
+
+        tree.remove(&300);
+        tree.compact().unwrap();
+        let descr = write_tree(&tree, &path, &Default::default()).unwrap();
+        assert_eq!(
+            ("fixed 8 bits", 3),
+            (descr.key_format.as_str(), descr.key_bytes)
+        );
+        let read = BkFile::open(&path, true)
+            .unwrap()
+            .trees()
+            .unwrap()
+            .pop()
+            .unwrap();
+        let mut keys = Vec::new();
+        read.preorder_each(|dist, _, key| keys.push((dist, *key)));
+        keys.sort();
+        assert_eq!(vec![(0, 0), (100, 100), (200, 200)], keys);
+    }
+
+    #[test]
+    fn reads_forest_roots() {
+        let mut shard = BTreeMap::new();
+        shard.insert("Shard".to_string(), "1/2".to_string());
+        let mut descr = FileDescrHeader {
+            node_format: "8 bits distance, 8 bits child".to_string(),
+            node_bytes: 24,
+            node_count: 3,
+            key_format: "fixed 64 bits".to_string(),
+            key_offset: 24,
+            key_bytes: 24,
+            roots: vec![
+                RootDescr {
+                    node_offset: 0,
+                    node_count: 2,
+                    metadata: BTreeMap::new(),
+                },
+                RootDescr {
+                    node_offset: 8,
+                    node_count: 1,
+                    metadata: shard.clone(),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut file = write_test_file(&mut descr, &[7; 48]);
+
+        let header = Header::read(&mut file, true).unwrap();
+        assert_eq!(0, header.data_offset() % 64);
+        assert_eq!(descr.roots, header.descr().roots());
+        assert_eq!(shard, header.descr().roots()[1].metadata);
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(header.data_offset())).unwrap();
+        io::Read::read_to_end(&mut file, &mut data).unwrap();
+        assert_eq!(vec![7; 48], data);
+    }
+
+    #[test]
+    fn reads_files_from_before_format_versions() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(include_bytes!("../testdata/pre_format_version.bk"))
+            .unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let file = BkFile::from_file(file, true).unwrap();
+        assert_eq!("", file.descr().key_type);
+        let trees = file.trees().unwrap();
+        assert_eq!(40, BkTree::len(&trees[0]));
+        assert!(trees[0].contains(&0x9e3779b97f4a7c15));
+        assert_eq!(Some(&b"one"[..]), trees[0].value(&0x9e3779b97f4a7c15));
+    }
+
+    #[test]
+    fn refuses_files_it_cant_read() {
+        let descr = FileDescrHeader {
+            node_bytes: 8,
+            node_count: 1,
+            key_format: "fixed 64 bits".to_string(),
+            key_offset: 8,
+            key_bytes: 8,
+            ..Default::default()
+        };
+        let open = |descr: &FileDescrHeader| {
+            BkFile::from_file(write_test_file(&mut descr.clone(), &[0; 16]), true)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(Ok(()), open(&descr));
+        let strings = FileDescrHeader {
+            key_type: KEY_TYPE_BYTES.to_string(),
+            ..descr.clone()
+        };
+        assert!(open(&strings).unwrap_err().contains("\"bytes\" keys"));
+        let newer = FileDescrHeader {
+            format_version: FORMAT_VERSION + 1,
+            ..descr.clone()
+        };
+        assert!(open(&newer).unwrap_err().contains("version"));
+        let big = FileDescrHeader {
+            endianness: "big".to_string(),
+            ..descr
+        };
+        assert!(open(&big).unwrap_err().contains("endianness"));
+    }
+
+    #[test]
+    fn refuses_files_without_a_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unsummed.bk");
+        for checksum in &["", "   "] {
+            let header = format!("{}\n{}:{}\n", MAGIC_VERSION, HASH_HEADER_NAME, checksum);
+            std::fs::write(&path, header).unwrap();
+            let err = BkFile::open(&path, false).map(|_| ()).unwrap_err();
+            assert_eq!("Missing checksum", err.to_string());
+        }
+    }
+
+    #[test]
+    fn sibling_deltas_shrink_dense_keys() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..2000u64 {
+            tree.add(&(0x5000_0000_0000 + i * 3)).unwrap();
+        }
+        tree.set_flags(&0x5000_0000_0003, 2);
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, delta) = (dir.path().join("plain.bk"), dir.path().join("delta.bk"));
+        let plain_descr = write_tree(&tree, &plain, &Default::default()).unwrap();
+        let options = WriteOptions {
+            sibling_deltas: true,
+            ..Default::default()
+        };
+        let descr = write_tree(&tree, &delta, &options).unwrap();
+        assert_eq!(DELTA_KEY_FORMAT, descr.key_format);
+        assert!(descr.key_bytes * 3 < plain_descr.key_bytes);
+
+        let file = BkFile::open(&delta, true).unwrap();
+        let read = &file.trees().unwrap()[0];
+        for needle in &[0x5000_0000_0003, 0x5000_0000_0fff, 0] {
+            assert_eq!(tree.find_sorted(needle, 5), read.find_sorted(needle, 5));
+        }
+        let mut flagged = Vec::new();
+        read.find_each_flagged(&0x5000_0000_0003, 0, |_, key, flags| {
+            flagged.push((*key, flags))
+        });
+        assert_eq!(vec![(0x5000_0000_0003, 2)], flagged);
+        let err = bkfile_tree::BkFileMut::open(&delta)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("sibling deltas") && err.contains(DELTA_KEY_FORMAT));
+
+        let options = WriteOptions {
+            sibling_deltas: true,
+            codec: Some(&codec::Varint),
+            ..Default::default()
+        };
+        assert!(write_tree(&tree, &delta, &options).is_err());
+    }
+
+    #[test]
+    fn checksum_kinds() {
+        let (_, tree) = hashed_tree(100);
+        let dir = tempfile::tempdir().unwrap();
+        for kind in &[
+            ChecksumKind::Sha256,
+            ChecksumKind::Blake3,
+            ChecksumKind::Xxh3,
+        ] {
+            let path = dir.path().join(format!("{}.bk", kind.name()));
+            let options = WriteOptions {
+                checksum: *kind,
+                ..Default::default()
+            };
+            let written = write_tree(&tree, &path, &options);
+            if cfg!(not(feature = "fast-checksums")) && *kind != ChecksumKind::Sha256 {
+                assert!(written.unwrap_err().to_string().contains("fast-checksums"));
+                continue;
+            }
+            let header = Header::read(&mut File::open(&path).unwrap(), true).unwrap();
+            assert_eq!(*kind, header.checksum_kind());
+            let prefix = std::fs::read(&path).unwrap()[..kind.prefix_size()].to_vec();
+            assert!(String::from_utf8(prefix).unwrap().ends_with(&format!(
+                "{}: {}\n",
+                kind.name(),
+                header.checksum()
+            )));
+            let trees = BkFile::open(&path, true).unwrap().trees().unwrap();
+            assert_eq!(100, BkTree::len(&trees[0]));
+        }
+
+        let mut file = write_test_file(&mut Default::default(), &[]);
+        let mut bytes = Vec::new();
+        io::Read::read_to_end(&mut file, &mut bytes).unwrap();
+        let at = MAGIC_VERSION.len() + 1;
+        bytes[at..at + HASH_HEADER_NAME.len()].copy_from_slice(b"SHA512");
+        let path = dir.path().join("sha512.bk");
+        std::fs::write(&path, &bytes).unwrap();
+        let err = Header::read(&mut File::open(&path).unwrap(), false).unwrap_err();
+        assert!(err.to_string().contains("Unknown checksum"));
+    }
+
+    #[test]
+    fn signed_files() {
+        let (_, tree) = hashed_tree(100);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signed.bk");
+        let options = WriteOptions {
+            signing_key: Some([7; 32]),
+            ..Default::default()
+        };
+        let written = write_tree(&tree, &path, &options);
+        if cfg!(not(feature = "signatures")) {
+            assert!(written
+                .unwrap_err()
+                .to_string()
+                .contains("signatures feature"));
+            return;
+        }
+        let unsigned = dir.path().join("unsigned.bk");
+        write_tree(&tree, &unsigned, &Default::default()).unwrap();
+        let header = |path: &Path, verify| Header::read(&mut File::open(path).unwrap(), verify);
+
+        #[cfg(feature = "signatures")]
+        {
+            let key = public_key(&[7; 32]);
+            header(&path, true).unwrap().verify_signature(&key).unwrap();
+            let other = public_key(&[8; 32]);
+            assert!(header(&path, true)
+                .unwrap()
+                .verify_signature(&other)
+                .is_err());
+            assert!(header(&path, false)
+                .unwrap()
+                .verify_signature(&key)
+                .is_err());
+            let err = header(&unsigned, true).unwrap().verify_signature(&key);
+            assert!(err.unwrap_err().to_string().contains("isn't signed"));
+
+            // BkFile checks its own mapping, so needn't have verified the checksum.
+            let file = BkFile::open(&path, false).unwrap();
+            file.verify_signature(&key).unwrap();
+            assert!(file.verify_signature(&other).is_err());
+            let err = BkFile::open(&unsigned, false)
+                .unwrap()
+                .verify_signature(&key);
+            assert!(err.unwrap_err().to_string().contains("isn't signed"));
+        }
+        // Signed files read like any other.
+        let trees = BkFile::open(&path, true).unwrap().trees().unwrap();
+        assert_eq!(100, BkTree::len(&trees[0]));
+        assert!(header(&path, false).unwrap().is_signed());
+
+        // Tampering with a section breaks the signature, even with the checksum fixed up.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - SIGNATURE_SIZE - 1;
+        bytes[last] ^= 1;
+        let mut hasher = Sha256::new();
+        hasher.input(&bytes[PREFIX_SIZE..]);
+        let at = MAGIC_VERSION.len() + 1 + HASH_HEADER_NAME.len() + 2;
+        bytes[at..at + 64].copy_from_slice(format!("{:064x}", hasher.result()).as_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        #[cfg(feature = "signatures")]
+        {
+            let key = public_key(&[7; 32]);
+            assert!(header(&path, true).unwrap().verify_signature(&key).is_err());
+            let file = BkFile::open(&path, false).unwrap();
+            assert!(file.verify_signature(&key).is_err());
+        }
+    }
+
+    #[test]
+    fn string_files_take_the_checksum_and_signing_key() {
+        let mut words: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for i in 0..50 {
+            words.add(&format!("word{}", i)).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.bk");
+        let reserved = WriteOptions {
+            reserve_nodes: 10,
+            ..Default::default()
+        };
+        assert!(write_string_tree(&words, &path, &reserved).is_err());
+
+        let options = WriteOptions {
+            checksum: ChecksumKind::Blake3,
+            signing_key: Some([7; 32]),
+            ..Default::default()
+        };
+        let written = write_string_tree(&words, &path, &options);
+        if cfg!(not(all(feature = "fast-checksums", feature = "signatures"))) {
+            assert!(written.is_err());
+            return;
+        }
+        let header = Header::read(&mut File::open(&path).unwrap(), true).unwrap();
+        assert_eq!(ChecksumKind::Blake3, header.checksum_kind());
+        #[cfg(feature = "signatures")]
+        header.verify_signature(&public_key(&[7; 32])).unwrap();
+    }
+
+    #[test]
+    fn reads_later_versions_fields_and_all() {
+        let mut descr = FileDescrHeader {
+            node_bytes: 8,
+            node_count: 1,
+            key_format: "fixed 64 bits".to_string(),
+            key_offset: 8,
+            key_bytes: 8,
+            ..Default::default()
+        };
+        // Over 23 fields in all, so the map's head takes two bytes.
+        for i in 0..22 {
+            let value = serde_cbor::to_vec(&format!("value {}", i)).unwrap();
+            descr.unknown.insert(
+                format!("Later-Field-{}", i),
+                serde_cbor::from_slice(&value).unwrap(),
+            );
+        }
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&42u64.to_le_bytes());
+        let later = |version: &[u8]| {
+            let mut file = write_test_file(&mut descr.clone(), &data);
+            file.seek(SeekFrom::Start(MAGIC_PREFIX.len() as u64))
+                .unwrap();
+            file.write_all(version).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file
+        };
+
+        let mut file = later(b"0001");
+        let header = Header::read(&mut file, true).unwrap();
+        assert_eq!((1, 22), (header.version(), header.descr().unknown.len()));
+        assert_eq!(
+            serde_cbor::to_vec(&descr.unknown).unwrap(),
+            serde_cbor::to_vec(&header.descr().unknown).unwrap()
+        );
+        assert_eq!(0, header.data_offset() % 64);
+        let tree = BkFile::from_file(later(b"0001"), true)
+            .unwrap()
+            .trees()
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert!(tree.contains(&42));
+
+        let err = Header::read(&mut later(b"0002"), true).unwrap_err();
+        assert!(err.to_string().contains("BKTREE: 0001"), "{}", err);
+    }
+
+    #[test]
+    fn single_root_without_roots_section() {
+        let mut descr = FileDescrHeader {
+            node_bytes: 8,
+            node_count: 1,
+            key_offset: 8,
+            key_bytes: 8,
+            ..Default::default()
+        };
+        let mut file = write_test_file(&mut descr, &[0; 16]);
+        let header = Header::read(&mut file, true).unwrap();
+        assert!(header.meta().is_empty());
+        let roots = header.descr().roots();
+        assert_eq!(1, roots.len());
+        assert_eq!((0, 1), (roots[0].node_offset, roots[0].node_count));
+    }
+
+    #[test]
+    fn open_any_reads_each_format() {
+        let (keys, tree) = hashed_tree(100);
+        let mut halves: Vec<BkInRamTree<U64Key, HammingMetric<u64>, _>> = (0..2)
+            .map(|_| BkInRamTree::new(Default::default(), &U64_ALLOC))
+            .collect();
+        let mut words: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for (i, key) in keys.iter().enumerate() {
+            halves[i % 2].add(key).unwrap();
+            words.add(&format!("word{}", i)).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..3)
+            .map(|i| dir.path().join(format!("{}.bk", i)))
+            .collect();
+        write_tree(&tree, &paths[0], &Default::default()).unwrap();
+        write_forest(&halves, &paths[1], &Default::default()).unwrap();
+        write_string_tree(&words, &paths[2], &Default::default()).unwrap();
+
+        let needle = keys[1] ^ 0b101;
+        let expected: Vec<(Dist, Vec<u8>)> = tree
+            .find_sorted(&needle, 20)
+            .into_iter()
+            .map(|(dist, key)| (dist, key.to_le_bytes().to_vec()))
+            .collect();
+        for path in &paths[..2] {
+            let any = open_any(path).unwrap();
+            assert_eq!(("u64", 100), (any.key_type(), any.len()));
+            let mut found = Vec::new();
+            any.find_each_bytes(&needle.to_le_bytes(), 20, &mut |dist, key| {
+                found.push((dist, key.to_vec()))
+            });
+            found.sort();
+            assert_eq!(expected, found);
+        }
+
+        let any = open_any(&paths[2]).unwrap();
+        assert_eq!(
+            ("bytes", "levenshtein"),
+            (any.key_type(), any.metric_name())
+        );
+        let mut found = Vec::new();
+        any.find_each_bytes(b"word7", 0, &mut |dist, key| {
+            found.push((dist, key.to_vec()))
+        });
+        assert_eq!(vec![(0, b"word7".to_vec())], found);
+
+        // String files are searched with the metric the header names, or Levenshtein distance
+        // if it names none.
+        let mut by_length: BkInRamTree<StringKey, StrLenMetric, _> =
+            BkInRamTree::new(StrLenMetric, &STRING_ALLOC);
+        let mut unnamed: BkInRamTree<StringKey, ScaledMetric<LevenshteinMetric>, _> =
+            BkInRamTree::new(ScaledMetric::new(LevenshteinMetric, 1), &STRING_ALLOC);
+        for word in &["a", "bb", "cc", "ddd"] {
+            by_length.add(word).unwrap();
+            unnamed.add(word).unwrap();
+        }
+        write_string_tree(&by_length, &paths[2], &Default::default()).unwrap();
+        let any = open_any(&paths[2]).unwrap();
+        assert_eq!("strlen", any.metric_name());
+        let mut found = Vec::new();
+        any.find_each_bytes(b"xx", 0, &mut |_, key| found.push(key.to_vec()));
+        found.sort();
+        assert_eq!(vec![b"bb".to_vec(), b"cc".to_vec()], found);
+        write_string_tree(&unnamed, &paths[2], &Default::default()).unwrap();
+        let header = Header::read(&mut File::open(&paths[2]).unwrap(), false).unwrap();
+        assert_eq!("", header.descr().metric);
+        assert_eq!("levenshtein", open_any(&paths[2]).unwrap().metric_name());
     }
 }
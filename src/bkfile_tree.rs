@@ -0,0 +1,2036 @@
+/*
+ * Searching bkfiles from applications.
+ *
+ *   let tree = BkFileTree::open("hashes.bk")?;
+ *   tree.find_each(&needle, 4, |dist, key| println!("{}\t{}", dist, key));
+ *
+ * Files holding a forest (see `bkfile::RootDescr`) are opened with `BkFile`, which hands out a
+ * tree per root. Trees are searched in place, through the file's mapping: `find_each` and its
+ * value and flag variants read nodes and keys straight out of it, decoding each key with the
+ * file's codec as the search reaches it, so opening a file doesn't read its keys into RAM. The
+ * generic `BkTree` searches decode the nodes they visit, and keep them for next time. Files of
+ * variable length keys (varints, sibling deltas) keep where each key starts, 8 bytes a node.
+ *
+ * Each tree is walked as it's opened, checking that every child is inside the node array and is
+ * reached only once, so a corrupt file is an error rather than a hang or a panic. Children must
+ * follow their parents, except in files with free nodes, where keys added in place (see
+ * `BkFileTreeMut`) move nodes to the end. Files cut short can be opened with
+ * `BkFile::open_truncated`, serving what survived.
+ *
+ * Files are mapped read only, and trees share the mapping with the file they came from, so both
+ * are `Send + Sync`: one `Arc<BkFile>` can open trees on many threads, and one tree can serve
+ * many query threads.
+ *
+ * Files of string or byte string keys, as `bkfile::write_string_tree` and `write_bytes_tree`
+ * write them, open straight into in-RAM trees, with the metric they were built with:
+ *
+ *   let words = open_string_tree("words.bk", LevenshteinMetric)?;
+ */
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use memmap::{Mmap, MmapMut, MmapOptions};
+
+use crate::array_storage::{F64BNode8, InStorageNode, InStorageNodeMut, VBNode16};
+use crate::bk::{BkFind, BkInRam, BkInRamAllocator, BkInRamTree, U64_ALLOC};
+use crate::bkfile::{self, FileDescrHeader, Header, RootDescr};
+use crate::bknode::{BkNode, BkNodeMut};
+use crate::bktree::{BkTree, BkTreeAdd, BkTreeRootMut};
+use crate::codec::{from_delta, u64_codec, FixedU64, FixedWidth, KeyCodec, Utf8, Varint};
+use crate::keyquery::KeyQuery;
+use crate::keys::{BytesKey, StringKey, U64Key};
+use crate::metric::hamming::{hamming_block, HammingMetric};
+use crate::metric::Metric;
+use crate::nodeallocator::NodeAllocator;
+use crate::Dist;
+
+// F64BNode8 uses 8 bytes per node
+const NODE_SIZE: usize = 8;
+// VBNode16 uses 12
+const VARIABLE_NODE_SIZE: usize = 12;
+
+type InRamTree = BkInRamTree<'static, U64Key, HammingMetric<u64>, BkInRamAllocator<'static, u64>>;
+
+/// A tree decoded into RAM, and its values by key (see `BkFileTree::into_parts`).
+pub type DecodedTree = (InRamTree, HashMap<u64, Vec<u8>>);
+
+fn at<B: AsRef<[u8]> + Default>(nodes: B, offset: usize) -> F64BNode8<B> {
+    F64BNode8 {
+        offset,
+        node_buffer: nodes,
+        // Keys are decoded with the file's codec rather than read through the node.
+        key_buffer: B::default(),
+    }
+}
+
+/// Where a tree sits in a node array, for `walk_nodes`.
+struct NodeArray<A> {
+    /// Reads the node at an offset.
+    at: A,
+    /// Bytes per node.
+    node_size: usize,
+    /// Bytes the header gives the array. Children must lie inside it.
+    node_bytes: usize,
+    /// Bytes of it that can be read. Children past them were lost to truncation, and are
+    /// skipped.
+    usable: usize,
+    /// Children must come after their parents, as in files that are only ever written whole.
+    forward_children: bool,
+}
+
+/// Walk the tree under the node at `root`, parents before children, checking every child
+/// follows its parent inside the node array, no node is reached twice and no two siblings share
+/// a slot. Calls `visit` with each node's offset and depth, and for all but the root, which call
+/// visited its parent and its slot there. Returns how many nodes it visited. Keeps one bit per
+/// node while it runs.
+fn walk_nodes<N, A, V>(
+    nodes: &NodeArray<A>,
+    root: usize,
+    mut visit: V,
+) -> Result<u64, Box<dyn Error>>
+where
+    N: InStorageNode,
+    A: Fn(usize) -> N,
+    V: FnMut(usize, Option<(usize, Dist)>, usize) -> Result<(), Box<dyn Error>>,
+{
+    let size = nodes.node_size;
+    let mut visited = vec![0u64; (nodes.node_bytes / size).div_ceil(64)];
+    let mut pending = vec![(root, None, 0)];
+    let mut slots = Vec::new();
+    let mut node_count = 0;
+    while let Some((offset, parent, depth)) = pending.pop() {
+        let index = offset / size;
+        if visited[index / 64] & 1 << (index % 64) != 0 {
+            return Err(format!("Node at offset {} is reached twice", offset).into());
+        }
+        visited[index / 64] |= 1 << (index % 64);
+        visit(offset, parent, depth)?;
+        let visit_index = node_count as usize;
+        node_count += 1;
+
+        let node = (nodes.at)(offset);
+        let child_count = node.child_count().ok_or("node has no child count")?;
+        let children = match node.children_offset() {
+            Some(children) => children,
+            None if child_count == 0 => continue,
+            None => return Err(format!("Node at offset {} has bad children", offset).into()),
+        };
+        if (nodes.forward_children && children <= offset)
+            || !children.is_multiple_of(size)
+            || children + size * child_count > nodes.node_bytes
+        {
+            return Err(format!("Node at offset {} has bad children", offset).into());
+        }
+        slots.clear();
+        let usable = child_count.min(nodes.usable.saturating_sub(children) / size);
+        for i in 0..usable {
+            let child = children + size * i;
+            let slot = (nodes.at)(child).dist().ok_or("node array truncated")?;
+            slots.push(slot);
+            pending.push((child, Some((visit_index, slot)), depth + 1));
+        }
+        slots.sort_unstable();
+        if let Some(pair) = slots.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("Two children share slot {}", pair[0]).into());
+        }
+    }
+    Ok(node_count)
+}
+
+/// A bkfile's mapping, and where its sections are in it. Shared by the file and every tree
+/// opened from it. `BkFileMut` maps its file writable.
+struct Mapped<R = Mmap> {
+    header: Header,
+    ram: R,
+    /// Each section's bytes in `ram`, or as much of it as survived truncation.
+    nodes: Range<usize>,
+    keys: Range<usize>,
+    flags: Range<usize>,
+    value_index: Range<usize>,
+    values: Range<usize>,
+    codec: Box<dyn KeyCodec<Key = u64>>,
+    /// Bytes per key, for fixed width key formats.
+    key_width: Option<usize>,
+    /// Where each node's key starts in the key section, then where the last one ends, for the
+    /// other formats.
+    key_starts: Vec<usize>,
+    /// Keys are stored as differences from their reference nodes' keys (see
+    /// `bkfile::delta_references`).
+    deltas: bool,
+    /// Bytes of the node array whose nodes and keys are both in the file. All of it, unless the
+    /// file was opened with `open_truncated`.
+    usable: usize,
+}
+
+impl<R: Deref<Target = [u8]>> Mapped<R> {
+    /// Read `file`'s header and find its sections in the mapping `map` makes of it. See
+    /// `BkFile::open_truncated` for `allow_truncation`.
+    fn read<F>(
+        mut file: File,
+        verify_checksum: bool,
+        allow_truncation: bool,
+        map: F,
+    ) -> Result<Self, Box<dyn Error>>
+    where
+        F: FnOnce(&File) -> io::Result<R>,
+    {
+        let header = Header::read(&mut file, verify_checksum)?;
+        let descr = header.descr();
+        descr.check_key_type(bkfile::KEY_TYPE_U64)?;
+        // Sibling deltas are read as plain varints, then added to their reference keys.
+        let deltas = descr.key_format == bkfile::DELTA_KEY_FORMAT;
+        let codec = if deltas {
+            Box::new(Varint)
+        } else {
+            u64_codec(&descr.key_format)
+                .ok_or_else(|| format!("Unsupported key format {:?}", descr.key_format))?
+        };
+        let ram = map(&file)?;
+        if (ram.len() as u64) < header.data_offset() {
+            return Err("File ends inside its header".into());
+        }
+        let data_offset = header.data_offset();
+        let data_len = ram.len() as u64 - data_offset;
+        let node_end = descr.node_offset + descr.node_bytes;
+        let key_end = descr.key_offset + descr.key_bytes;
+        if node_end > descr.key_offset || (key_end > data_len && !allow_truncation) {
+            return Err("Node and key arrays overlap or run past the end of the file".into());
+        }
+        if descr.node_bytes % NODE_SIZE as u64 != 0 {
+            return Err("Node array isn't a whole number of nodes".into());
+        }
+        // A section's bytes in the mapping, or as much of them as survived truncation.
+        let range = |offset: u64, bytes: u64| {
+            let end = (offset + bytes).min(data_len);
+            (data_offset + offset.min(end)) as usize..(data_offset + end) as usize
+        };
+        let nodes = range(descr.node_offset, descr.node_bytes);
+        let keys = range(descr.key_offset, descr.key_bytes);
+        let node_slots = descr.node_bytes as usize / NODE_SIZE;
+
+        // Keys are stored in node order, one per node, so a node is usable if its key is.
+        let key_width = if codec.name() == FixedU64.name() {
+            Some(8)
+        } else {
+            None
+        };
+        let mut key_starts = Vec::new();
+        let present = match key_width {
+            Some(width) => (keys.len() / width).min(node_slots),
+            None => {
+                key_starts.reserve(node_slots + 1);
+                key_starts.push(0);
+                let section = &ram[keys.clone()];
+                let mut start = 0;
+                while key_starts.len() <= node_slots {
+                    match codec.decode(&section[start..]) {
+                        Ok((_, used)) => start += used,
+                        Err(_) if allow_truncation => break,
+                        Err(e) => return Err(e),
+                    }
+                    key_starts.push(start);
+                }
+                key_starts.len() - 1
+            }
+        };
+        let usable = (nodes.len().min(present * NODE_SIZE)) / NODE_SIZE * NODE_SIZE;
+        // A section following the keys.
+        let section = |name: &str, offset: u64, bytes: u64| {
+            if offset < key_end || (offset + bytes > data_len && !allow_truncation) {
+                return Err(format!(
+                    "{} section overlaps the keys or runs past the end of the file",
+                    name
+                ));
+            }
+            Ok(range(offset, bytes))
+        };
+        // Nodes whose flags were lost are unflagged, and likewise for values.
+        let flags = match (descr.flags_offset, descr.flags_bytes) {
+            (Some(offset), Some(bytes)) => section("Flags", offset, bytes)?,
+            _ => 0..0,
+        };
+        let (value_index, values) = match (
+            descr.value_index_offset,
+            descr.value_index_bytes,
+            descr.value_offset,
+            descr.value_bytes,
+        ) {
+            (Some(index_offset), Some(index_bytes), Some(offset), Some(bytes)) => {
+                let index = section("Value index", index_offset, index_bytes)?;
+                for (i, entry) in ram[index.clone()].chunks_exact(8).enumerate() {
+                    let end = LittleEndian::read_u32(entry) as u64
+                        + LittleEndian::read_u32(&entry[4..]) as u64;
+                    if end > bytes {
+                        return Err(format!("Value for node {} runs past the value data", i).into());
+                    }
+                }
+                (index, section("Value", offset, bytes)?)
+            }
+            _ => (0..0, 0..0),
+        };
+        Ok(Mapped {
+            header,
+            ram,
+            nodes,
+            keys,
+            flags,
+            value_index,
+            values,
+            codec,
+            key_width,
+            key_starts,
+            deltas,
+            usable,
+        })
+    }
+
+    fn nodes(&self) -> &[u8] {
+        &self.ram[self.nodes.clone()]
+    }
+
+    /// The distance from its parent of the node at `offset`.
+    fn slot(&self, offset: usize) -> Dist {
+        self.nodes()[offset] as Dist
+    }
+
+    /// Where the node at `offset`'s children start, and how many of them are usable.
+    fn children(&self, offset: usize) -> (usize, usize) {
+        let node = at(self.nodes(), offset);
+        match (node.children_offset(), node.child_count()) {
+            (Some(start), Some(count)) => {
+                let usable = self.usable.saturating_sub(start) / NODE_SIZE;
+                (start, count.min(usable))
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Where the key of the node at `offset` is in the mapping.
+    fn key_range(&self, offset: usize) -> Range<usize> {
+        let i = offset / NODE_SIZE;
+        let (start, end) = match self.key_width {
+            Some(width) => (i * width, (i + 1) * width),
+            None => (self.key_starts[i], self.key_starts[i + 1]),
+        };
+        self.keys.start + start..self.keys.start + end
+    }
+
+    fn key_bytes(&self, offset: usize) -> &[u8] {
+        &self.ram[self.key_range(offset)]
+    }
+
+    /// The key as stored, which for sibling delta files is its difference from its reference
+    /// node's.
+    fn stored_key(&self, offset: usize) -> u64 {
+        // Fixed width keys can't fail to decode, and the rest were decoded once at open.
+        self.codec.decode(self.key_bytes(offset)).unwrap().0
+    }
+
+    /// The keys of the `count` siblings from `start` on, whose parent's key is `parent`.
+    fn child_keys(&self, parent: u64, start: usize, count: usize, keys: &mut Vec<u64>) {
+        keys.clear();
+        let mut reference = parent;
+        for i in 0..count {
+            let mut key = self.stored_key(start + NODE_SIZE * i);
+            if self.deltas {
+                key = from_delta(reference, key);
+                reference = key;
+            }
+            keys.push(key);
+        }
+    }
+
+    /// The key of the node at `offset`, wherever it is. For sibling delta files, that means
+    /// finding the nodes its key is relative to, which reads the whole node array.
+    fn key_at(&self, offset: usize) -> Result<u64, Box<dyn Error>> {
+        if !self.deltas {
+            return Ok(self.stored_key(offset));
+        }
+        let references = bkfile::delta_references(self.nodes(), self.usable / NODE_SIZE);
+        let mut chain = vec![offset / NODE_SIZE];
+        while let Some(&node) = chain.last() {
+            match references[node] {
+                Some(reference) if reference >= node => {
+                    return Err(
+                        format!("Key {} refers to key {}, after it", node, reference).into(),
+                    )
+                }
+                Some(reference) => chain.push(reference),
+                None => break,
+            }
+        }
+        let mut key = 0;
+        for (i, node) in chain.iter().rev().enumerate() {
+            let stored = self.stored_key(node * NODE_SIZE);
+            key = if i == 0 {
+                stored
+            } else {
+                from_delta(key, stored)
+            };
+        }
+        Ok(key)
+    }
+
+    fn flags(&self, offset: usize) -> u8 {
+        let flags = &self.ram[self.flags.clone()];
+        flags.get(offset / NODE_SIZE).cloned().unwrap_or(0)
+    }
+
+    /// The value stored with the node at `offset`, if it has one that survived.
+    fn value(&self, offset: usize) -> Option<&[u8]> {
+        let index = &self.ram[self.value_index.clone()];
+        let entry = index.get(offset / NODE_SIZE * 8..offset / NODE_SIZE * 8 + 8)?;
+        let start = LittleEndian::read_u32(entry) as usize;
+        let length = LittleEndian::read_u32(&entry[4..]) as usize;
+        match length {
+            0 => None,
+            _ => self.ram[self.values.clone()].get(start..start + length),
+        }
+    }
+
+    /// Check the tree under the node at `root` with `walk_nodes`, returning how many nodes it has
+    /// and the depth of the deepest.
+    fn check_tree(&self, root: usize) -> Result<(u64, usize), Box<dyn Error>> {
+        let nodes = NodeArray {
+            at: |offset| at(self.nodes(), offset),
+            node_size: NODE_SIZE,
+            node_bytes: self.header.descr().node_bytes as usize,
+            usable: self.usable,
+            // Keys added in place move nodes to the free space at the end of the array.
+            forward_children: self.header.descr().free_offset.is_none(),
+        };
+        let mut max_depth = 0;
+        let node_count = walk_nodes(&nodes, root, |_, _, depth| {
+            max_depth = max_depth.max(depth);
+            Ok(())
+        })?;
+        Ok((node_count, max_depth))
+    }
+
+    /// Call `visit` with each node's offset and key, and its distance from `needle`, for the
+    /// nodes within `tolerance` of it in the tree under `root`, whose key is `key`.
+    fn search<F>(&self, root: usize, key: u64, needle: &u64, tolerance: Dist, mut visit: F)
+    where
+        F: FnMut(Dist, usize, &u64),
+    {
+        let metric = HammingMetric::<u64>::default();
+        let mut stack = vec![(root, key, metric.distance(&key, needle))];
+        let mut keys = Vec::new();
+        let mut dists = Vec::new();
+        while let Some((offset, key, dist)) = stack.pop() {
+            if dist <= tolerance {
+                visit(dist, offset, &key);
+            }
+            let (start, count) = self.children(offset);
+            self.child_keys(key, start, count, &mut keys);
+            dists.clear();
+            if self.key_width == Some(8) && !self.deltas {
+                // Siblings' keys are adjacent, so they're compared as a block.
+                let at = self.keys.start + start / NODE_SIZE * 8;
+                hamming_block(*needle, &self.ram[at..at + 8 * count], &mut dists);
+            } else {
+                dists.extend(keys.iter().map(|key| metric.distance(key, needle)));
+            }
+            for (i, (key, child_dist)) in keys.iter().zip(&dists).enumerate() {
+                let child = start + NODE_SIZE * i;
+                let slot = self.slot(child);
+                if slot + tolerance >= dist && slot <= dist + tolerance {
+                    stack.push((child, *key, *child_dist));
+                }
+            }
+        }
+    }
+
+    /// Call `visit` with each node's distance from its parent, its offset, child count and key,
+    /// parents before children, for the tree under `root`, whose key is `key`.
+    fn preorder<F>(&self, root: usize, key: u64, mut visit: F)
+    where
+        F: FnMut(Dist, usize, usize, &u64),
+    {
+        let mut stack = vec![(0, root, key)];
+        let mut keys = Vec::new();
+        while let Some((slot, offset, key)) = stack.pop() {
+            let (start, count) = self.children(offset);
+            visit(slot, offset, count, &key);
+            self.child_keys(key, start, count, &mut keys);
+            for (i, key) in keys.iter().enumerate() {
+                let child = start + NODE_SIZE * i;
+                stack.push((self.slot(child), child, *key));
+            }
+        }
+    }
+}
+
+/// An opened bkfile, checked for consistency.
+pub struct BkFile {
+    file: Arc<Mapped>,
+}
+
+/// How much of a truncated file survived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recovery {
+    /// Nodes the header says the file holds.
+    pub expected: u64,
+    /// Nodes whose entries and keys are in the file.
+    pub present: u64,
+    /// Nodes reachable from the roots, which are all searches will see.
+    pub reachable: u64,
+}
+
+impl BkFile {
+    pub fn open<P: AsRef<Path>>(path: P, verify_checksum: bool) -> Result<Self, Box<dyn Error>> {
+        Self::from_file(File::open(path)?, verify_checksum)
+    }
+
+    pub fn from_file(file: File, verify_checksum: bool) -> Result<Self, Box<dyn Error>> {
+        Self::read(file, verify_checksum, false)
+    }
+
+    /// Open a file that may have been cut short, e.g. by an interrupted upload. Its checksum
+    /// can't be checked. Trees are cut back to the nodes whose entries and keys are in the file,
+    /// and roots that were lost come back as empty trees. See `recovery` for what's left.
+    pub fn open_truncated<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_file_truncated(File::open(path)?)
+    }
+
+    pub fn from_file_truncated(file: File) -> Result<Self, Box<dyn Error>> {
+        Self::read(file, false, true)
+    }
+
+    fn read(
+        file: File,
+        verify_checksum: bool,
+        allow_truncation: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = Mapped::read(file, verify_checksum, allow_truncation, |file| unsafe {
+            MmapOptions::new().map(file)
+        })?;
+        Ok(BkFile {
+            file: Arc::new(file),
+        })
+    }
+
+    /// Count what's left of a truncated file. Walks every tree to do so.
+    pub fn recovery(&self) -> Result<Recovery, Box<dyn Error>> {
+        let reachable = self.trees()?.iter().map(|tree| tree.node_count()).sum();
+        Ok(Recovery {
+            expected: self.descr().node_count,
+            present: (self.file.usable / NODE_SIZE) as u64,
+            reachable,
+        })
+    }
+
+    pub fn descr(&self) -> &FileDescrHeader {
+        self.file.header.descr()
+    }
+
+    /// Whether the file is signed by the ed25519 key `public_key`, like
+    /// `Header::verify_signature`, but checking the bytes this file searches: the file can't be
+    /// swapped between the check and the searches. Needs the `signatures` feature.
+    pub fn verify_signature(&self, public_key: &[u8; 32]) -> Result<(), Box<dyn Error>> {
+        let header = &self.file.header;
+        let signed = header.signed_range().ok_or("File isn't signed")?;
+        let signed = self
+            .file
+            .ram
+            .get(signed.start as usize..signed.end as usize)
+            .ok_or("File ends before its signature")?;
+        header.verify_signed_bytes(public_key, signed)
+    }
+
+    /// The file's checksum, as hex, of whichever kind its header names, which identifies it to
+    /// delta files (see `delta`).
+    pub fn checksum(&self) -> String {
+        self.file.header.checksum()
+    }
+
+    /// One tree per root in the file, in file order, each carrying its root's metadata.
+    pub fn trees(&self) -> Result<Vec<BkFileTree>, Box<dyn Error>> {
+        let mut trees = Vec::new();
+        for root in self.descr().roots() {
+            let mut tree = if root.node_offset as usize >= self.file.usable {
+                BkFileTree::empty()
+            } else {
+                self.tree_at(root.node_offset)?
+            };
+            tree.metadata = root.metadata;
+            trees.push(tree);
+        }
+        Ok(trees)
+    }
+
+    /// The subtree rooted at the node `node_offset` bytes into the node array.
+    pub fn tree_at(&self, node_offset: u64) -> Result<BkFileTree, Box<dyn Error>> {
+        let descr = self.descr();
+        if !node_offset.is_multiple_of(NODE_SIZE as u64) || node_offset >= descr.node_bytes {
+            return Err(format!("No node at offset {}", node_offset).into());
+        }
+        let offset = node_offset as usize;
+        if offset >= self.file.usable {
+            return Err(format!("Node at offset {} was lost to truncation", node_offset).into());
+        }
+        let (node_count, _) = self.file.check_tree(offset)?;
+        let root = FileNode::new(Arc::clone(&self.file), offset, self.file.key_at(offset)?);
+        Ok(BkFileTree {
+            root: Some(root),
+            metadata: BTreeMap::new(),
+            node_count,
+            metric: HammingMetric::default(),
+        })
+    }
+}
+
+/// A node of a `BkFileTree`. Its children are decoded from the file the first time they're
+/// asked for, then kept.
+pub struct FileNode {
+    file: Arc<Mapped>,
+    offset: usize,
+    key: u64,
+    children: OnceLock<Box<[FileNode]>>,
+}
+
+impl FileNode {
+    fn new(file: Arc<Mapped>, offset: usize, key: u64) -> Self {
+        FileNode {
+            file,
+            offset,
+            key,
+            children: OnceLock::new(),
+        }
+    }
+
+    fn children(&self) -> &[FileNode] {
+        self.children.get_or_init(|| {
+            let (start, count) = self.file.children(self.offset);
+            let mut keys = Vec::with_capacity(count);
+            self.file.child_keys(self.key, start, count, &mut keys);
+            keys.into_iter()
+                .enumerate()
+                .map(|(i, key)| FileNode::new(Arc::clone(&self.file), start + NODE_SIZE * i, key))
+                .collect()
+        })
+    }
+}
+
+impl BkNode for FileNode {
+    type Key = u64;
+
+    fn key(&self) -> &u64 {
+        &self.key
+    }
+
+    fn has_child_at(&self, dist: Dist) -> bool {
+        self.child_at(dist).is_some()
+    }
+
+    fn child_at(&self, dist: Dist) -> Option<&Self> {
+        self.children()
+            .iter()
+            .find(|child| self.file.slot(child.offset) == dist)
+    }
+
+    fn children_vector(&self) -> Vec<(Dist, &Self)> {
+        let mut children = Vec::new();
+        self.each_child(|dist, child| children.push((dist, child)));
+        children
+    }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        for child in self.children() {
+            f(self.file.slot(child.offset), child);
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        self.file.flags(self.offset)
+    }
+}
+
+/// A tree read from a bkfile. Searches read it in place; see the module docs.
+pub struct BkFileTree {
+    root: Option<FileNode>,
+    /// The root's metadata from the file, e.g. which shard it holds.
+    pub metadata: BTreeMap<String, String>,
+    node_count: u64,
+    metric: HammingMetric<u64>,
+}
+
+impl fmt::Debug for BkFileTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BkFileTree")
+            .field("node_count", &self.node_count)
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+impl BkFileTree {
+    fn empty() -> Self {
+        BkFileTree {
+            root: None,
+            metadata: BTreeMap::new(),
+            node_count: 0,
+            metric: HammingMetric::default(),
+        }
+    }
+
+    /// Open a bkfile holding a single tree, checking its checksum. A file with no nodes opens
+    /// as an empty tree.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(file: File) -> Result<Self, Box<dyn Error>> {
+        let file = BkFile::from_file(file, true)?;
+        let mut trees = file.trees()?;
+        match trees.len() {
+            0 => Ok(BkFileTree::empty()),
+            1 => Ok(trees.pop().unwrap()),
+            n => Err(format!("File holds {} trees; open it with BkFile::trees", n).into()),
+        }
+    }
+
+    pub fn node_count(&self) -> u64 {
+        self.node_count
+    }
+
+    /// `Mapped::search` over this tree.
+    fn search<F>(&self, needle: &u64, tolerance: Dist, visit: F)
+    where
+        F: FnMut(Dist, usize, &u64),
+    {
+        if let Some(root) = &self.root {
+            root.file
+                .search(root.offset, root.key, needle, tolerance, visit);
+        }
+    }
+
+    /// The application payload stored with `key`, if it has one.
+    pub fn value(&self, key: &u64) -> Option<&[u8]> {
+        let root = self.root.as_ref()?;
+        let mut found = None;
+        self.search(key, 0, |_, offset, _| found = Some(offset));
+        root.file.value(found?)
+    }
+
+    /// Call `callback` with every key, its flags and its value, parents before children.
+    pub fn each_entry<F>(&self, mut callback: F)
+    where
+        F: FnMut(&u64, u8, Option<&[u8]>),
+    {
+        if let Some(root) = &self.root {
+            let file = &root.file;
+            file.preorder(root.offset, root.key, |_, offset, _, key| {
+                callback(key, file.flags(offset), file.value(offset))
+            });
+        }
+    }
+
+    /// The tree decoded into RAM, ready for more keys, and the values by key. For picking up
+    /// where a file left off, e.g. resuming a build from a checkpoint.
+    pub fn into_parts(self) -> Result<DecodedTree, Box<dyn Error>> {
+        let mut tree: InRamTree = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+        let mut values = HashMap::new();
+        let mut added = Ok(());
+        // Adding keys parents first puts each back in the slot it came from.
+        self.each_entry(|key, flags, value| {
+            if added.is_ok() {
+                added = tree.add(key);
+            }
+            if flags != 0 {
+                tree.set_flags(key, flags);
+            }
+            if let Some(value) = value {
+                values.insert(*key, value.to_vec());
+            }
+        });
+        added?;
+        Ok((tree, values))
+    }
+
+    /// `find_each`, handing each match's key to `decode` as the bytes a "fixed 64 bits" file
+    /// stores (little endian), and the callback whatever it makes of them. For decoding straight
+    /// into an application's own key type, e.g. a hash newtype, without going through u64.
+    ///
+    /// Files in that format hand over their key section's bytes as they are; keys stored any
+    /// other way are put into it first.
+    pub fn find_each_decoded<T, D, F>(
+        &self,
+        needle: &u64,
+        tolerance: Dist,
+        mut decode: D,
+        mut callback: F,
+    ) where
+        D: FnMut(&[u8]) -> T,
+        F: FnMut(Dist, T),
+    {
+        let file = match &self.root {
+            Some(root) => &root.file,
+            None => return,
+        };
+        let in_place = file.key_width == Some(8) && !file.deltas;
+        self.search(needle, tolerance, |dist, offset, key| {
+            let decoded = if in_place {
+                decode(file.key_bytes(offset))
+            } else {
+                decode(&key.to_le_bytes())
+            };
+            callback(dist, decoded)
+        });
+    }
+
+    /// `find_each`, passing each match's payload to the callback too.
+    pub fn find_each_value<F>(&self, needle: &u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64, Option<&[u8]>),
+    {
+        if let Some(root) = &self.root {
+            let file = &root.file;
+            self.search(needle, tolerance, |dist, offset, key| {
+                callback(dist, key, file.value(offset))
+            });
+        }
+    }
+}
+
+impl BkTree<u64> for BkFileTree {
+    type KQ = U64Key;
+    type Metric = HammingMetric<u64>;
+    type Node = FileNode;
+
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &Self::Metric {
+        &self.metric
+    }
+
+    fn len(&self) -> usize {
+        self.node_count as usize
+    }
+
+    fn find_each<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        mut callback: F,
+    ) where
+        F: FnMut(Dist, &<Self::KQ as KeyQuery>::Key),
+    {
+        self.search(needle, tolerance, |dist, _, key| callback(dist, key));
+    }
+
+    fn find_each_flagged<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        mut callback: F,
+    ) where
+        F: FnMut(Dist, &u64, u8),
+    {
+        if let Some(root) = &self.root {
+            let file = &root.file;
+            self.search(needle, tolerance, |dist, offset, key| {
+                callback(dist, key, file.flags(offset))
+            });
+        }
+    }
+
+    fn preorder_each<F>(&self, mut callback: F)
+    where
+        F: FnMut(Dist, usize, &u64),
+    {
+        if let Some(root) = &self.root {
+            root.file
+                .preorder(root.offset, root.key, |slot, _, count, key| {
+                    callback(slot, count, key)
+                });
+        }
+    }
+}
+
+/// Writing nodes in place, for `BkFileMut`.
+impl Mapped<MmapMut> {
+    fn nodes_mut(&mut self) -> &mut [u8] {
+        &mut self.ram[self.nodes.clone()]
+    }
+
+    fn put_key(&mut self, offset: usize, key_bytes: &[u8]) {
+        let range = self.key_range(offset);
+        self.ram[range].copy_from_slice(key_bytes);
+    }
+
+    /// Copy `count` nodes at `from` to `to`, with their keys, flags and value index entries.
+    fn move_nodes(&mut self, from: usize, to: usize, count: usize) {
+        let (from, to) = (from / NODE_SIZE, to / NODE_SIZE);
+        let mut sections = vec![
+            (self.nodes.start, NODE_SIZE),
+            (self.keys.start, self.key_range(0).len()),
+        ];
+        if !self.flags.is_empty() {
+            sections.push((self.flags.start, 1));
+        }
+        if !self.value_index.is_empty() {
+            sections.push((self.value_index.start, 8));
+        }
+        for (start, size) in sections {
+            self.ram.copy_within(
+                start + from * size..start + (from + count) * size,
+                start + to * size,
+            );
+        }
+    }
+}
+
+/// A single tree bkfile opened to add keys in place, in the free nodes it was written with (see
+/// `bkfile::WriteOptions::reserve_nodes`). It holds the nodes of the tree it hands out, as a
+/// `BkArenaAllocator` does, and the tree is added to and searched through the usual traits:
+///
+///   let file = BkFileMut::open("hashes.bk")?;
+///   let mut tree = file.tree()?;
+///   tree.add(&key)?;
+///   tree.sync()?;
+///
+/// Nodes are read and written as `F64BNode8`s straight through the file's mapping. Adding a key
+/// under a node moves the node's children to the free nodes, unless they're the last nodes in
+/// use, and their old nodes go unused. `sync` rewrites the header and checksum; until then the
+/// file fails its checksum.
+pub struct BkFileMut {
+    mapped: RefCell<Mapped<MmapMut>>,
+    /// Where the free nodes start. Moves up as nodes are used.
+    free_offset: Cell<usize>,
+    header_len: usize,
+    codec: FixedWidth,
+    /// Whether the tree is out. It keeps the nodes it has read, so there's one at a time.
+    tree_out: Cell<bool>,
+    /// Whether flags were set that the file has no section for.
+    unsaved_flags: Cell<bool>,
+}
+
+impl BkFileMut {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mapped = Mapped::read(file, true, false, |file| unsafe { MmapMut::map_mut(file) })?;
+        let header = &mapped.header;
+        let descr = header.descr();
+        // A new child would change its siblings' deltas, and the key sizes with them.
+        if mapped.deltas {
+            return Err(format!(
+                "Can't add to sibling deltas ({:?} keys) in place",
+                bkfile::DELTA_KEY_FORMAT
+            )
+            .into());
+        }
+        let codec = FixedWidth::named(&descr.key_format)
+            .ok_or_else(|| format!("Can't add {:?} keys in place", descr.key_format))?;
+        if descr.node_format != "8 bits distance, 8 bits child" {
+            return Err(format!("Can't add to {:?} nodes in place", descr.node_format).into());
+        }
+        let free_offset = descr
+            .free_offset
+            .ok_or("File was written without room for more keys")?;
+        if descr.roots().len() > 1 {
+            return Err("Can't add to a forest in place".into());
+        }
+        // Adding keys would leave the signature signing the file as it was.
+        if header.is_signed() {
+            return Err("Can't add to a signed file in place".into());
+        }
+        let slots = descr.node_bytes / NODE_SIZE as u64;
+        if descr.key_bytes != slots * codec.bytes() as u64
+            || matches!(descr.flags_bytes, Some(bytes) if bytes != slots)
+            || matches!(descr.value_index_bytes, Some(bytes) if bytes != slots * 8)
+            || free_offset > descr.node_bytes
+            || free_offset % NODE_SIZE as u64 != 0
+        {
+            return Err("Sections don't have a slot for every node".into());
+        }
+        Ok(BkFileMut {
+            header_len: header.data_offset() as usize - header.checksum_kind().prefix_size(),
+            free_offset: Cell::new(free_offset as usize),
+            codec,
+            mapped: RefCell::new(mapped),
+            tree_out: Cell::new(false),
+            unsaved_flags: Cell::new(false),
+        })
+    }
+
+    /// The file's tree, to add keys to. Only one can be out at a time.
+    pub fn tree(&self) -> Result<BkFileTreeMut<'_>, Box<dyn Error>> {
+        if self.tree_out.get() {
+            return Err("The file's tree is already out".into());
+        }
+        let mut tree = BkFileTreeMut {
+            file: self,
+            root: None,
+            max_depth: 0,
+            node_count: 0,
+            tombstone_count: 0,
+            metric: HammingMetric::default(),
+        };
+        let mapped = self.mapped.borrow();
+        let descr = mapped.header.descr();
+        if let Some(root) = descr.roots().first() {
+            if !root.node_offset.is_multiple_of(NODE_SIZE as u64)
+                || root.node_offset >= descr.node_bytes
+            {
+                return Err(format!("No node at offset {}", root.node_offset).into());
+            }
+            let offset = root.node_offset as usize;
+            (tree.node_count, tree.max_depth) = mapped.check_tree(offset)?;
+            let key = mapped.stored_key(offset);
+            tree.root = Some(FileNodeMut::new(self, offset, key, 0, mapped.flags(offset)));
+        }
+        self.tree_out.set(true);
+        Ok(tree)
+    }
+
+    /// How many more nodes there's room for.
+    pub fn free_nodes(&self) -> u64 {
+        let node_bytes = self.mapped.borrow().header.descr().node_bytes;
+        (node_bytes - self.free_offset.get() as u64) / NODE_SIZE as u64
+    }
+
+    fn check_room(&self, count: usize) -> Result<(), Box<dyn Error>> {
+        if (count as u64) > self.free_nodes() {
+            return Err("No free nodes left: rewrite the file with more room".into());
+        }
+        Ok(())
+    }
+
+    /// A node for `key`, in the first free node.
+    fn new_node(&self, key: u64) -> Result<FileNodeMut<'_>, Box<dyn Error>> {
+        let mut key_bytes = Vec::new();
+        self.codec.encode(&key, &mut key_bytes)?;
+        self.check_room(1)?;
+        let offset = self.free_offset.get();
+        self.free_offset.set(offset + NODE_SIZE);
+        self.mapped.borrow_mut().put_key(offset, &key_bytes);
+        Ok(FileNodeMut::new(self, offset, key, 0, 0))
+    }
+
+    /// Make room for a child of the node at `parent` after its `count` children from `start`
+    /// on, in the first free node: moves them to the free nodes unless they're the last nodes
+    /// in use. Returns where they've moved to, if they have.
+    fn make_room(
+        &self,
+        parent: usize,
+        start: usize,
+        count: usize,
+    ) -> Result<Option<usize>, Box<dyn Error>> {
+        let free_offset = self.free_offset.get();
+        if count == 0 || start + NODE_SIZE * count == free_offset {
+            self.check_room(1)?;
+            return Ok(None);
+        }
+        self.check_room(count + 1)?;
+        let mut mapped = self.mapped.borrow_mut();
+        at(mapped.nodes_mut(), parent).set_child_offset(free_offset)?;
+        mapped.move_nodes(start, free_offset, count);
+        self.free_offset.set(free_offset + NODE_SIZE * count);
+        Ok(Some(free_offset))
+    }
+}
+
+impl<'a> NodeAllocator<'a> for BkFileMut {
+    type Key = u64;
+    type Node = FileNodeMut<'a>;
+
+    fn new_root(&'a self, key: u64) -> Result<Self::Node, Box<dyn Error>> {
+        self.new_node(key)
+    }
+
+    fn new_child(&'a self, key: u64) -> Result<Self::Node, Box<dyn Error>> {
+        self.new_node(key)
+    }
+}
+
+/// A node of a `BkFileTreeMut`. Like a `FileNode`, its children are read from the file the first
+/// time they're asked for, then kept, along with any added since.
+pub struct FileNodeMut<'a> {
+    file: &'a BkFileMut,
+    offset: usize,
+    key: u64,
+    /// Distance from the parent: the child slot this node fills.
+    dist: Dist,
+    flags: u8,
+    /// Bkfiles can't hold removed keys, so they're only tombstoned here.
+    tombstone: bool,
+    children: OnceCell<Vec<FileNodeMut<'a>>>,
+}
+
+impl<'a> FileNodeMut<'a> {
+    fn new(file: &'a BkFileMut, offset: usize, key: u64, dist: Dist, flags: u8) -> Self {
+        FileNodeMut {
+            file,
+            offset,
+            key,
+            dist,
+            flags,
+            tombstone: false,
+            children: OnceCell::new(),
+        }
+    }
+
+    fn children(&self) -> &[FileNodeMut<'a>] {
+        self.children.get_or_init(|| {
+            let mapped = self.file.mapped.borrow();
+            let (start, count) = mapped.children(self.offset);
+            let mut keys = Vec::with_capacity(count);
+            mapped.child_keys(self.key, start, count, &mut keys);
+            keys.into_iter()
+                .enumerate()
+                .map(|(i, key)| {
+                    let offset = start + NODE_SIZE * i;
+                    let (dist, flags) = (mapped.slot(offset), mapped.flags(offset));
+                    FileNodeMut::new(self.file, offset, key, dist, flags)
+                })
+                .collect()
+        })
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<FileNodeMut<'a>> {
+        self.children();
+        self.children.get_mut().unwrap()
+    }
+}
+
+impl<'a> BkNode for FileNodeMut<'a> {
+    type Key = u64;
+
+    fn key(&self) -> &u64 {
+        &self.key
+    }
+
+    fn has_child_at(&self, dist: Dist) -> bool {
+        self.child_at(dist).is_some()
+    }
+
+    fn child_at(&self, dist: Dist) -> Option<&Self> {
+        self.children().iter().find(|child| child.dist == dist)
+    }
+
+    fn children_vector(&self) -> Vec<(Dist, &Self)> {
+        self.children()
+            .iter()
+            .map(|child| (child.dist, child))
+            .collect()
+    }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        for child in self.children() {
+            f(child.dist, child);
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+}
+
+impl<'a> BkNodeMut for FileNodeMut<'a> {
+    /// `node` must be the child `reserve_child` made room for, in the node after the others.
+    fn set_child_node(&mut self, dist: Dist, mut node: Self) {
+        let (file, offset) = (self.file, self.offset);
+        let children = self.children_mut();
+        let start = node.offset - NODE_SIZE * children.len();
+        {
+            let mut mapped = file.mapped.borrow_mut();
+            let nodes = mapped.nodes_mut();
+            // Hamming distances between u64s fit in a byte, reserve_child checked the count
+            // does, and the nodes were mapped with the file.
+            at(&mut *nodes, node.offset).set_dist(dist).unwrap();
+            let mut parent = at(nodes, offset);
+            parent.set_num_children(children.len() + 1).unwrap();
+            parent.set_child_offset(start).unwrap();
+        }
+        node.dist = dist;
+        children.push(node);
+    }
+
+    fn reserve_child(&mut self) -> Result<(), Box<dyn Error>> {
+        let (file, offset, key) = (self.file, self.offset, self.key);
+        let children = self.children_mut();
+        if children.len() >= u8::MAX as usize {
+            return Err(format!("Key {} has no room for more children in 8 bit nodes", key).into());
+        }
+        let start = children.first().map_or(0, |child| child.offset);
+        if let Some(moved) = file.make_room(offset, start, children.len())? {
+            for (i, child) in children.iter_mut().enumerate() {
+                child.offset = moved + NODE_SIZE * i;
+            }
+        }
+        Ok(())
+    }
+
+    fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self> {
+        self.children_mut()
+            .iter_mut()
+            .find(|child| child.dist == dist)
+    }
+
+    fn set_tombstone(&mut self, tombstone: bool) {
+        self.tombstone = tombstone;
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+        let mut mapped = self.file.mapped.borrow_mut();
+        if mapped.flags.is_empty() {
+            if flags != 0 {
+                self.file.unsaved_flags.set(true);
+            }
+            return;
+        }
+        let at = mapped.flags.start + self.offset / NODE_SIZE;
+        mapped.ram[at] = flags;
+    }
+}
+
+/// The tree of a `BkFileMut`, to add keys to through `BkTreeAdd`. Searches read the file in
+/// place, through `Mapped::search`, as `BkFileTree`'s do, until a key is removed: removed keys
+/// are only tombstoned in RAM, so from then on they walk the nodes.
+pub struct BkFileTreeMut<'a> {
+    file: &'a BkFileMut,
+    root: Option<FileNodeMut<'a>>,
+    /// Depth of the deepest node, the root's being 0: the same as `TreeStats::max_depth`.
+    max_depth: usize,
+    node_count: u64,
+    /// Number of nodes in node_count whose keys have been removed.
+    tombstone_count: u64,
+    metric: HammingMetric<u64>,
+}
+
+impl<'a> BkFileTreeMut<'a> {
+    pub fn node_count(&self) -> u64 {
+        self.node_count
+    }
+
+    /// Rewrite the header and checksum for the keys added, and flush the file. Fails, writing
+    /// nothing, while any keys are removed, since bkfiles can't hold them, or if flags were set
+    /// in a file without a flags section.
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.tombstone_count > 0 {
+            return Err(
+                "Bkfiles can't hold removed keys: add them back or rewrite the file".into(),
+            );
+        }
+        if self.file.unsaved_flags.get() {
+            return Err("File has no flags section for the flags set: rewrite it".into());
+        }
+        let mut mapped = self.file.mapped.borrow_mut();
+        let mut descr = mapped.header.descr().clone();
+        descr.node_count = self.node_count;
+        descr.free_offset = Some(self.file.free_offset.get() as u64);
+        match &self.root {
+            None => descr.roots.clear(),
+            // Files without a roots section hold one tree, at the start of the node array.
+            Some(root) if descr.roots.is_empty() && root.offset == 0 => {}
+            Some(root) => {
+                let metadata = descr
+                    .roots
+                    .first()
+                    .map(|root| root.metadata.clone())
+                    .unwrap_or_default();
+                descr.roots = vec![RootDescr {
+                    node_offset: root.offset as u64,
+                    node_count: self.node_count,
+                    metadata,
+                }];
+            }
+        }
+        let header = descr
+            .encode_exactly(self.file.header_len)
+            .ok_or("Header has outgrown its padding: rewrite the file")?;
+        let checksum = mapped.header.checksum_kind();
+        let start = checksum.prefix_size();
+        mapped.ram[start..start + header.len()].copy_from_slice(&header);
+        let mut hasher = checksum.hasher()?;
+        hasher.update(&mapped.ram[start..]);
+        let hex = hasher.hex();
+        // The checksum line is "SHA256: <hex>\n", or another kind's, after the magic number line.
+        let at = bkfile::MAGIC_VERSION.len() + 1 + checksum.name().len() + 2;
+        mapped.ram[at..at + hex.len()].copy_from_slice(hex.as_bytes());
+        mapped.ram.flush()?;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for BkFileTreeMut<'a> {
+    fn drop(&mut self) {
+        self.file.tree_out.set(false);
+    }
+}
+
+impl<'a> BkTree<u64> for BkFileTreeMut<'a> {
+    type KQ = U64Key;
+    type Metric = HammingMetric<u64>;
+    type Node = FileNodeMut<'a>;
+
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &Self::Metric {
+        &self.metric
+    }
+
+    fn len(&self) -> usize {
+        (self.node_count - self.tombstone_count) as usize
+    }
+
+    fn recorded_counts(&self) -> Option<(u64, usize)> {
+        Some((self.node_count, self.max_depth))
+    }
+
+    fn find_each<'b, F>(&'b self, needle: &'b u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64),
+    {
+        match &self.root {
+            Some(root) if self.tombstone_count == 0 => {
+                let mapped = self.file.mapped.borrow();
+                mapped.search(root.offset, root.key, needle, tolerance, |dist, _, key| {
+                    callback(dist, key)
+                });
+            }
+            Some(root) => {
+                BkFind::new(self.max_depth, Some(root), tolerance, needle)
+                    .each::<U64Key, _, F>(&self.metric, callback);
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a> BkTreeRootMut<'a, u64> for BkFileTreeMut<'a> {
+    type Alloc = BkFileMut;
+
+    fn node_allocator(&mut self) -> &'a Self::Alloc {
+        self.file
+    }
+
+    fn root_mut(&mut self) -> &mut Option<Self::Node> {
+        &mut self.root
+    }
+
+    fn max_depth_mut(&mut self) -> &mut usize {
+        &mut self.max_depth
+    }
+
+    fn incr_node_count(&mut self) {
+        self.node_count += 1;
+    }
+
+    fn incr_tombstone_count(&mut self) {
+        self.tombstone_count += 1;
+    }
+
+    fn decr_tombstone_count(&mut self) {
+        self.tombstone_count -= 1;
+    }
+
+    /// The nodes stay in use: the file only gets room back by being rewritten.
+    fn clear(&mut self) {
+        self.root = None;
+        self.max_depth = 0;
+        self.node_count = 0;
+        self.tombstone_count = 0;
+    }
+}
+
+/// Open a bkfile of string keys, as `bkfile::write_string_tree` writes them, checking its
+/// checksum. `metric` must be the one the tree was built with.
+pub fn open_string_tree<M, P>(
+    path: P,
+    metric: M,
+) -> Result<BkInRamTree<'static, StringKey, M, BkInRamAllocator<'static, String>>, Box<dyn Error>>
+where
+    M: Metric<str>,
+    P: AsRef<Path>,
+{
+    Ok(open_string_map(path, metric)?.0)
+}
+
+/// `open_string_tree`, with the values `bkfile::write_string_map` stored with the keys.
+pub fn open_string_map<M, P>(
+    path: P,
+    metric: M,
+) -> Result<DecodedVariableKeyTree<String, StringKey, M>, Box<dyn Error>>
+where
+    M: Metric<str>,
+    P: AsRef<Path>,
+{
+    let formats = [Utf8.name(), bkfile::BYTES_KEY_FORMAT];
+    open_variable_key_tree(
+        path.as_ref(),
+        metric,
+        bkfile::KEY_TYPE_BYTES,
+        &formats,
+        |format, stored| {
+            if format == Utf8.name() {
+                decode_whole(&Utf8, stored)
+            } else {
+                Ok(String::from_utf8(stored.to_vec())?)
+            }
+        },
+    )
+}
+
+/// `open_string_tree`, for byte string keys.
+pub fn open_bytes_tree<M, P>(
+    path: P,
+    metric: M,
+) -> Result<VariableKeyTree<Vec<u8>, BytesKey, M>, Box<dyn Error>>
+where
+    M: Metric<[u8]>,
+    P: AsRef<Path>,
+{
+    let formats = [Utf8.name(), bkfile::BYTES_KEY_FORMAT];
+    let (tree, _) = open_variable_key_tree(
+        path.as_ref(),
+        metric,
+        bkfile::KEY_TYPE_BYTES,
+        &formats,
+        |format, stored| {
+            if format == Utf8.name() {
+                Ok(decode_whole(&Utf8, stored)?.into_bytes())
+            } else {
+                Ok(stored.to_vec())
+            }
+        },
+    )?;
+    Ok(tree)
+}
+
+/// Open a bkfile of keys stored by `codec`, as `bkfile::write_codec_tree` writes them, checking
+/// its checksum. `metric` must be the one the tree was built with.
+///
+///   let pairs: BkInRamTree<PairKey<u64, u32>, _, _> =
+///       open_codec_tree("pairs.bk", metric, &Pair::new(FixedU64, FixedU32))?;
+pub fn open_codec_tree<C, KQ, M, P>(
+    path: P,
+    metric: M,
+    codec: &C,
+) -> Result<VariableKeyTree<C::Key, KQ, M>, Box<dyn Error>>
+where
+    C: KeyCodec + ?Sized,
+    C::Key: 'static + Clone + Eq + std::hash::Hash,
+    KQ: KeyQuery<Key = C::Key> + Default,
+    M: Metric<KQ::Query>,
+    P: AsRef<Path>,
+{
+    let (tree, _) = open_variable_key_tree(
+        path.as_ref(),
+        metric,
+        bkfile::KEY_TYPE_CODEC,
+        &[codec.name()],
+        |_, stored| decode_whole(codec, stored),
+    )?;
+    Ok(tree)
+}
+
+/// `codec`'s key, which must take up all of `stored`.
+fn decode_whole<C: KeyCodec + ?Sized>(codec: &C, stored: &[u8]) -> Result<C::Key, Box<dyn Error>> {
+    match codec.decode(stored)? {
+        (key, used) if used == stored.len() => Ok(key),
+        _ => Err(format!("A key runs past its {} encoding", codec.name()).into()),
+    }
+}
+
+/// An in-RAM tree decoded from a file of variable length keys.
+pub type VariableKeyTree<K, KQ, M> = BkInRamTree<'static, KQ, M, BkInRamAllocator<'static, K>>;
+
+/// A `VariableKeyTree` and the values by key.
+pub type DecodedVariableKeyTree<K, KQ, M> = (VariableKeyTree<K, KQ, M>, HashMap<K, Vec<u8>>);
+
+/// Decode a `VARIABLE_KEY_NODE_FORMAT` file's single tree and its values, with the same checks as
+/// `BkFile::tree_at`. Its keys must be `key_type`, stored in one of `key_formats`, and `decode`
+/// gets the file's key format with each key's bytes.
+fn open_variable_key_tree<K, KQ, M, D>(
+    path: &Path,
+    metric: M,
+    key_type: &str,
+    key_formats: &[&str],
+    decode: D,
+) -> Result<DecodedVariableKeyTree<K, KQ, M>, Box<dyn Error>>
+where
+    K: 'static + Clone + Eq + std::hash::Hash,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<KQ::Query>,
+    D: Fn(&str, &[u8]) -> Result<K, Box<dyn Error>>,
+{
+    let mut file = File::open(path)?;
+    let header = Header::read(&mut file, true)?;
+    let descr = header.descr();
+    descr.check_key_type(key_type)?;
+    if descr.node_format != bkfile::VARIABLE_KEY_NODE_FORMAT
+        || !key_formats.contains(&descr.key_format.as_str())
+    {
+        return Err(format!(
+            "Not a file of variable length keys: {:?} nodes, {:?} keys",
+            descr.node_format, descr.key_format
+        )
+        .into());
+    }
+    let mut tree = BkInRamTree::new(metric, &BkInRamAllocator::SHARED);
+    let mut values = HashMap::new();
+    let roots = descr.roots();
+    let root = match roots.len() {
+        0 => return Ok((tree, values)),
+        1 => roots[0].node_offset as usize,
+        n => return Err(format!("File holds {} trees", n).into()),
+    };
+
+    let map = unsafe { Mmap::map(&file)? };
+    let data = map
+        .get(header.data_offset() as usize..)
+        .ok_or("File ends inside its header")?;
+    let node_end = descr.node_offset + descr.node_bytes;
+    let key_end = descr.key_offset + descr.key_bytes;
+    if node_end > descr.key_offset
+        || key_end > data.len() as u64
+        || !(descr.node_bytes as usize).is_multiple_of(VARIABLE_NODE_SIZE)
+    {
+        return Err("Node and key arrays overlap or run past the end of the file".into());
+    }
+    let nodes = &data[descr.node_offset as usize..node_end as usize];
+    let keys = &data[descr.key_offset as usize..key_end as usize];
+    let flags = match (descr.flags_offset, descr.flags_bytes) {
+        (Some(offset), Some(bytes)) => data
+            .get(offset as usize..(offset + bytes) as usize)
+            .ok_or("Flags section runs past the end of the file")?,
+        _ => &[],
+    };
+    let value_index = match (descr.value_index_offset, descr.value_index_bytes) {
+        (Some(offset), Some(bytes)) => bkfile::decode_value_index(
+            data.get(offset as usize..(offset + bytes) as usize)
+                .ok_or("Value index runs past the end of the file")?,
+        ),
+        _ => Vec::new(),
+    };
+    let value_data = match (descr.value_offset, descr.value_bytes) {
+        (Some(offset), Some(bytes)) => data
+            .get(offset as usize..(offset + bytes) as usize)
+            .ok_or("Values run past the end of the file")?,
+        _ => &[],
+    };
+    let at = |offset| VBNode16 {
+        offset,
+        node_buffer: nodes,
+        key_buffer: keys,
+    };
+
+    // Decode in pre-order, then assemble children into their parents in reverse.
+    let mut decoded = Vec::new();
+    let mut parents = Vec::new();
+    let array = NodeArray {
+        at,
+        node_size: VARIABLE_NODE_SIZE,
+        node_bytes: nodes.len(),
+        usable: nodes.len(),
+        forward_children: true,
+    };
+    walk_nodes(&array, root, |offset, parent, depth| {
+        let index = offset / VARIABLE_NODE_SIZE;
+        tree.max_depth = tree.max_depth.max(depth);
+        let node = at(offset);
+        let stored = node
+            .key_bytes()
+            .ok_or_else(|| format!("Node at offset {} has a bad key", offset))?;
+        let mut in_ram = BkInRam::new(decode(&descr.key_format, stored)?);
+        in_ram.set_flags(flags.get(index).cloned().unwrap_or(0));
+        if let Some(&(start, length)) = value_index.get(index).filter(|(_, length)| *length > 0) {
+            let value = value_data
+                .get(start..start + length)
+                .ok_or_else(|| format!("Node at offset {} has a bad value", offset))?;
+            values.insert(in_ram.key().clone(), value.to_vec());
+        }
+        decoded.push(Some(in_ram));
+        parents.push(parent);
+        Ok(())
+    })?;
+    tree.node_count = decoded.len() as u64;
+    for index in (1..decoded.len()).rev() {
+        let node = decoded[index].take().unwrap();
+        let (parent, slot) = parents[index].unwrap();
+        decoded[parent].as_mut().unwrap().set_child_node(slot, node);
+    }
+    tree.root = decoded.swap_remove(0);
+    tree.recount_stats();
+    Ok((tree, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, STRING_ALLOC};
+    use crate::bkfile::{encode_values, write_test_file, RootDescr};
+    use crate::bktree::BkTreeRemove;
+    use crate::metric::levenshtein::LevenshteinMetric;
+    use crate::stats::TreeStats;
+    use std::io::{Seek, SeekFrom};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Levenshtein distance between byte strings of text.
+    struct TextBytes;
+
+    impl Metric<[u8]> for TextBytes {
+        fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+            LevenshteinMetric.distance(&String::from_utf8_lossy(k1), &String::from_utf8_lossy(k2))
+        }
+    }
+
+    // Keys 0b1111 at the root, with 0b0111 (distance 1) and 0 (distance 4) below it, then a
+    // second tree holding just 0b1.
+    const NODES: &[u8] = &[
+        0, 2, 0, 0, 16, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        1, 0, 0, 0, 0, 0, 0, 0, //
+        4, 0, 0, 0, 0, 0, 0, 0, //
+    ];
+    const KEYS: &[u64] = &[0b1111, 0b1, 0b0111, 0];
+
+    fn forest(nodes: &[u8]) -> File {
+        let mut data = nodes.to_vec();
+        for key in KEYS {
+            data.extend_from_slice(&key.to_le_bytes());
+        }
+        data.extend_from_slice(&[0, 0, 3, 0]);
+        let (index, values) = encode_values(&["root", "", "below", "zero"]).unwrap();
+        data.extend_from_slice(&index);
+        data.extend_from_slice(&values);
+        let mut shard = BTreeMap::new();
+        shard.insert("Shard".to_string(), "1/2".to_string());
+        let mut descr = FileDescrHeader::default();
+        descr.node_format = "8 bits distance, 8 bits child".to_string();
+        descr.node_bytes = 32;
+        descr.node_count = 4;
+        descr.key_format = "fixed 64 bits".to_string();
+        descr.key_offset = 32;
+        descr.key_bytes = 32;
+        descr.flags_offset = Some(64);
+        descr.flags_bytes = Some(4);
+        descr.value_index_offset = Some(68);
+        descr.value_index_bytes = Some(32);
+        descr.value_offset = Some(100);
+        descr.value_bytes = Some(13);
+        descr.roots = vec![
+            RootDescr {
+                node_offset: 0,
+                node_count: 3,
+                metadata: BTreeMap::new(),
+            },
+            RootDescr {
+                node_offset: 8,
+                node_count: 1,
+                metadata: shard,
+            },
+        ];
+        write_test_file(&mut descr, &data)
+    }
+
+    #[test]
+    fn string_trees_round_trip() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        let words = [
+            "book", "books", "cake", "boo", "", "cape", "boon", "cook", "cart",
+        ];
+        for word in words.iter() {
+            tree.add(word).unwrap();
+        }
+        tree.set_flags("cape", 3);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.bk");
+        let descr = bkfile::write_string_tree(&tree, &path, &Default::default()).unwrap();
+        assert_eq!(words.len() as u64, descr.node_count);
+        assert_eq!("utf-8", descr.key_format);
+
+        let read = open_string_tree(&path, LevenshteinMetric).unwrap();
+        assert!(read == tree);
+        assert_eq!(Ok(()), read.validate());
+        assert_eq!(TreeStats::from_tree(&read), read.quick_stats());
+        let mut found = Vec::new();
+        read.find_each_flagged("cap", 1, |d, k, f| found.push((d, k.clone(), f)));
+        found.sort();
+        assert_eq!(vec![(1, "cape".to_string(), 3)], found);
+        // Not a file of u64 keys.
+        assert!(BkFile::open(&path, true).is_err());
+        assert!(open_string_map(&path, LevenshteinMetric)
+            .unwrap()
+            .1
+            .is_empty());
+
+        let mut values = HashMap::new();
+        values.insert("cake".to_string(), b"sponge".to_vec());
+        values.insert("boo".to_string(), b"!".to_vec());
+        values.insert("absent".to_string(), b"unwritten".to_vec());
+        let descr = bkfile::write_string_map(&tree, &path, &Default::default(), &values).unwrap();
+        assert!(descr.value_index_offset.is_some());
+        let (read, read_values) = open_string_map(&path, LevenshteinMetric).unwrap();
+        assert!(read == tree);
+        values.remove("absent");
+        assert_eq!(values, read_values);
+
+        let bytes = open_bytes_tree(&path, TextBytes).unwrap();
+        assert_eq!(Ok(()), bytes.validate());
+        assert!(bytes.contains(&b"cook"[..]));
+        // Strings stored as plain bytes read back the same.
+        bkfile::write_bytes_tree(&tree, &path, &Default::default()).unwrap();
+        assert!(open_string_tree(&path, LevenshteinMetric).unwrap() == tree);
+        let mut empty: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        bkfile::write_string_tree(&empty, &path, &Default::default()).unwrap();
+        assert!(open_string_tree(&path, LevenshteinMetric)
+            .unwrap()
+            .is_empty());
+        empty.add("a").unwrap();
+        empty.remove("a");
+        assert!(bkfile::write_string_tree(&empty, &path, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn pair_trees_round_trip() {
+        use crate::codec::{FixedU32, FixedU64, Pair};
+        use crate::keys::PairKey;
+        use crate::metric::combinators::{ScaledMetric, SumMetric};
+        use crate::metric::hamming::HammingMetric;
+
+        let metric = || {
+            SumMetric::new(
+                HammingMetric::<u64>::default(),
+                ScaledMetric::new(HammingMetric::<u32>::default(), 2),
+            )
+        };
+        let mut tree: BkInRamTree<PairKey<u64, u32>, _, _> =
+            BkInRamTree::new(metric(), &BkInRamAllocator::SHARED);
+        for i in 0..100u64 {
+            tree.add(&(i.wrapping_mul(0x9e3779b97f4a7c15), i as u32 * 7))
+                .unwrap();
+        }
+        let codec = Pair::new(FixedU64, FixedU32);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pairs.bk");
+        let descr = bkfile::write_codec_tree(&tree, &path, &Default::default(), &codec).unwrap();
+        assert_eq!(100, descr.node_count);
+        assert_eq!(codec.name(), descr.key_format);
+
+        let read: BkInRamTree<PairKey<u64, u32>, _, _> =
+            open_codec_tree(&path, metric(), &codec).unwrap();
+        assert!(read == tree);
+        assert_eq!(Ok(()), read.validate());
+        let needle = (0x9e3779b97f4a7c15 ^ 1, 7 ^ 1);
+        assert_eq!(tree.find_knn(&needle, 1, 3), read.find_knn(&needle, 1, 3));
+        assert_eq!(
+            vec![(3, (0x9e3779b97f4a7c15, 7))],
+            read.find_knn(&needle, 1, 3)
+        );
+        // Keys stored by one codec don't read back through another.
+        let swapped = Pair::new(FixedU32, FixedU64);
+        let swapped_metric = SumMetric::new(
+            HammingMetric::<u32>::default(),
+            HammingMetric::<u64>::default(),
+        );
+        assert!(
+            open_codec_tree::<_, PairKey<u32, u64>, _, _>(&path, swapped_metric, &swapped).is_err()
+        );
+        assert!(open_string_tree(&path, LevenshteinMetric).is_err());
+    }
+
+    #[test]
+    fn searches_each_tree_in_a_file() {
+        let file = BkFile::from_file(forest(NODES), true).unwrap();
+        let trees = file.trees().unwrap();
+        assert_eq!(
+            vec![3, 1],
+            trees.iter().map(|t| t.node_count()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("1/2"), trees[1].metadata.get("Shard").map(|s| &s[..]));
+
+        let mut found = Vec::new();
+        trees[0].find_each_flagged(&0b0110, 1, |d, k, f| found.push((d, *k, f)));
+        assert_eq!(vec![(1, 0b0111, 3)], found);
+        assert_eq!((1, 0b0111), trees[0].find_knn(&0b0011, 2, 2)[0]);
+
+        let mut values = Vec::new();
+        trees[0].find_each_value(&0b1111, 1, |d, k, v| {
+            values.push((d, *k, v.map(|v| v.to_vec())))
+        });
+        values.sort();
+        assert_eq!(
+            vec![
+                (0, 0b1111, Some(b"root".to_vec())),
+                (1, 0b0111, Some(b"below".to_vec()))
+            ],
+            values
+        );
+        assert_eq!(None, trees[1].value(&0b1));
+
+        // The same results, in the same order, as the keys in a tree of their own.
+        let mut in_ram: InRamTree = BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for key in &[0, 0b0111, 0b1111] {
+            in_ram.add(key).unwrap();
+        }
+        assert_eq!(
+            in_ram.find_sorted(&0b0110, 4),
+            trees[0].find_sorted(&0b0110, 4)
+        );
+
+        let subtree = file.tree_at(24).unwrap();
+        assert_eq!(1, subtree.node_count());
+        assert!(file.tree_at(4).is_err());
+    }
+
+    #[test]
+    fn searches_in_place() {
+        let (_, tree) = hashed_tree(500);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        for sibling_deltas in &[false, true] {
+            let options = bkfile::WriteOptions {
+                sibling_deltas: *sibling_deltas,
+                ..Default::default()
+            };
+            bkfile::write_tree(&tree, &path, &options).unwrap();
+            let file = BkFile::open(&path, true).unwrap();
+            let read = &file.trees().unwrap()[0];
+            assert_eq!(tree.find_sorted(&0, 20), read.find_sorted(&0, 20));
+            // Searching reads keys from the mapping, without decoding any nodes.
+            assert!(read.root().unwrap().children.get().is_none());
+            assert_eq!(Ok(()), read.validate());
+            assert!(read.root().unwrap().children.get().is_some());
+
+            // A subtree whose root's key is stored relative to its sibling's.
+            let child = &read.root().unwrap().children()[1];
+            let subtree = file.tree_at(child.offset as u64).unwrap();
+            assert_eq!(Some(&child.key), subtree.root().map(|root| root.key()));
+            assert_eq!(Ok(()), subtree.validate());
+        }
+    }
+
+    #[test]
+    fn one_mapping_serves_many_threads() {
+        fn shareable<T: Send + Sync>(_: &T) {}
+        let file = Arc::new(BkFile::from_file(forest(NODES), true).unwrap());
+        let trees = file.trees().unwrap();
+        shareable(&file);
+        shareable(&trees);
+        let decoded: Vec<u64> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    let file = Arc::clone(&file);
+                    let trees = &trees;
+                    scope.spawn(move || {
+                        let mut found = trees[0].find_sorted(&0b0110, 1);
+                        found.extend(file.tree_at(0).unwrap().find_sorted(&0b0110, 1));
+                        found.len() as u64 + file.trees().unwrap()[1].node_count()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert_eq!(vec![3; 4], decoded);
+    }
+
+    #[test]
+    fn decodes_keys_into_caller_types() {
+        #[derive(Debug, PartialEq)]
+        struct Hash([u8; 8]);
+
+        let file = BkFile::from_file(forest(NODES), true).unwrap();
+        let trees = file.trees().unwrap();
+        let mut found = Vec::new();
+        trees[0].find_each_decoded(
+            &0b0110,
+            1,
+            |bytes| {
+                let mut hash = [0; 8];
+                hash.copy_from_slice(bytes);
+                Hash(hash)
+            },
+            |dist, hash| found.push((dist, hash)),
+        );
+        assert_eq!(vec![(1, Hash([0b0111, 0, 0, 0, 0, 0, 0, 0]))], found);
+
+        // Handed straight from the mapping.
+        let file = &trees[0].root.as_ref().unwrap().file;
+        let key_section = file.ram[file.keys.clone()].as_ptr_range();
+        let mut mapped = Vec::new();
+        trees[0].find_each_decoded(
+            &0b0110,
+            1,
+            |bytes| key_section.contains(&bytes.as_ptr()),
+            |_, in_section| mapped.push(in_section),
+        );
+        assert_eq!(vec![true], mapped);
+    }
+
+    #[test]
+    fn decoded_trees_take_more_keys() {
+        let file = BkFile::from_file(forest(NODES), true).unwrap();
+        let (mut tree, values) = file.trees().unwrap().remove(0).into_parts().unwrap();
+        assert_eq!(Some(&b"below".to_vec()), values.get(&0b0111));
+        tree.add(&0b1110).unwrap();
+        tree.add(&0b0011).unwrap();
+        assert_eq!(5, tree.node_count);
+        assert_eq!(TreeStats::from_tree(&tree), tree.quick_stats());
+        assert_eq!(vec![(0, 0b0011)], tree.find_knn(&0b0011, 1, 0));
+    }
+
+    #[test]
+    fn adds_keys_in_place() {
+        let (_, half) = hashed_tree(100);
+        let options = bkfile::WriteOptions {
+            reserve_nodes: 1000,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        bkfile::write_tree(&half, &path, &options).unwrap();
+        let (keys, tree) = hashed_tree(200);
+
+        let file = BkFileMut::open(&path).unwrap();
+        let mut file_tree = file.tree().unwrap();
+        assert!(file.tree().is_err());
+        assert_eq!((100, 1000), (file_tree.node_count(), file.free_nodes()));
+        for key in &keys[100..] {
+            file_tree.add(key).unwrap();
+        }
+        file_tree.add(&keys[150]).unwrap();
+        assert_eq!(200, file_tree.len());
+        assert!(file_tree.contains(&keys[199]));
+        assert!(file_tree.validate().is_ok());
+        // Removed keys can't be written, but searches leave them out.
+        assert!(file_tree.remove(&keys[120]));
+        assert!(file_tree.find_sorted(&keys[120], 0).is_empty());
+        assert!(file_tree.sync().is_err());
+        file_tree.add(&keys[120]).unwrap();
+        file_tree.sync().unwrap();
+
+        let read = BkFileTree::open(&path).unwrap();
+        assert_eq!(200, read.node_count());
+        assert!(read.validate().is_ok());
+        for needle in &[0u64, keys[120] ^ 0b101] {
+            let mut expected = Vec::new();
+            tree.find_each(needle, 24, |d, k| expected.push((d, *k)));
+            let mut found = Vec::new();
+            read.find_each(needle, 24, |d, k| found.push((d, *k)));
+            let mut in_place = Vec::new();
+            file_tree.find_each(needle, 24, |d, k| in_place.push((d, *k)));
+            expected.sort();
+            found.sort();
+            in_place.sort();
+            assert_eq!(expected, found);
+            assert_eq!(expected, in_place);
+        }
+
+        // Out of room, with the tree as it was.
+        let free = file.free_nodes();
+        let mut added = 0;
+        while file_tree.add(&(added | 1 << 40)).is_ok() {
+            added += 1;
+        }
+        assert!(file.free_nodes() <= free);
+        assert_eq!(200 + added, file_tree.node_count());
+        assert!(file_tree.validate().is_ok());
+        file_tree.sync().unwrap();
+        assert_eq!(200 + added, BkFileTree::open(&path).unwrap().node_count());
+    }
+
+    #[test]
+    fn opens_empty_and_single_node_files() {
+        let mut descr = FileDescrHeader::default();
+        descr.node_format = "8 bits distance, 8 bits child".to_string();
+        descr.key_format = "fixed 64 bits".to_string();
+        let file = BkFile::from_file(write_test_file(&mut descr, &[]), true).unwrap();
+        assert!(file.trees().unwrap().is_empty());
+        assert!(file.tree_at(0).is_err());
+        let tree = BkFileTree::from_file(write_test_file(&mut descr, &[])).unwrap();
+        assert_eq!(0, tree.node_count());
+        tree.find_each(&0, 64, |_, _| panic!("found a key in an empty tree"));
+
+        descr.node_bytes = 8;
+        descr.node_count = 1;
+        descr.key_offset = 8;
+        descr.key_bytes = 8;
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&42u64.to_le_bytes());
+        let tree = BkFileTree::from_file(write_test_file(&mut descr, &data)).unwrap();
+        assert_eq!(1, tree.node_count());
+        assert_eq!(vec![(1, 42)], tree.find_knn(&43, 1, 64));
+    }
+
+    #[test]
+    fn rejects_backward_children() {
+        let mut nodes = NODES.to_vec();
+        // The second root claims itself as its child.
+        nodes[8 + 1] = 1;
+        nodes[8 + 4] = 8;
+        let file = BkFile::from_file(forest(&nodes), true).unwrap();
+        assert!(file.tree_at(0).is_ok());
+        assert!(file.tree_at(8).is_err());
+
+        // The first tree's last node claims the second root, before it, as its child. Only
+        // files with free nodes to add keys in place have children before their parents.
+        let mut nodes = NODES.to_vec();
+        nodes[24 + 1] = 1;
+        nodes[24 + 4] = 8;
+        let file = BkFile::from_file(forest(&nodes), true).unwrap();
+        assert!(file.tree_at(0).is_err());
+        assert!(file.tree_at(8).is_ok());
+    }
+
+    #[test]
+    fn opens_what_survived_truncation() {
+        // Lose the values, the flags and the last key.
+        let file = forest(NODES);
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 45 - 12).unwrap();
+        assert!(BkFile::from_file(file.try_clone().unwrap(), false).is_err());
+        (&file).seek(SeekFrom::Start(0)).unwrap();
+        let truncated = BkFile::from_file_truncated(file).unwrap();
+        assert_eq!(
+            Recovery {
+                expected: 4,
+                present: 3,
+                reachable: 3
+            },
+            truncated.recovery().unwrap()
+        );
+        let trees = truncated.trees().unwrap();
+        let mut keys = Vec::new();
+        trees[0].preorder_each(|_, _, key| keys.push(*key));
+        assert_eq!(vec![0b1111, 0b0111], keys);
+        assert!(truncated.tree_at(24).is_err());
+
+        // Cut inside the nodes, so no keys are left at all.
+        let file = forest(NODES);
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 45 - 48).unwrap();
+        let truncated = BkFile::from_file_truncated(file).unwrap();
+        assert_eq!(0, truncated.recovery().unwrap().reachable);
+        assert_eq!(2, truncated.trees().unwrap().len());
+    }
+}
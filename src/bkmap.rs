@@ -0,0 +1,389 @@
+/*
+ * BK maps: trees whose keys carry values, e.g. image hashes mapping to the images that have them.
+ *
+ *   let mut images: BkInRamMap<U64Key, HammingMetric<u64>, PathBuf> = BkInRamMap::new(metric);
+ *   images.add(&hash, path);
+ *   images.find_each_value(&needle, 4, |dist, hash, path| ...);
+ *
+ * A key may have any number of values: adding a key that's already present adds another value
+ * to it, so distinct items that hash alike are all kept.
+ *
+ * When only how often each key turned up matters, `BkInRamMultiset` counts duplicates instead:
+ *
+ *   let mut seen: BkInRamMultiset<U64Key, HammingMetric<u64>> = BkInRamMultiset::new(metric);
+ *   seen.add(&hash, ());
+ *   seen.find_each_count(&needle, 4, |dist, hash, count| ...);
+ */
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use crate::bk::BkFind;
+use crate::bknode::{BkNode, BkNodeMut};
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::observe;
+use crate::Dist;
+
+/// A key, its values, and its children. Keys with no values left are tombstones.
+pub struct BkMapNode<K, V> {
+    pub key: K,
+    values: Vec<V>,
+    children: Vec<Option<Self>>,
+    flags: u8,
+}
+
+impl<K, V> BkMapNode<K, V> {
+    pub fn new(key: K, value: V) -> Self {
+        BkMapNode {
+            key,
+            values: vec![value],
+            children: Vec::new(),
+            flags: 0,
+        }
+    }
+
+    /// In the order they were added.
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+}
+
+impl<K, V> BkNode for BkMapNode<K, V> {
+    type Key = K;
+
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn has_child_at(&self, dist: Dist) -> bool {
+        self.child_at(dist).is_some()
+    }
+
+    fn child_at(&self, dist: Dist) -> Option<&Self> {
+        self.children.get(dist).and_then(|child| child.as_ref())
+    }
+
+    fn children_vector(&self) -> Vec<(Dist, &Self)> {
+        let mut children = Vec::new();
+        self.each_child(|dist, child| children.push((dist, child)));
+        children
+    }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        // Same order as BkInRam.
+        for (dist, child) in self.children.iter().enumerate().rev() {
+            if let Some(child) = child {
+                f(dist, child);
+            }
+        }
+    }
+
+    fn max_child_dist(&self) -> Option<Dist> {
+        self.children.len().checked_sub(1)
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+}
+
+impl<K, V> BkNodeMut for BkMapNode<K, V> {
+    fn set_child_node(&mut self, dist: Dist, node: Self) {
+        if self.children.len() <= dist {
+            self.children.resize_with(dist + 1, || None);
+        }
+        assert!(!self.has_child_at(dist));
+        self.children[dist] = Some(node);
+    }
+
+    fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self> {
+        self.children.get_mut(dist).and_then(|child| child.as_mut())
+    }
+
+    /// Tombstoning drops the node's values. Values can't be brought back this way.
+    fn set_tombstone(&mut self, tombstone: bool) {
+        if tombstone {
+            self.values.clear();
+        }
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for BkMapNode<K, V> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let children: Vec<_> = self
+            .children
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.is_some())
+            .collect();
+        f.debug_map()
+            .entry(&(&self.key, &self.values), &children)
+            .finish()
+    }
+}
+
+pub struct BkInRamMap<KQ: KeyQuery, M, V> {
+    pub root: Option<BkMapNode<KQ::Key, V>>,
+    /// Depth of the deepest node, the root's being 0: the same as `TreeStats::max_depth`.
+    pub max_depth: usize,
+    /// Distinct keys, including tombstones.
+    pub node_count: u64,
+    /// Values across all keys.
+    pub value_count: u64,
+    metric: M,
+}
+
+/// A map whose values carry nothing, so each key's value list is just a count of how many times
+/// it was added. Vecs of `()` never allocate.
+pub type BkInRamMultiset<KQ, M> = BkInRamMap<KQ, M, ()>;
+
+impl<KQ: KeyQuery, M, V> Debug for BkInRamMap<KQ, M, V>
+where
+    KQ::Key: Debug,
+    V: Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BkInRamMap")
+            .field("node_count", &self.node_count)
+            .field("value_count", &self.value_count)
+            .field("max_depth", &self.max_depth)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl<KQ: KeyQuery, M, V> Drop for BkInRamMap<KQ, M, V> {
+    /// Iterative, like BkInRamTree's, so chain shaped maps can't overflow the stack.
+    fn drop(&mut self) {
+        let mut stack: Vec<BkMapNode<KQ::Key, V>> = self.root.take().into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.children.drain(..).flatten());
+        }
+    }
+}
+
+impl<K, Q, KQ, M, V> BkInRamMap<KQ, M, V>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    pub fn new(metric: M) -> Self {
+        BkInRamMap {
+            root: None,
+            max_depth: 0,
+            node_count: 0,
+            value_count: 0,
+            metric,
+        }
+    }
+
+    /// Add `value` to the values of `key`, adding `key` if it's new.
+    pub fn add(&mut self, key: &Q, value: V) {
+        self.value_count += 1;
+        let mut cur = match self.root {
+            Some(ref mut root) => root,
+            None => {
+                self.root = Some(BkMapNode::new(KQ::to_key_static(key), value));
+                self.node_count += 1;
+                return;
+            }
+        };
+        let mut depth = 0;
+        loop {
+            if KQ::eq_static(&cur.key, key) {
+                cur.values.push(value);
+                return;
+            }
+            let dist = self.metric.distance(KQ::to_query_static(&cur.key), key);
+            depth += 1;
+            if !cur.has_child_at(dist) {
+                cur.set_child_node(dist, BkMapNode::new(KQ::to_key_static(key), value));
+                self.node_count += 1;
+                self.max_depth = self.max_depth.max(depth);
+                return;
+            }
+            cur = cur.child_at_mut(dist).unwrap();
+        }
+    }
+
+    /// The values of `key`, in the order they were added. Empty if it's not in the map.
+    pub fn get<'a>(&'a self, key: &Q) -> &'a [V]
+    where
+        K: 'a,
+    {
+        let mut cur = match self.root {
+            Some(ref root) => root,
+            None => return &[],
+        };
+        while !KQ::eq_static(&cur.key, key) {
+            let dist = self.metric.distance(KQ::to_query_static(&cur.key), key);
+            cur = match cur.child_at(dist) {
+                Some(child) => child,
+                None => return &[],
+            };
+        }
+        cur.values()
+    }
+
+    /// How many values `key` has: for a multiset, how many times it was added.
+    pub fn count(&self, key: &Q) -> usize {
+        self.get(key).len()
+    }
+
+    /// Remove `key`, returning its values.
+    pub fn remove(&mut self, key: &Q) -> Vec<V> {
+        let mut cur = match self.root {
+            Some(ref mut root) => root,
+            None => return Vec::new(),
+        };
+        while !KQ::eq_static(&cur.key, key) {
+            let dist = self.metric.distance(KQ::to_query_static(&cur.key), key);
+            cur = match cur.child_at_mut(dist) {
+                Some(child) => child,
+                None => return Vec::new(),
+            };
+        }
+        let values = std::mem::take(&mut cur.values);
+        self.value_count -= values.len() as u64;
+        values
+    }
+
+    /// `find_each`, calling the callback once per value of each match.
+    pub fn find_each_value<F>(&self, needle: &Q, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K, &V),
+    {
+        BkFind::new(self.max_depth, self.root.as_ref(), tolerance, needle)
+            .each_node_while::<KQ, M, _>(&self.metric, |dist, node| {
+                for value in node.values() {
+                    callback(dist, &node.key, value);
+                }
+                true
+            });
+    }
+
+    /// `find_each`, also passing each match's number of values.
+    pub fn find_each_count<F>(&self, needle: &Q, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K, usize),
+    {
+        BkFind::new(self.max_depth, self.root.as_ref(), tolerance, needle)
+            .each_node_while::<KQ, M, _>(&self.metric, |dist, node| {
+                callback(dist, &node.key, node.values.len());
+                true
+            });
+    }
+}
+
+impl<K, Q, KQ, M, V> BkTree<K> for BkInRamMap<KQ, M, V>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    type KQ = KQ;
+    type Metric = M;
+    type Node = BkMapNode<K, V>;
+
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &M {
+        &self.metric
+    }
+
+    fn find_each<'a, F>(&'a self, needle: &'a Q, tolerance: Dist, callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        let visited = BkFind::new(self.max_depth, self.root.as_ref(), tolerance, needle)
+            .each::<KQ, M, F>(&self.metric, callback);
+        observe::query_served(visited as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{StringKey, U64Key};
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn keys_keep_every_value() {
+        let mut images: BkInRamMap<U64Key, HammingMetric<u64>, &str> =
+            BkInRamMap::new(Default::default());
+        images.add(&0b1010, "cat.jpg");
+        images.add(&0b1011, "cat-crop.jpg");
+        images.add(&0b1010, "cat-copy.jpg");
+        images.add(&0b0101, "dog.jpg");
+        assert_eq!((3, 4), (images.node_count, images.value_count));
+        assert_eq!(&["cat.jpg", "cat-copy.jpg"], images.get(&0b1010));
+
+        let mut found = Vec::new();
+        images.find_each_value(&0b1010, 1, |d, k, v| found.push((d, *k, *v)));
+        found.sort();
+        assert_eq!(
+            vec![
+                (0, 0b1010, "cat-copy.jpg"),
+                (0, 0b1010, "cat.jpg"),
+                (1, 0b1011, "cat-crop.jpg")
+            ],
+            found
+        );
+
+        assert_eq!(vec!["cat.jpg", "cat-copy.jpg"], images.remove(&0b1010));
+        assert!(images.get(&0b1010).is_empty());
+        assert_eq!(2, images.value_count);
+        let mut keys = Vec::new();
+        images.find_each(&0b1010, 1, |_, k| keys.push(*k));
+        assert_eq!(vec![0b1011], keys);
+    }
+
+    #[test]
+    fn multisets_count_duplicates() {
+        let mut seen: BkInRamMultiset<U64Key, HammingMetric<u64>> =
+            BkInRamMultiset::new(Default::default());
+        for hash in &[0b1100, 0b1100, 0b1101, 0b1100, 0b0011] {
+            seen.add(hash, ());
+        }
+        assert_eq!((3, 5), (seen.node_count, seen.value_count));
+        assert_eq!(3, seen.count(&0b1100));
+        assert_eq!(0, seen.count(&0b1111));
+
+        let mut found = Vec::new();
+        seen.find_each_count(&0b1100, 1, |d, k, n| found.push((d, *k, n)));
+        found.sort();
+        assert_eq!(vec![(0, 0b1100, 3), (1, 0b1101, 1)], found);
+    }
+
+    #[test]
+    fn string_keys() {
+        let mut words: BkInRamMap<StringKey, LevenshteinMetric, usize> =
+            BkInRamMap::new(LevenshteinMetric);
+        for (line, word) in ["book", "books", "cake", "book"].iter().enumerate() {
+            words.add(*word, line);
+        }
+        assert_eq!(
+            vec![(0, "book".to_string()), (1, "books".to_string())],
+            words.find_knn("book", 2, 1)
+        );
+        assert_eq!(&[0, 3], words.get("book"));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::Dist;
+use std::error::Error;
 use std::vec::Vec;
 
 pub trait BkNode {
@@ -7,8 +8,43 @@ pub trait BkNode {
     fn key(&self) -> &Self::Key;
     fn has_child_at(&self, dist: Dist) -> bool;
     fn child_at(&self, dist: Dist) -> Option<&Self>;
+    /// Each child with its slot, collected. Searches and traversals use `each_child`, which
+    /// needn't allocate.
     fn children_vector(&self) -> Vec<(Dist, &Self)>;
 
+    /// Call `f` with each child, in `children_vector` order. Node types that can enumerate their
+    /// children without collecting them override this, keeping searches allocation free.
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        for (dist, child) in self.children_vector() {
+            f(dist, child);
+        }
+    }
+
+    /// Largest slot distance with a child in it, or None for leaves.
+    fn max_child_dist(&self) -> Option<Dist> {
+        self.children_vector().iter().map(|(dist, _)| *dist).max()
+    }
+
+    /// True if this node's key has been removed from the tree. Tombstoned nodes still route
+    /// searches to their children, but are never reported as matches.
+    fn is_tombstone(&self) -> bool {
+        false
+    }
+
+    /// Application defined bits attached to the node's key, e.g. "reviewed" or "quarantined".
+    fn flags(&self) -> u8 {
+        0
+    }
+
+    /// Whether the node's child table keeps its own layout's rules, for `BkTree::validate`.
+    /// Node types whose tables have rules of their own check them.
+    fn children_well_formed(&self) -> bool {
+        true
+    }
+
     // Needs RFC 1598: GATs: because the child is not copyable and is owned by this code (or
     // rather, by its allocator)
     // fn children_iter(&self) -> impl Iterator<Item = (Dist, &Self)>;
@@ -16,5 +52,15 @@ pub trait BkNode {
 
 pub trait BkNodeMut: BkNode {
     fn set_child_node(&mut self, distance: Dist, node: Self);
+
+    /// Make room for one more child, before the new child is allocated and `set_child_node`
+    /// adds it. Nodes whose child tables can run out of room, like those of files with a fixed
+    /// number of free nodes, fail here, before anything changes.
+    fn reserve_child(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self>;
+    fn set_tombstone(&mut self, tombstone: bool);
+    fn set_flags(&mut self, flags: u8);
 }
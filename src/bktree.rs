@@ -1,23 +1,44 @@
-/***
- * let metric = ...  // e.g. metric : HammingMetric<KeyType> = Default::default();
- * let tree = BkTree::new(metric);
- * tree.add(key1);
-*/
-
+/*
+ * The tree traits, for every kind of tree in the crate. `BkTree` searches and walks a tree;
+ * `BkTreeAdd` and `BkTreeRemove` change any tree implementing `BkTreeRootMut`. The trees
+ * themselves live beside their nodes: `bk::BkInRamTree`, `arena::BkArenaTree`,
+ * `bkfile_tree::BkFileTree` and so on.
+ *
+ *   let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+ *       BkInRamTree::new(Default::default(), &U64_ALLOC);
+ *   tree.add(&key1)?;
+ *   tree.find_each(&needle, 4, |dist, key| ...);
+ *
+ * The crate root re-exports these traits and `BkInRamTree`.
+ */
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
 //use std::fmt;
 //use std::fmt::Debug;
 //use std::fmt::Formatter;
-//use std::marker::PhantomData;
+use std::marker::PhantomData;
 use std::option::Option;
 // use std::vec::Vec;
 use std::result::Result;
 
+use sha2::{Digest, Sha256};
+use smallvec::{Array, SmallVec};
+
+use crate::bk::{closest_slot_first, BkFind, FanOut};
 use crate::bknode::{BkNode, BkNodeMut};
+use crate::build;
+use crate::build::{BuildOrder, BuildReport};
 use crate::keyquery::KeyQuery;
 use crate::metric::Metric as MetricTrait;
 
 use crate::nodeallocator::NodeAllocator;
+use crate::stats::{QueryStats, TreeStats};
+use crate::validate;
+use crate::validate::InvariantError;
 use crate::Dist;
 
 pub trait BkTree<Key: Clone> {
@@ -25,6 +46,15 @@ pub trait BkTree<Key: Clone> {
     type Metric: MetricTrait<<Self::KQ as KeyQuery>::Query>;
     type Node: BkNode<Key = Key>;
 
+    fn root(&self) -> Option<&Self::Node>;
+    fn metric(&self) -> &Self::Metric;
+
+    /// The node count (tombstones included) and depth the tree keeps track of, if it does, for
+    /// `validate` to check.
+    fn recorded_counts(&self) -> Option<(u64, usize)> {
+        None
+    }
+
     fn find_each<'a, F>(
         &'a self,
         needle: &'a <Self::KQ as KeyQuery>::Query,
@@ -32,6 +62,684 @@ pub trait BkTree<Key: Clone> {
         callback: F,
     ) where
         F: FnMut(Dist, &<Self::KQ as KeyQuery>::Key);
+
+    /// Keys in the tree, not counting removed ones. A full traversal, unless the tree keeps
+    /// count.
+    fn len(&self) -> usize {
+        let mut keys = 0;
+        self.preorder_each(|_, _, _| keys += 1);
+        keys
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Traverse the tree, calling callback for each key. Parents are passed before children.
+    ///
+    /// Callback args:
+    ///    * distance from parent
+    ///    * number of children of the node on which key was found
+    ///    * key
+    fn preorder_each<F>(&self, mut callback: F)
+    where
+        F: FnMut(Dist, usize, &Key),
+    {
+        let mut stack: Vec<(Dist, &Self::Node)> = self.root().into_iter().map(|r| (0, r)).collect();
+        while let Some((dist, node)) = stack.pop() {
+            let before = stack.len();
+            node.each_child(|slot, child| stack.push((slot, child)));
+            if !node.is_tombstone() {
+                callback(dist, stack.len() - before, node.key());
+            }
+        }
+    }
+
+    /// Shape statistics, with a full traversal. Trees that keep their own may have a cheaper
+    /// way, e.g. `BkInRamTree::quick_stats`.
+    fn stats(&self) -> TreeStats
+    where
+        Self: Sized,
+    {
+        TreeStats::from_tree(self)
+    }
+
+    /// Check that every key is filed under the slots its distances from its ancestors say it
+    /// should be, that every child table is well formed, and that the tree's counts are right.
+    /// See `validate`.
+    fn validate(&self) -> Result<(), InvariantError> {
+        validate::validate(self)
+    }
+
+    /// Like `preorder_each`, but each node comes after its closest child's subtree and before
+    /// those of the rest, which are visited closest first: in-order, as for a binary tree whose
+    /// left subtree is the closest child. The child count passed is of live children: removed
+    /// ones are skipped, and their own children counted in their place.
+    fn inorder_each<F>(&self, mut callback: F)
+    where
+        F: FnMut(Dist, usize, &Key),
+    {
+        // (distance from parent, node, whether its children are already on the stack)
+        let mut stack: Vec<(Dist, &Self::Node, bool)> =
+            self.root().into_iter().map(|r| (0, r, false)).collect();
+        // Reused from node to node, rather than allocated for each.
+        let mut children = Vec::new();
+        while let Some((dist, node, expanded)) = stack.pop() {
+            children.clear();
+            node.each_child(|slot, child| children.push((slot, child)));
+            if expanded || children.is_empty() {
+                if !node.is_tombstone() {
+                    callback(dist, live_children(node), node.key());
+                }
+                continue;
+            }
+            children.sort_by_key(|(slot, _)| *slot);
+            stack.extend(children[1..].iter().rev().map(|(d, c)| (*d, *c, false)));
+            stack.push((dist, node, true));
+            stack.push((children[0].0, children[0].1, false));
+        }
+    }
+
+    /// Like `preorder_each`, but each node comes after all its children's subtrees, which are
+    /// visited closest first. For bottom up work: serializers that need a node's children
+    /// written first, or subtree sizes. Child counts are of live children, as for
+    /// `inorder_each`, so each node's are the last that many keys passed.
+    fn postorder_each<F>(&self, mut callback: F)
+    where
+        F: FnMut(Dist, usize, &Key),
+    {
+        // (distance from parent, node, whether its children are already on the stack)
+        let mut stack: Vec<(Dist, &Self::Node, bool)> =
+            self.root().into_iter().map(|r| (0, r, false)).collect();
+        let mut children = Vec::new();
+        while let Some((dist, node, expanded)) = stack.pop() {
+            children.clear();
+            node.each_child(|slot, child| children.push((slot, child)));
+            if expanded || children.is_empty() {
+                if !node.is_tombstone() {
+                    callback(dist, live_children(node), node.key());
+                }
+                continue;
+            }
+            children.sort_by_key(|(slot, _)| *slot);
+            stack.push((dist, node, true));
+            stack.extend(children.iter().rev().map(|(d, c)| (*d, *c, false)));
+        }
+    }
+
+    /// Whether both trees hold the same live keys, however they're arranged. Both trees' metrics
+    /// must give distance 0 between equal keys.
+    fn same_keys<T>(&self, other: &T) -> bool
+    where
+        T: BkTree<Key, KQ = Self::KQ>,
+    {
+        let mut mine: u64 = 0;
+        let mut all_found = true;
+        self.preorder_each(|_, _, key| {
+            if !all_found {
+                return;
+            }
+            mine += 1;
+            // `add_unique` can store a key more than once, so count copies rather than look
+            // for one.
+            let query = Self::KQ::to_query_static(key);
+            let (mut here, mut there) = (0, 0);
+            self.find_each(query, 0, |_, k| {
+                here += Self::KQ::eq_static(k, query) as u64
+            });
+            other.find_each(query, 0, |_, k| {
+                there += Self::KQ::eq_static(k, query) as u64
+            });
+            all_found = here == there;
+        });
+        if !all_found {
+            return false;
+        }
+        // Every key has as many copies in other, so equal totals mean other has nothing extra.
+        let mut theirs: u64 = 0;
+        other.preorder_each(|_, _, _| theirs += 1);
+        mine == theirs
+    }
+
+    /// A hash of the tree's live keys that doesn't depend on the order they were added in, for
+    /// cheaply checking that two indexes agree. Keys are hashed with SHA-256, so hashes can be
+    /// stored and compared across releases and platforms.
+    fn content_hash(&self) -> u64
+    where
+        Key: Hash,
+    {
+        let mut sum: u64 = 0;
+        let mut count: u64 = 0;
+        self.preorder_each(|_, _, key| {
+            let mut hasher = StableHasher::default();
+            key.hash(&mut hasher);
+            sum = sum.wrapping_add(hasher.finish());
+            count += 1;
+        });
+        let mut hasher = StableHasher::default();
+        (sum, count).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// `find_each`, passing each match's flags (see `BkNode::flags`) to the callback too.
+    fn find_each_flagged<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        mut callback: F,
+    ) where
+        F: FnMut(Dist, &Key, u8),
+    {
+        BkFind::new(0, self.root(), tolerance, needle)
+            .each_node_while::<Self::KQ, Self::Metric, _>(self.metric(), |dist, node| {
+                callback(dist, node.key(), node.flags());
+                true
+            });
+    }
+
+    /// `find_each` for keys from `min_dist` to `max_dist` from `needle`, e.g. `find_range(key, 1,
+    /// 4, ..)` for the near copies of a key but not the key itself. Unlike filtering in the
+    /// callback, this skips the subtrees whose keys are all too close.
+    fn find_range<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        min_dist: Dist,
+        max_dist: Dist,
+        callback: F,
+    ) where
+        F: FnMut(Dist, &Key),
+    {
+        BkFind::new(0, self.root(), max_dist, needle)
+            .at_least(min_dist)
+            .each::<Self::KQ, Self::Metric, F>(self.metric(), callback);
+    }
+
+    /// An approximate `find_each` that gives up after visiting `budget` nodes, for a ceiling on
+    /// the latency of searching big trees with slow metrics. Each node visited is one distance
+    /// computed. Returns true if the search was exhaustive, and so found every match.
+    fn find_each_within<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        budget: usize,
+        callback: F,
+    ) -> bool
+    where
+        F: FnMut(Dist, &Key),
+    {
+        BkFind::new(0, self.root(), tolerance, needle)
+            .ordered_by(closest_slot_first)
+            .within(budget)
+            .each_within::<Self::KQ, Self::Metric, F>(self.metric(), callback)
+            .exhaustive
+    }
+
+    /// `find_each` for distances that aren't quite metrics, e.g. some perceptual similarity
+    /// scores, which can exceed the triangle inequality's bound by up to `slack`. A strict search
+    /// would silently miss some of their matches; this one searches wider to find them.
+    fn find_each_relaxed<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        slack: Dist,
+        callback: F,
+    ) where
+        F: FnMut(Dist, &Key),
+    {
+        BkFind::new(0, self.root(), tolerance, needle)
+            .relaxed_by(slack)
+            .each::<Self::KQ, Self::Metric, F>(self.metric(), callback);
+    }
+
+    /// `find_each`, also returning what the search cost.
+    fn find_each_with_stats<'a, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        callback: F,
+    ) -> QueryStats
+    where
+        F: FnMut(Dist, &Key),
+    {
+        BkFind::new(0, self.root(), tolerance, needle)
+            .each_within::<Self::KQ, Self::Metric, F>(self.metric(), callback)
+            .stats
+    }
+
+    /// Every match, sorted by distance and then by key. Trees holding the same keys give the same
+    /// results whatever their layout or backend, so they can be diffed against each other or
+    /// kept as golden files. A key stored more than once (see `BkTreeAdd::add_unique`) is listed
+    /// once per copy.
+    fn find_sorted(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+    ) -> Vec<(Dist, Key)>
+    where
+        Key: Ord,
+    {
+        let mut found = Vec::new();
+        self.find_each(needle, tolerance, |dist, key| {
+            found.push((dist, key.clone()))
+        });
+        found.sort();
+        found
+    }
+
+    /// `find_each` for a batch of needles in one walk of the tree, calling back with the index
+    /// of the needle each match is for. Each node is visited once for all the needles that could
+    /// match under it, rather than once per needle, which saves the walk and any decoding a
+    /// backend does per node; the distances computed are the same.
+    fn find_many<N, F>(&self, needles: &[N], tolerance: Dist, mut callback: F)
+    where
+        N: Borrow<<Self::KQ as KeyQuery>::Query>,
+        F: FnMut(usize, Dist, &Key),
+    {
+        // Each node, with the needles still in range of it.
+        let mut stack: Vec<(&Self::Node, Vec<usize>)> = match self.root() {
+            Some(root) => vec![(root, (0..needles.len()).collect())],
+            None => return,
+        };
+        let mut dists = Vec::new();
+        while let Some((node, active)) = stack.pop() {
+            let query = Self::KQ::to_query_static(node.key());
+            dists.clear();
+            for &i in &active {
+                let dist = self.metric().distance(query, needles[i].borrow());
+                if dist <= tolerance && !node.is_tombstone() {
+                    callback(i, dist, node.key());
+                }
+                dists.push(dist);
+            }
+            node.each_child(|slot, child| {
+                let in_range: Vec<usize> = active
+                    .iter()
+                    .zip(&dists)
+                    .filter(|(_, dist)| {
+                        dist.saturating_sub(tolerance) <= slot
+                            && slot <= dist.saturating_add(tolerance)
+                    })
+                    .map(|(i, _)| *i)
+                    .collect();
+                if !in_range.is_empty() {
+                    stack.push((child, in_range));
+                }
+            });
+        }
+    }
+
+    /// The node reached from the root by following the child slots in `path`. The empty path is
+    /// the root. Paths stay valid as keys are added, so they can serve as handles to subtrees,
+    /// e.g. categories encoded in the first levels of the tree.
+    fn node_at(&self, path: &[Dist]) -> Option<&Self::Node> {
+        let mut node = self.root()?;
+        for dist in path {
+            node = node.child_at(*dist)?;
+        }
+        Some(node)
+    }
+
+    /// The stored key equal to `key`, unless it's been removed. Follows the one chain of
+    /// children `add` would, so is much cheaper than `find_each` with a tolerance of 0.
+    fn get(&self, key: &<Self::KQ as KeyQuery>::Query) -> Option<&Key> {
+        let mut node = self.root()?;
+        while !Self::KQ::eq_static(node.key(), key) {
+            let dist = self
+                .metric()
+                .distance(Self::KQ::to_query_static(node.key()), key);
+            node = node.child_at(dist)?;
+        }
+        if node.is_tombstone() {
+            None
+        } else {
+            Some(node.key())
+        }
+    }
+
+    /// Whether `key` is in the tree. See `get`.
+    fn contains(&self, key: &<Self::KQ as KeyQuery>::Query) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The path (see `node_at`) to the node holding `key`, if it's in the tree.
+    fn path_to(&self, key: &<Self::KQ as KeyQuery>::Query) -> Option<Vec<Dist>> {
+        let mut path = Vec::new();
+        let mut node = self.root()?;
+        while !Self::KQ::eq_static(node.key(), key) {
+            let dist = self
+                .metric()
+                .distance(Self::KQ::to_query_static(node.key()), key);
+            node = node.child_at(dist)?;
+            path.push(dist);
+        }
+        Some(path)
+    }
+
+    /// `find_each`, restricted to the subtree at `path`. Returns false if there's no such node.
+    fn find_from<'a, F>(
+        &'a self,
+        path: &[Dist],
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        callback: F,
+    ) -> bool
+    where
+        F: FnMut(Dist, &Key),
+    {
+        match self.node_at(path) {
+            Some(node) => {
+                BkFind::new(0, Some(node), tolerance, needle)
+                    .each::<Self::KQ, Self::Metric, F>(self.metric(), callback);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `find_each` for services that only want the first handful of matches: matches go into
+    /// `found` until it reaches its inline capacity, and the search stops there. Nothing is
+    /// allocated unless the search has more than 32 nodes pending at once. Returns false if the
+    /// search stopped early, with matches left unreported.
+    fn find_into<A>(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        found: &mut SmallVec<A>,
+    ) -> bool
+    where
+        A: Array<Item = (Dist, Key)>,
+    {
+        let room = found.inline_size().saturating_sub(found.len());
+        if room == 0 {
+            return false;
+        }
+        let mut complete = true;
+        BkFind::new(0, self.root(), tolerance, needle)
+            .ordered_by(closest_slot_first)
+            .each_while::<Self::KQ, Self::Metric, _>(self.metric(), |dist, key| {
+                if found.len() == found.inline_size() {
+                    complete = false;
+                    return false;
+                }
+                found.push((dist, key.clone()));
+                true
+            });
+        complete
+    }
+
+    /// `find_each`, but visiting subtrees in order of how close their keys could be to `needle`
+    /// rather than depth first, so matches come closest first. The search stops when the
+    /// callback returns false: taking the first k matches gives the k nearest, without the
+    /// rest of the search `find_knn` has to make to be sure of them. Ties come in no particular
+    /// order.
+    fn find_best_first<F>(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        mut callback: F,
+    ) where
+        F: FnMut(Dist, &Key) -> bool,
+    {
+        let mut queue: BinaryHeap<BestFirst<Self::Node>> = BinaryHeap::new();
+        if let Some(root) = self.root() {
+            queue.push(BestFirst {
+                bound: 0,
+                exact: false,
+                node: root,
+            });
+        }
+        while let Some(entry) = queue.pop() {
+            if entry.exact {
+                // Nothing left in the queue can be closer.
+                if !callback(entry.bound, entry.node.key()) {
+                    return;
+                }
+                continue;
+            }
+            let node = entry.node;
+            let dist = self
+                .metric()
+                .distance(Self::KQ::to_query_static(node.key()), needle);
+            if dist <= tolerance && !node.is_tombstone() {
+                queue.push(BestFirst {
+                    bound: dist,
+                    exact: true,
+                    node,
+                });
+            }
+            node.each_child(|slot, child| {
+                // Every key under the child is `slot` from this node.
+                let bound = entry.bound.max(slot.abs_diff(dist));
+                if bound <= tolerance {
+                    queue.push(BestFirst {
+                        bound,
+                        exact: false,
+                        node: child,
+                    });
+                }
+            });
+        }
+    }
+
+    /// The key nearest to `needle`, however far away, or None if the tree is empty. For when any
+    /// tolerance would be a guess. Ties are broken as in `find_knn`.
+    fn find_nearest(&self, needle: &<Self::KQ as KeyQuery>::Query) -> Option<(Dist, Key)> {
+        self.find_knn(needle, 1, Dist::MAX).pop()
+    }
+
+    /// The `k` keys nearest to `needle`, no further than `max_distance`, sorted by distance.
+    ///
+    /// A radius search whose radius shrinks to the k-th best distance found so far. Ties at the
+    /// k-th distance are broken by traversal order.
+    fn find_knn(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> Vec<(Dist, Key)> {
+        self.find_knn_ordered(needle, k, max_distance, closest_slot_first)
+    }
+
+    /// `find_knn`, visiting each node's children in the order `fan_out` gives them. The sooner
+    /// good keys turn up, the sooner the radius shrinks; the results are the same either way,
+    /// bar ties.
+    fn find_knn_ordered(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+        fan_out: FanOut,
+    ) -> Vec<(Dist, Key)> {
+        knn(
+            self,
+            needle,
+            k,
+            max_distance,
+            fan_out,
+            &mut QueryStats::default(),
+        )
+    }
+
+    /// `find_knn`, also returning what the search cost.
+    fn find_knn_with_stats(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> (Vec<(Dist, Key)>, QueryStats) {
+        let mut stats = QueryStats::default();
+        let found = knn(
+            self,
+            needle,
+            k,
+            max_distance,
+            closest_slot_first,
+            &mut stats,
+        );
+        (found, stats)
+    }
+}
+
+/// How many children `node` has that aren't removed keys, counting a removed child's children
+/// in its place, and so on down.
+fn live_children<N: BkNode>(node: &N) -> usize {
+    let mut live = 0;
+    let mut removed = vec![node];
+    while let Some(node) = removed.pop() {
+        node.each_child(|_, child| {
+            if child.is_tombstone() {
+                removed.push(child);
+            } else {
+                live += 1;
+            }
+        });
+    }
+    live
+}
+
+/// The body of `BkTree::find_knn_ordered`, counting its costs into `stats`.
+fn knn<Key, T>(
+    tree: &T,
+    needle: &<T::KQ as KeyQuery>::Query,
+    k: usize,
+    max_distance: Dist,
+    fan_out: FanOut,
+    stats: &mut QueryStats,
+) -> Vec<(Dist, Key)>
+where
+    Key: Clone,
+    T: BkTree<Key> + ?Sized,
+{
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut found: Vec<&Key> = Vec::new();
+    let computed = Cell::new(0);
+    // Max-heap of (distance, index into found), holding the best k so far.
+    let mut best: BinaryHeap<(Dist, usize)> = BinaryHeap::with_capacity(k + 1);
+    let distance = |node: &T::Node| {
+        computed.set(computed.get() + 1);
+        tree.metric()
+            .distance(T::KQ::to_query_static(node.key()), needle)
+    };
+    let mut stack: Vec<(Dist, &T::Node)> =
+        tree.root().into_iter().map(|r| (distance(r), r)).collect();
+    // Reused from node to node, rather than allocated for each.
+    let mut children: Vec<(Dist, &T::Node)> = Vec::new();
+    while let Some((dist, node)) = stack.pop() {
+        stats.nodes_visited += 1;
+        let radius = if best.len() == k {
+            best.peek().unwrap().0
+        } else {
+            max_distance
+        };
+        if dist <= radius && !node.is_tombstone() {
+            best.push((dist, found.len()));
+            found.push(node.key());
+            if best.len() > k {
+                best.pop();
+            }
+        }
+
+        let radius = if best.len() == k {
+            best.peek().unwrap().0
+        } else {
+            max_distance
+        };
+        children.clear();
+        node.each_child(|slot, child| {
+            if dist.saturating_sub(radius) <= slot && slot <= dist.saturating_add(radius) {
+                children.push((slot, child));
+            }
+        });
+        // Popped from the end, so the first to visit goes last.
+        children.sort_by_key(|(slot, _)| Reverse(fan_out(dist, *slot)));
+        stack.extend(children.iter().map(|(_, c)| (distance(c), *c)));
+        stats.max_stack_depth = stats.max_stack_depth.max(stack.len() + 1);
+    }
+    stats.distance_computations += computed.get();
+    best.into_sorted_vec()
+        .into_iter()
+        .map(|(dist, i)| (dist, found[i].clone()))
+        .collect()
+}
+
+/// A `find_best_first` queue entry: a node whose key is `bound` from the needle if `exact`, or
+/// else a subtree whose keys are all at least `bound` away.
+struct BestFirst<'n, N> {
+    bound: Dist,
+    exact: bool,
+    node: &'n N,
+}
+
+impl<'n, N> PartialEq for BestFirst<'n, N> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.bound, self.exact) == (other.bound, other.exact)
+    }
+}
+
+impl<'n, N> Eq for BestFirst<'n, N> {}
+
+impl<'n, N> PartialOrd for BestFirst<'n, N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'n, N> Ord for BestFirst<'n, N> {
+    /// Greatest first out of a BinaryHeap: the smallest bound, and matches ahead of subtrees
+    /// with the same bound.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .bound
+            .cmp(&self.bound)
+            .then(self.exact.cmp(&other.exact))
+    }
+}
+
+/// One subtree of a tree, searched as a tree of its own.
+pub(crate) struct Subtree<'t, KQ, M, N> {
+    metric: &'t M,
+    root: &'t N,
+    kq: PhantomData<KQ>,
+}
+
+impl<'t, KQ, M, N> Subtree<'t, KQ, M, N> {
+    /// The subtree under `root`, of a tree whose metric is `metric`.
+    pub(crate) fn new(metric: &'t M, root: &'t N) -> Self {
+        Subtree {
+            metric,
+            root,
+            kq: PhantomData,
+        }
+    }
+}
+
+impl<'t, Key, KQ, M, N> BkTree<Key> for Subtree<'t, KQ, M, N>
+where
+    Key: Clone,
+    KQ: KeyQuery<Key = Key>,
+    M: MetricTrait<KQ::Query>,
+    N: BkNode<Key = Key>,
+{
+    type KQ = KQ;
+    type Metric = M;
+    type Node = N;
+
+    fn root(&self) -> Option<&N> {
+        Some(self.root)
+    }
+
+    fn metric(&self) -> &M {
+        self.metric
+    }
+
+    fn find_each<'a, F>(&'a self, needle: &'a KQ::Query, tolerance: Dist, callback: F)
+    where
+        F: FnMut(Dist, &Key),
+    {
+        BkFind::new(0, Some(self.root), tolerance, needle).each::<KQ, M, F>(self.metric, callback);
+    }
 }
 
 pub trait BkTreeRootMut<'a, Key: Clone>: BkTree<Key>
@@ -43,20 +751,136 @@ where
 
     fn node_allocator(&mut self) -> &'a Self::Alloc;
     fn root_mut(&mut self) -> &mut Option<<Self as BkTree<Key>>::Node>;
+    /// Depth of the deepest node, the root's being 0. Inserts raise it to the new node's depth,
+    /// which `validate` checks it's at least.
     fn max_depth_mut(&mut self) -> &mut usize;
     fn incr_node_count(&mut self);
+    fn incr_tombstone_count(&mut self);
+    fn decr_tombstone_count(&mut self);
+
+    /// Remove every key, leaving the tree as it was new.
+    fn clear(&mut self);
+
+    /// Called as a node is inserted `depth` levels down, under `parent` (None for a new root),
+    /// before it's attached. For trees keeping statistics.
+    fn record_insert(&mut self, _depth: usize, _parent: Option<&<Self as BkTree<Key>>::Node>) {}
+
+    /// Whether `add` should insert this key. Trees with an admission policy reject keys here,
+    /// before any node is touched.
+    fn admit(&mut self, _query: &<Self::KQ as KeyQuery>::Query) -> bool {
+        true
+    }
 }
 
 pub trait BkTreeAdd<'a, Key: Clone>: BkTreeRootMut<'a, Key> + BkTree<Key>
 where
     <Self as BkTree<Key>>::Node: BkNodeMut<Key = Key>,
 {
-    fn add(&mut self, key: <Self::KQ as KeyQuery>::Query) -> Result<(), Box<dyn Error>>;
+    fn add(&mut self, key: &<Self::KQ as KeyQuery>::Query) -> Result<(), Box<dyn Error>>;
+
+    /// `add` for keys known not to be in the tree already, e.g. a deduplicated export. Skips the
+    /// equality checks `add` makes at each level; a key that is already present gets stored
+    /// twice.
+    ///
+    /// The saving is one key comparison per level, which only shows when comparing keys costs
+    /// about as much as a distance. For u64 keys it's lost in the noise: building a bkfile from
+    /// 3M sorted unique random u64s took 8-9s with and without `--assume-sorted-unique`.
+    fn add_unique(&mut self, key: &<Self::KQ as KeyQuery>::Query) -> Result<(), Box<dyn Error>>;
+
+    /// Add a batch of keys in the order `order` gives them, for a better shaped tree than adding
+    /// them as they come. See `build`.
+    fn bulk_build<I>(&mut self, keys: I, order: BuildOrder) -> Result<BuildReport, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = Key>,
+        Key: Ord,
+    {
+        let keys: Vec<Key> = match order {
+            BuildOrder::AsGiven => keys.into_iter().collect(),
+            BuildOrder::Shuffled(seed) => {
+                let mut keys: Vec<Key> = keys.into_iter().collect();
+                build::shuffle(&mut keys, seed);
+                keys
+            }
+            BuildOrder::MedianFirst => build::median_first(keys.into_iter().collect()),
+        };
+        for key in keys.iter() {
+            self.add(Self::KQ::to_query_static(key))?;
+        }
+        Ok(BuildReport {
+            added: keys.len() as u64,
+            max_depth: *self.max_depth_mut(),
+        })
+    }
+
+    /// Add every live key of `other`, e.g. to combine shards built in parallel. Keys already
+    /// here aren't added again; flags and values aren't copied.
+    fn merge<T>(&mut self, other: &T) -> Result<(), Box<dyn Error>>
+    where
+        T: BkTree<Key, KQ = Self::KQ>,
+    {
+        // Parents before children, so the merged tree keeps roughly the shape of `other`.
+        let mut result = Ok(());
+        other.preorder_each(|_, _, key| {
+            if result.is_ok() {
+                result = self.add(Self::KQ::to_query_static(key));
+            }
+        });
+        result
+    }
+}
+
+pub trait BkTreeRemove<'a, Key: Clone>: BkTreeRootMut<'a, Key> + BkTree<Key>
+where
+    <Self as BkTree<Key>>::Node: BkNodeMut<Key = Key>,
+{
+    fn remove(&mut self, key: &<Self::KQ as KeyQuery>::Query) -> bool;
+}
+
+/// A `Hasher` that gives the same hash on every platform and release: SHA-256, truncated, with
+/// integers fed to it little endian at a fixed width.
+#[derive(Default)]
+struct StableHasher(Sha256);
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.input(bytes);
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.write(&[n]);
+    }
+
+    fn write_u16(&mut self, n: u16) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().result();
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(first)
+    }
 }
 
 impl<
         'a,
-        Q: Sized,
+        Q: ?Sized,
         Key: Clone,
         KQ,
         M,
@@ -74,147 +898,180 @@ where
     /// Example:
     ///   let mut tree = BkTree::new(Metric, BkInRamAllocator());
     ///
-    ///   tree.add(1);
-    ///   tree.add(2);
-    ///   tree.add(3);
+    ///   tree.add(&1);
+    ///   tree.add(&2);
+    ///   tree.add(&3);
+    ///
+    /// Re-adding a removed key revives its node in place. Keys the tree doesn't admit are
+    /// dropped without error.
     fn add(
         &mut self,
-        query: <<Self as BkTree<Key>>::KQ as KeyQuery>::Query,
+        query: &<<Self as BkTree<Key>>::KQ as KeyQuery>::Query,
     ) -> Result<(), Box<dyn Error>> {
-        let mut root = self.root_mut().take();
-        let mut insert_depth: usize = 0;
-        let query_as_key: Key = <Self as BkTree<Key>>::KQ::to_key_static(&query);
-        match root {
-            None => {
-                root = Some(self.node_allocator().new_root(query_as_key)?);
-                self.incr_node_count();
-            }
-            Some(ref mut root) => {
-                let mut cur = root;
-                let mut dist = <Self as BkTree<Key>>::Metric::distance_static(
-                    <Self as BkTree<Key>>::KQ::to_query_static(cur.key()),
-                    &query,
-                );
-
-                // Find an empty child slot where the slot's distance from its node is the same as the
-                // query's distance from the same node, or that this query is already present in
-                // the tree.
-                while cur.has_child_at(dist)
-                    && (dist == 0 || !<Self as BkTree<Key>>::KQ::eq_static(cur.key(), &query))
-                {
-                    cur = cur.child_at_mut(dist).unwrap();
-                    dist = <Self as BkTree<Key>>::Metric::distance_static(
-                        <Self as BkTree<Key>>::KQ::to_query_static(cur.key()),
-                        &query,
-                    );
-                    insert_depth += 1;
-                }
-
-                assert!(
-                    !cur.has_child_at(dist)
-                        || <Self as BkTree<Key>>::KQ::eq_static(cur.key(), &query)
-                );
-                if !<Self as BkTree<Key>>::KQ::eq_static(cur.key(), &query) {
-                    let child = self.node_allocator().new_child(query_as_key)?;
-                    cur.set_child_node(dist, child);
-                    self.incr_node_count();
-                }
-            }
+        if !self.admit(query) {
+            return Ok(());
         }
-        if let Some(root2) = root.take() {
-            self.root_mut().replace(root2);
-        }
-        if *self.max_depth_mut() < insert_depth {
-            *self.max_depth_mut() = insert_depth;
+        insert(self, query, false)
+    }
+
+    fn add_unique(
+        &mut self,
+        query: &<<Self as BkTree<Key>>::KQ as KeyQuery>::Query,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.admit(query) {
+            return Ok(());
         }
-        return Ok(());
+        insert(self, query, true)
     }
 }
 
-/*
+/// The body of `add` and `add_unique`, past the admission policy: insert `query`, or revive it
+/// if it was removed. Keys `unique` isn't compared with the keys on the way down, so it always
+/// gets a new node. Also for re-adding keys a tree already admitted, e.g. when rebuilding it.
+pub(crate) fn insert<'a, Q, Key, KQ, M, N, Alloc, T>(
+    tree: &mut T,
+    query: &Q,
+    unique: bool,
+) -> Result<(), Box<dyn Error>>
+where
+    Q: ?Sized,
+    Key: Clone,
+    N: BkNodeMut<Key = Key>,
+    Alloc: 'a + NodeAllocator<'a, Node = N, Key = Key>,
+    KQ: KeyQuery<Key = Key, Query = Q>,
+    M: MetricTrait<Q>,
+    T: BkTreeRootMut<'a, Key, Metric = M, Node = N, Alloc = Alloc, KQ = KQ>,
+{
+    let is_query = |key: &Key| !unique && KQ::eq_static(key, query);
+    let mut root = tree.root_mut().take();
+    // The root goes back in the tree before any error is returned.
+    let mut added = Ok(());
+    let mut insert_depth: usize = 0;
+    let query_as_key: Key = KQ::to_key_static(query);
+    match root {
+        None => {
+            root = Some(tree.node_allocator().new_root(query_as_key)?);
+            tree.incr_node_count();
+            tree.record_insert(0, None);
+        }
+        Some(ref mut root) => {
+            let mut cur = root;
+            let mut dist = tree
+                .metric()
+                .distance(KQ::to_query_static(cur.key()), query);
 
-    // E0309: Needs GAT with lifetimes to express that the BkFind iterator's innards should not
-    // live longer than the tree itself.
-    pub fn find<'a, 'b: 'a>(
-        &'b self,
-        needle: &'b KQ::Query,
-        tolerance: Dist,
-    ) -> impl 'a + Iterator<Item = (Dist, K)> {
-        use super::find::BkFind;
-        BkFind::new(&self.kq, &self.metric, self.max_depth, self.root.as_ref(), tolerance, needle)
-    }
+            // Find an empty child slot where the slot's distance from its node is the same as the
+            // query's distance from the same node, or that this query is already present in
+            // the tree.
+            while cur.has_child_at(dist) && (dist == 0 || !is_query(cur.key())) {
+                cur = cur.child_at_mut(dist).unwrap();
+                dist = tree
+                    .metric()
+                    .distance(KQ::to_query_static(cur.key()), query);
+                insert_depth += 1;
+            }
 
-    pub fn in_order<'a, 'b: 'a>(&'b self) -> impl 'a + Iterator<Item=(Dist, K)> {
-         use super::inorder::BkInOrder;
-         BkInOrder::new(self.root.as_ref())
+            assert!(!cur.has_child_at(dist) || is_query(cur.key()));
+            if !is_query(cur.key()) {
+                match cur
+                    .reserve_child()
+                    .and_then(|()| tree.node_allocator().new_child(query_as_key))
+                {
+                    Ok(child) => {
+                        insert_depth += 1;
+                        tree.record_insert(insert_depth, Some(&*cur));
+                        cur.set_child_node(dist, child);
+                        tree.incr_node_count();
+                    }
+                    Err(e) => added = Err(e),
+                }
+            } else if cur.is_tombstone() {
+                cur.set_tombstone(false);
+                tree.decr_tombstone_count();
+            }
+        }
     }
-*/
-/*
-impl<'a, K: 'a + Clone, KQ, M, N, Alloc> BkTree<'a, KQ, M, Alloc>
-where
-    K: 'a + Clone,
-    KQ: KeyQuery<Key = K> + Default,
-    M: Metric<<KQ as KeyQuery>::Query>,
-    N: 'a + BkNode<Key = K>,
-    Alloc: NodeAllocator<'a, Key = K, Node = N>,
-{
-    pub fn find_each<F>(&'a self, needle: &'a KQ::Query, tolerance: Dist, callback: F)
-    where
-        F: FnMut(Dist, &KQ::Key),
-    {
-        use super::find::BkFind;
-        BkFind::new(
-            &self.kq,
-            &self.metric,
-            self.max_depth,
-            self.root.as_ref(),
-            tolerance,
-            needle,
-        )
-        .each(callback)
+    if let Some(root2) = root.take() {
+        tree.root_mut().replace(root2);
     }
+    added?;
+    if *tree.max_depth_mut() < insert_depth {
+        *tree.max_depth_mut() = insert_depth;
+    }
+    Ok(())
 }
-*/
 
-/*
-impl<'a, K: 'a + Clone, KQ, M, N, Alloc> BkTree<'a, KQ, M, Alloc>
+impl<
+        'a,
+        Q: ?Sized,
+        Key: Clone,
+        KQ,
+        M,
+        N: BkNodeMut<Key = Key>,
+        Alloc: 'a,
+        T: BkTreeRootMut<'a, Key, Metric = M, Node = N, Alloc = Alloc, KQ = KQ>,
+    > BkTreeRemove<'a, Key> for T
 where
-    K: 'a + Clone,
-    KQ: KeyQuery<Key = K> + Default,
-    M: Metric<<KQ as KeyQuery>::Query>,
-    N: 'a + BkNode<Key = K>,
-    Alloc: NodeAllocator<'a, Key = K, Node = N>,
+    Alloc: NodeAllocator<'a, Node = N, Key = Key>,
+    KQ: KeyQuery<Key = Key, Query = Q>,
+    M: MetricTrait<Q>,
 {
-    /// Traverse the tree, calling callback for each key. Parents are passed before children.
+    /// Remove a key from the tree, returning whether it was present.
     ///
-    /// Callback args:
-    ///    * distance from parent
-    ///    * number of children of the node on which key was found
-    ///    * key
-    pub fn preorder_each<F>(&'a self, callback: &mut F)
-    where
-        F: FnMut(Dist, usize, &K),
-    {
-        use super::preorder::BkPreOrder;
-        BkPreOrder::new(self.root.as_ref()).each(callback);
+    /// The node is tombstoned rather than unlinked, since its children were placed relative to
+    /// its key. Tombstoned nodes keep routing searches but are never reported; use the tree's
+    /// `compact()` to purge them.
+    fn remove(&mut self, query: &<<Self as BkTree<Key>>::KQ as KeyQuery>::Query) -> bool {
+        let mut root = self.root_mut().take();
+        let mut removed = false;
+        if let Some(ref mut root) = root {
+            let mut cur = root;
+            loop {
+                if <Self as BkTree<Key>>::KQ::eq_static(cur.key(), query) {
+                    if !cur.is_tombstone() {
+                        cur.set_tombstone(true);
+                        // So that re-adding the key doesn't bring back the old flags.
+                        cur.set_flags(0);
+                        removed = true;
+                    }
+                    break;
+                }
+                let dist = self
+                    .metric()
+                    .distance(<Self as BkTree<Key>>::KQ::to_query_static(cur.key()), query);
+                match cur.child_at_mut(dist) {
+                    Some(child) => cur = child,
+                    None => break,
+                }
+            }
+        }
+        if let Some(root2) = root.take() {
+            self.root_mut().replace(root2);
+        }
+        if removed {
+            self.incr_tombstone_count();
+        }
+        removed
     }
 }
-*/
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bk::{hashed_tree, BkInRamAllocator, BkInRamTree, STRING_ALLOC, U64_ALLOC};
+    use crate::bkmap::BkInRamMap;
     use crate::keys::StringKey;
     use crate::keys::U64Key;
     use crate::metric::hamming::HammingMetric;
     use crate::metric::strlen::StrLenMetric;
 
-    fn hamming_tree<'a>() -> BkInRamTree<'a, U64Key, HammingMetric<u64>> {
-        BkTree::new(Default::default(), &U64_ALLOC)
+    fn hamming_tree<'a>() -> BkInRamTree<'a, U64Key, HammingMetric<u64>, BkInRamAllocator<'a, u64>>
+    {
+        BkInRamTree::new(Default::default(), &U64_ALLOC)
     }
 
-    fn strlen_tree<'a>() -> BkInRamTree<'a, StringKey, StrLenMetric> {
-        BkTree::new(Default::default(), &STRING_ALLOC)
+    fn strlen_tree<'a>() -> BkInRamTree<'a, StringKey, StrLenMetric, BkInRamAllocator<'a, String>> {
+        BkInRamTree::new(Default::default(), &STRING_ALLOC)
     }
 
     #[test]
@@ -226,46 +1083,46 @@ mod tests {
     #[test]
     fn can_add_one_value() {
         let mut tree = hamming_tree();
-        tree.add(&0u64);
+        tree.add(&0u64).unwrap();
         println!("Zero Tree: {:?}", tree)
     }
 
     #[test]
     fn can_add_repeated_roots() {
         let mut tree = hamming_tree();
-        tree.add(&0u64);
-        tree.add(&0u64);
-        tree.add(&0u64);
+        tree.add(&0u64).unwrap();
+        tree.add(&0u64).unwrap();
+        tree.add(&0u64).unwrap();
         println!("Zeros Tree: {:?}", tree)
     }
 
     #[test]
     fn can_add_repeated_children() {
         let mut tree = hamming_tree();
-        tree.add(&0u64);
-        tree.add(&1u64);
-        tree.add(&1u64);
-        tree.add(&1u64);
+        tree.add(&0u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.add(&1u64).unwrap();
         println!("Ones Tree: {:?}", tree)
     }
 
     #[test]
     fn can_add_distinct_values() {
         let mut tree = hamming_tree();
-        tree.add(&0u64);
-        tree.add(&1u64);
-        tree.add(&2u64);
-        tree.add(&3u64);
+        tree.add(&0u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.add(&2u64).unwrap();
+        tree.add(&3u64).unwrap();
         println!("Many Tree: {:?}", tree)
     }
 
     #[test]
     fn can_add_distinct_values_in_reverse() {
         let mut tree = hamming_tree();
-        tree.add(&3u64);
-        tree.add(&2u64);
-        tree.add(&1u64);
-        tree.add(&0u64);
+        tree.add(&3u64).unwrap();
+        tree.add(&2u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.add(&0u64).unwrap();
         println!("Many Tree Reversed: {:?}", tree)
     }
 
@@ -278,38 +1135,331 @@ mod tests {
     #[test]
     fn can_add_empty_string() {
         let mut tree = strlen_tree();
-        tree.add("");
+        tree.add("").unwrap();
         println!("empty string tree: {:?}", tree);
     }
 
     #[test]
     fn can_add_string() {
         let mut tree = strlen_tree();
-        tree.add("foo");
+        tree.add("foo").unwrap();
         println!("foo string tree: {:?}", tree);
     }
 
     #[test]
     fn can_add_many_strings() {
         let mut tree = strlen_tree();
-        tree.add("foo");
-        tree.add("foo");
-        tree.add("bar");
-        tree.add("baz");
-        tree.add("left");
-        tree.add("ship");
+        tree.add("foo").unwrap();
+        tree.add("foo").unwrap();
+        tree.add("bar").unwrap();
+        tree.add("baz").unwrap();
+        tree.add("left").unwrap();
+        tree.add("ship").unwrap();
         println!("many string tree: {:?}", tree);
     }
 
+    #[test]
+    fn can_remove_key() {
+        let mut tree = hamming_tree();
+        tree.add(&0u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.add(&3u64).unwrap();
+        assert!(tree.remove(&1u64));
+        assert!(!tree.remove(&1u64));
+        assert!(!tree.remove(&7u64));
+        assert_eq!(1, tree.tombstone_count);
+
+        let mut results = Vec::new();
+        tree.find_each(&1u64, 64, |_, k| results.push(*k));
+        results.sort();
+        assert_eq!(vec![0u64, 3], results);
+    }
+
+    #[test]
+    fn can_readd_removed_key() {
+        let mut tree = hamming_tree();
+        tree.add(&0u64).unwrap();
+        tree.add(&1u64).unwrap();
+        tree.remove(&1u64);
+        tree.add(&1u64).unwrap();
+        assert_eq!(2, tree.node_count);
+        assert_eq!(0, tree.tombstone_count);
+
+        let mut results = Vec::new();
+        tree.find_each(&1u64, 0, |_, k| results.push(*k));
+        assert_eq!(vec![1u64], results);
+    }
+
+    #[test]
+    fn compact_purges_tombstones() {
+        let mut tree = hamming_tree();
+        for i in 0..16u64 {
+            tree.add(&i).unwrap();
+        }
+        tree.remove(&0u64);
+        tree.remove(&5u64);
+        tree.compact().unwrap();
+        assert_eq!(14, tree.node_count);
+        assert_eq!(0, tree.tombstone_count);
+
+        let mut results = Vec::new();
+        tree.find_each(&0u64, 64, |_, k| results.push(*k));
+        results.sort();
+        let expected: Vec<u64> = (1..16).filter(|&i| i != 5).collect();
+        assert_eq!(expected, results);
+    }
+
+    #[test]
+    fn admission_rejects_keys() {
+        let blocklist = [3u64, 7];
+        let mut tree = hamming_tree();
+        tree.set_admission(|key| !blocklist.contains(key));
+        for i in 0..10u64 {
+            tree.add(&i).unwrap();
+        }
+        assert_eq!(8, tree.node_count);
+        assert_eq!(2, tree.rejected_count);
+
+        let mut results = Vec::new();
+        tree.find_each(&3u64, 0, |_, k| results.push(*k));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn len_counts_live_keys() {
+        let mut tree = strlen_tree();
+        assert!(tree.is_empty());
+        for word in &["a", "bb", "ccc", "dd"] {
+            tree.add(word).unwrap();
+        }
+        tree.remove("bb");
+        assert_eq!(3, tree.len());
+        let map: BkInRamMap<StringKey, StrLenMetric, u8> = BkInRamMap::new(StrLenMetric);
+        assert!(map.is_empty());
+
+        tree.clear();
+        assert!(tree.is_empty());
+        assert_eq!(
+            (0, 0, 0),
+            (tree.node_count, tree.tombstone_count, tree.max_depth)
+        );
+        tree.add("eeee").unwrap();
+        assert_eq!(1, tree.len());
+        assert_eq!(TreeStats::from_tree(&tree), tree.quick_stats());
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn trees_round_trip_through_serde() {
+        let mut tree: BkInRamTree<StringKey, StrLenMetric, _> = ["a", "bb", "ccc", "dd"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        tree.remove("bb");
+        tree.set_flags("ccc", 2);
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: BkInRamTree<StringKey, StrLenMetric, _> = serde_json::from_str(&json).unwrap();
+        assert!(loaded == tree);
+        assert_eq!((4, 1), (loaded.node_count, loaded.tombstone_count));
+        assert_eq!(TreeStats::from_tree(&loaded), loaded.quick_stats());
+        let mut flagged = Vec::new();
+        loaded.find_each_flagged("xyz", 0, |_, k, f| flagged.push((k.clone(), f)));
+        assert_eq!(vec![("ccc".to_string(), 2)], flagged);
+
+        // Filed by length, so a different metric's distances don't check out.
+        let misfiled = json.replace("\"dd\"", "\"ddd\"");
+        let wrong: Result<BkInRamTree<StringKey, StrLenMetric, _>, _> =
+            serde_json::from_str(&misfiled);
+        assert!(wrong.is_err());
+    }
+
+    #[test]
+    fn contains_follows_exact_children() {
+        let mut tree = strlen_tree();
+        for word in &["a", "bb", "ccc", "dd", "eeee"] {
+            tree.add(word).unwrap();
+        }
+        tree.remove("dd");
+        assert!(tree.contains("ccc"));
+        assert_eq!(Some(&"eeee".to_string()), tree.get("eeee"));
+        // Filed where "dd" was, but not it.
+        assert!(!tree.contains("xx"));
+        assert!(!tree.contains("dd"));
+        assert_eq!(None, tree.get("fffff"));
+        assert!(!strlen_tree().contains("a"));
+    }
+
+    #[test]
+    fn find_from_searches_a_subtree() {
+        let mut tree = hamming_tree();
+        for i in 0..64u64 {
+            tree.add(&i).unwrap();
+        }
+        let mut everything = Vec::new();
+        assert!(tree.find_from(&[], &0u64, 64, |_, k| everything.push(*k)));
+        assert_eq!(64, everything.len());
+
+        let path = tree.path_to(&5u64).unwrap();
+        assert!(!path.is_empty());
+        assert_eq!(5, *tree.node_at(&path).unwrap().key());
+
+        let mut subtree = Vec::new();
+        assert!(tree.find_from(&path, &5u64, 64, |_, k| subtree.push(*k)));
+        let mut expected = Vec::new();
+        let mut stack = vec![tree.node_at(&path).unwrap()];
+        while let Some(node) = stack.pop() {
+            expected.push(*node.key());
+            stack.extend(node.children_vector().into_iter().map(|(_, c)| c));
+        }
+        subtree.sort();
+        expected.sort();
+        assert!(subtree.len() < 64);
+        assert_eq!(expected, subtree);
+
+        assert_eq!(None, tree.path_to(&1000u64));
+        assert!(!tree.find_from(&[63, 63], &0u64, 64, |_, _| ()));
+    }
+
+    #[test]
+    fn add_unique_builds_the_same_tree() {
+        let mut checked = hamming_tree();
+        let mut unchecked = hamming_tree();
+        for i in 0..256u64 {
+            checked.add(&i).unwrap();
+            unchecked.add_unique(&i).unwrap();
+        }
+        assert_eq!(checked.node_count, unchecked.node_count);
+        assert_eq!(checked.max_depth, unchecked.max_depth);
+        assert_eq!(format!("{:?}", checked), format!("{:?}", unchecked));
+    }
+
+    #[test]
+    fn sparse_nodes_build_the_same_tree() {
+        use crate::bk::SPARSE_STRING_ALLOC;
+        use crate::metric::levenshtein::LevenshteinMetric;
+        let mut dense: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        let mut sparse: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &SPARSE_STRING_ALLOC);
+        let words: Vec<String> = (0..200u64)
+            .map(|i| format!("{:x}", i.wrapping_mul(0x9e3779b97f4a7c15)))
+            .collect();
+        for word in words.iter() {
+            dense.add(word).unwrap();
+            sparse.add(word).unwrap();
+        }
+        assert_eq!(format!("{:?}", dense), format!("{:?}", sparse));
+        assert_eq!(
+            dense.find_knn("9e37", 5, 12),
+            sparse.find_knn("9e37", 5, 12)
+        );
+        sparse.remove(&words[3][..]);
+        sparse.compact().unwrap();
+        assert_eq!(199, sparse.node_count);
+        assert!(sparse.find_knn(&words[3][..], 1, 0).is_empty());
+    }
+
+    #[test]
+    fn trees_compare_by_keys() {
+        let mut forward = hamming_tree();
+        let mut backward = hamming_tree();
+        for i in 0..100u64 {
+            forward.add(&i).unwrap();
+            backward.add(&(99 - i)).unwrap();
+        }
+        assert!(forward == backward);
+        assert_eq!(forward.content_hash(), backward.content_hash());
+
+        backward.remove(&42u64);
+        assert!(forward != backward);
+        assert!(backward != forward);
+        assert_ne!(forward.content_hash(), backward.content_hash());
+
+        backward.add(&42u64).unwrap();
+        backward.add(&1000u64).unwrap();
+        assert!(forward != backward);
+        assert!(backward != forward);
+        assert!(hamming_tree() == hamming_tree());
+
+        // The same number of keys, but one of them twice.
+        let mut doubled = hamming_tree();
+        let mut distinct = hamming_tree();
+        for i in 1..4u64 {
+            distinct.add(&i).unwrap();
+            doubled.add(&i).unwrap();
+        }
+        distinct.add(&4u64).unwrap();
+        doubled.add_unique(&3u64).unwrap();
+        assert!(doubled != distinct);
+        assert!(distinct != doubled);
+
+        // Pinned, as stored hashes are compared across releases.
+        assert_eq!(4994743246404382402, distinct.content_hash());
+    }
+
+    #[test]
+    fn flags_reach_find_callbacks() {
+        const REVIEWED: u8 = 1;
+        const QUARANTINED: u8 = 2;
+        let mut tree = hamming_tree();
+        for i in 0..32u64 {
+            tree.add(&i).unwrap();
+        }
+        assert!(tree.set_flags(&3u64, REVIEWED));
+        assert!(tree.set_flags(&7u64, REVIEWED | QUARANTINED));
+        assert!(!tree.set_flags(&100u64, REVIEWED));
+
+        let mut flagged = Vec::new();
+        tree.find_each_flagged(&3u64, 1, |_, k, flags| {
+            if flags != 0 {
+                flagged.push((*k, flags))
+            }
+        });
+        flagged.sort();
+        assert_eq!(vec![(3, REVIEWED), (7, REVIEWED | QUARANTINED)], flagged);
+
+        tree.remove(&1u64);
+        tree.compact().unwrap();
+        let mut kept = Vec::new();
+        tree.find_each_flagged(&7u64, 0, |_, k, flags| kept.push((*k, flags)));
+        assert_eq!(vec![(7, REVIEWED | QUARANTINED)], kept);
+
+        // A removed and re-added key starts out unflagged.
+        assert!(tree.remove(&3u64));
+        tree.add(&3u64).unwrap();
+        let mut revived = Vec::new();
+        tree.find_each_flagged(&3u64, 0, |_, k, flags| revived.push((*k, flags)));
+        assert_eq!(vec![(3, 0)], revived);
+    }
+
+    #[test]
+    fn find_into_stops_when_full() {
+        let mut tree = hamming_tree();
+        for i in 0..64u64 {
+            tree.add(&i).unwrap();
+        }
+        let mut few: SmallVec<[(Dist, u64); 4]> = SmallVec::new();
+        assert!(!tree.find_into(&0u64, 64, &mut few));
+        assert_eq!(4, few.len());
+        assert!(!few.spilled());
+        for (dist, key) in few.iter() {
+            assert_eq!(*dist, key.count_ones() as Dist);
+        }
+
+        let mut exact: SmallVec<[(Dist, u64); 4]> = SmallVec::new();
+        assert!(tree.find_into(&7u64, 0, &mut exact));
+        assert_eq!(&[(0, 7u64)], &exact[..]);
+    }
+
     /*
     #[test]
     fn can_add_find_exact_match() {
         let mut tree = strlen_tree();
-        tree.add("foo");
-        tree.add("bar");
-        tree.add("baz");
-        tree.add("left");
-        tree.add("ship");
+        tree.add("foo").unwrap();
+        tree.add("bar").unwrap();
+        tree.add("baz").unwrap();
+        tree.add("left").unwrap();
+        tree.add("ship").unwrap();
         println!("exact_match tree: {:?}", tree);
         let mut results = Vec::new();
         tree.find_each("foo", 0, |_, k| results.push(k.clone()));
@@ -319,16 +1469,492 @@ mod tests {
     #[test]
     fn can_add_find_distant_match() {
         let mut tree = strlen_tree();
-        tree.add("quux");
-        tree.add("foo");
-        tree.add("bar");
-        tree.add("baz");
-        tree.add("left");
-        tree.add("ship");
+        tree.add("quux").unwrap();
+        tree.add("foo").unwrap();
+        tree.add("bar").unwrap();
+        tree.add("baz").unwrap();
+        tree.add("left").unwrap();
+        tree.add("ship").unwrap();
         println!("distant_match tree: {:?}", tree);
         let mut results = Vec::new();
         tree.find_each("foo", 1, |_, k| results.push(k.clone()));
         assert_eq!(vec!["quux", "left", "ship", "foo", "bar", "baz"], results);
     }
     */
+
+    #[test]
+    fn best_first_finds_closest_first() {
+        let (_, tree) = hashed_tree(3000);
+        let needle = 0x3c6ef372fe94f82a;
+        let mut found = Vec::new();
+        tree.find_best_first(&needle, 20, |d, k| {
+            found.push((d, *k));
+            true
+        });
+        assert!(found.windows(2).all(|w| w[0].0 <= w[1].0));
+        let mut sorted = found.clone();
+        sorted.sort();
+        assert_eq!(tree.find_sorted(&needle, 20), sorted);
+
+        let mut first = Vec::new();
+        tree.find_best_first(&needle, 64, |d, _| {
+            first.push(d);
+            first.len() < 5
+        });
+        let knn: Vec<Dist> = tree
+            .find_knn(&needle, 5, 64)
+            .iter()
+            .map(|(d, _)| *d)
+            .collect();
+        assert_eq!(knn, first);
+    }
+
+    #[test]
+    fn find_nearest_matches_a_scan() {
+        assert_eq!(None, hashed_tree(0).1.find_nearest(&0));
+        let (keys, mut tree) = hashed_tree(3000);
+        tree.remove(&keys[5]);
+        for needle in &[keys[5], keys[9] ^ 0xff, 0x3c6ef372fe94f82a, !0] {
+            let nearest = keys[..]
+                .iter()
+                .filter(|k| **k != keys[5])
+                .map(|k| (k ^ needle).count_ones() as Dist)
+                .min();
+            let (dist, key) = tree.find_nearest(needle).unwrap();
+            assert_eq!(nearest, Some(dist));
+            assert_eq!(dist, (key ^ needle).count_ones() as Dist);
+        }
+    }
+
+    #[test]
+    fn fan_out_orders_each_nodes_children() {
+        use crate::bk::{closest_slot_first, BkFind};
+        use std::collections::HashMap;
+
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..5000u64 {
+            tree.add(&(i * 0x10001)).unwrap();
+        }
+        let by_slot = |_, slot| slot;
+        for i in 0..20u64 {
+            let needle = (i * 0x1234567) ^ 0b1001;
+            for &fan_out in [closest_slot_first as FanOut, by_slot].iter() {
+                // Everything matches, so every node is reported, in the order it's searched.
+                let mut visited = Vec::new();
+                BkFind::new(0, tree.root(), 64, &needle)
+                    .ordered_by(fan_out)
+                    .each_node_while::<U64Key, _, _>(tree.metric(), |_, node| {
+                        visited.push(node);
+                        true
+                    });
+                assert_eq!(tree.len(), visited.len());
+                let order: HashMap<_, _> = visited
+                    .iter()
+                    .enumerate()
+                    .map(|(at, node)| (*node as *const _, at))
+                    .collect();
+                for node in visited.iter() {
+                    let dist = tree.metric().distance(node.key(), &needle);
+                    let mut children = node.children_vector();
+                    children.sort_by_key(|(_, child)| order[&(*child as *const _)]);
+                    let priorities: Vec<Dist> = children
+                        .iter()
+                        .map(|(slot, _)| fan_out(dist, *slot))
+                        .collect();
+                    assert!(priorities.windows(2).all(|w| w[0] <= w[1]));
+                }
+
+                // The order only changes how soon the radius shrinks, not the distances found.
+                let knn: Vec<Dist> = tree
+                    .find_knn_ordered(&needle, 3, 64, fan_out)
+                    .iter()
+                    .map(|r| r.0)
+                    .collect();
+                let nearest: Vec<Dist> = tree
+                    .find_sorted(&needle, 64)
+                    .iter()
+                    .take(3)
+                    .map(|r| r.0)
+                    .collect();
+                assert_eq!(nearest, knn);
+            }
+        }
+    }
+
+    #[test]
+    fn budgets_bound_distance_computations() {
+        use crate::metric::counting::CountingMetric;
+
+        let mut tree: BkInRamTree<U64Key, CountingMetric<HammingMetric<u64>>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        assert!(tree.find_each_within(&0, 3, 0, |_, _| ()));
+        for i in 0..5000u64 {
+            tree.add(&(i * 0x10001)).unwrap();
+        }
+        let needle = (0x10001 * 77) ^ 0b101;
+        tree.metric().reset();
+        let expected = tree.find_sorted(&needle, 4);
+        let cost = tree.metric().reset();
+        assert!(cost > 10);
+        for &budget in &[0, 1, 10, cost / 2, cost] {
+            let mut found = Vec::new();
+            let exhaustive =
+                tree.find_each_within(&needle, 4, budget as usize, |d, k| found.push((d, *k)));
+            assert!(tree.metric().reset() <= budget);
+            assert_eq!(budget == cost, exhaustive);
+            assert!(found.iter().all(|m| expected.contains(m)));
+            if exhaustive {
+                found.sort();
+                assert_eq!(expected, found);
+            }
+        }
+    }
+
+    #[test]
+    fn query_stats_count_the_search() {
+        use crate::metric::counting::CountingMetric;
+
+        let mut tree: BkInRamTree<U64Key, CountingMetric<HammingMetric<u64>>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        assert_eq!(
+            QueryStats::default(),
+            tree.find_each_with_stats(&0, 3, |_, _| ())
+        );
+        for i in 0..3000u64 {
+            tree.add(&(i * 0x10001)).unwrap();
+        }
+        let needle = (0x10001 * 1234) ^ 0b11;
+        tree.metric().reset();
+        let mut found = 0;
+        let stats = tree.find_each_with_stats(&needle, 4, |_, _| found += 1);
+        assert!(found > 0);
+        assert_eq!(tree.metric().reset(), stats.distance_computations as u64);
+        // Nothing stops early, so every node measured is visited.
+        assert_eq!(stats.distance_computations, stats.nodes_visited);
+        assert!(1 < stats.max_stack_depth && stats.max_stack_depth < stats.nodes_visited);
+
+        let (nearest, stats) = tree.find_knn_with_stats(&needle, 2, 64);
+        assert_eq!(tree.find_knn(&needle, 2, 64), nearest);
+        assert_eq!(
+            tree.metric().reset(),
+            2 * stats.distance_computations as u64
+        );
+        assert!(stats.nodes_visited <= stats.distance_computations);
+    }
+
+    #[test]
+    fn slack_finds_what_non_metrics_hide() {
+        /// Hamming distance, one further for about half of all pairs of keys, picked by a hash:
+        /// up to 1 past the triangle inequality.
+        #[derive(Default)]
+        struct NearHamming;
+        impl MetricTrait<u64> for NearHamming {
+            fn distance(&self, k1: &u64, k2: &u64) -> Dist {
+                let d = (k1 ^ k2).count_ones() as Dist;
+                d + (d > 0 && (k1 ^ k2).wrapping_mul(0x9e3779b97f4a7c15) >> 63 == 1) as Dist
+            }
+        }
+
+        let mut tree: BkInRamTree<U64Key, NearHamming, _> =
+            BkInRamTree::new(NearHamming, &U64_ALLOC);
+        let keys: Vec<u64> = (0..1000u64)
+            .map(|i| i.wrapping_mul(0x9e37) & 0xffff)
+            .collect();
+        for key in keys.iter() {
+            tree.add(key).unwrap();
+        }
+        let (mut strict, mut relaxed) = (0, 0);
+        let (mut strict_visits, mut relaxed_visits) = (0, 0);
+        let needles: Vec<u64> = keys.iter().take(50).map(|k| k ^ 0b1011).collect();
+        for needle in needles.iter() {
+            let mut scan: Vec<(Dist, u64)> = keys
+                .iter()
+                .map(|k| (NearHamming.distance(k, needle), *k))
+                .filter(|(d, _)| *d <= 3)
+                .collect();
+            scan.sort();
+            scan.dedup();
+            tree.find_each(needle, 3, |_, _| strict += 1);
+            let mut found = Vec::new();
+            tree.find_each_relaxed(needle, 3, 1, |d, k| found.push((d, *k)));
+            found.sort();
+            relaxed += found.len();
+            assert_eq!(scan, found);
+
+            strict_visits += BkFind::new(0, tree.root(), 3, needle)
+                .each_within::<U64Key, _, _>(tree.metric(), |_, _| ())
+                .stats
+                .nodes_visited;
+            relaxed_visits += BkFind::new(0, tree.root(), 3, needle)
+                .relaxed_by(1)
+                .each_within::<U64Key, _, _>(tree.metric(), |_, _| ())
+                .stats
+                .nodes_visited;
+        }
+        assert!(strict < relaxed);
+        // A slack of 1 searches wider, but still prunes: about 5/8 of the tree, to the strict
+        // search's 2/5.
+        assert!(strict_visits < relaxed_visits);
+        assert!(relaxed_visits < needles.len() * tree.len() * 7 / 10);
+    }
+
+    #[test]
+    fn max_depth_is_the_deepest_nodes() {
+        let mut tree = hamming_tree();
+        let mut map: BkInRamMap<U64Key, HammingMetric<u64>, ()> =
+            BkInRamMap::new(Default::default());
+        // 0b10 is 1 from 0b00, so goes under 0b01, 2 from it.
+        for (depth, key) in [0b00u64, 0b01, 0b10].iter().enumerate() {
+            tree.add(key).unwrap();
+            map.add(key, ());
+            assert_eq!(depth, tree.max_depth);
+            assert_eq!(depth, map.max_depth);
+            assert_eq!(Some(depth), tree.stats().max_depth());
+        }
+        assert_eq!(Ok(()), tree.validate());
+    }
+
+    #[test]
+    fn traversals_order_nodes_around_their_children() {
+        let mut tree = hamming_tree();
+        for key in &[0b0000u64, 0b0001, 0b0011, 0b0111, 0b0010, 0b0110, 0b1111] {
+            tree.add(key).unwrap();
+        }
+        // 0000 has children 0001 (1), 0011 (2), 0111 (3) and 1111 (4); 0001 has 0010 (2) and
+        // 0011 has 0110 (2).
+        type Visit<'v> = &'v mut dyn FnMut(Dist, usize, &u64);
+        let order = |f: &dyn Fn(Visit)| {
+            let mut keys = Vec::new();
+            f(&mut |_, _, k| keys.push(*k));
+            keys
+        };
+        assert_eq!(
+            vec![0b0000, 0b0001, 0b0010, 0b0011, 0b0110, 0b0111, 0b1111],
+            order(&|cb| tree.preorder_each(cb))
+        );
+        assert_eq!(
+            vec![0b0010, 0b0001, 0b0000, 0b0110, 0b0011, 0b0111, 0b1111],
+            order(&|cb| tree.inorder_each(cb))
+        );
+        assert_eq!(
+            vec![0b0010, 0b0001, 0b0110, 0b0011, 0b0111, 0b1111, 0b0000],
+            order(&|cb| tree.postorder_each(cb))
+        );
+
+        // Subtree sizes, bottom up.
+        let mut sizes: Vec<u64> = Vec::new();
+        tree.postorder_each(|_, children, _| {
+            let size = 1 + sizes.drain(sizes.len() - children..).sum::<u64>();
+            sizes.push(size);
+        });
+        assert_eq!(vec![7], sizes);
+
+        // Removed keys are skipped, and their children counted in their places.
+        tree.remove(&0b0001);
+        tree.remove(&0b0011);
+        assert_eq!(
+            vec![0b0010, 0b0110, 0b0111, 0b1111, 0b0000],
+            order(&|cb| tree.postorder_each(cb))
+        );
+        let mut children = Vec::new();
+        tree.postorder_each(|_, count, key| children.push((*key, count)));
+        assert_eq!((0b0000, 4), children[4]);
+        let mut children = Vec::new();
+        tree.inorder_each(|_, count, key| children.push((*key, count)));
+        assert_eq!(
+            vec![
+                (0b0010, 0),
+                (0b0000, 4),
+                (0b0110, 0),
+                (0b0111, 0),
+                (0b1111, 0)
+            ],
+            children
+        );
+        let mut sizes: Vec<u64> = Vec::new();
+        tree.postorder_each(|_, children, _| {
+            let size = 1 + sizes.drain(sizes.len() - children..).sum::<u64>();
+            sizes.push(size);
+        });
+        assert_eq!(vec![5], sizes);
+    }
+
+    #[test]
+    fn trees_collect_from_iterators() {
+        let (keys, added) = hashed_tree(500);
+        let collected: BkInRamTree<U64Key, HammingMetric<u64>, _> = keys.iter().cloned().collect();
+        assert_eq!(500, collected.node_count);
+        assert!(collected == added);
+
+        let mut extended = hamming_tree();
+        extended.extend(keys[..250].iter().cloned());
+        extended.extend(keys[250..].iter().cloned());
+        assert!(extended == added);
+    }
+
+    #[test]
+    fn merged_shards_hold_every_key() {
+        let (keys, whole) = hashed_tree(900);
+        let mut shards: Vec<BkInRamTree<U64Key, HammingMetric<u64>, _>> = keys
+            .chunks(300)
+            .map(|c| c.iter().cloned().collect())
+            .collect();
+        // Shards may overlap.
+        shards[2].add(&keys[0]).unwrap();
+
+        let mut merged = hamming_tree();
+        merged.absorb(shards.remove(0)).unwrap();
+        assert_eq!(300, merged.node_count);
+        for shard in shards.iter() {
+            merged.merge(shard).unwrap();
+        }
+        assert_eq!(900, merged.node_count);
+        assert!(merged == whole);
+        assert_eq!(
+            crate::stats::TreeStats::from_tree(&merged),
+            merged.quick_stats()
+        );
+
+        let mut again = hamming_tree();
+        again.merge(&merged).unwrap();
+        again.absorb(merged).unwrap();
+        assert!(again == whole);
+    }
+
+    #[test]
+    fn find_range_skips_close_keys() {
+        use crate::metric::counting::CountingMetric;
+
+        let mut tree: BkInRamTree<U64Key, CountingMetric<HammingMetric<u64>>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..5000u64 {
+            tree.add(&(i * 0x10001)).unwrap();
+        }
+        for &(min, max) in &[(0, 3), (1, 3), (4, 6), (9, 30)] {
+            tree.metric().reset();
+            let mut expected = Vec::new();
+            tree.find_each(&0x10001, max, |d, k| {
+                if d >= min {
+                    expected.push((d, *k))
+                }
+            });
+            let filtered = tree.metric().distances();
+            tree.metric().reset();
+            let mut found = Vec::new();
+            tree.find_range(&0x10001, min, max, |d, k| found.push((d, *k)));
+            expected.sort();
+            found.sort();
+            assert_eq!(expected, found);
+            assert!(tree.metric().distances() <= filtered);
+            if min > 8 {
+                // Far enough out that whole subtrees are too close.
+                assert!(tree.metric().distances() < filtered);
+            }
+        }
+        let mut found = Vec::new();
+        tree.find_range(&0x10001, 1, 2, |d, k| found.push((d, *k)));
+        assert!(found.contains(&(2, 0)));
+        assert!(found.iter().all(|(_, k)| *k != 0x10001));
+    }
+
+    #[test]
+    fn find_many_matches_one_search_per_needle() {
+        use crate::metric::counting::CountingMetric;
+
+        let mut tree: BkInRamTree<U64Key, CountingMetric<HammingMetric<u64>>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..3000u64 {
+            tree.add(&i.wrapping_mul(0x9e3779b97f4a7c15)).unwrap();
+        }
+        tree.remove(&0x9e3779b97f4a7c15);
+        let needles: Vec<u64> = (0..40u64).map(|i| i.wrapping_mul(0x5555_0001)).collect();
+        tree.metric().reset();
+        let mut expected = Vec::new();
+        for (i, needle) in needles.iter().enumerate() {
+            tree.find_each(needle, 20, |d, k| expected.push((i, d, *k)));
+        }
+        let separate = tree.metric().distances();
+        tree.metric().reset();
+        let mut found = Vec::new();
+        tree.find_many(&needles, 20, |i, d, k| found.push((i, d, *k)));
+        expected.sort();
+        found.sort();
+        assert!(!found.is_empty());
+        assert_eq!(expected, found);
+        assert_eq!(separate, tree.metric().distances());
+    }
+
+    #[test]
+    fn sorted_results_agree_across_backends() {
+        use crate::arena::{BkArenaAllocator, BkArenaTree};
+        use crate::bk::BkSparseInRamAllocator;
+        use crate::flat::BkFlatTree;
+        use crate::spill::BkSpillTree;
+
+        let (keys, mut in_ram) = hashed_tree(5000);
+        let sparse_alloc = BkSparseInRamAllocator::new();
+        let mut sparse: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &sparse_alloc);
+        let arena = BkArenaAllocator::new();
+        let mut arena_tree: BkArenaTree<U64Key, HammingMetric<u64>> =
+            BkArenaTree::new(Default::default(), &arena);
+        // Small enough that most of it ends up on disk.
+        let mut spilled = BkSpillTree::new(HammingMetric::default(), 16 << 10);
+        for key in keys.iter() {
+            arena_tree.add(key).unwrap();
+            spilled.add(key).unwrap();
+        }
+        for key in keys.iter().rev() {
+            sparse.add(key).unwrap();
+        }
+        let flat = BkFlatTree::from_tree(&sparse, HammingMetric::default()).unwrap();
+        assert!(spilled.spilled_len() > 2500);
+
+        for needle in &[0u64, keys[7] ^ 0b1001, 0x3c6ef372fe94f82a] {
+            let expected = in_ram.find_sorted(needle, 22);
+            assert!(expected.len() > 1);
+            assert!(expected.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!(expected, sparse.find_sorted(needle, 22));
+            assert_eq!(expected, arena_tree.find_sorted(needle, 22));
+            assert_eq!(expected, flat.find_sorted(needle, 22));
+            assert_eq!(expected, spilled.find_sorted(needle, 22));
+        }
+
+        // Copies are listed once each.
+        in_ram.add_unique(&keys[7]).unwrap();
+        let found = in_ram.find_sorted(&keys[7], 0);
+        assert_eq!(vec![(0, keys[7]), (0, keys[7])], found);
+    }
+
+    #[test]
+    fn deep_chain_is_stack_safe() {
+        use crate::bk::BkInRam;
+
+        // A pathological 100k deep chain: node i holds key i and node i + 1 in slot 1. Built by
+        // hand since sane inserts can't produce it.
+        const DEPTH: u64 = 100_000;
+        let mut chain = BkInRam::new(DEPTH - 1);
+        for key in (0..DEPTH - 1).rev() {
+            let mut parent = BkInRam::new(key);
+            parent.set_child_node(1, chain);
+            chain = parent;
+        }
+        let mut tree = hamming_tree();
+        tree.root = Some(chain);
+        tree.node_count = DEPTH;
+        tree.max_depth = DEPTH as usize;
+
+        let mut count = 0;
+        tree.preorder_each(|_, _, _| count += 1);
+        assert_eq!(DEPTH, count);
+
+        // Every key is within 64 bits of every other, so this walks the whole chain.
+        let mut found = 0;
+        tree.find_each(&0, 64, |_, _| found += 1);
+        assert_eq!(DEPTH, found);
+
+        // Dropping must not recurse either.
+        drop(tree);
+    }
 }
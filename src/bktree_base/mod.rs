@@ -1,7 +0,0 @@
-pub mod bktree;
-pub mod find;
-pub mod preorder;
-pub mod inbytes;
-
-pub use bktree::BkInRamTree;
-pub use bktree::BkTree;
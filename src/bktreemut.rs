@@ -1 +1,5 @@
-
+/*
+ * Kept so `use bkchainsaw::bktreemut::...` still compiles. Adding and removing keys are in
+ * `bktree`, with the other tree traits.
+ */
+pub use crate::bktree::{BkTreeAdd, BkTreeRemove, BkTreeRootMut};
@@ -0,0 +1,144 @@
+/*
+ * Bulk loading: the order keys go into a BK tree decides its shape, and sorted or clustered input
+ * (sequential ids, hashes from one source after another) builds deep, lopsided trees that are
+ * slow to search. `BkTreeAdd::bulk_build` reorders a batch before adding it.
+ *
+ *   let report = tree.bulk_build(keys, BuildOrder::Shuffled(seed))?;
+ *   println!("{} keys, {} deep", report.added, report.max_depth);
+ *
+ * Shuffles are seeded, so the same keys and seed always build the same tree.
+ */
+use std::collections::VecDeque;
+
+/// How `bulk_build` orders keys before adding them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildOrder {
+    /// Add them as they come, as a loop over `add` would.
+    AsGiven,
+    /// A random permutation, from this seed.
+    Shuffled(u64),
+    /// Sorted, then the median first, then the medians of each half, and so on: every prefix of
+    /// the order is spread across the whole range of keys.
+    MedianFirst,
+}
+
+/// What `bulk_build` made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuildReport {
+    /// Keys added, including any already in the tree.
+    pub added: u64,
+    /// The tree's depth afterwards, as its `max_depth`: 0 for a lone root.
+    pub max_depth: usize,
+}
+
+/// splitmix64: small, fast, and plenty random enough to break up sorted input.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Fisher-Yates.
+pub(crate) fn shuffle<K>(keys: &mut [K], seed: u64) {
+    let mut state = seed;
+    for i in (1..keys.len()).rev() {
+        let j = (next_random(&mut state) % (i as u64 + 1)) as usize;
+        keys.swap(i, j);
+    }
+}
+
+/// `keys`, sorted, in `BuildOrder::MedianFirst` order.
+pub(crate) fn median_first<K: Ord>(mut keys: Vec<K>) -> Vec<K> {
+    keys.sort();
+    let mut slots: Vec<Option<K>> = keys.into_iter().map(Some).collect();
+    let mut order = Vec::with_capacity(slots.len());
+    let mut ranges = VecDeque::new();
+    ranges.push_back((0, slots.len()));
+    while let Some((start, end)) = ranges.pop_front() {
+        if start < end {
+            let mid = start + (end - start) / 2;
+            order.extend(slots[mid].take());
+            ranges.push_back((start, mid));
+            ranges.push_back((mid + 1, end));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd, BkTreeRemove};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+    use crate::stats::TreeStats;
+    use crate::Dist;
+
+    #[test]
+    fn reordering_builds_shallower_trees() {
+        let build = |order| {
+            let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+                BkInRamTree::new(Default::default(), &U64_ALLOC);
+            let report = tree.bulk_build(0..20000u64, order).unwrap();
+            assert_eq!(20000, report.added);
+            assert_eq!(tree.max_depth, report.max_depth);
+            assert_eq!(20000, tree.node_count);
+            tree
+        };
+        let sequential = build(BuildOrder::AsGiven);
+        let shuffled = build(BuildOrder::Shuffled(7));
+        let median = build(BuildOrder::MedianFirst);
+        assert!(shuffled.max_depth < sequential.max_depth);
+        assert!(median.max_depth < sequential.max_depth);
+        assert!(shuffled == sequential && median == sequential);
+        assert_eq!(shuffled.max_depth, build(BuildOrder::Shuffled(7)).max_depth);
+    }
+
+    #[test]
+    fn rebuilds_undo_sequential_adds() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        // Keys one after another in a few low bits each side: a clustered, sorted batch.
+        for i in 0..20000u64 {
+            tree.add(&(i * 0x10001)).unwrap();
+        }
+        tree.remove(&(5 * 0x10001));
+        tree.set_flags(&(6 * 0x10001), 3);
+        // Keys already in the tree stay, whatever the admission policy now says.
+        tree.set_admission(|key| *key != 7 * 0x10001);
+        let depth = tree.max_depth;
+        let before: Vec<(Dist, u64, u8)> = {
+            let mut found = Vec::new();
+            tree.find_each_flagged(&0xff, 3, |d, k, f| found.push((d, *k, f)));
+            found.sort();
+            found
+        };
+
+        let report = tree.rebuild().unwrap();
+        assert_eq!(
+            (19999, 19999, 0),
+            (report.added, tree.node_count, tree.tombstone_count)
+        );
+        assert_eq!(tree.max_depth, report.max_depth);
+        assert!(tree.max_depth <= depth);
+        assert!(tree.contains(&(7 * 0x10001)));
+        assert_eq!(0, tree.rejected_count);
+        let mut after = Vec::new();
+        tree.find_each_flagged(&0xff, 3, |d, k, f| after.push((d, *k, f)));
+        after.sort();
+        assert_eq!(before, after);
+        assert_eq!(TreeStats::from_tree(&tree), tree.quick_stats());
+    }
+
+    #[test]
+    fn median_first_spreads_keys() {
+        assert_eq!(
+            vec![4, 2, 6, 1, 3, 5, 7],
+            median_first(vec![7, 6, 5, 4, 3, 2, 1])
+        );
+        assert!(median_first(Vec::<u8>::new()).is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Query result caching for trees that don't change, e.g. ones opened from a bkfile.
+ *
+ * Serving traffic repeats itself: the same trending hash can be looked up thousands of times a
+ * minute. A `QueryCache` remembers the results of each (needle, tolerance) it's asked about, up to
+ * a capacity and optionally for a limited time, and shares them between callers.
+ *
+ *   let cache = QueryCache::new(BkFileTree::open(path)?, 10_000).ttl(Duration::from_secs(60));
+ *   for (dist, key) in cache.find(&needle, 4).iter() { ... }
+ *
+ * Nothing invalidates entries when the tree changes, so only wrap trees that don't.
+ */
+use std::borrow::ToOwned;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::Dist;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for new ones.
+    pub evictions: u64,
+    /// Entries found too old to use.
+    pub expirations: u64,
+}
+
+struct Entry<K> {
+    results: Arc<Vec<(Dist, K)>>,
+    created: Instant,
+    last_used: u64,
+}
+
+struct State<O, K> {
+    entries: HashMap<Arc<(O, Dist)>, Entry<K>>,
+    /// Each use of an entry, oldest first, with its tick. A use is stale once its entry has been
+    /// used again or dropped; stale uses are skipped, and cleared out once they outnumber the
+    /// entries.
+    uses: VecDeque<(u64, Arc<(O, Dist)>)>,
+    /// Counts uses, to order entries by last use.
+    clock: u64,
+    stats: QueryCacheStats,
+}
+
+impl<O: Hash + Eq, K> State<O, K> {
+    /// Note that the entry for `key` was used, just now.
+    fn used(&mut self, key: Arc<(O, Dist)>) {
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_used = now;
+        }
+        self.uses.push_back((now, key));
+        if self.uses.len() > 2 * self.entries.len() + 16 {
+            let entries = &self.entries;
+            self.uses
+                .retain(|(tick, key)| entries.get(key).is_some_and(|e| e.last_used == *tick));
+        }
+    }
+
+    /// Drop the least recently used entry.
+    fn evict(&mut self) {
+        while let Some((tick, key)) = self.uses.pop_front() {
+            if self.entries.get(&key).is_some_and(|e| e.last_used == tick) {
+                self.entries.remove(&key);
+                self.stats.evictions += 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Wraps a tree to remember the results of the searches made through `find`.
+///
+/// Full caches drop their least recently used entry, in constant time. Safe to share between
+/// threads if the tree is; searches run without holding the cache's lock.
+pub struct QueryCache<T, Q: ?Sized + ToOwned, K> {
+    tree: T,
+    capacity: usize,
+    ttl: Option<Duration>,
+    state: Mutex<State<Q::Owned, K>>,
+}
+
+impl<T, Q, K> QueryCache<T, Q, K>
+where
+    K: Clone,
+    Q: ?Sized + ToOwned,
+    Q::Owned: Hash + Eq,
+    T: BkTree<K>,
+    T::KQ: KeyQuery<Query = Q>,
+{
+    /// Cache the results of up to `capacity` searches of `tree`.
+    pub fn new(tree: T, capacity: usize) -> Self {
+        QueryCache {
+            tree,
+            capacity: capacity.max(1),
+            ttl: None,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                uses: VecDeque::new(),
+                clock: 0,
+                stats: Default::default(),
+            }),
+        }
+    }
+
+    /// Only use results for `ttl` after they're computed.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn tree(&self) -> &T {
+        &self.tree
+    }
+
+    /// The matches `tree.find_each(needle, tolerance, ..)` reports, in the order it reports them.
+    pub fn find(&self, needle: &Q, tolerance: Dist) -> Arc<Vec<(Dist, K)>> {
+        let key = (needle.to_owned(), tolerance);
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some((shared, entry)) = state.entries.get_key_value(&key) {
+                if self.ttl.is_some_and(|ttl| entry.created.elapsed() >= ttl) {
+                    state.entries.remove(&key);
+                    state.stats.expirations += 1;
+                } else {
+                    let (shared, results) = (shared.clone(), entry.results.clone());
+                    state.used(shared);
+                    state.stats.hits += 1;
+                    return results;
+                }
+            }
+            state.stats.misses += 1;
+        }
+
+        let mut results = Vec::new();
+        self.tree.find_each(needle, tolerance, |dist, key| {
+            results.push((dist, key.clone()))
+        });
+        let results = Arc::new(results);
+
+        let mut state = self.state.lock().unwrap();
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+            state.evict();
+        }
+        let key = Arc::new(key);
+        state.entries.insert(
+            key.clone(),
+            Entry {
+                results: results.clone(),
+                created: Instant::now(),
+                last_used: 0,
+            },
+        );
+        state.used(key);
+        results
+    }
+
+    /// Forget every remembered result.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.uses.clear();
+    }
+
+    /// Number of searches remembered.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().entries.is_empty()
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+impl<T: Debug, Q: ?Sized + ToOwned, K> Debug for QueryCache<T, Q, K> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("QueryCache")
+            .field("tree", &self.tree)
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::StringKey;
+    use crate::metric::counting::CountingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn repeated_queries_skip_the_tree() {
+        let mut tree: BkInRamTree<StringKey, CountingMetric<LevenshteinMetric>, _> =
+            BkInRamTree::new(Default::default(), &STRING_ALLOC);
+        for word in &["book", "books", "cake", "boo", "cape", "boon", "cook"] {
+            tree.add(word).unwrap();
+        }
+        let cache = QueryCache::new(tree, 2);
+        cache.tree().metric().reset();
+
+        let first = cache.find("bool", 1);
+        let computed = cache.tree().metric().distances();
+        assert!(!first.is_empty());
+        assert_eq!(first, cache.find("bool", 1));
+        assert_eq!(computed, cache.tree().metric().distances());
+
+        // A different tolerance is a different search. Then "bool" 2 is the least recently
+        // used, so it makes way for "cap".
+        cache.find("bool", 2);
+        cache.find("bool", 1);
+        cache.find("cap", 1);
+        assert_eq!(2, cache.len());
+        cache.find("bool", 1);
+        assert_eq!(
+            QueryCacheStats {
+                hits: 3,
+                misses: 3,
+                evictions: 1,
+                expirations: 0
+            },
+            cache.stats()
+        );
+    }
+
+    #[test]
+    fn hits_keep_the_use_queue_short() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        tree.add("book").unwrap();
+        let cache = QueryCache::new(tree, 4);
+        for i in 0..1000 {
+            cache.find(["boo", "bok", "cook", "look", "took"][i % 5], 1);
+        }
+        assert!(cache.state.lock().unwrap().uses.len() <= 2 * 4 + 16);
+        // Cycling through one more search than fits evicts every time.
+        let stats = cache.stats();
+        assert_eq!((0, 1000, 996), (stats.hits, stats.misses, stats.evictions));
+        for _ in 0..100 {
+            cache.find("took", 1);
+        }
+        cache.find("boo", 1);
+        // The others were used less recently than "took".
+        assert!(cache
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&("took".to_string(), 1)));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        tree.add("book").unwrap();
+        let cache = QueryCache::new(tree, 10).ttl(Duration::from_secs(0));
+        cache.find("boo", 1);
+        assert_eq!(vec![(1, "book".to_string())], *cache.find("boo", 1));
+        let stats = cache.stats();
+        assert_eq!((0, 2, 1), (stats.hits, stats.misses, stats.expirations));
+    }
+}
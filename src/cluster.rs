@@ -0,0 +1,128 @@
+/*
+ * Connected components of keys, where two keys are connected if they are within a tolerance of
+ * each other. This is typically the final step of a dedup job: every cluster is one "thing".
+ */
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::vec::Vec;
+
+use crate::bktree::BkTree;
+use crate::join::self_join_within;
+use crate::Dist;
+
+/// Union-find over dense indices, with path halving and union by size.
+#[derive(Debug, Clone)]
+pub struct DisjointSets {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl DisjointSets {
+    pub fn new(n: usize) -> Self {
+        DisjointSets {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    pub fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    /// Merge the sets containing a and b. Returns false if they were already merged.
+    pub fn union(&mut self, a: usize, b: usize) -> bool {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.size[a] < self.size[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+        true
+    }
+}
+
+/// Cluster assignment for every key in a tree.
+#[derive(Debug, Clone)]
+pub struct Clusters<K> {
+    /// Keys in tree preorder.
+    pub keys: Vec<K>,
+    /// ids[i] is the cluster of keys[i]. Cluster ids are dense, numbered in order of first
+    /// appearance in keys.
+    pub ids: Vec<usize>,
+    /// Number of distinct clusters.
+    pub count: usize,
+}
+
+impl<K> Clusters<K> {
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &K)> {
+        self.ids.iter().cloned().zip(self.keys.iter())
+    }
+}
+
+/// Compute the connected components of the keys in `tree`, joining keys within `tolerance`.
+///
+/// Pairs are discovered with a self-join, which skips whole pairs of subtrees that can't be within
+/// tolerance rather than querying the tree once per key.
+pub fn clusters<K, T>(tree: &T, tolerance: Dist) -> Clusters<K>
+where
+    K: Clone + Hash + Eq,
+    T: BkTree<K>,
+{
+    let mut keys: Vec<K> = Vec::new();
+    let mut index: HashMap<K, usize> = HashMap::new();
+    tree.preorder_each(|_, _, key| {
+        index.insert(key.clone(), keys.len());
+        keys.push(key.clone());
+    });
+
+    let mut sets = DisjointSets::new(keys.len());
+    self_join_within(tree, tolerance, |_, a, b| {
+        sets.union(index[a], index[b]);
+    });
+
+    let mut dense: HashMap<usize, usize> = HashMap::new();
+    let mut ids = Vec::with_capacity(keys.len());
+    for i in 0..keys.len() {
+        let root = sets.find(i);
+        let next = dense.len();
+        ids.push(*dense.entry(root).or_insert(next));
+    }
+    Clusters {
+        keys,
+        ids,
+        count: dense.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn clusters_chain_transitively() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        // 0 - 1 - 3 - 7 is a chain of distance 1 hops; 0xff00 and 0xff01 are their own cluster.
+        for k in &[0u64, 0xff00, 7, 1, 0xff01, 3] {
+            tree.add(k).unwrap();
+        }
+        let clusters = clusters(&tree, 1);
+        assert_eq!(2, clusters.count);
+        let id_of = |k: u64| clusters.iter().find(|(_, key)| **key == k).unwrap().0;
+        assert_eq!(id_of(0), id_of(7));
+        assert_eq!(id_of(1), id_of(3));
+        assert_eq!(id_of(0xff00), id_of(0xff01));
+        assert_ne!(id_of(0), id_of(0xff00));
+    }
+}
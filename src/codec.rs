@@ -0,0 +1,508 @@
+/*
+ * Key codecs: how keys are stored in a bkfile's key section.
+ *
+ * The key section holds one key per node, in node order, each encoded by the codec the header
+ * names in "Key-Format". Node layouts don't depend on it, so a new key type only needs a codec:
+ *
+ *   let keys = codec::encode_keys(&Varint, tree_keys)?;     // builder
+ *   let keys: Vec<u64> = codec::decode_keys(&Varint, &section, node_count)?;    // reader
+ *
+ * Applications with their own key formats implement `KeyCodec` and `register` it under the name
+ * the header gives, which `u64_codec` then finds it by, for writers and readers alike.
+ * Composite keys (`keys::PairKey`) store each field with its own codec, one after the other:
+ *
+ *   let codec = Pair::new(FixedU64, FixedU32);      // "pair(fixed 64 bits, fixed 32 bits)"
+ *   bkfile::write_codec_tree(&tree, "pairs.bk", &Default::default(), &codec)?;
+ *   let tree = bkfile_tree::open_codec_tree("pairs.bk", metric, &codec)?;
+ *
+ * The "delta varint" format stores keys relative to other nodes' keys, so it's no codec: keys
+ * are varints, put through `to_deltas` on the way in and `from_deltas` on the way out.
+ */
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use crate::bkfile;
+
+pub trait KeyCodec: Debug + Send + Sync {
+    type Key;
+
+    /// What the header's "Key-Format" calls this codec.
+    fn name(&self) -> &str;
+    fn encode(&self, key: &Self::Key, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>>;
+    /// Decode the key at the start of `bytes`, returning it and how many bytes it took up.
+    fn decode(&self, bytes: &[u8]) -> Result<(Self::Key, usize), Box<dyn Error>>;
+}
+
+/// Fixed size little endian integers, 8 bytes per key: the original bkfile key format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedU64;
+
+impl KeyCodec for FixedU64 {
+    type Key = u64;
+
+    fn name(&self) -> &str {
+        "fixed 64 bits"
+    }
+
+    fn encode(&self, key: &u64, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        out.extend_from_slice(&key.to_le_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(u64, usize), Box<dyn Error>> {
+        if bytes.len() < 8 {
+            return Err("Truncated fixed 64 bit key".into());
+        }
+        let mut key = [0u8; 8];
+        key.copy_from_slice(&bytes[..8]);
+        Ok((u64::from_le_bytes(key), 8))
+    }
+}
+
+/// Fixed size little endian integers, 4 bytes per key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedU32;
+
+impl KeyCodec for FixedU32 {
+    type Key = u32;
+
+    fn name(&self) -> &str {
+        "fixed 32 bits"
+    }
+
+    fn encode(&self, key: &u32, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        out.extend_from_slice(&key.to_le_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(u32, usize), Box<dyn Error>> {
+        if bytes.len() < 4 {
+            return Err("Truncated fixed 32 bit key".into());
+        }
+        let mut key = [0u8; 4];
+        key.copy_from_slice(&bytes[..4]);
+        Ok((u32::from_le_bytes(key), 4))
+    }
+}
+
+/// Fixed size little endian integers of `bytes` bytes, for u64 keys that all fit in fewer than
+/// 8. Called "fixed N bits", so at 8 and 4 bytes it reads and writes `FixedU64` and `FixedU32`
+/// files.
+#[derive(Debug, Clone)]
+pub struct FixedWidth {
+    bytes: usize,
+    name: String,
+}
+
+impl FixedWidth {
+    /// `bytes` is between 1 and 8.
+    pub fn new(bytes: usize) -> Self {
+        assert!((1..=8).contains(&bytes), "FixedWidth keys are 1 to 8 bytes");
+        FixedWidth {
+            bytes,
+            name: format!("fixed {} bits", 8 * bytes),
+        }
+    }
+
+    /// The one called `name`, if any is.
+    pub fn named(name: &str) -> Option<Self> {
+        let bits: usize = name
+            .strip_prefix("fixed ")?
+            .strip_suffix(" bits")?
+            .parse()
+            .ok()?;
+        match bits {
+            8 | 16 | 24 | 32 | 40 | 48 | 56 | 64 => Some(Self::new(bits / 8)),
+            _ => None,
+        }
+    }
+
+    /// The narrowest that holds `max_key`.
+    pub fn fitting(max_key: u64) -> Self {
+        let bits = 64 - max_key.leading_zeros() as usize;
+        Self::new(bits.div_ceil(8).max(1))
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl KeyCodec for FixedWidth {
+    type Key = u64;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn encode(&self, key: &u64, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if self.bytes < 8 && *key >> (8 * self.bytes) != 0 {
+            return Err(format!("Key {} doesn't fit in {}", key, self.name).into());
+        }
+        out.extend_from_slice(&key.to_le_bytes()[..self.bytes]);
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(u64, usize), Box<dyn Error>> {
+        if bytes.len() < self.bytes {
+            return Err(format!("Truncated {} key", self.name).into());
+        }
+        let mut key = [0u8; 8];
+        key[..self.bytes].copy_from_slice(&bytes[..self.bytes]);
+        Ok((u64::from_le_bytes(key), self.bytes))
+    }
+}
+
+/// LEB128: 7 bits per byte, low bits first, with the top bit set on all but the last byte.
+/// Small keys, e.g. database IDs, take a byte or two.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Varint;
+
+impl KeyCodec for Varint {
+    type Key = u64;
+
+    fn name(&self) -> &str {
+        "varint"
+    }
+
+    fn encode(&self, key: &u64, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut rest = *key;
+        while rest >= 0x80 {
+            out.push((rest as u8 & 0x7f) | 0x80);
+            rest >>= 7;
+        }
+        out.push(rest as u8);
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(u64, usize), Box<dyn Error>> {
+        let mut key: u64 = 0;
+        for (i, byte) in bytes.iter().enumerate().take(10) {
+            let bits = (*byte & 0x7f) as u64;
+            if i == 9 && bits > 1 {
+                return Err("Varint key overflows 64 bits".into());
+            }
+            key |= bits << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok((key, i + 1));
+            }
+        }
+        Err("Truncated varint key".into())
+    }
+}
+
+/// Strings, each a varint byte length followed by that much UTF-8.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8;
+
+impl KeyCodec for Utf8 {
+    type Key = String;
+
+    fn name(&self) -> &str {
+        "utf-8"
+    }
+
+    fn encode(&self, key: &String, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Varint.encode(&(key.len() as u64), out)?;
+        out.extend_from_slice(key.as_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(String, usize), Box<dyn Error>> {
+        let (len, prefix) = Varint.decode(bytes)?;
+        let end = prefix
+            .checked_add(usize::try_from(len)?)
+            .filter(|&end| end <= bytes.len())
+            .ok_or("Truncated string key")?;
+        Ok((String::from_utf8(bytes[prefix..end].to_vec())?, end))
+    }
+}
+
+/// Pairs, the first field then the second, each in its own codec.
+#[derive(Debug, Clone)]
+pub struct Pair<A, B> {
+    pub first: A,
+    pub second: B,
+    name: String,
+}
+
+impl<A: KeyCodec, B: KeyCodec> Pair<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        let name = format!("pair({}, {})", first.name(), second.name());
+        Pair {
+            first,
+            second,
+            name,
+        }
+    }
+}
+
+impl<A: KeyCodec, B: KeyCodec> KeyCodec for Pair<A, B> {
+    type Key = (A::Key, B::Key);
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn encode(&self, key: &Self::Key, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.first.encode(&key.0, out)?;
+        self.second.encode(&key.1, out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(Self::Key, usize), Box<dyn Error>> {
+        let (first, used) = self.first.decode(bytes)?;
+        let (second, more) = self.second.decode(&bytes[used..])?;
+        Ok(((first, second), used + more))
+    }
+}
+
+/// Makes a codec for `register`.
+pub type CodecConstructor = fn() -> Box<dyn KeyCodec<Key = u64>>;
+
+/// Codecs added with `register`, looked up before the built in ones.
+static REGISTERED: RwLock<Vec<(&'static str, CodecConstructor)>> = RwLock::new(Vec::new());
+
+/// Make `name` a key format `u64_codec` knows, replacing any codec registered as `name` before.
+/// Files can only be written with codecs `u64_codec` knows by their names, so that they can be
+/// read back. The built in formats' names can't be taken, since files already use them.
+pub fn register(name: &'static str, constructor: CodecConstructor) -> Result<(), Box<dyn Error>> {
+    if name == "varint" || name == bkfile::DELTA_KEY_FORMAT || FixedWidth::named(name).is_some() {
+        return Err(format!("{:?} is a built in key format", name).into());
+    }
+    let mut registered = REGISTERED.write().unwrap();
+    registered.retain(|(known, _)| *known != name);
+    registered.push((name, constructor));
+    Ok(())
+}
+
+/// The codec for u64 keys called `name` in headers: a `register`ed one, or a built in one.
+pub fn u64_codec(name: &str) -> Option<Box<dyn KeyCodec<Key = u64>>> {
+    let registered = REGISTERED
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, constructor)| *constructor);
+    if let Some(constructor) = registered {
+        return Some(constructor());
+    }
+    match name {
+        "fixed 64 bits" => Some(Box::new(FixedU64)),
+        "varint" => Some(Box::new(Varint)),
+        _ => Some(Box::new(FixedWidth::named(name)?)),
+    }
+}
+
+/// Check that `u64_codec` knows `codec` by its name, for writers.
+pub fn check_registered(codec: &dyn KeyCodec<Key = u64>) -> Result<(), Box<dyn Error>> {
+    if u64_codec(codec.name()).is_none() {
+        return Err(format!(
+            "Key codec {:?} isn't registered: files it writes couldn't be read",
+            codec.name()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A key section holding `keys`, in order.
+pub fn encode_keys<'k, C, I>(codec: &C, keys: I) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    C: KeyCodec + ?Sized,
+    C::Key: 'k,
+    I: IntoIterator<Item = &'k C::Key>,
+{
+    let mut out = Vec::new();
+    for key in keys {
+        codec.encode(key, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Keys as differences from earlier keys, for the "delta varint" key format (see
+/// `bkfile::WriteOptions::sibling_deltas`): key i becomes its difference from key
+/// `references[i]`, zigzag encoded so that near keys either side are small numbers, or stays
+/// whole where that's None. Runs of dense keys become a byte or two each as varints.
+pub fn to_deltas(keys: &[u64], references: &[Option<usize>]) -> Vec<u64> {
+    keys.iter()
+        .enumerate()
+        .map(|(i, key)| match references.get(i).cloned().flatten() {
+            Some(reference) => {
+                let delta = key.wrapping_sub(keys[reference]) as i64;
+                ((delta << 1) ^ (delta >> 63)) as u64
+            }
+            None => *key,
+        })
+        .collect()
+}
+
+/// Undo `to_deltas`, in place. Keys may only refer to keys before them.
+pub fn from_deltas(keys: &mut [u64], references: &[Option<usize>]) -> Result<(), Box<dyn Error>> {
+    for i in 0..keys.len() {
+        if let Some(reference) = references.get(i).cloned().flatten() {
+            if reference >= i {
+                return Err(format!("Key {} refers to key {}, after it", i, reference).into());
+            }
+            keys[i] = from_delta(keys[reference], keys[i]);
+        }
+    }
+    Ok(())
+}
+
+/// The key stored as `delta` from `reference`'s key, for reading one key at a time.
+pub fn from_delta(reference: u64, delta: u64) -> u64 {
+    let delta = ((delta >> 1) as i64) ^ -((delta & 1) as i64);
+    reference.wrapping_add(delta as u64)
+}
+
+/// The first `count` keys of a key section.
+pub fn decode_keys<C>(
+    codec: &C,
+    mut bytes: &[u8],
+    count: usize,
+) -> Result<Vec<C::Key>, Box<dyn Error>>
+where
+    C: KeyCodec + ?Sized,
+{
+    let mut keys = Vec::with_capacity(count);
+    while keys.len() < count {
+        let (key, used) = codec.decode(bytes)?;
+        keys.push(key);
+        bytes = &bytes[used..];
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let ints = vec![0, 1, 127, 128, 300, u64::MAX];
+        for codec in &[
+            u64_codec("fixed 64 bits").unwrap(),
+            u64_codec("varint").unwrap(),
+        ] {
+            let bytes = encode_keys(&**codec, &ints).unwrap();
+            assert_eq!(ints, decode_keys(&**codec, &bytes, ints.len()).unwrap());
+            assert!(decode_keys(&**codec, &bytes[..bytes.len() - 1], ints.len()).is_err());
+        }
+        assert_eq!(vec![0xac, 0x02], encode_keys(&Varint, &[300]).unwrap());
+        assert!(Varint.decode(&[0xff; 10]).is_err());
+
+        let words = vec!["kitten".to_string(), String::new(), "naïve".to_string()];
+        let bytes = encode_keys(&Utf8, &words).unwrap();
+        assert_eq!(6 + 1 + 1 + 6 + 1, bytes.len());
+        assert_eq!(words, decode_keys(&Utf8, &bytes, 3).unwrap());
+        assert!(u64_codec("utf-8").is_none());
+        // A length too big to add to the prefix's.
+        let mut huge = vec![0xff; 9];
+        huge.push(0x01);
+        assert!(Utf8.decode(&huge).is_err());
+
+        let narrow = FixedWidth::fitting(300);
+        assert_eq!("fixed 16 bits", narrow.name());
+        let bytes = encode_keys(&narrow, &ints[..5]).unwrap();
+        assert_eq!(10, bytes.len());
+        let reader = u64_codec("fixed 16 bits").unwrap();
+        assert_eq!(ints[..5], decode_keys(&*reader, &bytes, 5).unwrap()[..]);
+        assert!(narrow.encode(&65536, &mut Vec::new()).is_err());
+        assert_eq!(1, FixedWidth::fitting(0).bytes());
+        assert!(u64_codec("fixed 12 bits").is_none());
+
+        let keys = vec![1000, 998, 1001, 1005, 3, u64::MAX];
+        let references = [None, Some(0), Some(1), Some(2), Some(0), Some(4)];
+        let deltas = to_deltas(&keys, &references);
+        assert_eq!(vec![1000, 3, 6, 8, 1993, 7], deltas);
+        let mut decoded = deltas.clone();
+        from_deltas(&mut decoded, &references).unwrap();
+        assert_eq!(keys, decoded);
+        assert!(from_deltas(&mut [1, 2], &[Some(1), None]).is_err());
+    }
+
+    #[test]
+    fn registered_codecs_write_and_read_files() {
+        use crate::bk::hashed_tree;
+        use crate::bkfile::{self, WriteOptions};
+        use crate::bkfile_tree::BkFileTree;
+        use crate::bktree::BkTree;
+
+        #[derive(Debug)]
+        struct BigEndian;
+
+        impl KeyCodec for BigEndian {
+            type Key = u64;
+
+            fn name(&self) -> &str {
+                "test big endian"
+            }
+
+            fn encode(&self, key: &u64, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+                out.extend_from_slice(&key.to_be_bytes());
+                Ok(())
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<(u64, usize), Box<dyn Error>> {
+                let mut key = [0u8; 8];
+                key.copy_from_slice(bytes.get(..8).ok_or("Truncated key")?);
+                Ok((u64::from_be_bytes(key), 8))
+            }
+        }
+
+        let (_, tree) = hashed_tree(100);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        let options = WriteOptions {
+            codec: Some(&BigEndian),
+            ..Default::default()
+        };
+        assert!(bkfile::write_tree(&tree, &path, &options).is_err());
+        register("test big endian", || Box::new(BigEndian)).unwrap();
+        for built_in in &["fixed 64 bits", "fixed 16 bits", "varint"] {
+            assert!(register(built_in, || Box::new(BigEndian)).is_err());
+        }
+        let descr = bkfile::write_tree(&tree, &path, &options).unwrap();
+        assert_eq!("test big endian", descr.key_format);
+        let read = BkFileTree::open(&path).unwrap();
+        assert_eq!(tree.find_sorted(&0, 30), read.find_sorted(&0, 30));
+    }
+
+    #[test]
+    fn pair_keys_rebuild_their_tree() {
+        use crate::bk::{BkInRamAllocator, BkInRamTree};
+        use crate::bktree::{BkTree, BkTreeAdd};
+        use crate::keys::PairKey;
+        use crate::metric::combinators::{ScaledMetric, SumMetric};
+        use crate::metric::hamming::HammingMetric;
+
+        let alloc: BkInRamAllocator<(u64, u32)> = BkInRamAllocator::new();
+        let metric = || {
+            SumMetric::new(
+                HammingMetric::<u64>::default(),
+                ScaledMetric::new(HammingMetric::<u32>::default(), 2),
+            )
+        };
+        let mut tree: BkInRamTree<PairKey<u64, u32>, _, _> = BkInRamTree::new(metric(), &alloc);
+        for i in 0..100u64 {
+            tree.add(&(i.wrapping_mul(0x9e3779b97f4a7c15), i as u32 * 7))
+                .unwrap();
+        }
+        let mut keys = Vec::new();
+        tree.preorder_each(|_, _, key| keys.push(*key));
+
+        let codec = Pair::new(FixedU64, FixedU32);
+        assert_eq!("pair(fixed 64 bits, fixed 32 bits)", codec.name());
+        let bytes = encode_keys(&codec, &keys).unwrap();
+        assert_eq!(12 * keys.len(), bytes.len());
+        let mut rebuilt: BkInRamTree<PairKey<u64, u32>, _, _> = BkInRamTree::new(metric(), &alloc);
+        for key in decode_keys(&codec, &bytes, keys.len()).unwrap() {
+            rebuilt.add(&key).unwrap();
+        }
+        assert!(tree == rebuilt);
+        let needle = (keys[10].0 ^ 1, keys[10].1 ^ 1);
+        assert_eq!(vec![(3, keys[10])], rebuilt.find_knn(&needle, 1, 3));
+    }
+}
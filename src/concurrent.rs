@@ -0,0 +1,288 @@
+/*
+ * A tree one thread adds to while others search it, e.g. an ingestion thread feeding a service.
+ *
+ *   let mut tree = ConcurrentBkTree::new(HammingMetric::default(), 1024);
+ *   let reader = tree.reader();
+ *   thread::spawn(move || reader.snapshot().find_each(&needle, 4, |dist, key| ...));
+ *   tree.add(&key)?;
+ *   tree.publish()?;
+ *
+ * Readers search snapshots: immutable trees behind an `Arc`, which the writer swaps for newer
+ * ones and which stay searchable for as long as anyone holds them. Neither side waits on the
+ * other beyond the swap of one pointer.
+ *
+ * As in `lsm::LsmForest`, keys go into a buffer tree, and full buffers are frozen and merged
+ * into levels of doubling size. Merges build new trees rather than changing ones a snapshot may
+ * hold. Keys are visible to readers once their buffer freezes, or after a `publish`, which
+ * copies the buffer.
+ */
+use std::error::Error;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use crate::bk::{BkInRamAllocator, BkInRamTree};
+use crate::bktree::{BkTree, BkTreeAdd};
+use crate::forest::merge_knn;
+use crate::keyquery::KeyQuery;
+use crate::lsm::{add_all, Tree};
+use crate::metric::Metric;
+use crate::Dist;
+
+/// The trees as of one `publish`.
+pub struct Snapshot<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    trees: Vec<Arc<Tree<KQ, M>>>,
+}
+
+impl<K, KQ, M> Snapshot<KQ, M>
+where
+    K: 'static + Clone,
+    KQ: KeyQuery<Key = K>,
+    M: Metric<KQ::Query>,
+{
+    pub fn len(&self) -> usize {
+        self.trees.iter().map(|tree| tree.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.iter().all(|tree| tree.is_empty())
+    }
+
+    pub fn contains(&self, key: &KQ::Query) -> bool {
+        self.trees.iter().any(|tree| tree.contains(key))
+    }
+
+    pub fn find_each<F>(&self, needle: &KQ::Query, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        for tree in &self.trees {
+            tree.find_each(needle, tolerance, &mut callback);
+        }
+    }
+
+    /// The `k` keys nearest to `needle`, no further than `max_distance`, sorted by distance and
+    /// then by key.
+    pub fn find_knn(&self, needle: &KQ::Query, k: usize, max_distance: Dist) -> Vec<(Dist, K)>
+    where
+        K: Ord,
+    {
+        merge_knn(
+            self.trees
+                .iter()
+                .map(|tree| tree.find_knn(needle, k, max_distance))
+                .collect(),
+            k,
+        )
+    }
+}
+
+type Published<KQ, M> = Arc<RwLock<Arc<Snapshot<KQ, M>>>>;
+
+/// The writing side: the only one that adds keys.
+pub struct ConcurrentBkTree<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    metric: M,
+    buffer_keys: usize,
+    buffer: Tree<KQ, M>,
+    /// Level i holds up to `buffer_keys << i` keys.
+    levels: Vec<Option<Arc<Tree<KQ, M>>>>,
+    published: Published<KQ, M>,
+}
+
+/// A searching side, for as many threads as want one.
+pub struct BkTreeReader<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    published: Published<KQ, M>,
+}
+
+impl<KQ, M> Clone for BkTreeReader<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    fn clone(&self) -> Self {
+        BkTreeReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+}
+
+impl<KQ, M> BkTreeReader<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    /// The latest published trees. Later writes don't change it.
+    pub fn snapshot(&self) -> Arc<Snapshot<KQ, M>> {
+        Arc::clone(&self.published.read().unwrap())
+    }
+}
+
+impl<K, KQ, M> ConcurrentBkTree<KQ, M>
+where
+    K: 'static + Clone,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<KQ::Query> + Clone,
+{
+    /// Freeze the buffer once it has `buffer_keys` keys.
+    pub fn new(metric: M, buffer_keys: usize) -> Self {
+        ConcurrentBkTree {
+            buffer: BkInRamTree::new(metric.clone(), &BkInRamAllocator::SHARED),
+            metric,
+            buffer_keys: buffer_keys.max(1),
+            levels: Vec::new(),
+            published: Arc::new(RwLock::new(Arc::new(Snapshot { trees: Vec::new() }))),
+        }
+    }
+
+    pub fn reader(&self) -> BkTreeReader<KQ, M> {
+        BkTreeReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+
+    fn new_tree(&self) -> Tree<KQ, M> {
+        BkInRamTree::new(self.metric.clone(), &BkInRamAllocator::SHARED)
+    }
+
+    /// Add `key`, unless it's there already. Readers see it once its buffer freezes, or after
+    /// the next `publish`.
+    pub fn add(&mut self, key: &KQ::Query) -> Result<(), Box<dyn Error>> {
+        if self.buffer.contains(key) || self.levels.iter().flatten().any(|t| t.contains(key)) {
+            return Ok(());
+        }
+        self.buffer.add(key)?;
+        if self.buffer.len() >= self.buffer_keys {
+            let fresh = self.new_tree();
+            let full = mem::replace(&mut self.buffer, fresh);
+            self.freeze(Arc::new(full))?;
+            self.swap(Vec::new());
+        }
+        Ok(())
+    }
+
+    /// Merge `tree` into the levels, carrying up through the full ones.
+    fn freeze(&mut self, mut tree: Arc<Tree<KQ, M>>) -> Result<(), Box<dyn Error>> {
+        for i in 0..self.levels.len() {
+            match self.levels[i].take() {
+                None => {
+                    self.levels[i] = Some(tree);
+                    return Ok(());
+                }
+                Some(full) => {
+                    // Snapshots may still be searching `full`, so the merge is a new tree.
+                    let mut merged = self.new_tree();
+                    add_all(&mut merged, &full)?;
+                    add_all(&mut merged, &tree)?;
+                    tree = Arc::new(merged);
+                }
+            }
+        }
+        self.levels.push(Some(tree));
+        Ok(())
+    }
+
+    /// Make every key added so far visible to readers. Copies the buffer, so publishing after
+    /// every add costs up to `buffer_keys` adds each time.
+    pub fn publish(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut copy = self.new_tree();
+        add_all(&mut copy, &self.buffer)?;
+        self.swap(vec![Arc::new(copy)]);
+        Ok(())
+    }
+
+    /// Publish the levels and `extra`.
+    fn swap(&self, mut extra: Vec<Arc<Tree<KQ, M>>>) {
+        extra.extend(self.levels.iter().flatten().cloned());
+        extra.retain(|tree| !tree.is_empty());
+        *self.published.write().unwrap() = Arc::new(Snapshot { trees: extra });
+    }
+
+    /// Keys added, published or not.
+    pub fn len(&self) -> usize {
+        self.buffer.len() + self.levels.iter().flatten().map(|t| t.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+    use std::thread;
+
+    #[test]
+    fn readers_search_snapshots_while_keys_are_added() {
+        let keys: Vec<u64> = (0..1000u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut tree: ConcurrentBkTree<U64Key, _> =
+            ConcurrentBkTree::new(HammingMetric::default(), 64);
+        let reader = tree.reader();
+        tree.add(&keys[0]).unwrap();
+        let before = reader.snapshot();
+        tree.publish().unwrap();
+        assert!(before.is_empty());
+        assert!(reader.snapshot().contains(&keys[0]));
+
+        thread::scope(|scope| {
+            let searchers: Vec<_> = (0..3)
+                .map(|_| {
+                    let reader = reader.clone();
+                    scope.spawn(move || {
+                        // Snapshots only grow, and each is complete as published.
+                        let mut seen = 0;
+                        while seen < 1000 {
+                            let snapshot = reader.snapshot();
+                            let len = snapshot.len();
+                            assert!(len >= seen);
+                            let mut found = 0;
+                            snapshot.find_each(&0, 64, |_, _| found += 1);
+                            assert_eq!(len, found);
+                            seen = len;
+                            thread::yield_now();
+                        }
+                    })
+                })
+                .collect();
+            for key in &keys {
+                tree.add(key).unwrap();
+            }
+            tree.publish().unwrap();
+            for searcher in searchers {
+                searcher.join().unwrap();
+            }
+        });
+
+        let snapshot = reader.snapshot();
+        let mut all: Tree<U64Key, _> =
+            BkInRamTree::new(HammingMetric::default(), &BkInRamAllocator::SHARED);
+        for key in &keys {
+            all.add(key).unwrap();
+        }
+        let mut found = Vec::new();
+        snapshot.find_each(&(keys[10] ^ 0b11), 20, |dist, key| found.push((dist, *key)));
+        found.sort();
+        assert_eq!(all.find_sorted(&(keys[10] ^ 0b11), 20), found);
+        assert_eq!((1000, 1000), (tree.len(), snapshot.len()));
+    }
+}
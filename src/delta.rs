@@ -0,0 +1,380 @@
+/*
+ * Delta files: cheap updates to a bkfile that's too big to rebuild for every new key.
+ *
+ * A delta is a bkfile of keys the base file doesn't have, whose "Delta-Of" metadata is the base
+ * file's checksum. An `Overlay` opens a base with its deltas and searches them as one tree:
+ *
+ *   let overlay = Overlay::open("hashes.bk", &["monday.bk"])?;
+ *   overlay.write_delta(todays_hashes, "tuesday.bk")?;
+ *   let overlay = Overlay::open("hashes.bk", &["monday.bk", "tuesday.bk"])?;
+ *   overlay.find_each(&needle, 4, |dist, key| ...);
+ *
+ * Searches cost a search per file, so once there are a few deltas, `compact` merges everything
+ * into a new base (see the bkcompact binary). Deltas only add keys; they can't remove them.
+ *
+ * Servers taking new keys as they run keep them in RAM over the file instead, in an
+ * `OverlayTree`, and write them out as a delta now and then:
+ *
+ *   let mut tree = OverlayTree::new(BkFileTree::open("hashes.bk")?);
+ *   tree.add(&todays_hash)?;
+ *   tree.find_knn(&needle, 10, 8);
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::Path;
+
+use crate::bk::{BkInRamAllocator, BkInRamTree, FanOut, U64_ALLOC};
+use crate::bkfile::{self, FileDescrHeader, WriteOptions};
+use crate::bkfile_tree::{BkFile, BkFileTree, FileNode};
+use crate::bktree::{BkTree, BkTreeAdd};
+use crate::forest::merge_knn;
+use crate::keys::U64Key;
+use crate::metric::hamming::HammingMetric;
+use crate::stats::QueryStats;
+use crate::Dist;
+
+type InRamTree = BkInRamTree<'static, U64Key, HammingMetric<u64>, BkInRamAllocator<'static, u64>>;
+
+/// The metadata naming the file a delta adds to, by its checksum.
+pub const DELTA_OF: &str = "Delta-Of";
+
+/// A base bkfile and the deltas on top of it, searched as one tree.
+pub struct Overlay {
+    checksum: String,
+    /// The base's metadata, for `compact` to keep.
+    metadata: BTreeMap<String, String>,
+    /// The base's trees first, then each delta's.
+    trees: Vec<BkFileTree>,
+}
+
+impl Overlay {
+    /// Open `base` and `deltas`, checking each delta was written for this base.
+    pub fn open<P, D>(base: P, deltas: &[D]) -> Result<Self, Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+        D: AsRef<Path>,
+    {
+        let file = BkFile::open(base, true)?;
+        let checksum = file.checksum();
+        let metadata = file.descr().metadata.clone();
+        let mut trees = file.trees()?;
+        for path in deltas {
+            let delta = BkFile::open(path, true)?;
+            if delta.descr().metadata.get(DELTA_OF) != Some(&checksum) {
+                return Err(format!("{:?} is not a delta of this base", path.as_ref()).into());
+            }
+            trees.extend(delta.trees()?);
+        }
+        Ok(Overlay {
+            checksum,
+            metadata,
+            trees,
+        })
+    }
+
+    pub fn find_each<F>(&self, needle: &u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64),
+    {
+        for tree in &self.trees {
+            tree.find_each(needle, tolerance, &mut callback);
+        }
+    }
+
+    pub fn contains(&self, key: &u64) -> bool {
+        self.trees.iter().any(|tree| tree.contains(key))
+    }
+
+    /// The payload stored with `key` in whichever file has it.
+    pub fn value(&self, key: &u64) -> Option<&[u8]> {
+        self.trees.iter().find_map(|tree| tree.value(key))
+    }
+
+    /// Write those of `keys` that aren't here already to `path`, as a delta of this base.
+    pub fn write_delta<I, P>(&self, keys: I, path: P) -> Result<FileDescrHeader, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = u64>,
+        P: AsRef<Path>,
+    {
+        let mut tree: InRamTree = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+        for key in keys {
+            if !self.contains(&key) {
+                tree.add(&key)?;
+            }
+        }
+        let mut metadata = BTreeMap::new();
+        metadata.insert(DELTA_OF.to_string(), self.checksum.clone());
+        let options = WriteOptions {
+            metadata,
+            ..Default::default()
+        };
+        bkfile::write_tree(&tree, path, &options)
+    }
+
+    /// Merge the base and every delta into a single tree bkfile at `path`, with their flags and
+    /// values, and the base's metadata.
+    pub fn compact<P: AsRef<Path>>(&self, path: P) -> Result<FileDescrHeader, Box<dyn Error>> {
+        let mut merged: InRamTree = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+        let mut values = HashMap::new();
+        let mut added = Ok(());
+        for tree in &self.trees {
+            tree.each_entry(|key, flags, value| {
+                if added.is_ok() {
+                    added = merged.add(key);
+                }
+                if flags != 0 {
+                    merged.set_flags(key, flags);
+                }
+                if let Some(value) = value {
+                    values.insert(*key, value.to_vec());
+                }
+            });
+        }
+        added?;
+        let options = WriteOptions {
+            metadata: self.metadata.clone(),
+            values: Some(&values),
+            ..Default::default()
+        };
+        bkfile::write_tree(&merged, path, &options)
+    }
+}
+
+/// A file-backed tree with the keys added since it was opened in RAM on top, searched as one.
+/// Its keys are in two trees with no root over both, so its root is the file's: the searches,
+/// lookups and counts here cover both trees, while what walks down from the root (`stats`,
+/// `validate`, `find_range` and the like) sees only the file.
+pub struct OverlayTree {
+    base: BkFileTree,
+    delta: InRamTree,
+}
+
+impl OverlayTree {
+    pub fn new(base: BkFileTree) -> Self {
+        OverlayTree {
+            base,
+            delta: BkInRamTree::new(HammingMetric::default(), &U64_ALLOC),
+        }
+    }
+
+    /// Add `key` to the RAM delta, unless the file has it already.
+    pub fn add(&mut self, key: &u64) -> Result<(), Box<dyn Error>> {
+        if self.base.contains(key) {
+            return Ok(());
+        }
+        self.delta.add(key)
+    }
+
+    pub fn base(&self) -> &BkFileTree {
+        &self.base
+    }
+
+    /// The keys added since the file was opened.
+    pub fn delta(&self) -> &InRamTree {
+        &self.delta
+    }
+
+    pub fn into_parts(self) -> (BkFileTree, InRamTree) {
+        (self.base, self.delta)
+    }
+}
+
+impl BkTree<u64> for OverlayTree {
+    type KQ = U64Key;
+    type Metric = HammingMetric<u64>;
+    type Node = FileNode;
+
+    fn root(&self) -> Option<&FileNode> {
+        self.base.root()
+    }
+
+    fn metric(&self) -> &HammingMetric<u64> {
+        self.base.metric()
+    }
+
+    fn len(&self) -> usize {
+        self.base.len() + self.delta.len()
+    }
+
+    /// The file's matches, then the RAM delta's.
+    fn find_each<'a, F>(&'a self, needle: &'a u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64),
+    {
+        self.base.find_each(needle, tolerance, &mut callback);
+        self.delta.find_each(needle, tolerance, &mut callback);
+    }
+
+    fn find_each_flagged<'a, F>(&'a self, needle: &'a u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64, u8),
+    {
+        self.base
+            .find_each_flagged(needle, tolerance, &mut callback);
+        self.delta
+            .find_each_flagged(needle, tolerance, &mut callback);
+    }
+
+    /// The file's keys, then the RAM delta's.
+    fn preorder_each<F>(&self, mut callback: F)
+    where
+        F: FnMut(Dist, usize, &u64),
+    {
+        self.base.preorder_each(&mut callback);
+        self.delta.preorder_each(&mut callback);
+    }
+
+    fn get(&self, key: &u64) -> Option<&u64> {
+        self.base.get(key).or_else(|| self.delta.get(key))
+    }
+
+    fn contains(&self, key: &u64) -> bool {
+        self.base.contains(key) || self.delta.contains(key)
+    }
+
+    /// Each tree's `k` nearest, merged, so ties at the k-th distance go to the smaller key.
+    fn find_knn_ordered(
+        &self,
+        needle: &u64,
+        k: usize,
+        max_distance: Dist,
+        fan_out: FanOut,
+    ) -> Vec<(Dist, u64)> {
+        let found = vec![
+            self.base.find_knn_ordered(needle, k, max_distance, fan_out),
+            self.delta
+                .find_knn_ordered(needle, k, max_distance, fan_out),
+        ];
+        merge_knn(found, k)
+    }
+
+    /// `find_knn`, also returning what the searches of both trees cost.
+    fn find_knn_with_stats(
+        &self,
+        needle: &u64,
+        k: usize,
+        max_distance: Dist,
+    ) -> (Vec<(Dist, u64)>, QueryStats) {
+        let (found, mut stats) = self.base.find_knn_with_stats(needle, k, max_distance);
+        let (more, more_stats) = self.delta.find_knn_with_stats(needle, k, max_distance);
+        stats.nodes_visited += more_stats.nodes_visited;
+        stats.distance_computations += more_stats.distance_computations;
+        stats.max_stack_depth = stats.max_stack_depth.max(more_stats.max_stack_depth);
+        (merge_knn(vec![found, more], k), stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_overlay_and_compact() {
+        let keys: Vec<u64> = (0..300u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut base: InRamTree = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+        for key in &keys[..200] {
+            base.add(key).unwrap();
+        }
+        base.set_flags(&keys[10], 2);
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let write_base = |name: &str, source: &str| {
+            let mut metadata = BTreeMap::new();
+            metadata.insert("Source".to_string(), source.to_string());
+            let options = WriteOptions {
+                metadata,
+                ..Default::default()
+            };
+            bkfile::write_tree(&base, path(name), &options).unwrap();
+        };
+        write_base("base.bk", "here");
+        write_base("other.bk", "elsewhere");
+
+        let no_deltas: &[&Path] = &[];
+        let overlay = Overlay::open(path("base.bk"), no_deltas).unwrap();
+        let descr = overlay
+            .write_delta(keys[150..250].iter().cloned(), path("one.bk"))
+            .unwrap();
+        assert_eq!(50, descr.node_count);
+        let overlay = Overlay::open(path("base.bk"), &[path("one.bk")]).unwrap();
+        overlay
+            .write_delta(keys[200..].iter().cloned(), path("two.bk"))
+            .unwrap();
+        let overlay = Overlay::open(path("base.bk"), &[path("one.bk"), path("two.bk")]).unwrap();
+        assert!(keys.iter().all(|key| overlay.contains(key)));
+        let mut found = Vec::new();
+        overlay.find_each(&keys[260], 0, |_, key| found.push(*key));
+        assert_eq!(vec![keys[260]], found);
+
+        // Deltas of one base don't go on another, even with the same keys.
+        assert!(Overlay::open(path("other.bk"), &[path("one.bk")]).is_err());
+
+        overlay.compact(path("compact.bk")).unwrap();
+        let file = BkFile::open(path("compact.bk"), true).unwrap();
+        assert_eq!(
+            Some("here"),
+            file.descr().metadata.get("Source").map(|s| &s[..])
+        );
+        let compact = BkFileTree::open(path("compact.bk")).unwrap();
+        assert_eq!(300, compact.node_count());
+        let mut flagged = Vec::new();
+        compact.find_each_flagged(&keys[10], 0, |_, key, flags| flagged.push((*key, flags)));
+        assert_eq!(vec![(keys[10], 2)], flagged);
+    }
+
+    #[test]
+    fn overlay_tree_searches_file_and_ram() {
+        let keys: Vec<u64> = (0..400u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut all: InRamTree = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+        for key in &keys[..300] {
+            all.add(key).unwrap();
+        }
+        all.set_flags(&keys[20], 3);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("base.bk");
+        bkfile::write_tree(&all, &path, &Default::default()).unwrap();
+
+        let mut tree = OverlayTree::new(BkFileTree::open(&path).unwrap());
+        for key in &keys[250..] {
+            tree.add(key).unwrap();
+            all.add(key).unwrap();
+        }
+        assert_eq!((400, 100), (tree.len(), tree.delta().len()));
+        assert!(!tree.is_empty());
+        assert_eq!(Some(&keys[350]), tree.get(&keys[350]));
+        assert!(tree.contains(&keys[10]) && tree.contains(&keys[350]));
+        assert!(!tree.contains(&!keys[10]));
+        // The trait's own searches go through both trees too.
+        assert_eq!(Some((0, keys[350])), tree.find_nearest(&keys[350]));
+        assert_eq!(all.find_sorted(&0, 64), tree.find_sorted(&0, 64));
+        let mut flagged = Vec::new();
+        tree.find_each_flagged(&keys[20], 64, |_, key, flags| {
+            if flags != 0 {
+                flagged.push((*key, flags));
+            }
+        });
+        assert_eq!(vec![(keys[20], 3)], flagged);
+
+        for needle in &[keys[5] ^ 0b11, keys[390] ^ 0b101, 0] {
+            let mut found = Vec::new();
+            tree.find_each(needle, 20, |dist, key| found.push((dist, *key)));
+            found.sort();
+            assert_eq!(all.find_sorted(needle, 20), found);
+            assert_eq!(all.find_sorted(needle, 20), tree.find_sorted(needle, 20));
+            // Ties at the k-th distance may go either way.
+            let dists =
+                |found: Vec<(Dist, u64)>| -> Vec<Dist> { found.iter().map(|f| f.0).collect() };
+            let expected = dists(all.find_knn(needle, 7, 64));
+            assert_eq!(expected, dists(tree.find_knn(needle, 7, 64)));
+            let by_slot = tree.find_knn_ordered(needle, 7, 64, |_, slot| slot);
+            assert_eq!(expected, dists(by_slot));
+            let (found, stats) = tree.find_knn_with_stats(needle, 7, 64);
+            assert_eq!(expected, dists(found));
+            assert!(stats.nodes_visited > 0);
+        }
+    }
+}
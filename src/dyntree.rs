@@ -0,0 +1,313 @@
+/*
+ * Trees searched without knowing their key type at compile time, e.g. whatever bkfile a user
+ * points a tool at:
+ *
+ *   let tree = bkfile::open_any("hashes.bk")?;
+ *   tree.find_each_bytes(&needle.to_le_bytes(), 4, &mut |dist, key| ...);
+ *
+ * Needles and keys go in and come out as bytes, encoded as `key_type` says. Trees of any
+ * `ByteKeyQuery` keys join them behind the same pointer by way of `DynTree`, e.g. for a plugin
+ * that registers indexes of its own:
+ *
+ *   let trees: Vec<Box<dyn DynBkTree>> = vec![
+ *       bkfile::open_any("hashes.bk")?,
+ *       Box::new(DynTree::new(words, "levenshtein")),
+ *   ];
+ */
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+use crate::bk::{BkInRamAllocator, BkInRamTree};
+use crate::bkfile_tree::BkFileTree;
+use crate::bktree::BkTree;
+use crate::forest::BkForest;
+use crate::keyquery::KeyQuery;
+use crate::keys::{ArrayKey, BytesKey, StringKey, U64Key};
+use crate::metric::dynamic::DynMetric;
+use crate::Dist;
+
+/// An object safe view of a tree.
+pub trait DynBkTree: Send + Sync {
+    /// How keys are encoded as bytes: "u64" for 8 little endian bytes, "string" for UTF-8, or
+    /// "bytes" for keys that are byte strings already (see `ByteKeyQuery`).
+    fn key_type(&self) -> &str;
+
+    /// The name `metric::dynamic::by_name` knows the tree's metric by.
+    fn metric_name(&self) -> &str;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `BkTree::find_each`, with the needle and the keys found encoded as `key_type` says.
+    /// Needles that aren't the encoding of any key find nothing.
+    fn find_each_bytes(
+        &self,
+        needle: &[u8],
+        tolerance: Dist,
+        callback: &mut dyn FnMut(Dist, &[u8]),
+    );
+}
+
+/// Key types whose keys and queries have a byte encoding, to pass through `DynBkTree`.
+pub trait ByteKeyQuery: KeyQuery {
+    /// What `DynBkTree::key_type` says of trees of these keys.
+    const KEY_TYPE: &'static str;
+
+    /// Call `f` with the query `bytes` encode, or return None if they encode none.
+    fn with_query<R, F: FnOnce(&Self::Query) -> R>(bytes: &[u8], f: F) -> Option<R>;
+
+    /// Call `f` with the encoding of `key`.
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(key: &Self::Key, f: F) -> R;
+}
+
+impl ByteKeyQuery for U64Key {
+    const KEY_TYPE: &'static str = "u64";
+
+    /// Little endian, with any high zero bytes left off.
+    fn with_query<R, F: FnOnce(&u64) -> R>(bytes: &[u8], f: F) -> Option<R> {
+        u64_needle(bytes).map(|needle| f(&needle))
+    }
+
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(key: &u64, f: F) -> R {
+        f(&key.to_le_bytes())
+    }
+}
+
+impl ByteKeyQuery for StringKey {
+    const KEY_TYPE: &'static str = "string";
+
+    /// UTF-8.
+    fn with_query<R, F: FnOnce(&str) -> R>(bytes: &[u8], f: F) -> Option<R> {
+        std::str::from_utf8(bytes).ok().map(f)
+    }
+
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(key: &String, f: F) -> R {
+        f(key.as_bytes())
+    }
+}
+
+impl ByteKeyQuery for BytesKey {
+    const KEY_TYPE: &'static str = "bytes";
+
+    fn with_query<R, F: FnOnce(&[u8]) -> R>(bytes: &[u8], f: F) -> Option<R> {
+        Some(f(bytes))
+    }
+
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(key: &Vec<u8>, f: F) -> R {
+        f(key)
+    }
+}
+
+impl<const N: usize> ByteKeyQuery for ArrayKey<N> {
+    const KEY_TYPE: &'static str = "bytes";
+
+    /// Exactly N bytes.
+    fn with_query<R, F: FnOnce(&[u8; N]) -> R>(bytes: &[u8], f: F) -> Option<R> {
+        <&[u8; N]>::try_from(bytes).ok().map(f)
+    }
+
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(key: &[u8; N], f: F) -> R {
+        f(key)
+    }
+}
+
+/// Little endian u64s may have their high zero bytes left off.
+fn u64_needle(needle: &[u8]) -> Option<u64> {
+    if needle.len() > 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..needle.len()].copy_from_slice(needle);
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Any tree of `ByteKeyQuery` keys, as a `DynBkTree`.
+pub struct DynTree<T, K> {
+    tree: T,
+    metric_name: String,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<T, K> DynTree<T, K> {
+    /// `metric_name` is what `metric_name` reports: the name `metric::dynamic::by_name` knows
+    /// the tree's metric by, where it has one.
+    pub fn new<S: Into<String>>(tree: T, metric_name: S) -> Self {
+        DynTree {
+            tree,
+            metric_name: metric_name.into(),
+            key: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.tree
+    }
+
+    pub fn into_inner(self) -> T {
+        self.tree
+    }
+}
+
+impl<T, K> DynBkTree for DynTree<T, K>
+where
+    K: Clone,
+    T: BkTree<K> + Send + Sync,
+    T::KQ: ByteKeyQuery<Key = K>,
+{
+    fn key_type(&self) -> &str {
+        T::KQ::KEY_TYPE
+    }
+
+    fn metric_name(&self) -> &str {
+        &self.metric_name
+    }
+
+    fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    fn find_each_bytes(
+        &self,
+        needle: &[u8],
+        tolerance: Dist,
+        callback: &mut dyn FnMut(Dist, &[u8]),
+    ) {
+        T::KQ::with_query(needle, |needle| {
+            self.tree.find_each(needle, tolerance, |dist, key| {
+                T::KQ::with_bytes(key, |key| callback(dist, key))
+            })
+        });
+    }
+}
+
+impl DynBkTree for BkFileTree {
+    fn key_type(&self) -> &str {
+        "u64"
+    }
+
+    fn metric_name(&self) -> &str {
+        "hamming-u64"
+    }
+
+    fn len(&self) -> usize {
+        BkTree::len(self)
+    }
+
+    fn find_each_bytes(
+        &self,
+        needle: &[u8],
+        tolerance: Dist,
+        callback: &mut dyn FnMut(Dist, &[u8]),
+    ) {
+        if let Some(needle) = u64_needle(needle) {
+            self.find_each(&needle, tolerance, |dist, key| {
+                callback(dist, &key.to_le_bytes())
+            });
+        }
+    }
+}
+
+impl DynBkTree for BkForest<BkFileTree> {
+    fn key_type(&self) -> &str {
+        "u64"
+    }
+
+    fn metric_name(&self) -> &str {
+        "hamming-u64"
+    }
+
+    fn len(&self) -> usize {
+        BkForest::len(self)
+    }
+
+    fn find_each_bytes(
+        &self,
+        needle: &[u8],
+        tolerance: Dist,
+        callback: &mut dyn FnMut(Dist, &[u8]),
+    ) {
+        for shard in self.shards() {
+            shard.find_each_bytes(needle, tolerance, callback);
+        }
+    }
+}
+
+impl DynBkTree
+    for BkInRamTree<'static, BytesKey, Box<dyn DynMetric>, BkInRamAllocator<'static, Vec<u8>>>
+{
+    fn key_type(&self) -> &str {
+        "bytes"
+    }
+
+    fn metric_name(&self) -> &str {
+        self.metric().name()
+    }
+
+    fn len(&self) -> usize {
+        BkTree::len(self)
+    }
+
+    fn find_each_bytes(
+        &self,
+        needle: &[u8],
+        tolerance: Dist,
+        callback: &mut dyn FnMut(Dist, &[u8]),
+    ) {
+        self.find_each(needle, tolerance, |dist, key| callback(dist, key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{STRING_ALLOC, U64_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn trees_of_different_keys_behind_one_pointer() {
+        let mut hashes: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        let mut words: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for word in &["kitten", "sitting", "mitten"] {
+            words.add(word).unwrap();
+        }
+        for key in &[0b1111u64, 0b0111, 0] {
+            hashes.add(key).unwrap();
+        }
+        let trees: Vec<Box<dyn DynBkTree>> = vec![
+            Box::new(DynTree::new(words, "levenshtein")),
+            Box::new(DynTree::new(hashes, "hamming-u64")),
+        ];
+        let search = |tree: &dyn DynBkTree, needle: &[u8]| {
+            let mut found = Vec::new();
+            tree.find_each_bytes(needle, 1, &mut |dist, key| found.push((dist, key.to_vec())));
+            found.sort();
+            found
+        };
+
+        assert_eq!(("string", 3), (trees[0].key_type(), trees[0].len()));
+        assert_eq!(
+            vec![(0, b"kitten".to_vec()), (1, b"mitten".to_vec())],
+            search(&*trees[0], b"kitten")
+        );
+        assert!(search(&*trees[0], b"\xff").is_empty());
+        assert_eq!(
+            ("u64", "hamming-u64"),
+            (trees[1].key_type(), trees[1].metric_name())
+        );
+        assert_eq!(
+            vec![
+                (0, vec![0b0111, 0, 0, 0, 0, 0, 0, 0]),
+                (1, 0b1111u64.to_le_bytes().to_vec())
+            ],
+            search(&*trees[1], &[0b0111])
+        );
+        assert!(search(&*trees[1], &[0; 9]).is_empty());
+    }
+}
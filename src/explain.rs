@@ -0,0 +1,187 @@
+/*
+ * Query plans: why a search was slow, or why it missed a key.
+ *
+ * `explain` runs the same search as `find_each`, recording what happened at every node it looked
+ * at: compared with the needle, or skipped without a comparison, and which bound ruled it out.
+ *
+ *   let plan = tree.explain(&needle, 3);
+ *   println!("{} compared, {} skipped", plan.visited, plan.skipped());
+ *   // Why isn't `expected` in the results?
+ *   let step = plan.pruned_at(&tree.path_to(&expected).unwrap());
+ */
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    /// Compared with the needle. Children in slots `band.0..=band.1` were considered; the rest
+    /// can't be within tolerance, by the triangle inequality.
+    Visited {
+        dist: Dist,
+        matched: bool,
+        band: (Dist, Dist),
+    },
+    /// Skipped, with everything under it: its slot was outside its parent's band.
+    OutsideBand { slot: Dist, band: (Dist, Dist) },
+    /// Skipped, with everything under it: the metric's lower bound on its distance from the
+    /// needle exceeds its largest child slot plus the tolerance.
+    LowerBound { bound: Dist, reach: Dist },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step<K> {
+    /// The node's path from the root, as for `BkTree::node_at`.
+    pub path: Vec<Dist>,
+    pub key: K,
+    pub decision: Decision,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan<K> {
+    pub tolerance: Dist,
+    /// In the order the search made them.
+    pub steps: Vec<Step<K>>,
+    pub matches: Vec<(Dist, K)>,
+    /// Nodes compared with the needle.
+    pub visited: usize,
+    pub outside_band: usize,
+    pub lower_bound: usize,
+}
+
+impl<K> QueryPlan<K> {
+    /// Subtrees skipped without comparing their roots to the needle.
+    pub fn skipped(&self) -> usize {
+        self.outside_band + self.lower_bound
+    }
+
+    /// The step that decided the fate of the node at `path` (see `BkTree::path_to`): the node's
+    /// own step, or the one that skipped the subtree holding it.
+    pub fn pruned_at(&self, path: &[Dist]) -> Option<&Step<K>> {
+        self.steps
+            .iter()
+            .filter(|step| path.starts_with(&step.path))
+            .max_by_key(|step| step.path.len())
+    }
+}
+
+/// Query plans. Implemented for every tree.
+pub trait Explain<K: Clone>: BkTree<K> {
+    /// Run `find_each(needle, tolerance, ..)`, recording the pruning decisions it makes.
+    fn explain(&self, needle: &<Self::KQ as KeyQuery>::Query, tolerance: Dist) -> QueryPlan<K> {
+        let mut plan = QueryPlan {
+            tolerance,
+            steps: Vec::new(),
+            matches: Vec::new(),
+            visited: 0,
+            outside_band: 0,
+            lower_bound: 0,
+        };
+        let metric = self.metric();
+        let distance =
+            |node: &Self::Node| metric.distance(Self::KQ::to_query_static(node.key()), needle);
+        // Mirrors BkFind::each_node_while, so the plan matches what find_each does.
+        let mut stack: Vec<(Vec<Dist>, Dist, &Self::Node)> = Vec::new();
+        if let Some(root) = self.root() {
+            stack.push((Vec::new(), distance(root), root));
+        }
+        while let Some((path, dist, node)) = stack.pop() {
+            plan.visited += 1;
+            let band = (
+                dist.saturating_sub(tolerance),
+                dist.saturating_add(tolerance),
+            );
+            node.each_child(|slot, child| {
+                let mut child_path = path.clone();
+                child_path.push(slot);
+                let decision = if slot < band.0 || band.1 < slot {
+                    plan.outside_band += 1;
+                    Decision::OutsideBand { slot, band }
+                } else {
+                    let bound = metric.lower_bound(Self::KQ::to_query_static(child.key()), needle);
+                    let reach = child.max_child_dist().unwrap_or(0) + tolerance;
+                    if bound <= reach {
+                        stack.push((child_path, distance(child), child));
+                        return;
+                    }
+                    plan.lower_bound += 1;
+                    Decision::LowerBound { bound, reach }
+                };
+                plan.steps.push(Step {
+                    path: child_path,
+                    key: child.key().clone(),
+                    decision,
+                });
+            });
+
+            let matched = dist <= tolerance && !node.is_tombstone();
+            if matched {
+                plan.matches.push((dist, node.key().clone()));
+            }
+            plan.steps.push(Step {
+                path,
+                key: node.key().clone(),
+                decision: Decision::Visited {
+                    dist,
+                    matched,
+                    band,
+                },
+            });
+        }
+        plan
+    }
+}
+
+impl<K: Clone, T: BkTree<K>> Explain<K> for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamTree, STRING_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::StringKey;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn plan_matches_find_each() {
+        let (keys, tree) = hashed_tree(300);
+        let needle = keys[5] ^ 0b10110;
+        let plan = tree.explain(&needle, 8);
+
+        let mut found = Vec::new();
+        tree.find_each(&needle, 8, |d, k| found.push((d, *k)));
+        let mut matches = plan.matches.clone();
+        matches.sort();
+        found.sort();
+        assert!(!found.is_empty());
+        assert_eq!(found, matches);
+        assert!(plan.outside_band > 0);
+        assert_eq!(plan.visited + plan.skipped(), plan.steps.len());
+    }
+
+    #[test]
+    fn explains_a_missed_key() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for word in &["book", "rook", "cake", "cape", "boo"] {
+            tree.add(*word).unwrap();
+        }
+        let plan = tree.explain("cook", 1);
+        // "cape" is under "cake", which is 4 edits from the root "book", and "cook" is only
+        // 1 from "book", so nothing under "cake" can be within 1 of "cook".
+        let step = plan.pruned_at(&tree.path_to("cape").unwrap()).unwrap();
+        assert_eq!("cake", step.key);
+        assert_eq!(vec![4], step.path);
+        assert_eq!(
+            Decision::OutsideBand {
+                slot: 4,
+                band: (0, 2)
+            },
+            step.decision
+        );
+        let root = plan.pruned_at(&[]).unwrap();
+        assert_eq!("book", root.key);
+    }
+}
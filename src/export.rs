@@ -0,0 +1,212 @@
+/*
+ * Neighbour exports: every key with its k nearest neighbours and their distances, as features
+ * for downstream dedup classifiers.
+ *
+ * Output is JSON lines, one record per key:
+ *   {"key":5,"neighbors":[{"key":7,"distance":1},{"key":1,"distance":1}]}
+ * Parquet is left to a conversion step; nothing here depends on an Arrow stack.
+ */
+use std::error::Error;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::vec::Vec;
+
+use serde::Serialize;
+
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::Dist;
+
+/// Keys are queried in batches of this many, so results don't pile up in memory before being
+/// handed to the callback.
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// Keys a worker claims from its batch at a time.
+const CLAIM_SIZE: usize = 64;
+
+/// The `k` nearest neighbours of `key` (excluding itself) within `max_distance` of it.
+pub fn neighbors_of<K, T>(tree: &T, key: &K, k: usize, max_distance: Dist) -> Vec<(Dist, K)>
+where
+    K: Clone,
+    T: BkTree<K>,
+{
+    let query = T::KQ::to_query_static(key);
+    let mut found = tree.find_knn(query, k + 1, max_distance);
+    match found.iter().position(|(_, n)| T::KQ::eq_static(n, query)) {
+        Some(me) => {
+            found.remove(me);
+        }
+        None => found.truncate(k),
+    }
+    found
+}
+
+/// Compute the k nearest neighbours of every key in the tree using `threads` worker threads, and
+/// pass them to `callback` in tree preorder.
+///
+/// Workers claim small runs of keys from a shared counter, so a few expensive queries don't hold
+/// up the rest of a batch.
+pub fn each_knn<K, T, F>(tree: &T, k: usize, max_distance: Dist, threads: usize, mut callback: F)
+where
+    K: Clone + Send + Sync,
+    T: BkTree<K> + Sync,
+    F: FnMut(&K, Vec<(Dist, K)>),
+{
+    let threads = threads.max(1);
+    let mut keys: Vec<K> = Vec::new();
+    tree.preorder_each(|_, _, key| keys.push(key.clone()));
+
+    for batch in keys.chunks(BATCH_SIZE) {
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<Option<Vec<(Dist, K)>>> = Vec::with_capacity(batch.len());
+        results.resize_with(batch.len(), || None);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let start = next.fetch_add(CLAIM_SIZE, Ordering::Relaxed);
+                            if start >= batch.len() {
+                                return done;
+                            }
+                            let end = (start + CLAIM_SIZE).min(batch.len());
+                            for (i, key) in batch[start..end].iter().enumerate() {
+                                done.push((start + i, neighbors_of(tree, key, k, max_distance)));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (i, neighbors) in worker.join().unwrap() {
+                    results[i] = Some(neighbors);
+                }
+            }
+        });
+        for (key, neighbors) in batch.iter().zip(results) {
+            callback(key, neighbors.unwrap());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Neighbor<'k, K> {
+    key: &'k K,
+    distance: Dist,
+}
+
+#[derive(Serialize)]
+struct NeighborRecord<'k, K> {
+    key: &'k K,
+    neighbors: Vec<Neighbor<'k, K>>,
+}
+
+/// Write the k nearest neighbours of every key in the tree to `out` as JSON lines. Returns the
+/// number of records written.
+pub fn write_knn_jsonl<K, T, W>(
+    tree: &T,
+    k: usize,
+    max_distance: Dist,
+    threads: usize,
+    out: &mut W,
+) -> Result<u64, Box<dyn Error>>
+where
+    K: Clone + Send + Sync + Serialize,
+    T: BkTree<K> + Sync,
+    W: Write,
+{
+    let mut written: u64 = 0;
+    let mut failure: Option<Box<dyn Error>> = None;
+    each_knn(tree, k, max_distance, threads, |key, neighbors| {
+        if failure.is_some() {
+            return;
+        }
+        let record = NeighborRecord {
+            key,
+            neighbors: neighbors
+                .iter()
+                .map(|(distance, key)| Neighbor {
+                    key,
+                    distance: *distance,
+                })
+                .collect(),
+        };
+        let line = serde_json::to_writer(&mut *out, &record)
+            .map_err(|e| -> Box<dyn Error> { e.into() })
+            .and_then(|_| out.write_all(b"\n").map_err(|e| e.into()));
+        match line {
+            Ok(()) => written += 1,
+            Err(e) => failure = Some(e),
+        }
+    });
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(written),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree, U64_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::Metric;
+
+    fn tree_of(
+        keys: &[u64],
+    ) -> BkInRamTree<'static, U64Key, HammingMetric<u64>, BkInRamAllocator<'static, u64>> {
+        let mut tree = BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for k in keys {
+            tree.add(k).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn knn_matches_brute_force() {
+        let keys: Vec<u64> = (0..500u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) >> 48)
+            .collect();
+        let tree = tree_of(&keys);
+        let metric: HammingMetric<u64> = Default::default();
+        let mut unique = keys.clone();
+        unique.sort();
+        unique.dedup();
+
+        let mut records = 0;
+        each_knn(&tree, 3, 6, 4, |key, neighbors| {
+            records += 1;
+            let mut expected: Vec<Dist> = unique
+                .iter()
+                .filter(|other| *other != key)
+                .map(|other| metric.distance(key, other))
+                .filter(|d| *d <= 6)
+                .collect();
+            expected.sort();
+            expected.truncate(3);
+            let dists: Vec<Dist> = neighbors.iter().map(|(d, _)| *d).collect();
+            assert_eq!(expected, dists, "neighbours of {}", key);
+            for (d, n) in neighbors.iter() {
+                assert_eq!(*d, metric.distance(key, n));
+            }
+        });
+        assert_eq!(unique.len(), records);
+    }
+
+    #[test]
+    fn writes_jsonl() {
+        let tree = tree_of(&[0b0000, 0b0001, 0b0011, 0b1111_0000]);
+        let mut out: Vec<u8> = Vec::new();
+        let written = write_knn_jsonl(&tree, 1, 2, 2, &mut out).unwrap();
+        assert_eq!(4, written);
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(4, lines.len());
+        assert!(lines.contains(&r#"{"key":3,"neighbors":[{"key":1,"distance":1}]}"#));
+        assert!(lines.contains(&r#"{"key":240,"neighbors":[]}"#));
+    }
+}
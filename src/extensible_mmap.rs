@@ -6,33 +6,39 @@ use memmap::MmapMut;
 use memmap::MmapOptions;
 
 // TODO: let the caller parameterize our growth strategy.
-const ONE_GIB: usize = 1 * 1024 * 1024 * 1024;
+const ONE_GIB: usize = 1024 * 1024 * 1024;
+const MIN_SIZE: usize = 4096;
 
 pub struct ExtensibleMmapMut {
     backing: File,
-    options: MmapOptions,
     alloc: usize,
     ram: MmapMut,
 }
 
 impl ExtensibleMmapMut {
     pub fn on(backing: File) -> IoResult<Self> {
-        let options = MmapOptions::new();
-        let ram = unsafe { options.map_mut(&backing) }?;
+        // Empty files can't be mapped, so they start at a page.
+        if backing.metadata()?.len() == 0 {
+            backing.set_len(MIN_SIZE as u64)?;
+        }
+        let ram = unsafe { MmapOptions::new().map_mut(&backing) }?;
         Ok(ExtensibleMmapMut {
             backing,
-            options,
             ram,
             alloc: 0,
         })
     }
 
     pub fn len(&self) -> usize {
-        return self.alloc;
+        self.alloc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alloc == 0
     }
 
     pub fn capacity(&self) -> usize {
-        return self.ram.len();
+        self.ram.len()
     }
 
     pub fn next_offset(&self) -> usize {
@@ -74,6 +80,6 @@ impl ExtensibleMmapMut {
         let end = self.alloc + additional;
         self.ensure_capacity(end)?;
         self.alloc = end;
-        return Ok((start, &mut self.ram[start..end]));
+        Ok((start, &mut self.ram[start..end]))
     }
 }
@@ -0,0 +1,153 @@
+/*
+ * External memory building: bkfiles of more keys than fit in RAM.
+ *
+ * An `ExternalBuilder` adds keys to an in-RAM tree until it holds `tree_keys` of them, then
+ * writes that tree out through a `bkfile::ForestWriter` and starts another, so only one tree's
+ * nodes are ever in RAM. The file it makes holds a forest of those trees:
+ *
+ *   let mut builder = ExternalBuilder::new(HammingMetric::default(), 50_000_000, &FixedU64)?;
+ *   for key in keys { builder.add(&key, 0, None)?; }
+ *   builder.finish("hashes.bk", metadata)?;
+ *
+ * Searching the file searches every tree in it, so bigger budgets make faster files. Keys are
+ * only checked for duplicates against the tree in RAM: one added again after its tree was
+ * written out is stored twice.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::Path;
+
+use crate::bk::{BkInRamAllocator, BkInRamTree, U64_ALLOC};
+use crate::bkfile::{ChecksumKind, FileDescrHeader, ForestWriter};
+use crate::bktree::{BkTree, BkTreeAdd, BkTreeRootMut};
+use crate::codec::KeyCodec;
+use crate::keys::U64Key;
+use crate::metric::Metric;
+
+pub struct ExternalBuilder<'a, M: Metric<u64>> {
+    tree: BkInRamTree<'static, U64Key, M, BkInRamAllocator<'static, u64>>,
+    values: HashMap<u64, Vec<u8>>,
+    writer: ForestWriter<'a>,
+    tree_keys: usize,
+    trees: usize,
+}
+
+impl<'a, M: Metric<u64>> ExternalBuilder<'a, M> {
+    /// Write out each tree once it has `tree_keys` keys, encoding them with `codec`.
+    pub fn new(
+        metric: M,
+        tree_keys: usize,
+        codec: &'a dyn KeyCodec<Key = u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if tree_keys == 0 {
+            return Err("Trees need room for at least one key".into());
+        }
+        Ok(ExternalBuilder {
+            tree: BkInRamTree::new(metric, &U64_ALLOC),
+            values: HashMap::new(),
+            writer: ForestWriter::new(codec)?,
+            tree_keys,
+            trees: 0,
+        })
+    }
+
+    /// Add `key`, with its flags and value if it has any.
+    pub fn add(
+        &mut self,
+        key: &u64,
+        flags: u8,
+        value: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.tree.add(key)?;
+        if flags != 0 {
+            self.tree.set_flags(key, flags);
+        }
+        if let Some(value) = value {
+            self.values.insert(*key, value.to_vec());
+        }
+        if self.tree.len() >= self.tree_keys {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// How the file's checksum line hashes it. SHA-256 unless set.
+    pub fn set_checksum(&mut self, checksum: ChecksumKind) {
+        self.writer.set_checksum(checksum);
+    }
+
+    /// Sign the file with this ed25519 secret key. Needs the `signatures` feature.
+    pub fn set_signing_key(&mut self, signing_key: [u8; 32]) {
+        self.writer.set_signing_key(signing_key);
+    }
+
+    /// Trees written out so far, not counting the one in RAM.
+    pub fn trees_written(&self) -> usize {
+        self.trees
+    }
+
+    fn spill(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.add(&self.tree, Some(&self.values))?;
+        self.tree.clear();
+        self.values.clear();
+        self.trees += 1;
+        Ok(())
+    }
+
+    /// Write out the tree in RAM, then the file to `path`, with `metadata` about it.
+    pub fn finish<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<FileDescrHeader, Box<dyn Error>> {
+        if !self.tree.is_empty() {
+            self.spill()?;
+        }
+        self.writer.finish(path, metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bkfile_tree::BkFile;
+    use crate::codec::FixedU64;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn builds_a_forest_a_tree_at_a_time() {
+        let keys: Vec<u64> = (0..1000u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut builder = ExternalBuilder::new(HammingMetric::default(), 300, &FixedU64).unwrap();
+        for key in &keys {
+            let value = if *key == keys[500] {
+                Some(&b"five hundred"[..])
+            } else {
+                None
+            };
+            builder.add(key, (*key == keys[900]) as u8, value).unwrap();
+        }
+        assert_eq!(3, builder.trees_written());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("external.bk");
+        let descr = builder.finish(&path, BTreeMap::new()).unwrap();
+        assert_eq!((1000, 4), (descr.node_count, descr.roots.len()));
+
+        let file = BkFile::open(&path, true).unwrap();
+        let trees = file.trees().unwrap();
+        let mut found = Vec::new();
+        let mut flagged = Vec::new();
+        for tree in &trees {
+            tree.find_each_flagged(&keys[900], 0, |_, key, flags| flagged.push((*key, flags)));
+            tree.preorder_each(|_, _, key| found.push(*key));
+        }
+        assert_eq!(vec![(keys[900], 1)], flagged);
+        found.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(expected, found);
+        let values: Vec<&[u8]> = trees.iter().filter_map(|t| t.value(&keys[500])).collect();
+        assert_eq!(vec![&b"five hundred"[..]], values);
+    }
+}
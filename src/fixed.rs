@@ -0,0 +1,376 @@
+/*
+ * Trees of nodes with a fixed size child table, for metrics with a small maximum distance.
+ *
+ * A `BkInRam` node keeps its children in a Vec, which is a second allocation per node and,
+ * for dense nodes, spare capacity besides. A `BkInRamFixed<K, MAXD>` node holds a slot for each
+ * distance below MAXD inline, so a node is one allocation, and finding a child is an index.
+ * Trees check their metric's `max_distance` fits when they're made:
+ *
+ *   const ALLOC: BkFixedAllocator<u64, 65> = BkFixedAllocator::new();
+ *   let mut tree: BkFixedTree<U64Key, HammingMetric<u64>, 65> =
+ *       BkFixedTree::new(HammingMetric::default(), &ALLOC)?;
+ *   tree.add(&key)?;
+ *
+ * Every node pays for MAXD slots, filled or not: 8 bytes each, so 520 for Hamming over u64s.
+ * That beats a Vec for the upper levels of big trees, which fill most of their slots, and loses
+ * for metrics whose distances spread thin.
+ */
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+use crate::bk::BkFind;
+use crate::bknode::{BkNode, BkNodeMut};
+use crate::bktree::{BkTree, BkTreeRootMut};
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::nodeallocator::NodeAllocator;
+use crate::observe;
+use crate::Dist;
+
+/// A node with a child slot for each distance from 0 to MAXD - 1.
+pub struct BkInRamFixed<K, const MAXD: usize> {
+    pub key: K,
+    children: [Option<Box<Self>>; MAXD],
+    tombstone: bool,
+    flags: u8,
+}
+
+impl<K, const MAXD: usize> BkInRamFixed<K, MAXD> {
+    pub fn new(key: K) -> Self {
+        BkInRamFixed {
+            key,
+            children: std::array::from_fn(|_| None),
+            tombstone: false,
+            flags: 0,
+        }
+    }
+
+    /// Move the children onto `out`, leaving the node childless.
+    fn take_children(&mut self, out: &mut Vec<Self>) {
+        out.extend(
+            self.children
+                .iter_mut()
+                .filter_map(|c| c.take())
+                .map(|c| *c),
+        );
+    }
+}
+
+impl<K: Debug, const MAXD: usize> Debug for BkInRamFixed<K, MAXD> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_map()
+            .entry(&self.key, &self.children_vector())
+            .finish()
+    }
+}
+
+impl<K, const MAXD: usize> BkNode for BkInRamFixed<K, MAXD> {
+    type Key = K;
+
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn has_child_at(&self, dist: Dist) -> bool {
+        self.child_at(dist).is_some()
+    }
+
+    fn child_at(&self, dist: Dist) -> Option<&Self> {
+        self.children.get(dist)?.as_deref()
+    }
+
+    fn children_vector(&self) -> Vec<(Dist, &Self)> {
+        let mut children = Vec::new();
+        self.each_child(|dist, child| children.push((dist, child)));
+        children
+    }
+
+    fn each_child<'s, F>(&'s self, mut f: F)
+    where
+        F: FnMut(Dist, &'s Self),
+    {
+        // Furthest first, as BkInRam does.
+        for (dist, child) in self.children.iter().enumerate().rev() {
+            if let Some(child) = child {
+                f(dist, child);
+            }
+        }
+    }
+
+    fn max_child_dist(&self) -> Option<Dist> {
+        self.children.iter().rposition(Option::is_some)
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    fn flags(&self) -> u8 {
+        self.flags
+    }
+}
+
+impl<K, const MAXD: usize> BkNodeMut for BkInRamFixed<K, MAXD> {
+    /// Panics past the last slot; `BkFixedTree::new` makes sure the metric can't get there.
+    fn set_child_node(&mut self, dist: Dist, node: Self) {
+        assert!(self.children[dist].is_none());
+        self.children[dist] = Some(Box::new(node));
+    }
+
+    fn child_at_mut(&mut self, dist: Dist) -> Option<&mut Self> {
+        self.children.get_mut(dist)?.as_deref_mut()
+    }
+
+    fn set_tombstone(&mut self, tombstone: bool) {
+        self.tombstone = tombstone;
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct BkFixedAllocator<'a, K, const MAXD: usize>(
+    #[derivative(Debug = "ignore")] PhantomData<&'a K>,
+);
+
+impl<'a, K, const MAXD: usize> BkFixedAllocator<'a, K, MAXD> {
+    pub const fn new() -> Self {
+        BkFixedAllocator(PhantomData)
+    }
+}
+
+impl<'a, K, const MAXD: usize> Default for BkFixedAllocator<'a, K, MAXD> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, K: Clone, const MAXD: usize> NodeAllocator<'a> for BkFixedAllocator<'a, K, MAXD> {
+    type Key = K;
+    type Node = BkInRamFixed<K, MAXD>;
+
+    fn new_root(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRamFixed::new(key))
+    }
+
+    fn new_child(&'a self, key: K) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRamFixed::new(key))
+    }
+}
+
+/// A BK tree of `BkInRamFixed` nodes. Add, remove and search it through the usual traits.
+pub struct BkFixedTree<'a, KQ: KeyQuery, M, const MAXD: usize> {
+    pub root: Option<BkInRamFixed<KQ::Key, MAXD>>,
+    /// Depth of the deepest node, the root's being 0: the same as `TreeStats::max_depth`.
+    pub max_depth: usize,
+    pub node_count: u64,
+    /// Number of nodes in node_count whose keys have been removed.
+    pub tombstone_count: u64,
+    metric: M,
+    node_allocator: &'a BkFixedAllocator<'a, KQ::Key, MAXD>,
+    observe_id: observe::TreeId,
+}
+
+impl<'a, KQ, M, const MAXD: usize> BkFixedTree<'a, KQ, M, MAXD>
+where
+    KQ: KeyQuery,
+    M: Metric<KQ::Query>,
+{
+    /// Errors unless `metric` has a `max_distance` below MAXD, so every child has a slot.
+    pub fn new(
+        metric: M,
+        alloc: &'a BkFixedAllocator<'a, KQ::Key, MAXD>,
+    ) -> Result<Self, Box<dyn Error>> {
+        match metric.max_distance() {
+            Some(max) if max < MAXD => Ok(BkFixedTree {
+                root: None,
+                max_depth: 0,
+                node_count: 0,
+                tombstone_count: 0,
+                metric,
+                node_allocator: alloc,
+                observe_id: observe::TreeId::next(),
+            }),
+            Some(max) => Err(format!(
+                "Distances up to {} need {} child slots, not {}",
+                max,
+                max + 1,
+                MAXD
+            )
+            .into()),
+            None => Err("The metric has no maximum distance to size child slots by".into()),
+        }
+    }
+}
+
+impl<'a, KQ: KeyQuery, M, const MAXD: usize> Drop for BkFixedTree<'a, KQ, M, MAXD> {
+    /// Tear the tree down with an explicit stack, as `BkInRamTree` does, so chain shaped trees
+    /// don't overflow it.
+    fn drop(&mut self) {
+        let mut stack: Vec<_> = self.root.take().into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            node.take_children(&mut stack);
+        }
+        observe::tree_dropped(self.observe_id);
+    }
+}
+
+impl<'a, KQ: KeyQuery, M, const MAXD: usize> Debug for BkFixedTree<'a, KQ, M, MAXD>
+where
+    KQ::Key: Debug,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BkFixedTree")
+            .field("node_count", &self.node_count)
+            .field("tombstone_count", &self.tombstone_count)
+            .field("max_depth", &self.max_depth)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl<'a, K, Q, KQ, M, const MAXD: usize> BkTree<K> for BkFixedTree<'a, KQ, M, MAXD>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    type KQ = KQ;
+    type Metric = M;
+    type Node = BkInRamFixed<K, MAXD>;
+
+    fn root(&self) -> Option<&Self::Node> {
+        self.root.as_ref()
+    }
+
+    fn metric(&self) -> &M {
+        &self.metric
+    }
+
+    fn len(&self) -> usize {
+        (self.node_count - self.tombstone_count) as usize
+    }
+
+    fn recorded_counts(&self) -> Option<(u64, usize)> {
+        Some((self.node_count, self.max_depth))
+    }
+
+    fn find_each<'b, F>(&'b self, needle: &'b Q, tolerance: Dist, callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        if let Some(ref root) = self.root {
+            let finder = BkFind::new(self.max_depth, Some(root), tolerance, needle);
+            let visited = finder.each::<KQ, M, F>(&self.metric, callback);
+            observe::query_served(visited as u64);
+        }
+    }
+}
+
+impl<'a, K, Q, KQ, M, const MAXD: usize> BkTreeRootMut<'a, K> for BkFixedTree<'a, KQ, M, MAXD>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    type Alloc = BkFixedAllocator<'a, K, MAXD>;
+
+    fn node_allocator(&mut self) -> &'a Self::Alloc {
+        self.node_allocator
+    }
+
+    fn root_mut(&mut self) -> &mut Option<Self::Node> {
+        &mut self.root
+    }
+
+    fn max_depth_mut(&mut self) -> &mut usize {
+        &mut self.max_depth
+    }
+
+    fn incr_node_count(&mut self) {
+        self.node_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn incr_tombstone_count(&mut self) {
+        self.tombstone_count += 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn decr_tombstone_count(&mut self) {
+        self.tombstone_count -= 1;
+        observe::tree_size(self.observe_id, self.node_count, self.tombstone_count);
+    }
+
+    fn clear(&mut self) {
+        let mut stack: Vec<_> = self.root.take().into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            node.take_children(&mut stack);
+        }
+        self.max_depth = 0;
+        self.node_count = 0;
+        self.tombstone_count = 0;
+        observe::tree_size(self.observe_id, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+    use crate::bktree::{BkTreeAdd, BkTreeRemove};
+    use crate::keys::{StringKey, U64Key};
+    use crate::metric::combinators::ClampedMetric;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    const ALLOC: BkFixedAllocator<u64, 65> = BkFixedAllocator::new();
+
+    #[test]
+    fn fixed_trees_match_in_ram_trees() {
+        let mut tree: BkFixedTree<U64Key, HammingMetric<u64>, 65> =
+            BkFixedTree::new(Default::default(), &ALLOC).unwrap();
+        let (keys, mut in_ram) = hashed_tree(1000);
+        for key in &keys {
+            tree.add(key).unwrap();
+        }
+        tree.add(&u64::MAX).unwrap();
+        tree.add(&!u64::MAX).unwrap();
+        in_ram.add(&u64::MAX).unwrap();
+        assert_eq!(1001, tree.len());
+        assert!(tree.validate().is_ok());
+        assert!(tree.same_keys(&in_ram));
+        assert!(tree.remove(&u64::MAX));
+        for needle in &[keys[1] ^ 0b11, 0] {
+            assert_eq!(in_ram.find_sorted(needle, 20), tree.find_sorted(needle, 20));
+        }
+        tree.clear();
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn metrics_must_fit_the_slots() {
+        let alloc: BkFixedAllocator<u64, 64> = BkFixedAllocator::new();
+        let small: Result<BkFixedTree<U64Key, HammingMetric<u64>, 64>, _> =
+            BkFixedTree::new(Default::default(), &alloc);
+        assert!(small.is_err());
+
+        let alloc: BkFixedAllocator<String, 9> = BkFixedAllocator::new();
+        let unbounded: Result<BkFixedTree<StringKey, LevenshteinMetric, 9>, _> =
+            BkFixedTree::new(LevenshteinMetric, &alloc);
+        assert!(unbounded.is_err());
+        let mut clamped: BkFixedTree<StringKey, _, 9> =
+            BkFixedTree::new(ClampedMetric::new(LevenshteinMetric, 8), &alloc).unwrap();
+        for word in &["kitten", "sitting", "a much longer sentence than either"] {
+            clamped.add(word).unwrap();
+        }
+        assert_eq!(3, clamped.len());
+    }
+}
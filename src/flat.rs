@@ -0,0 +1,250 @@
+/*
+ * Flat trees: every node in one Vec, children referred to by index.
+ *
+ * A `BkFlatTree` is a frozen copy of another tree, laid out the way bkfiles are: in pre-order,
+ * with each node's children side by side after it, sorted by distance. Searches walk a single
+ * allocation instead of chasing a pointer per node, and look up the children in a node's band
+ * by binary search instead of testing every child.
+ *
+ *   let flat = BkFlatTree::from_tree(&tree, HammingMetric::default());
+ *   flat.find_each(&needle, 4, |dist, key| ...);
+ *
+ * `nodes()` is the file layout as is: node `i`'s children are `nodes()[node.children()]`.
+ */
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ops::Range;
+
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::observe;
+use crate::Dist;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatNode<K> {
+    pub key: K,
+    /// Distance from the parent: the child slot this node fills.
+    dist: u32,
+    /// Index of the first child.
+    children: u32,
+    child_count: u32,
+    tombstone: bool,
+    flags: u8,
+}
+
+impl<K> FlatNode<K> {
+    pub fn dist(&self) -> Dist {
+        self.dist as Dist
+    }
+
+    /// Indexes of this node's children in `BkFlatTree::nodes`, sorted by distance.
+    pub fn children(&self) -> Range<usize> {
+        self.children as usize..(self.children + self.child_count) as usize
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+}
+
+#[derive(Debug)]
+pub struct BkFlatTree<KQ: KeyQuery, M> {
+    nodes: Vec<FlatNode<KQ::Key>>,
+    metric: M,
+}
+
+fn index(i: usize) -> Result<u32, Box<dyn Error>> {
+    u32::try_from(i).map_err(|_| "Too many nodes for a flat tree".into())
+}
+
+impl<K, Q, KQ, M> BkFlatTree<KQ, M>
+where
+    K: Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+{
+    /// Copy `tree`, tombstones and flags included. Errors past 2^32 nodes.
+    pub fn from_tree<T: BkTree<K, KQ = KQ>>(tree: &T, metric: M) -> Result<Self, Box<dyn Error>> {
+        let mut nodes: Vec<FlatNode<K>> = Vec::new();
+        // (index the node was given, the node), for nodes whose children aren't placed yet.
+        let mut pending: Vec<(usize, &T::Node)> = Vec::new();
+        if let Some(root) = tree.root() {
+            nodes.push(FlatNode {
+                key: root.key().clone(),
+                dist: 0,
+                children: 0,
+                child_count: 0,
+                tombstone: root.is_tombstone(),
+                flags: root.flags(),
+            });
+            pending.push((0, root));
+        }
+        while let Some((parent, node)) = pending.pop() {
+            let mut children = node.children_vector();
+            children.sort_by_key(|(dist, _)| *dist);
+            let first = nodes.len();
+            nodes[parent].children = index(first)?;
+            nodes[parent].child_count = index(children.len())?;
+            for (dist, child) in children.iter() {
+                nodes.push(FlatNode {
+                    key: child.key().clone(),
+                    dist: index(*dist)?,
+                    children: 0,
+                    child_count: 0,
+                    tombstone: child.is_tombstone(),
+                    flags: child.flags(),
+                });
+            }
+            // Reversed so the closest child's subtree comes next, as in bkfiles.
+            for (i, (_, child)) in children.iter().enumerate().rev() {
+                pending.push((first + i, *child));
+            }
+        }
+        Ok(BkFlatTree { nodes, metric })
+    }
+
+    /// In pre-order, the root first.
+    pub fn nodes(&self) -> &[FlatNode<K>] {
+        &self.nodes
+    }
+
+    /// Nodes, including tombstones.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn metric(&self) -> &M {
+        &self.metric
+    }
+
+    /// Same results as `BkTree::find_each` on the tree this was copied from.
+    pub fn find_each<F>(&self, needle: &Q, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        self.find_each_flagged(needle, tolerance, |dist, key, _| callback(dist, key));
+    }
+
+    /// As `BkTree::find_sorted`: every match, by distance and then key.
+    pub fn find_sorted(&self, needle: &Q, tolerance: Dist) -> Vec<(Dist, K)>
+    where
+        K: Ord,
+    {
+        let mut found = Vec::new();
+        self.find_each(needle, tolerance, |dist, key| {
+            found.push((dist, key.clone()))
+        });
+        found.sort();
+        found
+    }
+
+    /// `find_each`, also passing each match's flags.
+    pub fn find_each_flagged<F>(&self, needle: &Q, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K, u8),
+    {
+        let root = match self.nodes.first() {
+            Some(root) => root,
+            None => return,
+        };
+        let distance =
+            |node: &FlatNode<K>| self.metric.distance(KQ::to_query_static(&node.key), needle);
+        let mut visited: u64 = 1;
+        let mut stack: Vec<(Dist, &FlatNode<K>)> = vec![(distance(root), root)];
+        while let Some((dist, node)) = stack.pop() {
+            let children = &self.nodes[node.children()];
+            let min = dist.saturating_sub(tolerance);
+            let max = dist.saturating_add(tolerance);
+            let start = children.partition_point(|child| child.dist() < min);
+            for child in children[start..].iter().take_while(|c| c.dist() <= max) {
+                // As BkFind: skip subtrees the metric can cheaply rule out.
+                let reach = match self.nodes[child.children()].last() {
+                    Some(last) => last.dist(),
+                    None => 0,
+                } + tolerance;
+                if self
+                    .metric
+                    .lower_bound(KQ::to_query_static(&child.key), needle)
+                    > reach
+                {
+                    continue;
+                }
+                visited += 1;
+                stack.push((distance(child), child));
+            }
+            if dist <= tolerance && !node.tombstone {
+                callback(dist, &node.key, node.flags);
+            }
+        }
+        observe::query_served(visited);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamTree, STRING_ALLOC};
+    use crate::bktree::{BkTreeAdd, BkTreeRemove};
+    use crate::keys::StringKey;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn finds_what_the_tree_finds() {
+        let (keys, mut tree) = hashed_tree(2000);
+        tree.remove(&keys[1]);
+        tree.set_flags(&keys[2], 5);
+        let flat = BkFlatTree::from_tree(&tree, HammingMetric::default()).unwrap();
+        assert_eq!(tree.node_count as usize, flat.len());
+
+        for needle in &[0u64, keys[1] ^ 0b101, 0x3c6ef372fe94f82a] {
+            let mut expected = Vec::new();
+            tree.find_each_flagged(needle, 20, |d, k, f| expected.push((d, *k, f)));
+            let mut found = Vec::new();
+            flat.find_each_flagged(needle, 20, |d, k, f| found.push((d, *k, f)));
+            expected.sort();
+            found.sort();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn children_follow_their_parent() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for word in &[
+            "book", "books", "cake", "boo", "cape", "boon", "cook", "cart",
+        ] {
+            tree.add(word).unwrap();
+        }
+        let flat = BkFlatTree::from_tree(&tree, LevenshteinMetric).unwrap();
+        assert_eq!("book", flat.nodes()[0].key);
+        for (i, node) in flat.nodes().iter().enumerate() {
+            let children = &flat.nodes()[node.children()];
+            assert!(node.children().start > i || children.is_empty());
+            assert!(children.windows(2).all(|w| w[0].dist() < w[1].dist()));
+        }
+        let mut found = Vec::new();
+        flat.find_each("bool", 1, |d, k| found.push((d, k.clone())));
+        found.sort();
+        assert_eq!(
+            vec![
+                (1, "boo".to_string()),
+                (1, "book".to_string()),
+                (1, "boon".to_string())
+            ],
+            found
+        );
+    }
+}
@@ -0,0 +1,285 @@
+/*
+ * Forests: several trees searched as one, e.g. a corpus indexed a file per day or per source.
+ *
+ *   let forest = BkForest::open(&["monday.bk", "tuesday.bk"])?;
+ *   forest.find_each(&needle, 4, |dist, key| ...);
+ *   let nearest = forest.find_knn_parallel(&needle, 10, 16);
+ *
+ * Each search runs on every shard in turn, or with the `_parallel` methods, on a pool of at most
+ * a thread per CPU, and the results are merged. Keys in more than one shard are found once per
+ * shard.
+ */
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::bkfile_tree::{BkFile, BkFileTree};
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::Dist;
+
+/// Trees searched together, in the order they were added.
+pub struct BkForest<T> {
+    shards: Vec<T>,
+}
+
+impl<T> BkForest<T> {
+    pub fn new(shards: Vec<T>) -> Self {
+        BkForest { shards }
+    }
+
+    pub fn push(&mut self, shard: T) {
+        self.shards.push(shard);
+    }
+
+    pub fn shards(&self) -> &[T] {
+        &self.shards
+    }
+
+    pub fn into_shards(self) -> Vec<T> {
+        self.shards
+    }
+}
+
+impl BkForest<BkFileTree> {
+    /// Every tree in each of the bkfiles at `paths`, checking their checksums.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, Box<dyn Error>> {
+        let mut shards = Vec::new();
+        for path in paths {
+            shards.extend(BkFile::open(path, true)?.trees()?);
+        }
+        Ok(BkForest { shards })
+    }
+}
+
+impl<T> BkForest<T> {
+    pub fn len<K: Clone>(&self) -> usize
+    where
+        T: BkTree<K>,
+    {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty<K: Clone>(&self) -> bool
+    where
+        T: BkTree<K>,
+    {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    pub fn find_each<K, F>(
+        &self,
+        needle: &<T::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        mut callback: F,
+    ) where
+        K: Clone,
+        T: BkTree<K>,
+        F: FnMut(Dist, &K),
+    {
+        for shard in &self.shards {
+            shard.find_each(needle, tolerance, &mut callback);
+        }
+    }
+
+    pub fn contains<K: Clone>(&self, key: &<T::KQ as KeyQuery>::Query) -> bool
+    where
+        T: BkTree<K>,
+    {
+        self.shards.iter().any(|shard| shard.contains(key))
+    }
+
+    /// The `k` keys nearest to `needle` across the shards, no further than `max_distance`,
+    /// sorted by distance and then by key.
+    pub fn find_knn<K: Clone + Ord>(
+        &self,
+        needle: &<T::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> Vec<(Dist, K)>
+    where
+        T: BkTree<K>,
+    {
+        merge_knn(
+            self.shards
+                .iter()
+                .map(|shard| shard.find_knn(needle, k, max_distance))
+                .collect(),
+            k,
+        )
+    }
+
+    /// `find_each`, the shards spread over the pool, returning the matches shard by shard.
+    pub fn find_all_parallel<K>(
+        &self,
+        needle: &<T::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+    ) -> Vec<(Dist, K)>
+    where
+        K: Clone + Send,
+        T: BkTree<K> + Sync,
+        <T::KQ as KeyQuery>::Query: Sync,
+    {
+        self.each_shard_parallel(|shard| {
+            let mut found = Vec::new();
+            shard.find_each(needle, tolerance, |dist, key| {
+                found.push((dist, key.clone()))
+            });
+            found
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// `find_knn`, the shards spread over the pool.
+    pub fn find_knn_parallel<K>(
+        &self,
+        needle: &<T::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> Vec<(Dist, K)>
+    where
+        K: Clone + Ord + Send,
+        T: BkTree<K> + Sync,
+        <T::KQ as KeyQuery>::Query: Sync,
+    {
+        merge_knn(
+            self.each_shard_parallel(|shard| shard.find_knn(needle, k, max_distance)),
+            k,
+        )
+    }
+
+    /// What `search` finds in each shard, in shard order. Workers, one per CPU but no more than
+    /// there are shards, claim shards from a shared counter until none are left.
+    fn each_shard_parallel<R, S>(&self, search: S) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        S: Fn(&T) -> R + Sync,
+    {
+        let threads = thread::available_parallelism()
+            .map_or(1, |cpus| cpus.get())
+            .min(self.shards.len());
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<Option<R>> = Vec::with_capacity(self.shards.len());
+        results.resize_with(self.shards.len(), || None);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            match self.shards.get(i) {
+                                Some(shard) => done.push((i, search(shard))),
+                                None => return done,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (i, found) in worker.join().unwrap() {
+                    results[i] = Some(found);
+                }
+            }
+        });
+        results.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+/// The `k` nearest of each shard's `k` nearest, sorted by distance and then by key, as
+/// `OverlayTree` merges its two trees'.
+pub(crate) fn merge_knn<K: Ord>(found: Vec<Vec<(Dist, K)>>, k: usize) -> Vec<(Dist, K)> {
+    let mut merged: Vec<(Dist, K)> = found.into_iter().flatten().collect();
+    merged.sort();
+    merged.truncate(k);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamAllocator, BkInRamTree, U64_ALLOC};
+    use crate::bkfile;
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    type Tree = BkInRamTree<'static, U64Key, HammingMetric<u64>, BkInRamAllocator<'static, u64>>;
+
+    #[test]
+    fn searches_every_shard() {
+        let (keys, all) = hashed_tree(900);
+        let mut shards: Vec<Tree> = (0..3)
+            .map(|_| BkInRamTree::new(HammingMetric::default(), &U64_ALLOC))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            shards[i % 3].add(key).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..2)
+            .map(|i| dir.path().join(format!("{}.bk", i)))
+            .collect();
+        bkfile::write_tree(&shards[0], &paths[0], &Default::default()).unwrap();
+        bkfile::write_forest(&shards[1..], &paths[1], &Default::default()).unwrap();
+
+        let in_ram = BkForest::new(shards);
+        let files = BkForest::open(&paths).unwrap();
+        assert_eq!(
+            (3, 900, 900),
+            (files.shards().len(), files.len(), in_ram.len())
+        );
+        let dists = |found: Vec<(Dist, u64)>| -> Vec<Dist> { found.iter().map(|f| f.0).collect() };
+        for needle in &[keys[7] ^ 0b1001, 0] {
+            let mut expected = all.find_sorted(needle, 22);
+            let mut found = Vec::new();
+            files.find_each(needle, 22, |dist, key| found.push((dist, *key)));
+            found.sort();
+            assert_eq!(expected, found);
+            let mut found = in_ram.find_all_parallel(needle, 22);
+            found.sort();
+            assert_eq!(expected, found);
+
+            expected = all.find_knn(needle, 5, 64);
+            assert_eq!(
+                dists(expected.clone()),
+                dists(files.find_knn(needle, 5, 64))
+            );
+            assert_eq!(
+                dists(expected),
+                dists(in_ram.find_knn_parallel(needle, 5, 64))
+            );
+        }
+        assert!(files.contains(&keys[899]));
+    }
+
+    #[test]
+    fn merges_shards_in_order() {
+        // More shards than the pool has threads.
+        let shards: Vec<Tree> = (0..100u64)
+            .map(|i| {
+                let mut shard = BkInRamTree::new(HammingMetric::default(), &U64_ALLOC);
+                shard.add(&(i << 8)).unwrap();
+                shard
+            })
+            .collect();
+        let forest = BkForest::new(shards);
+        let found: Vec<u64> = forest
+            .find_all_parallel(&0, 64)
+            .iter()
+            .map(|f| f.1)
+            .collect();
+        assert_eq!((0..100u64).map(|i| i << 8).collect::<Vec<_>>(), found);
+
+        // Ties go to the smaller key, whichever shard it's from.
+        let merged = merge_knn(vec![vec![(1, 9), (2, 3)], vec![(1, 4), (2, 1)]], 3);
+        assert_eq!(vec![(1, 4), (1, 9), (2, 1)], merged);
+        assert_eq!(
+            forest.find_knn(&0x300, 3, 64),
+            forest.find_knn_parallel(&0x300, 3, 64)
+        );
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ * Distance histograms sampled from a tree's structure, and tolerance suggestions derived from
+ * them.
+ *
+ * Every non-root node is stored at a slot equal to its distance from its parent, so a preorder
+ * walk yields one true key-to-key distance per node without evaluating the metric at all. The
+ * parent is a nearby key (it's the key the new key was routed towards), so these distances are an
+ * upper bound on each key's nearest-neighbour distance.
+ */
+use std::vec::Vec;
+
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::Dist;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DistanceHistogram {
+    /// counts[d] is the number of samples at distance d.
+    pub counts: Vec<u64>,
+    pub total: u64,
+}
+
+impl DistanceHistogram {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(&mut self, dist: Dist) {
+        if self.counts.len() <= dist {
+            self.counts.resize(dist + 1, 0);
+        }
+        self.counts[dist] += 1;
+        self.total += 1;
+    }
+
+    /// Sample the parent-to-child distance of every node in the tree.
+    pub fn from_tree<K: Clone, T: BkTree<K>>(tree: &T) -> Self {
+        Self::from_tree_sampled(tree, 1)
+    }
+
+    /// Sample the parent-to-child distance of every `stride`th node in preorder.
+    pub fn from_tree_sampled<K: Clone, T: BkTree<K>>(tree: &T, stride: usize) -> Self {
+        let stride = stride.max(1);
+        let mut hist = Self::new();
+        let mut seen: usize = 0;
+        // Removed keys still measure their children's distances, so only the root is skipped:
+        // it has no parent to be distant from.
+        let mut stack: Vec<(Dist, usize, &T::Node)> =
+            tree.root().into_iter().map(|r| (0, 0, r)).collect();
+        while let Some((dist, depth, node)) = stack.pop() {
+            node.each_child(|slot, child| stack.push((slot, depth + 1, child)));
+            if depth == 0 || node.is_tombstone() {
+                continue;
+            }
+            if seen.is_multiple_of(stride) {
+                hist.add(dist);
+            }
+            seen += 1;
+        }
+        hist
+    }
+
+    /// Smallest distance d such that at least `fraction` of the samples are <= d.
+    pub fn quantile(&self, fraction: f64) -> Option<Dist> {
+        if self.total == 0 {
+            return None;
+        }
+        let fraction = fraction.clamp(0.0, 1.0);
+        let target = ((fraction * self.total as f64).ceil() as u64).max(1);
+        let mut cumulative: u64 = 0;
+        for (dist, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                return Some(dist);
+            }
+        }
+        Some(self.counts.len() - 1)
+    }
+
+    /// Recommend a tolerance expected to return `target_recall_fraction` of each key's true
+    /// near-duplicates.
+    ///
+    /// The samples overestimate nearest-neighbour distances, so the suggestion errs on the side of
+    /// recall. Treat it as a starting point to tune from, not a guarantee.
+    pub fn suggest_tolerance(&self, target_recall_fraction: f64) -> Option<Dist> {
+        self.quantile(target_recall_fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::bktree::{BkTreeAdd, BkTreeRemove};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn quantiles() {
+        let mut hist = DistanceHistogram::new();
+        for d in &[1, 1, 2, 2, 2, 3, 4, 8, 8, 9] {
+            hist.add(*d);
+        }
+        assert_eq!(Some(1), hist.quantile(0.0));
+        assert_eq!(Some(1), hist.quantile(0.2));
+        assert_eq!(Some(2), hist.quantile(0.5));
+        assert_eq!(Some(8), hist.quantile(0.9));
+        assert_eq!(Some(9), hist.quantile(1.0));
+        assert_eq!(None, DistanceHistogram::new().quantile(0.5));
+    }
+
+    #[test]
+    fn samples_tree_edges() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for k in &[0u64, 1, 3, 7, 0xff] {
+            tree.add(k).unwrap();
+        }
+        let hist = DistanceHistogram::from_tree(&tree);
+        assert_eq!(4, hist.total);
+        assert_eq!(Some(8), hist.suggest_tolerance(1.0));
+
+        // Removing the root leaves its children's edges to sample.
+        assert!(tree.remove(&0));
+        let hist = DistanceHistogram::from_tree(&tree);
+        assert_eq!((4, Some(8)), (hist.total, hist.suggest_tolerance(1.0)));
+    }
+}
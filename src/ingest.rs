@@ -0,0 +1,290 @@
+/*
+ * Streaming ingestion: producers push keys into a bounded channel, and the thread that owns the
+ * tree drains it in batches.
+ *
+ *   let (sender, mut ingestor) = ingest::channel(10_000, 1_000);
+ *   thread::spawn(move || for key in collector { sender.send(key).unwrap(); });
+ *   ingestor.run(&mut tree)?;
+ *
+ * The channel is bounded, so producers block (or get their key handed back from try_send) when
+ * they outrun the inserts. There is no write-ahead log in this crate; callers that need
+ * durability hook one in with `Ingestor::on_batch`, which sees every batch before it's inserted.
+ */
+use std::error::Error;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::vec::Vec;
+
+use crate::bknode::BkNodeMut;
+use crate::bktree::{BkTree, BkTreeAdd};
+use crate::keyquery::KeyQuery;
+
+enum Message<K> {
+    Key(K),
+    /// Reply with the total number of keys inserted once everything sent before this is in.
+    Flush(SyncSender<u64>),
+}
+
+/// Producer side of an ingestion channel. Clone it to feed the same tree from several threads.
+pub struct IngestSender<K> {
+    sender: SyncSender<Message<K>>,
+}
+
+impl<K> Clone for IngestSender<K> {
+    fn clone(&self) -> Self {
+        IngestSender {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<K> IngestSender<K> {
+    /// Queue a key, blocking while the queue is full.
+    pub fn send(&self, key: K) -> Result<(), Box<dyn Error>> {
+        self.sender
+            .send(Message::Key(key))
+            .map_err(|_| "Ingestor has shut down".into())
+    }
+
+    /// Queue a key without blocking. If the queue is full the key is handed back, so the caller
+    /// can decide whether to wait, retry later or drop it.
+    pub fn try_send(&self, key: K) -> Result<Option<K>, Box<dyn Error>> {
+        match self.sender.try_send(Message::Key(key)) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(Message::Key(key))) => Ok(Some(key)),
+            Err(TrySendError::Full(Message::Flush(_))) => unreachable!(),
+            Err(TrySendError::Disconnected(_)) => Err("Ingestor has shut down".into()),
+        }
+    }
+
+    /// Block until every key this sender has queued so far is in the tree. Returns the total
+    /// number of keys the ingestor has inserted.
+    pub fn flush(&self) -> Result<u64, Box<dyn Error>> {
+        let (reply, notice) = sync_channel(1);
+        self.sender
+            .send(Message::Flush(reply))
+            .map_err(|_| -> Box<dyn Error> { "Ingestor has shut down".into() })?;
+        notice
+            .recv()
+            .map_err(|_| "Ingestor stopped before flushing".into())
+    }
+}
+
+type BatchHook<'h, K> = Box<dyn 'h + FnMut(&[K]) -> Result<(), Box<dyn Error>>>;
+type AdmissionHook<'h, K> = Box<dyn 'h + FnMut(&K) -> bool>;
+
+/// Consumer side of an ingestion channel. Runs on the thread that owns the tree.
+pub struct Ingestor<'h, K> {
+    receiver: Receiver<Message<K>>,
+    batch_size: usize,
+    on_batch: Option<BatchHook<'h, K>>,
+    on_flush: Option<Box<dyn 'h + FnMut(u64)>>,
+    admission: Option<AdmissionHook<'h, K>>,
+    /// Number of keys inserted so far.
+    pub inserted: u64,
+    /// Number of keys turned away by the admission hook so far.
+    pub rejected: u64,
+    /// Number of batches inserted so far.
+    pub batches: u64,
+}
+
+/// Create an ingestion channel holding up to `capacity` queued keys, inserted `batch_size` at a
+/// time.
+pub fn channel<'h, K>(capacity: usize, batch_size: usize) -> (IngestSender<K>, Ingestor<'h, K>) {
+    let (sender, receiver) = sync_channel(capacity);
+    (
+        IngestSender { sender },
+        Ingestor {
+            receiver,
+            batch_size: batch_size.max(1),
+            on_batch: None,
+            on_flush: None,
+            admission: None,
+            inserted: 0,
+            rejected: 0,
+            batches: 0,
+        },
+    )
+}
+
+impl<'h, K: Clone> Ingestor<'h, K> {
+    /// Called with each batch before it's inserted. An error stops ingestion without inserting the
+    /// batch. This is where a write-ahead log gets appended to.
+    pub fn on_batch<F>(&mut self, hook: F)
+    where
+        F: 'h + FnMut(&[K]) -> Result<(), Box<dyn Error>>,
+    {
+        self.on_batch = Some(Box::new(hook));
+    }
+
+    /// Called with the total inserted count whenever a flush completes, including the final one
+    /// when all senders have hung up.
+    pub fn on_flush<F>(&mut self, hook: F)
+    where
+        F: 'h + FnMut(u64),
+    {
+        self.on_flush = Some(Box::new(hook));
+    }
+
+    /// Only insert keys for which `hook` returns true. Rejected keys are dropped before
+    /// `on_batch` sees them, and counted in `rejected`.
+    pub fn admission<F>(&mut self, hook: F)
+    where
+        F: 'h + FnMut(&K) -> bool,
+    {
+        self.admission = Some(Box::new(hook));
+    }
+
+    /// Insert keys into `tree` until every sender has been dropped.
+    pub fn run<'a, T>(&mut self, tree: &mut T) -> Result<(), Box<dyn Error>>
+    where
+        T: BkTreeAdd<'a, K>,
+        <T as BkTree<K>>::Node: BkNodeMut<Key = K>,
+    {
+        let mut batch: Vec<K> = Vec::with_capacity(self.batch_size);
+        let mut flushes: Vec<SyncSender<u64>> = Vec::new();
+        loop {
+            // Block for the first message, then take whatever else is already queued.
+            let mut hung_up = match self.receiver.recv() {
+                Ok(message) => {
+                    Self::accept(message, &mut batch, &mut flushes);
+                    false
+                }
+                Err(_) => true,
+            };
+            while !hung_up && batch.len() < self.batch_size && flushes.is_empty() {
+                match self.receiver.try_recv() {
+                    Ok(message) => Self::accept(message, &mut batch, &mut flushes),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => hung_up = true,
+                }
+            }
+
+            self.insert_batch(tree, &mut batch)?;
+            if hung_up || !flushes.is_empty() {
+                for reply in flushes.drain(..) {
+                    // The flusher may have given up waiting; that's fine.
+                    let _ = reply.send(self.inserted);
+                }
+                if let Some(hook) = self.on_flush.as_mut() {
+                    hook(self.inserted);
+                }
+            }
+            if hung_up {
+                return Ok(());
+            }
+        }
+    }
+
+    fn accept(message: Message<K>, batch: &mut Vec<K>, flushes: &mut Vec<SyncSender<u64>>) {
+        match message {
+            Message::Key(key) => batch.push(key),
+            Message::Flush(reply) => flushes.push(reply),
+        }
+    }
+
+    fn insert_batch<'a, T>(
+        &mut self,
+        tree: &mut T,
+        batch: &mut Vec<K>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        T: BkTreeAdd<'a, K>,
+        <T as BkTree<K>>::Node: BkNodeMut<Key = K>,
+    {
+        if let Some(admit) = self.admission.as_mut() {
+            let before = batch.len();
+            batch.retain(|key| admit(key));
+            self.rejected += (before - batch.len()) as u64;
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if let Some(hook) = self.on_batch.as_mut() {
+            hook(batch)?;
+        }
+        for key in batch.iter() {
+            tree.add(<T::KQ as KeyQuery>::to_query_static(key))?;
+        }
+        self.inserted += batch.len() as u64;
+        self.batches += 1;
+        batch.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+    use std::thread;
+
+    #[test]
+    fn ingests_from_producer_threads() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        let mut logged: u64 = 0;
+        let mut flushed: Vec<u64> = Vec::new();
+        let (sender, mut ingestor) = channel(4, 16);
+        ingestor.on_batch(|batch| {
+            logged += batch.len() as u64;
+            Ok(())
+        });
+        ingestor.on_flush(|count| flushed.push(count));
+
+        let producers: Vec<_> = (0..4u64)
+            .map(|p| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for k in 0..100u64 {
+                        sender.send(p * 1000 + k).unwrap();
+                    }
+                    sender.flush().unwrap()
+                })
+            })
+            .collect();
+        drop(sender);
+        ingestor.run(&mut tree).unwrap();
+        let inserted = ingestor.inserted;
+        drop(ingestor);
+
+        for producer in producers {
+            assert!(producer.join().unwrap() >= 100);
+        }
+        assert_eq!(400, inserted);
+        assert_eq!(400, logged);
+        assert_eq!(400, tree.node_count);
+        assert_eq!(Some(&400), flushed.last());
+    }
+
+    #[test]
+    fn admission_rejects_before_logging() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        let mut logged: Vec<u64> = Vec::new();
+        let (sender, mut ingestor) = channel(16, 4);
+        ingestor.admission(|key| key % 3 != 0);
+        ingestor.on_batch(|batch| {
+            logged.extend_from_slice(batch);
+            Ok(())
+        });
+        for k in 0..10u64 {
+            sender.send(k).unwrap();
+        }
+        drop(sender);
+        ingestor.run(&mut tree).unwrap();
+        assert_eq!((6, 4), (ingestor.inserted, ingestor.rejected));
+        drop(ingestor);
+        logged.sort();
+        assert_eq!(vec![1, 2, 4, 5, 7, 8], logged);
+        assert_eq!(6, tree.node_count);
+    }
+
+    #[test]
+    fn try_send_hands_back_keys_when_full() {
+        let (sender, _ingestor) = channel::<u64>(1, 1);
+        assert_eq!(None, sender.try_send(1).unwrap());
+        assert_eq!(Some(2), sender.try_send(2).unwrap());
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ * Key interning for string trees.
+ *
+ * An `InterningAllocator` hands every tree node built through it a shared `Arc<str>` from one
+ * pool, so a key stored in several trees (shards, per-field indexes, rebuilds) is only stored
+ * once. Interning is of whole keys; keys that merely share a prefix are still stored separately.
+ *
+ *   let alloc = InterningAllocator::new();
+ *   let mut tree: BkInRamTree<ArcStrKey, LevenshteinMetric, _> =
+ *       BkInRamTree::new(LevenshteinMetric, &alloc);
+ *   ...
+ *   println!("saved {} bytes", alloc.stats().bytes_saved());
+ */
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::mem;
+use std::sync::Arc;
+
+use crate::bk::BkInRam;
+use crate::nodeallocator::NodeAllocator;
+
+/// The reference counts heading every `Arc` allocation.
+const ARC_HEADER: u64 = 2 * mem::size_of::<usize>() as u64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InternStats {
+    /// Keys interned, counting repeats.
+    pub keys: u64,
+    /// Distinct keys in the pool.
+    pub unique_keys: u64,
+    /// Bytes the keys would have taken in an `Arc` each.
+    pub bytes_requested: u64,
+    /// Bytes of the pooled `Arc`s.
+    pub bytes_stored: u64,
+    /// Bytes of the pool's hash table, roughly: a pointer and a control byte per slot.
+    pub table_bytes: u64,
+}
+
+impl InternStats {
+    /// Zero if the pool costs more than it shares, e.g. when few keys repeat.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_requested
+            .saturating_sub(self.bytes_stored + self.table_bytes)
+    }
+}
+
+/// A pool of shared strings.
+#[derive(Debug, Default)]
+pub struct StringInterner {
+    pool: HashSet<Arc<str>>,
+    stats: InternStats,
+}
+
+impl StringInterner {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The pooled copy of `key`, adding it to the pool if it's new.
+    pub fn intern(&mut self, key: &str) -> Arc<str> {
+        match self.pool.get(key).cloned() {
+            Some(pooled) => {
+                self.count(&pooled, false);
+                pooled
+            }
+            None => self.intern_arc(Arc::from(key)),
+        }
+    }
+
+    /// As `intern`, but pools `key` itself if it's new rather than copying it.
+    pub fn intern_arc(&mut self, key: Arc<str>) -> Arc<str> {
+        let pooled = self.pool.get(&key).cloned();
+        self.count(&key, pooled.is_none());
+        pooled.unwrap_or_else(|| {
+            self.pool.insert(key.clone());
+            key
+        })
+    }
+
+    fn count(&mut self, key: &str, unique: bool) {
+        let bytes = ARC_HEADER + key.len() as u64;
+        self.stats.keys += 1;
+        self.stats.bytes_requested += bytes;
+        if unique {
+            self.stats.unique_keys += 1;
+            self.stats.bytes_stored += bytes;
+        }
+    }
+
+    pub fn stats(&self) -> InternStats {
+        InternStats {
+            table_bytes: self.pool.capacity() as u64 * (mem::size_of::<Arc<str>>() as u64 + 1),
+            ..self.stats
+        }
+    }
+
+    /// Drop pooled strings no longer referenced by any tree. Returns the number released.
+    pub fn purge_unused(&mut self) -> usize {
+        let before = self.pool.len();
+        self.pool.retain(|key| Arc::strong_count(key) > 1);
+        let released = before - self.pool.len();
+        self.stats.unique_keys = self.pool.len() as u64;
+        self.stats.bytes_stored = self
+            .pool
+            .iter()
+            .map(|key| ARC_HEADER + key.len() as u64)
+            .sum();
+        released
+    }
+}
+
+/// Node allocator for `keys::ArcStrKey` trees that interns every key it's given.
+#[derive(Debug, Default)]
+pub struct InterningAllocator {
+    interner: RefCell<StringInterner>,
+}
+
+impl InterningAllocator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn stats(&self) -> InternStats {
+        self.interner.borrow().stats()
+    }
+
+    pub fn purge_unused(&self) -> usize {
+        self.interner.borrow_mut().purge_unused()
+    }
+
+    fn intern(&self, key: Arc<str>) -> Arc<str> {
+        self.interner.borrow_mut().intern_arc(key)
+    }
+}
+
+impl<'a> NodeAllocator<'a> for InterningAllocator {
+    type Key = Arc<str>;
+    type Node = BkInRam<Arc<str>>;
+
+    fn new_root(&'a self, key: Arc<str>) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRam::new(self.intern(key)))
+    }
+
+    fn new_child(&'a self, key: Arc<str>) -> Result<Self::Node, Box<dyn Error>> {
+        Ok(BkInRam::new(self.intern(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::BkInRamTree;
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::ArcStrKey;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn trees_share_interned_keys() {
+        let alloc = InterningAllocator::new();
+        let mut first: BkInRamTree<ArcStrKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &alloc);
+        let mut second: BkInRamTree<ArcStrKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &alloc);
+        for word in &["antidisestablishment", "antidisestablishmentarian", "anti"] {
+            first.add(*word).unwrap();
+            second.add(*word).unwrap();
+        }
+
+        let stats = alloc.stats();
+        assert_eq!(6, stats.keys);
+        assert_eq!(3, stats.unique_keys);
+        // Each copy of the 49 bytes of keys is an Arc with its counts.
+        let stored = 49 + 3 * ARC_HEADER;
+        assert_eq!(
+            (2 * stored, stored),
+            (stats.bytes_requested, stats.bytes_stored)
+        );
+        assert!(stats.table_bytes > 0);
+        assert_eq!(stored - stats.table_bytes, stats.bytes_saved());
+
+        let mut from_first = Vec::new();
+        first.find_each("anti", 0, |_, k| from_first.push(k.clone()));
+        let mut from_second = Vec::new();
+        second.find_each("anti", 0, |_, k| from_second.push(k.clone()));
+        assert!(Arc::ptr_eq(&from_first[0], &from_second[0]));
+
+        drop(from_first);
+        drop(from_second);
+        drop(first);
+        assert_eq!(0, alloc.purge_unused());
+        drop(second);
+        assert_eq!(3, alloc.purge_unused());
+        assert_eq!(0, alloc.stats().bytes_stored);
+    }
+
+    #[test]
+    fn new_keys_are_pooled_without_copying() {
+        let mut interner = StringInterner::new();
+        let key: Arc<str> = Arc::from("anti");
+        assert!(Arc::ptr_eq(&key, &interner.intern_arc(key.clone())));
+        let again = interner.intern_arc(Arc::from("anti"));
+        assert!(Arc::ptr_eq(&key, &again));
+        assert!(Arc::ptr_eq(&key, &interner.intern("anti")));
+    }
+}
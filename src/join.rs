@@ -0,0 +1,249 @@
+/*
+ * Similarity joins: enumerate all pairs of keys within a tolerance of each other.
+ *
+ * In a BK tree every key below slot j of a node is exactly j from that node's key. So for nodes x
+ * (in tree A) and b (in tree B) at distance D, any key u under x's slot i and key v under b's slot
+ * j satisfy
+ *
+ *     d(u, v) >= max(|D - i| - j, |D - j| - i)
+ *
+ * by the triangle inequality. Pairs of subtrees whose bound exceeds the tolerance are skipped
+ * without computing any of their distances.
+ */
+use std::vec::Vec;
+
+use crate::bk::BkFind;
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// Lower bound on the distance between any key under slot `i` of x and any key under slot `j` of
+/// b, where d(x, b) == `dist`.
+fn subtree_pair_bound(dist: Dist, i: Dist, j: Dist) -> Dist {
+    dist.abs_diff(i)
+        .saturating_sub(j)
+        .max(dist.abs_diff(j).saturating_sub(i))
+}
+
+/// Join the subtree under `x` against the subtree under `b`.
+///
+/// Callback args: distance, key from x's subtree, key from b's subtree.
+pub(crate) fn join_subtrees<'n, K, Q, KQ, M, NA, NB, F>(
+    metric: &M,
+    x: &'n NA,
+    b: &'n NB,
+    tolerance: Dist,
+    callback: &mut F,
+) where
+    K: 'n + Clone,
+    Q: ?Sized,
+    KQ: KeyQuery<Key = K, Query = Q>,
+    M: Metric<Q>,
+    NA: 'n + BkNode<Key = K>,
+    NB: 'n + BkNode<Key = K>,
+    F: FnMut(Dist, &K, &K),
+{
+    let mut stack: Vec<(&'n NA, &'n NB)> = vec![(x, b)];
+    while let Some((x, b)) = stack.pop() {
+        let x_query = KQ::to_query_static(x.key());
+        let b_query = KQ::to_query_static(b.key());
+        let dist = metric.distance(x_query, b_query);
+        if dist <= tolerance && !x.is_tombstone() && !b.is_tombstone() {
+            callback(dist, x.key(), b.key());
+        }
+
+        let x_children = x.children_vector();
+        let b_children = b.children_vector();
+
+        // x against everything under b.
+        if !x.is_tombstone() {
+            for (j, c) in b_children.iter() {
+                if dist.abs_diff(*j) <= tolerance {
+                    BkFind::new(0, Some(*c), tolerance, x_query)
+                        .each::<KQ, M, _>(metric, |d, v| callback(d, x.key(), v));
+                }
+            }
+        }
+
+        // b against everything under x.
+        if !b.is_tombstone() {
+            for (i, a) in x_children.iter() {
+                if dist.abs_diff(*i) <= tolerance {
+                    BkFind::new(0, Some(*a), tolerance, b_query)
+                        .each::<KQ, M, _>(metric, |d, u| callback(d, u, b.key()));
+                }
+            }
+        }
+
+        // Everything under x against everything under b.
+        for (i, a) in x_children.iter() {
+            for (j, c) in b_children.iter() {
+                if subtree_pair_bound(dist, *i, *j) <= tolerance {
+                    stack.push((*a, *c));
+                }
+            }
+        }
+    }
+}
+
+/// Enumerate all pairs (a, b), a from tree_a and b from tree_b, with distance <= tolerance.
+///
+/// Uses dual-tree traversal, which is usually far cheaper than querying tree_b for every key of
+/// tree_a.
+///
+/// Callback args: distance, key from tree_a, key from tree_b.
+pub fn join_within<K, TA, TB, F>(tree_a: &TA, tree_b: &TB, tolerance: Dist, mut callback: F)
+where
+    K: Clone,
+    TA: BkTree<K>,
+    TB: BkTree<K, KQ = TA::KQ, Metric = TA::Metric>,
+    F: FnMut(Dist, &K, &K),
+{
+    if let (Some(a), Some(b)) = (tree_a.root(), tree_b.root()) {
+        join_subtrees::<K, _, TA::KQ, TA::Metric, _, _, _>(
+            tree_a.metric(),
+            a,
+            b,
+            tolerance,
+            &mut callback,
+        );
+    }
+}
+
+/// Enumerate all unordered pairs of distinct keys in `tree` with distance <= tolerance. Each pair
+/// is reported once.
+///
+/// Within one subtree no metric evaluations are needed to pair a node with its descendants: every
+/// key under slot i is exactly i away. Sibling subtrees under slots i and j are at least |i - j|
+/// apart, so only sibling pairs with |i - j| <= tolerance are joined.
+///
+/// Callback args: distance, key, other key.
+pub fn self_join_within<K, T, F>(tree: &T, tolerance: Dist, mut callback: F)
+where
+    K: Clone,
+    T: BkTree<K>,
+    F: FnMut(Dist, &K, &K),
+{
+    let mut stack: Vec<&T::Node> = tree.root().into_iter().collect();
+    while let Some(x) = stack.pop() {
+        let children = x.children_vector();
+        for (n, (i, a)) in children.iter().enumerate() {
+            // x against its descendants under slot i, which are all exactly i away.
+            if *i <= tolerance && !x.is_tombstone() {
+                let mut under: Vec<&T::Node> = vec![*a];
+                while let Some(u) = under.pop() {
+                    if !u.is_tombstone() {
+                        callback(*i, x.key(), u.key());
+                    }
+                    under.extend(u.children_vector().into_iter().map(|(_, c)| c));
+                }
+            }
+
+            // Pairs within slot i's subtree.
+            stack.push(*a);
+
+            // Pairs across slot i's and slot j's subtrees.
+            for (j, b) in children[n + 1..].iter() {
+                if i.abs_diff(*j) <= tolerance {
+                    join_subtrees::<K, _, T::KQ, T::Metric, _, _, _>(
+                        tree.metric(),
+                        *a,
+                        *b,
+                        tolerance,
+                        &mut callback,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree, U64_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    type Tree<'a> = BkInRamTree<'a, U64Key, HammingMetric<u64>, BkInRamAllocator<'a, u64>>;
+
+    fn tree_of(keys: &[u64]) -> Tree<'static> {
+        let mut tree = BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for k in keys {
+            tree.add(k).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn join_matches_brute_force() {
+        let a_keys: Vec<u64> = (0..200u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) >> 50)
+            .collect();
+        let b_keys: Vec<u64> = (0..150u64)
+            .map(|i| i.wrapping_mul(0xc2b2ae3d27d4eb4f) >> 50)
+            .collect();
+        let tree_a = tree_of(&a_keys);
+        let tree_b = tree_of(&b_keys);
+        let metric: HammingMetric<u64> = Default::default();
+
+        for tolerance in 0..4 {
+            let mut found = Vec::new();
+            join_within(&tree_a, &tree_b, tolerance, |d, a, b| {
+                found.push((*a, *b, d))
+            });
+            found.sort();
+
+            let mut a_unique = a_keys.clone();
+            a_unique.sort();
+            a_unique.dedup();
+            let mut b_unique = b_keys.clone();
+            b_unique.sort();
+            b_unique.dedup();
+            let mut expected = Vec::new();
+            for a in a_unique.iter() {
+                for b in b_unique.iter() {
+                    let d = metric.distance(a, b);
+                    if d <= tolerance {
+                        expected.push((*a, *b, d));
+                    }
+                }
+            }
+            expected.sort();
+            assert_eq!(expected, found, "tolerance {}", tolerance);
+        }
+    }
+
+    #[test]
+    fn self_join_matches_brute_force() {
+        let mut keys: Vec<u64> = (0..300u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) >> 52)
+            .collect();
+        let tree = tree_of(&keys);
+        keys.sort();
+        keys.dedup();
+        let metric: HammingMetric<u64> = Default::default();
+
+        for tolerance in 0..4 {
+            let mut found = Vec::new();
+            self_join_within(&tree, tolerance, |d, a, b| {
+                found.push((*a.min(b), *a.max(b), d))
+            });
+            found.sort();
+
+            let mut expected = Vec::new();
+            for (n, a) in keys.iter().enumerate() {
+                for b in keys[n + 1..].iter() {
+                    let d = metric.distance(a, b);
+                    if d <= tolerance {
+                        expected.push((*a, *b, d));
+                    }
+                }
+            }
+            assert_eq!(expected, found, "tolerance {}", tolerance);
+        }
+    }
+}
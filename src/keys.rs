@@ -1,3 +1,6 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
 use crate::keyquery::KeyQuery;
 use crate::metric::Metric;
 use crate::Dist;
@@ -40,7 +43,7 @@ impl KeyQuery for U64Key {
 
     #[inline]
     fn to_query_static(key: &Self::Key) -> &Self::Query {
-        &key
+        key
     }
     #[inline]
     fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
@@ -67,7 +70,7 @@ impl KeyQuery for StringKey {
         key: &Self::Key,
         query: &Self::Query,
     ) -> Dist {
-        metric.distance(key.as_str(), &query)
+        metric.distance(key.as_str(), query)
     }
 
     #[inline]
@@ -76,7 +79,7 @@ impl KeyQuery for StringKey {
         key: &Self::Key,
         query: &Self::Query,
     ) -> Dist {
-        metric.distance(key.as_str(), &query)
+        metric.distance(key.as_str(), query)
     }
 
     #[inline]
@@ -104,3 +107,379 @@ impl KeyQuery for StringKey {
         key.as_str() == query
     }
 }
+
+/// Shared string keys, for trees whose allocator interns keys (see `intern::InterningAllocator`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArcStrKey;
+
+impl KeyQuery for ArcStrKey {
+    type Key = Arc<str>;
+    type Query = str;
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Arc<str> {
+        Arc::from(query)
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Arc<str> {
+        Arc::from(query)
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &str {
+        key
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        &**key == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        &**key == query
+    }
+}
+
+/// Fixed-size byte array keys, e.g. 256-bit hashes as `ArrayKey<32>`. Keys are stored inline in
+/// the node, with no heap allocation per key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArrayKey<const N: usize>;
+
+impl<const N: usize> KeyQuery for ArrayKey<N> {
+    type Key = [u8; N];
+    type Query = [u8; N];
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &Self::Query {
+        key
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+}
+
+/// Variable length vector keys, e.g. quantized feature vectors as `VecKey<u32>`. Queries are
+/// slices, as `StringKey` queries are `str`.
+#[derive(Derivative)]
+#[derivative(Debug, Clone, Copy, Default)]
+pub struct VecKey<T>(#[derivative(Debug = "ignore")] PhantomData<T>);
+
+impl<T: Clone + PartialEq> KeyQuery for VecKey<T> {
+    type Key = Vec<T>;
+    type Query = [T];
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key.as_slice(), query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key.as_slice(), query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Self::Key {
+        query.to_vec()
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Self::Key {
+        query.to_vec()
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &Self::Query {
+        key.as_slice()
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        key.as_slice() == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        key.as_slice() == query
+    }
+}
+
+/// Raw byte string keys, as used with runtime-selected metrics (see `metric::by_name`).
+pub type BytesKey = VecKey<u8>;
+
+/// Fixed-size u32 vector keys, stored inline like `ArrayKey<N>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U32ArrayKey<const N: usize>;
+
+impl<const N: usize> KeyQuery for U32ArrayKey<N> {
+    type Key = [u32; N];
+    type Query = [u32; N];
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &Self::Query {
+        key
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+}
+
+/// Fixed-size multi-word keys, e.g. 256-bit hashes as `U64ArrayKey<4>`, stored inline like
+/// `ArrayKey<N>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64ArrayKey<const N: usize>;
+
+impl<const N: usize> KeyQuery for U64ArrayKey<N> {
+    type Key = [u64; N];
+    type Query = [u64; N];
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Self::Key {
+        *query
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &Self::Query {
+        key
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+}
+
+/// Tuple keys, for metrics composed from per-field metrics (see `metric::combinators`).
+#[derive(Derivative)]
+#[derivative(Debug, Clone, Copy, Default)]
+pub struct PairKey<A, B>(#[derivative(Debug = "ignore")] PhantomData<(A, B)>);
+
+impl<A: Clone + PartialEq, B: Clone + PartialEq> KeyQuery for PairKey<A, B> {
+    type Key = (A, B);
+    type Query = (A, B);
+
+    #[inline]
+    fn distance<M: Metric<Self::Query>>(
+        &self,
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn distance_static<M: Metric<Self::Query>>(
+        metric: &M,
+        key: &Self::Key,
+        query: &Self::Query,
+    ) -> Dist {
+        metric.distance(key, query)
+    }
+
+    #[inline]
+    fn to_key(&self, query: &Self::Query) -> Self::Key {
+        query.clone()
+    }
+
+    #[inline]
+    fn to_key_static(query: &Self::Query) -> Self::Key {
+        query.clone()
+    }
+
+    #[inline]
+    fn to_query_static(key: &Self::Key) -> &Self::Query {
+        key
+    }
+
+    #[inline]
+    fn eq(&self, key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+
+    #[inline]
+    fn eq_static(key: &Self::Key, query: &Self::Query) -> bool {
+        key == query
+    }
+}
+
+/// Heap memory a key owns, beyond its own `size_of`. For sizing trees: see
+/// `BkInRamTree::approx_heap_bytes`.
+pub trait HeapBytes {
+    fn heap_bytes(&self) -> usize;
+}
+
+macro_rules! no_heap_bytes {
+    ($($t:ty),*) => {
+        $(impl HeapBytes for $t {
+            fn heap_bytes(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+no_heap_bytes!(u8, u16, u32, u64);
+
+impl HeapBytes for String {
+    fn heap_bytes(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// All of it, though interned keys share theirs.
+impl HeapBytes for Arc<str> {
+    fn heap_bytes(&self) -> usize {
+        // The reference counts, then the text.
+        2 * std::mem::size_of::<usize>() + self.len()
+    }
+}
+
+impl<T: HeapBytes> HeapBytes for Vec<T> {
+    fn heap_bytes(&self) -> usize {
+        self.capacity() * std::mem::size_of::<T>() + self.iter().map(T::heap_bytes).sum::<usize>()
+    }
+}
+
+impl<T: HeapBytes, const N: usize> HeapBytes for [T; N] {
+    fn heap_bytes(&self) -> usize {
+        self.iter().map(T::heap_bytes).sum()
+    }
+}
+
+impl<A: HeapBytes, B: HeapBytes> HeapBytes for (A, B) {
+    fn heap_bytes(&self) -> usize {
+        self.0.heap_bytes() + self.1.heap_bytes()
+    }
+}
@@ -6,27 +6,53 @@ extern crate byteorder;
 extern crate serde_cbor;
 extern crate sha2;
 
+pub mod arena;
 pub mod array_storage;
 pub mod bkfile;
+pub mod bkfile_tree;
+pub mod cluster;
+pub mod codec;
 pub mod metric;
 
 pub mod bk;
+pub mod bkmap;
 pub mod bknode;
 pub mod bktree;
+#[deprecated(note = "the mutation traits are in bktree")]
 pub mod bktreemut;
+pub mod cache;
 pub mod keyquery;
 pub mod keys;
 pub mod nodeallocator;
 
+pub mod build;
+pub mod concurrent;
+pub mod delta;
+pub mod dyntree;
+pub mod explain;
+pub mod export;
 pub mod extensible_mmap;
+pub mod external;
+pub mod fixed;
+pub mod flat;
+pub mod forest;
+pub mod histogram;
+pub mod ingest;
+pub mod intern;
+pub mod join;
+pub mod lsm;
+pub mod observe;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod query;
+pub mod score;
+pub mod spill;
+pub mod stats;
+pub mod validate;
 
-/*
-
+pub use bk::BkInRamTree;
 pub use bknode::BkNode;
-pub use bktree::BkInRamTree;
-pub use bktree::BkTree;
-
-*/
+pub use bktree::{BkTree, BkTreeAdd, BkTreeRemove};
 pub use metric::hamming::HammingMetric;
 
 /// The concrete distance type shared across this crate. This is the result of all metric
@@ -0,0 +1,284 @@
+/*
+ * Log-structured forests: cheap inserts into an index that's mostly searched.
+ *
+ * Adding to a big BK tree is a search down it, and freezing trees (writing them to bkfiles,
+ * sharing them between threads) wants them to stop changing. An `LsmForest` adds keys to a small
+ * buffer tree instead. Once that's full, it's frozen into the levels: level i holds a tree of up
+ * to `buffer_keys << i` keys or nothing, and a new tree merges with each full level in turn,
+ * carrying up like a binary counter, until it finds an empty one. Each key is merged
+ * O(log n) times, and searches cost one search per level:
+ *
+ *   let mut forest = LsmForest::new(HammingMetric::default(), 10_000);
+ *   for key in keys { forest.add(&key)?; }
+ *   forest.find_each(&needle, 4, |dist, key| ...);
+ *
+ * With `set_auto_compact(false)` full buffers are frozen as they are, and merged by an explicit
+ * `compact`, e.g. in a quiet period; `compact_all` merges everything into one tree.
+ */
+use std::error::Error;
+use std::mem;
+
+use crate::bk::{BkInRamAllocator, BkInRamTree};
+use crate::bknode::BkNode;
+use crate::bktree::{BkTree, BkTreeAdd};
+use crate::forest::merge_knn;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+pub(crate) type Tree<KQ, M> =
+    BkInRamTree<'static, KQ, M, BkInRamAllocator<'static, <KQ as KeyQuery>::Key>>;
+
+pub struct LsmForest<KQ, M>
+where
+    KQ: KeyQuery,
+    KQ::Key: 'static,
+    M: Metric<KQ::Query>,
+{
+    metric: M,
+    buffer_keys: usize,
+    buffer: Tree<KQ, M>,
+    /// Full buffers waiting for `compact`, when it isn't automatic.
+    frozen: Vec<Tree<KQ, M>>,
+    /// Level i holds up to `buffer_keys << i` keys.
+    levels: Vec<Option<Tree<KQ, M>>>,
+    auto_compact: bool,
+}
+
+impl<K, KQ, M> LsmForest<KQ, M>
+where
+    K: 'static + Clone,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<KQ::Query> + Clone,
+{
+    /// Freeze the buffer once it has `buffer_keys` keys.
+    pub fn new(metric: M, buffer_keys: usize) -> Self {
+        LsmForest {
+            buffer: BkInRamTree::new(metric.clone(), &BkInRamAllocator::SHARED),
+            metric,
+            buffer_keys: buffer_keys.max(1),
+            frozen: Vec::new(),
+            levels: Vec::new(),
+            auto_compact: true,
+        }
+    }
+
+    /// Whether full buffers are merged into the levels as they fill, or left for `compact`.
+    pub fn set_auto_compact(&mut self, auto_compact: bool) {
+        self.auto_compact = auto_compact;
+    }
+
+    fn new_tree(&self) -> Tree<KQ, M> {
+        BkInRamTree::new(self.metric.clone(), &BkInRamAllocator::SHARED)
+    }
+
+    /// Every tree, the buffer first.
+    fn trees(&self) -> impl Iterator<Item = &Tree<KQ, M>> {
+        std::iter::once(&self.buffer)
+            .chain(self.frozen.iter())
+            .chain(self.levels.iter().flatten())
+    }
+
+    /// Add `key`, unless some tree has it already.
+    pub fn add(&mut self, key: &KQ::Query) -> Result<(), Box<dyn Error>> {
+        if self.contains(key) {
+            return Ok(());
+        }
+        self.buffer.add(key)?;
+        if self.buffer.len() >= self.buffer_keys {
+            let fresh = self.new_tree();
+            let full = mem::replace(&mut self.buffer, fresh);
+            if self.auto_compact {
+                self.freeze(full)?;
+            } else {
+                self.frozen.push(full);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set `key`'s flags in whichever tree has it. Returns whether one did.
+    pub fn set_flags(&mut self, key: &KQ::Query, flags: u8) -> bool {
+        self.buffer.set_flags(key, flags)
+            || self
+                .frozen
+                .iter_mut()
+                .any(|tree| tree.set_flags(key, flags))
+            || self
+                .levels
+                .iter_mut()
+                .flatten()
+                .any(|tree| tree.set_flags(key, flags))
+    }
+
+    /// Merge `tree` into the levels, carrying up through the full ones.
+    fn freeze(&mut self, mut tree: Tree<KQ, M>) -> Result<(), Box<dyn Error>> {
+        for level in self.levels.iter_mut() {
+            match level.take() {
+                None => {
+                    *level = Some(tree);
+                    return Ok(());
+                }
+                Some(mut full) => {
+                    add_all(&mut full, &tree)?;
+                    tree = full;
+                }
+            }
+        }
+        self.levels.push(Some(tree));
+        Ok(())
+    }
+
+    /// Merge the buffers frozen since the last compaction into the levels. Returns how many
+    /// there were.
+    pub fn compact(&mut self) -> Result<usize, Box<dyn Error>> {
+        let frozen = mem::take(&mut self.frozen);
+        let count = frozen.len();
+        for tree in frozen {
+            self.freeze(tree)?;
+        }
+        Ok(count)
+    }
+
+    /// Merge every tree, the buffer included, into one, for the fastest searches until the next
+    /// keys are added.
+    pub fn compact_all(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut trees: Vec<Tree<KQ, M>> =
+            mem::take(&mut self.levels).into_iter().flatten().collect();
+        trees.append(&mut self.frozen);
+        let fresh = self.new_tree();
+        trees.push(mem::replace(&mut self.buffer, fresh));
+        // Into the biggest, so the fewest keys are added again.
+        let biggest = (0..trees.len()).max_by_key(|&i| trees[i].len()).unwrap();
+        let mut merged = trees.swap_remove(biggest);
+        for tree in &trees {
+            add_all(&mut merged, tree)?;
+        }
+        // It goes at the level its size calls for.
+        let mut level = 0;
+        while self.buffer_keys << level < merged.len() {
+            level += 1;
+        }
+        self.levels.resize_with(level + 1, || None);
+        self.levels[level] = Some(merged);
+        Ok(())
+    }
+
+    /// Trees searched by each query.
+    pub fn tree_count(&self) -> usize {
+        self.trees().filter(|tree| !tree.is_empty()).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees().map(|tree| tree.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees().all(|tree| tree.is_empty())
+    }
+
+    pub fn contains(&self, key: &KQ::Query) -> bool {
+        self.trees().any(|tree| tree.contains(key))
+    }
+
+    pub fn find_each<F>(&self, needle: &KQ::Query, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &K),
+    {
+        for tree in self.trees() {
+            tree.find_each(needle, tolerance, &mut callback);
+        }
+    }
+
+    /// The `k` keys nearest to `needle`, no further than `max_distance`, sorted by distance and
+    /// then by key.
+    pub fn find_knn(&self, needle: &KQ::Query, k: usize, max_distance: Dist) -> Vec<(Dist, K)>
+    where
+        K: Ord,
+    {
+        merge_knn(
+            self.trees()
+                .map(|tree| tree.find_knn(needle, k, max_distance))
+                .collect(),
+            k,
+        )
+    }
+}
+
+/// Add each live key of `from` to `into`, with its flags.
+pub(crate) fn add_all<K, KQ, M>(
+    into: &mut Tree<KQ, M>,
+    from: &Tree<KQ, M>,
+) -> Result<(), Box<dyn Error>>
+where
+    K: 'static + Clone,
+    KQ: KeyQuery<Key = K> + Default,
+    M: Metric<KQ::Query>,
+{
+    let mut stack: Vec<_> = from.root().into_iter().collect();
+    while let Some(node) = stack.pop() {
+        node.each_child(|_, child| stack.push(child));
+        if node.is_tombstone() {
+            continue;
+        }
+        let query = KQ::to_query_static(node.key());
+        into.add(query)?;
+        if node.flags() != 0 {
+            into.set_flags(query, node.flags());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn levels_carry_like_a_counter() {
+        let keys: Vec<u64> = (0..1000u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut forest: LsmForest<U64Key, _> = LsmForest::new(HammingMetric::default(), 100);
+        let mut all: Tree<U64Key, _> =
+            BkInRamTree::new(HammingMetric::default(), &BkInRamAllocator::SHARED);
+        for key in &keys {
+            forest.add(key).unwrap();
+            all.add(key).unwrap();
+        }
+        forest.add(&keys[3]).unwrap();
+        assert!(forest.set_flags(&keys[3], 5));
+        // 10 buffers of 100 keys: 0b1010, so levels 1 and 3.
+        assert_eq!(1000, forest.len());
+        assert_eq!(
+            vec![false, true, false, true],
+            forest
+                .levels
+                .iter()
+                .map(Option::is_some)
+                .collect::<Vec<_>>()
+        );
+        for needle in &[keys[500] ^ 0b110, 0] {
+            let mut found = Vec::new();
+            forest.find_each(needle, 22, |dist, key| found.push((dist, *key)));
+            found.sort();
+            assert_eq!(all.find_sorted(needle, 22), found);
+        }
+
+        forest.set_auto_compact(false);
+        for key in 1000..1250u64 {
+            forest.add(&key).unwrap();
+        }
+        assert_eq!((2, 5), (forest.frozen.len(), forest.tree_count()));
+        assert_eq!(2, forest.compact().unwrap());
+        forest.compact_all().unwrap();
+        assert_eq!((1250, 1), (forest.len(), forest.tree_count()));
+        let mut flagged = Vec::new();
+        for tree in forest.trees() {
+            tree.find_each_flagged(&keys[3], 0, |_, key, flags| flagged.push((*key, flags)));
+        }
+        assert_eq!(vec![(keys[3], 5)], flagged);
+    }
+}
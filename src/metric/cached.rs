@@ -0,0 +1,159 @@
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use crate::metric::Metric;
+use crate::observe;
+use crate::Dist;
+
+/// Default number of distances a `CachedMetric` holds before starting over.
+pub const DEFAULT_CACHE_CAPACITY: usize = 64 * 1024;
+
+type DistanceCache<K> = Mutex<HashMap<(K, K), Dist>>;
+
+/// Wraps an expensive metric (e.g. `WeightedLevenshteinMetric`) to remember the distances it has
+/// computed, so batched or repeated searches over the same tree don't recompute them.
+///
+/// The cache is one query context: `clear()` it between unrelated batches. It also clears itself
+/// when it reaches its capacity. Lookups are reported to the installed `observe::Observer`.
+pub struct CachedMetric<M, K: ?Sized + ToOwned> {
+    pub inner: M,
+    capacity: usize,
+    cache: DistanceCache<K::Owned>,
+}
+
+impl<M, K> CachedMetric<M, K>
+where
+    K: ?Sized + ToOwned,
+    K::Owned: Hash + Eq,
+{
+    pub fn new(inner: M) -> Self {
+        Self::with_capacity(inner, DEFAULT_CACHE_CAPACITY)
+    }
+
+    pub fn with_capacity(inner: M, capacity: usize) -> Self {
+        CachedMetric {
+            inner,
+            capacity: capacity.max(1),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forget all remembered distances, starting a new query context.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Number of distances remembered.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.lock().unwrap().is_empty()
+    }
+}
+
+impl<M: Default, K> Default for CachedMetric<M, K>
+where
+    K: ?Sized + ToOwned,
+    K::Owned: Hash + Eq,
+{
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<M: Debug, K: ?Sized + ToOwned> Debug for CachedMetric<M, K> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("CachedMetric")
+            .field("inner", &self.inner)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl<M, K> Metric<K> for CachedMetric<M, K>
+where
+    M: Metric<K>,
+    K: ?Sized + ToOwned,
+    K::Owned: Hash + Eq,
+{
+    fn distance(&self, k1: &K, k2: &K) -> Dist {
+        let pair = (k1.to_owned(), k2.to_owned());
+        if let Some(dist) = self.cache.lock().unwrap().get(&pair) {
+            observe::cache_lookup(true);
+            return *dist;
+        }
+        observe::cache_lookup(false);
+        // Computed without holding the lock, so other threads' lookups aren't held up by it.
+        let dist = self.inner.distance(k1, k2);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.capacity {
+            cache.clear();
+        }
+        cache.insert(pair, dist);
+        dist
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &K, k2: &K) -> Dist {
+        self.inner.lower_bound(k1, k2)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        self.inner.max_distance()
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        self.inner.registered_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::StringKey;
+    use crate::metric::counting::CountingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn repeated_queries_hit_the_cache() {
+        let mut tree: BkInRamTree<
+            StringKey,
+            CachedMetric<CountingMetric<LevenshteinMetric>, str>,
+            _,
+        > = BkInRamTree::new(Default::default(), &STRING_ALLOC);
+        for word in &["book", "books", "cake", "boo", "cape", "boon", "cook"] {
+            tree.add(word).unwrap();
+        }
+        tree.metric().clear();
+        tree.metric().inner.reset();
+
+        let mut first = Vec::new();
+        tree.find_each("bool", 1, |d, k| first.push((d, k.clone())));
+        let computed = tree.metric().inner.distances();
+        assert!(computed > 0);
+        let mut second = Vec::new();
+        tree.find_each("bool", 1, |d, k| second.push((d, k.clone())));
+        assert_eq!(first, second);
+        assert_eq!(computed, tree.metric().inner.distances());
+        assert_eq!(computed as usize, tree.metric().len());
+    }
+
+    #[test]
+    fn starts_over_at_capacity() {
+        let metric: CachedMetric<LevenshteinMetric, str> =
+            CachedMetric::with_capacity(LevenshteinMetric, 2);
+        assert_eq!(1, metric.distance("a", "b"));
+        assert_eq!(2, metric.distance("a", "bc"));
+        assert_eq!(2, metric.len());
+        assert_eq!(3, metric.distance("a", "bcd"));
+        assert_eq!(1, metric.len());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::metric::manhattan::zero_padded;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// L∞ (Chebyshev, chessboard) distance between integer vectors: the largest absolute component
+/// difference. Use with `keys::VecKey<u32>` or `keys::U32ArrayKey<N>`, e.g. for tile or grid
+/// coordinates.
+///
+/// Vectors of different lengths are compared as if the shorter were padded with zeros.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ChebyshevMetric;
+
+fn chebyshev(k1: &[u32], k2: &[u32]) -> Dist {
+    zero_padded(k1, k2)
+        .map(|(a, b)| a.abs_diff(b))
+        .max()
+        .unwrap_or(0) as Dist
+}
+
+impl Metric<[u32]> for ChebyshevMetric {
+    #[inline]
+    fn distance(&self, k1: &[u32], k2: &[u32]) -> Dist {
+        chebyshev(k1, k2)
+    }
+}
+
+impl Metric<Vec<u32>> for ChebyshevMetric {
+    #[inline]
+    fn distance(&self, k1: &Vec<u32>, k2: &Vec<u32>) -> Dist {
+        chebyshev(k1, k2)
+    }
+}
+
+impl<const N: usize> Metric<[u32; N]> for ChebyshevMetric {
+    #[inline]
+    fn distance(&self, k1: &[u32; N], k2: &[u32; N]) -> Dist {
+        chebyshev(k1, k2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::U32ArrayKey;
+
+    #[test]
+    fn chebyshev_distance() {
+        let metric = ChebyshevMetric;
+        assert_eq!(0, metric.distance(&[1u32, 2, 3], &[1, 2, 3]));
+        assert_eq!(2, metric.distance(&[1u32, 2, 3], &[3, 0, 2]));
+        assert_eq!(2, metric.distance(&[3u32, 0, 2], &[1, 2, 3]));
+        assert_eq!(
+            u32::MAX as Dist,
+            metric.distance(&[0u32, 1], &[u32::MAX, 0])
+        );
+        assert_eq!(7, metric.distance(&vec![1u32, 2], &vec![1, 2, 7]));
+        assert_eq!(0, metric.distance(&[][..], &[][..]));
+    }
+
+    #[test]
+    fn grid_neighbourhood() {
+        let alloc = BkInRamAllocator::new();
+        let mut tiles: BkInRamTree<U32ArrayKey<2>, ChebyshevMetric, _> =
+            BkInRamTree::new(ChebyshevMetric, &alloc);
+        for x in 0..10u32 {
+            for y in 0..10u32 {
+                tiles.add(&[x, y]).unwrap();
+            }
+        }
+        let mut found = Vec::new();
+        tiles.find_each(&[5, 5], 1, |_, k| found.push(*k));
+        found.sort();
+        assert_eq!(
+            vec![
+                [4, 4],
+                [4, 5],
+                [4, 6],
+                [5, 4],
+                [5, 5],
+                [5, 6],
+                [6, 4],
+                [6, 5],
+                [6, 6]
+            ],
+            found
+        );
+    }
+}
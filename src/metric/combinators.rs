@@ -0,0 +1,222 @@
+/*
+ * Metrics built out of other metrics.
+ *
+ * Sums and maxima of metrics are metrics, as are metrics scaled by a positive factor or clamped
+ * to a ceiling, so these compose freely. Sums and scalings saturate at `Dist::MAX` rather than
+ * overflow, which keeps them metrics. Sum and Max work over pairs, for use with
+ * `keys::PairKey`, and nest for wider tuples:
+ *
+ *   // Similar image hashes at nearby positions, with a grid step costing as much as two bits.
+ *   let metric = SumMetric::new(HammingMetric::default(), ScaledMetric::new(ManhattanMetric, 2));
+ *   let mut tiles: BkInRamTree<PairKey<u64, Vec<u32>>, _, _> = BkInRamTree::new(metric, &alloc);
+ */
+use crate::metric::Metric;
+use crate::Dist;
+
+/// The sum of two metrics over the two halves of a pair.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct SumMetric<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A, B> SumMetric<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        SumMetric { first, second }
+    }
+}
+
+impl<K1, K2, A, B> Metric<(K1, K2)> for SumMetric<A, B>
+where
+    A: Metric<K1>,
+    B: Metric<K2>,
+{
+    #[inline]
+    fn distance(&self, k1: &(K1, K2), k2: &(K1, K2)) -> Dist {
+        self.first
+            .distance(&k1.0, &k2.0)
+            .saturating_add(self.second.distance(&k1.1, &k2.1))
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &(K1, K2), k2: &(K1, K2)) -> Dist {
+        self.first
+            .lower_bound(&k1.0, &k2.0)
+            .saturating_add(self.second.lower_bound(&k1.1, &k2.1))
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(
+            self.first
+                .max_distance()?
+                .saturating_add(self.second.max_distance()?),
+        )
+    }
+}
+
+/// The larger of two metrics over the two halves of a pair.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct MaxMetric<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A, B> MaxMetric<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        MaxMetric { first, second }
+    }
+}
+
+impl<K1, K2, A, B> Metric<(K1, K2)> for MaxMetric<A, B>
+where
+    A: Metric<K1>,
+    B: Metric<K2>,
+{
+    #[inline]
+    fn distance(&self, k1: &(K1, K2), k2: &(K1, K2)) -> Dist {
+        self.first
+            .distance(&k1.0, &k2.0)
+            .max(self.second.distance(&k1.1, &k2.1))
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &(K1, K2), k2: &(K1, K2)) -> Dist {
+        self.first
+            .lower_bound(&k1.0, &k2.0)
+            .max(self.second.lower_bound(&k1.1, &k2.1))
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(self.first.max_distance()?.max(self.second.max_distance()?))
+    }
+}
+
+/// A metric multiplied by a constant factor, to weight it against others in a sum.
+#[derive(Clone, Copy, Debug)]
+pub struct ScaledMetric<M> {
+    pub inner: M,
+    pub factor: Dist,
+}
+
+impl<M> ScaledMetric<M> {
+    /// Panics if `factor` is 0, which would make distinct keys indistinguishable.
+    pub fn new(inner: M, factor: Dist) -> Self {
+        assert!(factor > 0, "ScaledMetric factor must be positive");
+        ScaledMetric { inner, factor }
+    }
+}
+
+impl<K: ?Sized, M: Metric<K>> Metric<K> for ScaledMetric<M> {
+    #[inline]
+    fn distance(&self, k1: &K, k2: &K) -> Dist {
+        self.inner.distance(k1, k2).saturating_mul(self.factor)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &K, k2: &K) -> Dist {
+        self.inner.lower_bound(k1, k2).saturating_mul(self.factor)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(self.inner.max_distance()?.saturating_mul(self.factor))
+    }
+}
+
+/// A metric capped at `max`: everything further away than `max` is exactly `max` away. Keeps
+/// one wildly different field from dominating a sum, and bounds the fan out of tree nodes.
+#[derive(Clone, Copy, Debug)]
+pub struct ClampedMetric<M> {
+    pub inner: M,
+    pub max: Dist,
+}
+
+impl<M> ClampedMetric<M> {
+    pub fn new(inner: M, max: Dist) -> Self {
+        ClampedMetric { inner, max }
+    }
+}
+
+impl<K: ?Sized, M: Metric<K>> Metric<K> for ClampedMetric<M> {
+    #[inline]
+    fn distance(&self, k1: &K, k2: &K) -> Dist {
+        self.inner.distance(k1, k2).min(self.max)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &K, k2: &K) -> Dist {
+        self.inner.lower_bound(k1, k2).min(self.max)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(
+            self.inner
+                .max_distance()
+                .map_or(self.max, |max| max.min(self.max)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::PairKey;
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::manhattan::ManhattanMetric;
+    use crate::metric::strlen::StrLenMetric;
+
+    #[test]
+    fn combined_distances() {
+        let hamming: HammingMetric<u64> = Default::default();
+        let a = (0b1111u64, 0b0000u64);
+        let b = (0b0000u64, 0b0001u64);
+        assert_eq!(5, SumMetric::new(hamming, hamming).distance(&a, &b));
+        assert_eq!(4, MaxMetric::new(hamming, hamming).distance(&a, &b));
+        assert_eq!(
+            6,
+            SumMetric::new(
+                ClampedMetric::new(hamming, 2),
+                ScaledMetric::new(hamming, 4)
+            )
+            .distance(&a, &b)
+        );
+        assert_eq!(6, ScaledMetric::new(StrLenMetric, 3).distance("abc", "a"));
+        assert_eq!(1, ClampedMetric::new(StrLenMetric, 1).distance("abc", "a"));
+
+        let huge = ScaledMetric::new(hamming, Dist::MAX);
+        assert_eq!(Dist::MAX, huge.distance(&0b11, &0));
+        assert_eq!(Dist::MAX, SumMetric::new(huge, hamming).distance(&a, &b));
+        assert_eq!(Some(Dist::MAX), huge.max_distance());
+    }
+
+    #[test]
+    fn tree_over_pairs() {
+        let alloc = BkInRamAllocator::new();
+        let metric = SumMetric::new(
+            HammingMetric::default(),
+            ScaledMetric::new(ManhattanMetric, 2),
+        );
+        let mut tiles: BkInRamTree<PairKey<u64, Vec<u32>>, _, _> = BkInRamTree::new(metric, &alloc);
+        for (hash, pos) in &[
+            (0b1010u64, vec![3, 3]),
+            (0b1011, vec![3, 3]),
+            (0b1010, vec![3, 4]),
+            (0b1010, vec![9, 9]),
+            (0b0101, vec![3, 3]),
+        ] {
+            tiles.add(&(*hash, pos.clone())).unwrap();
+        }
+        let mut found = Vec::new();
+        tiles.find_each(&(0b1010, vec![3, 3]), 2, |d, k| found.push((d, k.clone())));
+        found.sort();
+        assert_eq!(
+            vec![
+                (0, (0b1010, vec![3, 3])),
+                (1, (0b1011, vec![3, 3])),
+                (2, (0b1010, vec![3, 4])),
+            ],
+            found
+        );
+    }
+}
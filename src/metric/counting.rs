@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metric::Metric;
+use crate::Dist;
+
+/// Wraps a metric to count how often it's used, for tuning tolerances and comparing tree
+/// layouts. Counts are shared by everything using the metric, e.g. all of a tree's queries:
+///
+///   tree.metric().reset();
+///   tree.find_each(needle, 3, |_, _| ());
+///   println!("{} distances", tree.metric().distances());
+#[derive(Debug, Default)]
+pub struct CountingMetric<M> {
+    pub inner: M,
+    distances: AtomicU64,
+    lower_bounds: AtomicU64,
+}
+
+impl<M> CountingMetric<M> {
+    pub fn new(inner: M) -> Self {
+        CountingMetric {
+            inner,
+            distances: AtomicU64::new(0),
+            lower_bounds: AtomicU64::new(0),
+        }
+    }
+
+    /// Full distance computations so far.
+    pub fn distances(&self) -> u64 {
+        self.distances.load(Ordering::Relaxed)
+    }
+
+    /// Lower bound computations so far.
+    pub fn lower_bounds(&self) -> u64 {
+        self.lower_bounds.load(Ordering::Relaxed)
+    }
+
+    /// Zero the counts, returning the distance count from before.
+    pub fn reset(&self) -> u64 {
+        self.lower_bounds.store(0, Ordering::Relaxed);
+        self.distances.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl<K: ?Sized, M: Metric<K>> Metric<K> for CountingMetric<M> {
+    #[inline]
+    fn distance(&self, k1: &K, k2: &K) -> Dist {
+        self.distances.fetch_add(1, Ordering::Relaxed);
+        self.inner.distance(k1, k2)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &K, k2: &K) -> Dist {
+        self.lower_bounds.fetch_add(1, Ordering::Relaxed);
+        self.inner.lower_bound(k1, k2)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        self.inner.max_distance()
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        self.inner.registered_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn counts_query_work() {
+        let mut tree: BkInRamTree<U64Key, CountingMetric<HammingMetric<u64>>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..256u64 {
+            tree.add(&i).unwrap();
+        }
+        assert!(tree.metric().reset() > 0);
+
+        tree.find_each(&0, 64, |_, _| ());
+        assert_eq!(256, tree.metric().reset());
+
+        tree.find_each(&0, 1, |_, _| ());
+        let narrow = tree.metric().distances();
+        assert!(0 < narrow && narrow < 256, "{}", narrow);
+        assert!(tree.metric().lower_bounds() >= narrow - 1);
+    }
+}
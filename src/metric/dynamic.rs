@@ -0,0 +1,305 @@
+/*
+ * Runtime metric selection, for binaries and servers that pick their metric from configuration
+ * rather than being compiled once per metric.
+ *
+ * Dynamic metrics all work on raw bytes, so they pair with `keys::BytesKey`:
+ *
+ *   let metric = metric::by_name("levenshtein")?;
+ *   let mut tree: BkInRamTree<BytesKey, Box<dyn DynMetric>, _> = BkInRamTree::new(metric, &alloc);
+ *   tree.add(b"kitten")?;
+ *
+ * Applications add metrics of their own to the names `by_name` knows with `register`.
+ */
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use crate::metric::chebyshev::ChebyshevMetric;
+use crate::metric::jaccard::{quantized_jaccard, DEFAULT_JACCARD_SCALE};
+#[cfg(feature = "unicode")]
+use crate::metric::levenshtein::GraphemeLevenshteinMetric;
+use crate::metric::levenshtein::{levenshtein, LevenshteinMetric};
+use crate::metric::manhattan::ManhattanMetric;
+use crate::metric::qgram::QGramMetric;
+use crate::metric::strlen::StrLenMetric;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// An object safe metric over byte string keys.
+pub trait DynMetric: Debug + Send + Sync {
+    /// The name `by_name` knows this metric by.
+    fn name(&self) -> &str;
+    fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist;
+    fn lower_bound(&self, _k1: &[u8], _k2: &[u8]) -> Dist {
+        0
+    }
+}
+
+impl Metric<[u8]> for Box<dyn DynMetric> {
+    #[inline]
+    fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        (**self).distance(k1, k2)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        (**self).lower_bound(k1, k2)
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some((**self).name())
+    }
+}
+
+/// A built in metric, adapted to bytes.
+#[derive(Debug, Clone, Copy)]
+struct NamedMetric {
+    name: &'static str,
+    distance: fn(&[u8], &[u8]) -> Dist,
+}
+
+impl DynMetric for NamedMetric {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    #[inline]
+    fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        (self.distance)(k1, k2)
+    }
+}
+
+fn zero_padded_bytes<'a>(k1: &'a [u8], k2: &'a [u8]) -> impl 'a + Iterator<Item = (u8, u8)> {
+    let len = k1.len().max(k2.len());
+    (0..len).map(move |i| {
+        (
+            k1.get(i).cloned().unwrap_or(0),
+            k2.get(i).cloned().unwrap_or(0),
+        )
+    })
+}
+
+/// Little endian u32s, with a short final word zero padded.
+fn u32_words(key: &[u8]) -> Vec<u32> {
+    key.chunks(4)
+        .map(|chunk| {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+fn hamming_bytes(k1: &[u8], k2: &[u8]) -> Dist {
+    zero_padded_bytes(k1, k2)
+        .map(|(a, b)| (a ^ b).count_ones() as Dist)
+        .sum()
+}
+
+fn jaccard_bytes(k1: &[u8], k2: &[u8]) -> Dist {
+    let (intersection, union) = zero_padded_bytes(k1, k2).fold((0, 0), |(i, u), (a, b)| {
+        (i + (a & b).count_ones(), u + (a | b).count_ones())
+    });
+    quantized_jaccard(intersection as Dist, union as Dist, DEFAULT_JACCARD_SCALE)
+}
+
+const BUILT_IN: &[NamedMetric] = &[
+    NamedMetric {
+        name: "hamming",
+        distance: hamming_bytes,
+    },
+    NamedMetric {
+        name: "hamming-u64",
+        distance: hamming_bytes,
+    },
+    NamedMetric {
+        name: "levenshtein",
+        distance: |k1, k2| {
+            LevenshteinMetric.distance(&String::from_utf8_lossy(k1), &String::from_utf8_lossy(k2))
+        },
+    },
+    NamedMetric {
+        name: "levenshtein-bytes",
+        distance: |k1, k2| levenshtein(k1, k2),
+    },
+    NamedMetric {
+        name: "strlen",
+        distance: |k1, k2| {
+            StrLenMetric.distance(&String::from_utf8_lossy(k1), &String::from_utf8_lossy(k2))
+        },
+    },
+    NamedMetric {
+        name: "qgram",
+        distance: |k1, k2| {
+            QGramMetric::default()
+                .distance(&String::from_utf8_lossy(k1), &String::from_utf8_lossy(k2))
+        },
+    },
+    NamedMetric {
+        name: "jaccard",
+        distance: jaccard_bytes,
+    },
+    NamedMetric {
+        name: "manhattan-u32",
+        distance: |k1, k2| ManhattanMetric.distance(&u32_words(k1)[..], &u32_words(k2)[..]),
+    },
+    NamedMetric {
+        name: "chebyshev-u32",
+        distance: |k1, k2| ChebyshevMetric.distance(&u32_words(k1)[..], &u32_words(k2)[..]),
+    },
+    #[cfg(feature = "unicode")]
+    NamedMetric {
+        name: "levenshtein-graphemes",
+        distance: |k1, k2| {
+            GraphemeLevenshteinMetric
+                .distance(&String::from_utf8_lossy(k1), &String::from_utf8_lossy(k2))
+        },
+    },
+];
+
+/// Makes a metric for `by_name`.
+pub type MetricConstructor = fn() -> Box<dyn DynMetric>;
+
+/// Metrics added with `register`, looked up before the built in ones.
+static REGISTERED: RwLock<Vec<(&'static str, MetricConstructor)>> = RwLock::new(Vec::new());
+
+/// Have `by_name` make metrics named `name` with `constructor`, replacing whatever it made for
+/// that name before. For applications with metrics of their own, e.g. to open bkfiles that
+/// record them by name.
+pub fn register(name: &'static str, constructor: MetricConstructor) {
+    let mut registered = REGISTERED.write().unwrap();
+    registered.retain(|(known, _)| *known != name);
+    registered.push((name, constructor));
+}
+
+/// Names accepted by `by_name`.
+pub fn names() -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = BUILT_IN.iter().map(|m| m.name).collect();
+    for (name, _) in REGISTERED.read().unwrap().iter() {
+        if !names.contains(name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Look a metric up by name: a `register`ed one, or a built in one. Byte keys are interpreted
+/// per built in metric: bitsets for hamming and jaccard, UTF-8 for levenshtein, strlen and
+/// qgram, and little endian u32 vectors for manhattan-u32 and chebyshev-u32.
+pub fn by_name(name: &str) -> Result<Box<dyn DynMetric>, Box<dyn Error>> {
+    let registered = REGISTERED
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, constructor)| *constructor);
+    if let Some(constructor) = registered {
+        return Ok(constructor());
+    }
+    match BUILT_IN.iter().find(|m| m.name == name) {
+        Some(metric) => Ok(Box::new(*metric)),
+        None => Err(format!(
+            "Unknown metric {:?} (expected one of: {})",
+            name,
+            names().join(", ")
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::BytesKey;
+
+    #[test]
+    fn looks_up_metrics() {
+        let hamming = by_name("hamming-u64").unwrap();
+        assert_eq!("hamming-u64", hamming.name());
+        assert_eq!(
+            3,
+            hamming.distance(&1u64.to_le_bytes(), &(1u64 << 40 | 2).to_le_bytes())
+        );
+        let lev = by_name("levenshtein").unwrap();
+        assert_eq!(3, lev.distance(b"kitten", b"sitting"));
+        assert_eq!(1, lev.distance("naïve".as_bytes(), b"naive"));
+        assert_eq!(
+            2,
+            by_name("levenshtein-bytes")
+                .unwrap()
+                .distance("naïve".as_bytes(), b"naive")
+        );
+        assert_eq!(
+            500,
+            by_name("jaccard").unwrap().distance(&[0b0111], &[0b1110])
+        );
+        let words =
+            |v: &[u32]| -> Vec<u8> { v.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect() };
+        assert_eq!(
+            6,
+            by_name("manhattan-u32")
+                .unwrap()
+                .distance(&words(&[1, 2, 3]), &words(&[3, 0, 1]))
+        );
+        assert_eq!(
+            2,
+            by_name("chebyshev-u32")
+                .unwrap()
+                .distance(&words(&[1, 2, 3]), &words(&[3, 0, 1]))
+        );
+
+        let err = by_name("cosine").unwrap_err().to_string();
+        assert!(
+            err.contains("cosine") && err.contains("levenshtein"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn tree_with_runtime_metric() {
+        let alloc = BkInRamAllocator::new();
+        let mut tree: BkInRamTree<BytesKey, Box<dyn DynMetric>, _> =
+            BkInRamTree::new(by_name("levenshtein").unwrap(), &alloc);
+        for word in &["book", "books", "cake", "boo", "cape", "boon", "cook"] {
+            tree.add(word.as_bytes()).unwrap();
+        }
+        let mut found = Vec::new();
+        tree.find_each(b"bool", 1, |d, k| {
+            found.push((d, String::from_utf8(k.clone()).unwrap()))
+        });
+        found.sort();
+        assert_eq!(
+            vec![
+                (1, "boo".to_string()),
+                (1, "book".to_string()),
+                (1, "boon".to_string())
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn registers_metrics() {
+        #[derive(Debug)]
+        struct Length;
+        impl DynMetric for Length {
+            fn name(&self) -> &str {
+                "test-length"
+            }
+            fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+                (k1.len() as i64 - k2.len() as i64).unsigned_abs() as Dist
+            }
+        }
+        assert!(by_name("test-length").is_err());
+        register("test-length", || Box::new(Length));
+        assert!(names().contains(&"test-length"));
+        let metric = by_name("test-length").unwrap();
+        assert_eq!(
+            (Some("test-length"), 2),
+            (metric.registered_name(), metric.distance(b"a", b"abc"))
+        );
+    }
+}
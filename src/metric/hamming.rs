@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::marker::PhantomData;
 use std::ops::BitXor;
 
@@ -5,65 +6,300 @@ use crate::metric::Metric;
 use crate::Dist;
 
 pub trait CountOnes {
-    #[inline]
     fn count_ones(self) -> u32;
+
+    /// Bits in the type, if every value has the same number.
+    fn bits() -> Option<u32> {
+        None
+    }
 }
 impl CountOnes for u8 {
     #[inline]
     fn count_ones(self) -> u32 {
         self.count_ones()
     }
+
+    fn bits() -> Option<u32> {
+        Some(u8::BITS)
+    }
 }
 impl CountOnes for u16 {
     #[inline]
     fn count_ones(self) -> u32 {
         self.count_ones()
     }
+
+    fn bits() -> Option<u32> {
+        Some(u16::BITS)
+    }
 }
 impl CountOnes for u32 {
     #[inline]
     fn count_ones(self) -> u32 {
         self.count_ones()
     }
+
+    fn bits() -> Option<u32> {
+        Some(u32::BITS)
+    }
 }
 impl CountOnes for u64 {
     #[inline]
     fn count_ones(self) -> u32 {
         self.count_ones()
     }
+
+    fn bits() -> Option<u32> {
+        Some(u64::BITS)
+    }
 }
 impl CountOnes for u128 {
     #[inline]
     fn count_ones(self) -> u32 {
         self.count_ones()
     }
+
+    fn bits() -> Option<u32> {
+        Some(u128::BITS)
+    }
+}
+
+/// Number of differing bits between two values. Implemented for everything whose references can
+/// be XORed into something `CountOnes`, which covers the primitive integers and Clone-only big
+/// integer hash types alike; implement it directly for types that can't offer that.
+pub trait HammingOps {
+    fn hamming(&self, other: &Self) -> u32;
+
+    /// The most bits two values can differ by, if there's a limit.
+    fn max_hamming() -> Option<u32> {
+        None
+    }
+}
+
+impl<I> HammingOps for I
+where
+    for<'a> &'a I: BitXor<&'a I>,
+    for<'a> <&'a I as BitXor<&'a I>>::Output: CountOnes,
+{
+    #[inline]
+    fn hamming(&self, other: &Self) -> u32 {
+        (self ^ other).count_ones()
+    }
+
+    fn max_hamming() -> Option<u32> {
+        <&I as BitXor<&I>>::Output::bits()
+    }
 }
 
-#[derive(Default, Clone, Copy, Derivative)]
-#[derivative(Debug)]
+// Derivative, so the metric is Default and Copy whether or not the keys are.
+#[derive(Derivative)]
+#[derivative(Debug, Default(bound = ""), Clone(bound = ""), Copy(bound = ""))]
 pub struct HammingMetric<I>(#[derivative(Debug = "ignore")] PhantomData<I>)
 where
-    I: BitXor<I>,
-    <I as BitXor<I>>::Output: CountOnes;
+    I: HammingOps;
 
 impl<I> Metric<I> for HammingMetric<I>
 where
-    I: Copy + BitXor<I>,
-    <I as BitXor<I>>::Output: CountOnes,
+    I: HammingOps,
 {
     #[inline]
     fn distance(&self, k1: &I, k2: &I) -> Dist {
-        (*k1 ^ *k2).count_ones() as usize
+        k1.hamming(k2) as Dist
     }
 
     #[inline]
     fn distance_static(k1: &I, k2: &I) -> Dist {
-        (*k1 ^ *k2).count_ones() as usize
+        k1.hamming(k2) as Dist
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        I::max_hamming().map(|bits| bits as Dist)
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        // Bitwise over little endian bytes, so any width of integer measures as "hamming" does.
+        match I::max_hamming() {
+            Some(64) => Some("hamming-u64"),
+            _ => Some("hamming"),
+        }
+    }
+}
+
+/// Hamming distance over fixed-size byte arrays, e.g. `[u8; 32]` for 256-bit hashes. Use with
+/// `keys::ArrayKey<N>`.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ArrayHammingMetric<const N: usize>;
+
+#[inline]
+fn array_hamming<const N: usize>(k1: &[u8; N], k2: &[u8; N]) -> Dist {
+    // Whole words first, then whatever bytes are left over.
+    let words1 = k1.chunks_exact(8);
+    let words2 = k2.chunks_exact(8);
+    let tail: u32 = words1
+        .remainder()
+        .iter()
+        .zip(words2.remainder())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    let words: u32 = words1
+        .zip(words2)
+        .map(|(a, b)| {
+            let a = u64::from_ne_bytes(a.try_into().unwrap());
+            let b = u64::from_ne_bytes(b.try_into().unwrap());
+            (a ^ b).count_ones()
+        })
+        .sum();
+    (words + tail) as Dist
+}
+
+impl<const N: usize> Metric<[u8; N]> for ArrayHammingMetric<N> {
+    #[inline]
+    fn distance(&self, k1: &[u8; N], k2: &[u8; N]) -> Dist {
+        array_hamming(k1, k2)
+    }
+
+    #[inline]
+    fn distance_static(k1: &[u8; N], k2: &[u8; N]) -> Dist {
+        array_hamming(k1, k2)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(8 * N)
+    }
+}
+
+/// Hamming distance over multi-word bit strings, e.g. 256-bit pHashes stored as four u64s. Use
+/// with `keys::VecKey<u64>` or `keys::U64ArrayKey<N>`.
+///
+/// Strings of different lengths are compared as if the shorter were padded with zero words.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct MultiWordHammingMetric;
+
+#[inline]
+fn multi_word_hamming(k1: &[u64], k2: &[u64]) -> Dist {
+    let (short, long) = if k1.len() <= k2.len() {
+        (k1, k2)
+    } else {
+        (k2, k1)
+    };
+    let common: u32 = short
+        .iter()
+        .zip(long)
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    let rest: u32 = long[short.len()..].iter().map(|w| w.count_ones()).sum();
+    (common + rest) as Dist
+}
+
+impl Metric<[u64]> for MultiWordHammingMetric {
+    #[inline]
+    fn distance(&self, k1: &[u64], k2: &[u64]) -> Dist {
+        multi_word_hamming(k1, k2)
+    }
+}
+
+impl Metric<Vec<u64>> for MultiWordHammingMetric {
+    #[inline]
+    fn distance(&self, k1: &Vec<u64>, k2: &Vec<u64>) -> Dist {
+        multi_word_hamming(k1, k2)
+    }
+}
+
+impl<const N: usize> Metric<[u64; N]> for MultiWordHammingMetric {
+    #[inline]
+    fn distance(&self, k1: &[u64; N], k2: &[u64; N]) -> Dist {
+        multi_word_hamming(k1, k2)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(64 * N)
+    }
+}
+
+/// The distance from `needle` to each of a block of keys, pushed onto `out` in order. `keys` is
+/// little endian u64s back to back, as fixed 64 bit bkfiles store a node's children's keys;
+/// trailing bytes short of a whole key are ignored. With the `simd` feature, blocks are done
+/// four keys at a time with AVX2 where the CPU has it, or two at a time with NEON.
+pub fn hamming_block(needle: u64, keys: &[u8], out: &mut Vec<Dist>) {
+    let whole = keys.len() / 8 * 8;
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safe: the CPU has AVX2.
+            unsafe { simd::hamming_block_avx2(needle, &keys[..whole], out) };
+            return;
+        }
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    {
+        // Safe: NEON is part of aarch64.
+        unsafe { simd::hamming_block_neon(needle, &keys[..whole], out) };
+        return;
+    }
+    #[allow(unreachable_code)]
+    hamming_block_scalar(needle, &keys[..whole], out);
+}
+
+fn hamming_block_scalar(needle: u64, keys: &[u8], out: &mut Vec<Dist>) {
+    out.extend(
+        keys.chunks_exact(8)
+            .map(|key| (u64::from_le_bytes(key.try_into().unwrap()) ^ needle).count_ones() as Dist),
+    );
+}
+
+#[cfg(feature = "simd")]
+mod simd {
+    use super::hamming_block_scalar;
+    use crate::Dist;
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn hamming_block_avx2(needle: u64, keys: &[u8], out: &mut Vec<Dist>) {
+        use std::arch::x86_64::*;
+
+        // Bits set in each nibble, looked up a byte at a time with a shuffle.
+        let lookup = _mm256_setr_epi8(
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, //
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        );
+        let nibble = _mm256_set1_epi8(0x0f);
+        let needles = _mm256_set1_epi64x(needle.to_le() as i64);
+        let blocks = keys.chunks_exact(32);
+        let rest = blocks.remainder();
+        let mut sums = [0u64; 4];
+        for block in blocks {
+            let bits = _mm256_xor_si256(
+                _mm256_loadu_si256(block.as_ptr() as *const __m256i),
+                needles,
+            );
+            let low = _mm256_shuffle_epi8(lookup, _mm256_and_si256(bits, nibble));
+            let high =
+                _mm256_shuffle_epi8(lookup, _mm256_and_si256(_mm256_srli_epi16(bits, 4), nibble));
+            // Summing each key's 8 byte counts gives its distance, in its own 64 bit lane.
+            let counts = _mm256_sad_epu8(_mm256_add_epi8(low, high), _mm256_setzero_si256());
+            _mm256_storeu_si256(sums.as_mut_ptr() as *mut __m256i, counts);
+            out.extend(sums.iter().map(|sum| *sum as Dist));
+        }
+        hamming_block_scalar(needle, rest, out);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub(super) unsafe fn hamming_block_neon(needle: u64, keys: &[u8], out: &mut Vec<Dist>) {
+        use std::arch::aarch64::*;
+
+        let needles = vreinterpretq_u8_u64(vdupq_n_u64(needle.to_le()));
+        let blocks = keys.chunks_exact(16);
+        let rest = blocks.remainder();
+        for block in blocks {
+            let counts = vcntq_u8(veorq_u8(vld1q_u8(block.as_ptr()), needles));
+            // Pairwise widening adds, down to a sum per 64 bit lane.
+            let sums = vpaddlq_u32(vpaddlq_u16(vpaddlq_u8(counts)));
+            out.push(vgetq_lane_u64::<0>(sums) as Dist);
+            out.push(vgetq_lane_u64::<1>(sums) as Dist);
+        }
+        hamming_block_scalar(needle, rest, out);
     }
 }
-// TODO: figure out how to declare a HammingMetric over Clone and over BitXor<&I> that doesn't conflict with the above
-// implementation for Copy. (The code difference is k1.clone() instead of *k1 for Clone and no
-// deref for BitXor<&I>). Better yet, handle a constraint that means <&I as BitXor<&I>>::Output: CountOnes.
 
 #[cfg(test)]
 mod tests {
@@ -79,4 +315,115 @@ mod tests {
         assert_eq!(2usize, metric.distance(&1u64, &2u64));
         assert_eq!(1usize, metric.distance(&0u64, &2u64));
     }
+
+    #[test]
+    fn blocks_match_one_at_a_time() {
+        let keys: Vec<u64> = (0..37u64)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15))
+            .collect();
+        let mut bytes: Vec<u8> = keys.iter().flat_map(|k| k.to_le_bytes()).collect();
+        // Not a whole key, so ignored.
+        bytes.push(0xff);
+        let metric: HammingMetric<u64> = Default::default();
+        for &needle in &[0, u64::MAX, keys[5] ^ 0b1011] {
+            let mut found = Vec::new();
+            hamming_block(needle, &bytes, &mut found);
+            let expected: Vec<Dist> = keys.iter().map(|k| metric.distance(k, &needle)).collect();
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn clone_only_hamming_distance() {
+        use std::ops::BitXor;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct U256(Box<[u64; 4]>);
+
+        impl<'a> BitXor<&'a U256> for &'a U256 {
+            type Output = U256;
+            fn bitxor(self, other: &U256) -> U256 {
+                let mut words = [0u64; 4];
+                for (i, w) in words.iter_mut().enumerate() {
+                    *w = self.0[i] ^ other.0[i];
+                }
+                U256(Box::new(words))
+            }
+        }
+
+        impl CountOnes for U256 {
+            fn count_ones(self) -> u32 {
+                self.0.iter().map(|w| w.count_ones()).sum()
+            }
+        }
+
+        let metric: HammingMetric<U256> = Default::default();
+        let a = U256(Box::new([0, 0b111, 0, 1 << 63]));
+        let b = U256(Box::new([0, 0b100, 0, 0]));
+        assert_eq!(3usize, metric.distance(&a, &b));
+        assert_eq!(0usize, metric.distance(&a, &a.clone()));
+    }
+
+    #[test]
+    fn multi_word_hamming_distance() {
+        let metric = MultiWordHammingMetric;
+        assert_eq!(0, metric.distance(&[1u64, 2, 3, 4], &[1, 2, 3, 4]));
+        assert_eq!(
+            66,
+            metric.distance(&[0u64, u64::MAX, 0, 0b11], &[0, 0, 0, 0])
+        );
+        assert_eq!(3, metric.distance(&vec![1u64], &vec![0, 0b11]));
+        assert_eq!(3, metric.distance(&[0u64, 0b11][..], &[1u64][..]));
+    }
+
+    #[test]
+    fn multi_word_tree_find() {
+        use crate::bk::{BkInRamAllocator, BkInRamTree};
+        use crate::bktree::{BkTree, BkTreeAdd};
+        use crate::keys::U64ArrayKey;
+
+        let alloc = BkInRamAllocator::new();
+        let mut tree: BkInRamTree<U64ArrayKey<4>, MultiWordHammingMetric, _> =
+            BkInRamTree::new(MultiWordHammingMetric, &alloc);
+        for i in 0..64u64 {
+            tree.add(&[i, i << 8, !i, 0]).unwrap();
+        }
+        let mut found = Vec::new();
+        tree.find_each(&[5, 5 << 8, !5, 1], 1, |d, k| found.push((d, *k)));
+        assert_eq!(vec![(1, [5, 5 << 8, !5, 0])], found);
+    }
+
+    #[test]
+    fn array_hamming_distance() {
+        let metric: ArrayHammingMetric<12> = Default::default();
+        let zero = [0u8; 12];
+        let mut other = [0u8; 12];
+        assert_eq!(0usize, metric.distance(&zero, &other));
+        other[0] = 0b101;
+        other[11] = 0xff;
+        assert_eq!(10usize, metric.distance(&zero, &other));
+        assert_eq!(10usize, metric.distance(&other, &zero));
+    }
+
+    #[test]
+    fn array_tree_find() {
+        use crate::bk::{BkInRamAllocator, BkInRamTree};
+        use crate::bktree::{BkTree, BkTreeAdd};
+        use crate::keys::ArrayKey;
+
+        let alloc = BkInRamAllocator::new();
+        let mut tree: BkInRamTree<ArrayKey<32>, ArrayHammingMetric<32>, _> =
+            BkInRamTree::new(Default::default(), &alloc);
+        for i in 0..64u8 {
+            let mut key = [0u8; 32];
+            key[(i / 8) as usize * 4] = 1 << (i % 8);
+            key[31] = i;
+            tree.add(&key).unwrap();
+        }
+        let mut needle = [0u8; 32];
+        needle[0] = 1;
+        let mut results = Vec::new();
+        tree.find_each(&needle, 1, |d, k| results.push((d, *k)));
+        assert_eq!(vec![(0, needle)], results);
+    }
 }
@@ -0,0 +1,162 @@
+use std::marker::PhantomData;
+use std::ops::{BitAnd, BitOr};
+
+use crate::metric::hamming::CountOnes;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// Jaccard distances are scaled to 0..=DEFAULT_JACCARD_SCALE unless configured otherwise.
+pub const DEFAULT_JACCARD_SCALE: Dist = 1000;
+
+/// ceil(scale * (1 - intersection / union)).
+///
+/// Rounding up keeps the triangle inequality: ceil(a) + ceil(b) >= ceil(a + b). It also keeps
+/// distinct sets at least 1 apart. Two empty sets are identical.
+#[inline]
+pub(crate) fn quantized_jaccard(intersection: Dist, union: Dist, scale: Dist) -> Dist {
+    if union == 0 {
+        return 0;
+    }
+    (scale * (union - intersection)).div_ceil(union)
+}
+
+/// Jaccard (Tanimoto) distance between integer keys treated as bitsets, e.g. u64 minhash
+/// sketches or u128 fingerprints. Use with `keys::U64Key` for u64s.
+#[derive(Clone, Copy, Derivative)]
+#[derivative(Debug)]
+pub struct JaccardMetric<I> {
+    scale: Dist,
+    #[derivative(Debug = "ignore")]
+    _bits: PhantomData<I>,
+}
+
+impl<I> JaccardMetric<I> {
+    /// Distances range over 0..=scale. A larger scale resolves finer differences, at the cost of
+    /// needing proportionally larger tolerances.
+    pub fn with_scale(scale: Dist) -> Self {
+        assert!(scale >= 1, "Jaccard scale must be at least 1");
+        JaccardMetric {
+            scale,
+            _bits: PhantomData,
+        }
+    }
+
+    pub fn scale(&self) -> Dist {
+        self.scale
+    }
+}
+
+impl<I> Default for JaccardMetric<I> {
+    fn default() -> Self {
+        Self::with_scale(DEFAULT_JACCARD_SCALE)
+    }
+}
+
+impl<I> Metric<I> for JaccardMetric<I>
+where
+    I: Copy + BitAnd<I> + BitOr<I>,
+    <I as BitAnd<I>>::Output: CountOnes,
+    <I as BitOr<I>>::Output: CountOnes,
+{
+    #[inline]
+    fn distance(&self, k1: &I, k2: &I) -> Dist {
+        let intersection = (*k1 & *k2).count_ones() as Dist;
+        let union = (*k1 | *k2).count_ones() as Dist;
+        quantized_jaccard(intersection, union, self.scale)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(self.scale)
+    }
+}
+
+/// Jaccard distance between fixed-size bit vectors, e.g. 2048 bit chemical fingerprints as
+/// `[u8; 256]`. Use with `keys::ArrayKey<N>`.
+#[derive(Clone, Copy, Debug)]
+pub struct ArrayJaccardMetric<const N: usize> {
+    scale: Dist,
+}
+
+impl<const N: usize> ArrayJaccardMetric<N> {
+    pub fn with_scale(scale: Dist) -> Self {
+        assert!(scale >= 1, "Jaccard scale must be at least 1");
+        ArrayJaccardMetric { scale }
+    }
+
+    pub fn scale(&self) -> Dist {
+        self.scale
+    }
+}
+
+impl<const N: usize> Default for ArrayJaccardMetric<N> {
+    fn default() -> Self {
+        Self::with_scale(DEFAULT_JACCARD_SCALE)
+    }
+}
+
+impl<const N: usize> Metric<[u8; N]> for ArrayJaccardMetric<N> {
+    #[inline]
+    fn distance(&self, k1: &[u8; N], k2: &[u8; N]) -> Dist {
+        let (intersection, union) = k1.iter().zip(k2.iter()).fold((0, 0), |(i, u), (a, b)| {
+            (i + (a & b).count_ones(), u + (a | b).count_ones())
+        });
+        quantized_jaccard(intersection as Dist, union as Dist, self.scale)
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        Some(self.scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jaccard_distance() {
+        let metric: JaccardMetric<u64> = Default::default();
+        assert_eq!(0, metric.distance(&0, &0));
+        assert_eq!(0, metric.distance(&0b1011, &0b1011));
+        assert_eq!(1000, metric.distance(&0b0011, &0b1100));
+        // 2 shared of 4 set.
+        assert_eq!(500, metric.distance(&0b0111, &0b1110));
+        // 1 shared of 3 set: 666.67 rounds up.
+        assert_eq!(667, metric.distance(&0b011, &0b110));
+        assert_eq!(1000, metric.distance(&0, &1));
+        assert_eq!(Some(1000), metric.max_distance());
+
+        let coarse: JaccardMetric<u128> = JaccardMetric::with_scale(10);
+        assert_eq!(1, coarse.distance(&(u128::MAX - 1), &u128::MAX));
+    }
+
+    #[test]
+    fn array_jaccard_matches_integer_jaccard() {
+        let ints: JaccardMetric<u64> = Default::default();
+        let arrays: ArrayJaccardMetric<8> = Default::default();
+        assert_eq!(ints.max_distance(), arrays.max_distance());
+        let keys = [0u64, 1, 0xff, 0xf0f0, 0x1234_5678_9abc_def0, u64::MAX];
+        for a in keys.iter() {
+            for b in keys.iter() {
+                assert_eq!(
+                    ints.distance(a, b),
+                    arrays.distance(&a.to_le_bytes(), &b.to_le_bytes())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_inequality() {
+        let metric: JaccardMetric<u8> = JaccardMetric::with_scale(7);
+        for a in 0..=255u8 {
+            for b in (0..=255u8).step_by(7) {
+                for c in (0..=255u8).step_by(13) {
+                    assert!(
+                        metric.distance(&a, &c)
+                            <= metric.distance(&a, &b) + metric.distance(&b, &c)
+                    );
+                }
+            }
+        }
+    }
+}
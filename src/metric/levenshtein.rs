@@ -0,0 +1,156 @@
+use std::cmp::min;
+
+use crate::metric::Metric;
+use crate::Dist;
+
+/// Unit cost edit distance between two strings, counted in chars.
+///
+/// Use with `keys::StringKey`.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct LevenshteinMetric;
+
+/// Standard O(n*m) dynamic program, keeping only the previous and current rows.
+pub fn levenshtein<T: PartialEq>(a: &[T], b: &[T]) -> Dist {
+    // Iterate over the longer input so the rows are as short as possible.
+    let (a, b) = if a.len() < b.len() { (b, a) } else { (a, b) };
+    let mut prev: Vec<Dist> = (0..=b.len()).collect();
+    let mut cur: Vec<Dist> = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == cb { 0 } else { 1 };
+            cur[j + 1] = min(substitute, min(prev[j + 1], cur[j]) + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+fn char_levenshtein(k1: &str, k2: &str) -> Dist {
+    if k1 == k2 {
+        return 0;
+    }
+    let a: Vec<char> = k1.chars().collect();
+    let b: Vec<char> = k2.chars().collect();
+    levenshtein(&a, &b)
+}
+
+impl Metric<str> for LevenshteinMetric {
+    #[inline]
+    fn distance(&self, k1: &str, k2: &str) -> Dist {
+        char_levenshtein(k1, k2)
+    }
+
+    #[inline]
+    fn distance_static(k1: &str, k2: &str) -> Dist {
+        char_levenshtein(k1, k2)
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some("levenshtein")
+    }
+}
+
+/// Unit cost edit distance between two strings, counted in extended grapheme clusters: what a
+/// reader sees as one character, e.g. "e" plus a combining accent, or a flag emoji made of two
+/// regional indicators, is one edit rather than several.
+///
+/// Use with `keys::StringKey`.
+#[cfg(feature = "unicode")]
+#[derive(Default, Clone, Copy, Debug)]
+pub struct GraphemeLevenshteinMetric;
+
+#[cfg(feature = "unicode")]
+fn grapheme_levenshtein(k1: &str, k2: &str) -> Dist {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    if k1 == k2 {
+        return 0;
+    }
+    let a: Vec<&str> = k1.graphemes(true).collect();
+    let b: Vec<&str> = k2.graphemes(true).collect();
+    levenshtein(&a, &b)
+}
+
+#[cfg(feature = "unicode")]
+impl Metric<str> for GraphemeLevenshteinMetric {
+    #[inline]
+    fn distance(&self, k1: &str, k2: &str) -> Dist {
+        grapheme_levenshtein(k1, k2)
+    }
+
+    #[inline]
+    fn distance_static(k1: &str, k2: &str) -> Dist {
+        grapheme_levenshtein(k1, k2)
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some("levenshtein-graphemes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::StringKey;
+
+    #[test]
+    fn levenshtein_distance() {
+        let metric = LevenshteinMetric;
+        assert_eq!(0, metric.distance("", ""));
+        assert_eq!(3, metric.distance("", "foo"));
+        assert_eq!(3, metric.distance("foo", ""));
+        assert_eq!(0, metric.distance("foo", "foo"));
+        assert_eq!(1, metric.distance("foo", "fo"));
+        assert_eq!(1, metric.distance("foo", "fob"));
+        assert_eq!(3, metric.distance("kitten", "sitting"));
+        assert_eq!(3, metric.distance("sitting", "kitten"));
+        assert_eq!(1, metric.distance("naïve", "naive"));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn grapheme_levenshtein_distance() {
+        let chars = LevenshteinMetric;
+        let graphemes = GraphemeLevenshteinMetric;
+        // "e" + combining acute accent vs a plain "e".
+        assert_eq!(1, chars.distance("cafe\u{301}", "cafe"));
+        assert_eq!(1, graphemes.distance("cafe\u{301}", "cafe"));
+        assert_eq!(2, chars.distance("cafe\u{301}", "cafx"));
+        assert_eq!(1, graphemes.distance("cafe\u{301}", "cafx"));
+        // Two flags, each a pair of regional indicators.
+        assert_eq!(
+            2,
+            chars.distance("\u{1F1EB}\u{1F1F7}", "\u{1F1E9}\u{1F1EA}")
+        );
+        assert_eq!(
+            1,
+            graphemes.distance("\u{1F1EB}\u{1F1F7}", "\u{1F1E9}\u{1F1EA}")
+        );
+        assert_eq!(3, graphemes.distance("kitten", "sitting"));
+    }
+
+    #[test]
+    fn string_tree_find() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for word in &[
+            "book", "books", "cake", "boo", "cape", "cart", "boon", "cook",
+        ] {
+            tree.add(*word).unwrap();
+        }
+        let mut results = Vec::new();
+        tree.find_each("bool", 1, |d, k| results.push((d, k.clone())));
+        results.sort();
+        assert_eq!(
+            vec![
+                (1, "boo".to_string()),
+                (1, "book".to_string()),
+                (1, "boon".to_string())
+            ],
+            results
+        );
+    }
+}
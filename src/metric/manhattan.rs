@@ -0,0 +1,106 @@
+use crate::metric::Metric;
+use crate::Dist;
+
+/// L1 (Manhattan, taxicab) distance between integer vectors: the sum of absolute component
+/// differences. Use with `keys::VecKey<u32>` or `keys::U32ArrayKey<N>`.
+///
+/// Vectors of different lengths are compared as if the shorter were padded with zeros.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ManhattanMetric;
+
+pub(crate) fn zero_padded<'a>(
+    k1: &'a [u32],
+    k2: &'a [u32],
+) -> impl 'a + Iterator<Item = (u32, u32)> {
+    let len = k1.len().max(k2.len());
+    (0..len).map(move |i| {
+        (
+            k1.get(i).cloned().unwrap_or(0),
+            k2.get(i).cloned().unwrap_or(0),
+        )
+    })
+}
+
+fn manhattan(k1: &[u32], k2: &[u32]) -> Dist {
+    zero_padded(k1, k2)
+        .map(|(a, b)| a.abs_diff(b) as u64)
+        .sum::<u64>() as Dist
+}
+
+impl Metric<[u32]> for ManhattanMetric {
+    #[inline]
+    fn distance(&self, k1: &[u32], k2: &[u32]) -> Dist {
+        manhattan(k1, k2)
+    }
+}
+
+impl Metric<Vec<u32>> for ManhattanMetric {
+    #[inline]
+    fn distance(&self, k1: &Vec<u32>, k2: &Vec<u32>) -> Dist {
+        manhattan(k1, k2)
+    }
+}
+
+impl<const N: usize> Metric<[u32; N]> for ManhattanMetric {
+    #[inline]
+    fn distance(&self, k1: &[u32; N], k2: &[u32; N]) -> Dist {
+        manhattan(k1, k2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamAllocator, BkInRamTree};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::{U32ArrayKey, VecKey};
+
+    #[test]
+    fn manhattan_distance() {
+        let metric = ManhattanMetric;
+        assert_eq!(0, metric.distance(&[1u32, 2, 3], &[1, 2, 3]));
+        assert_eq!(6, metric.distance(&[1u32, 2, 3], &[3, 0, 1]));
+        assert_eq!(6, metric.distance(&[3u32, 0, 1], &[1, 2, 3]));
+        assert_eq!(
+            2 * u32::MAX as Dist,
+            metric.distance(&[0u32, u32::MAX], &[u32::MAX, 0])
+        );
+        assert_eq!(7, metric.distance(&vec![1u32, 2], &vec![1, 2, 7]));
+        assert_eq!(7, metric.distance(&[1u32, 2, 7][..], &[1, 2][..]));
+    }
+
+    #[test]
+    fn vector_tree_find() {
+        let vec_alloc = BkInRamAllocator::new();
+        let mut vecs: BkInRamTree<VecKey<u32>, ManhattanMetric, _> =
+            BkInRamTree::new(ManhattanMetric, &vec_alloc);
+        let array_alloc = BkInRamAllocator::new();
+        let mut arrays: BkInRamTree<U32ArrayKey<3>, ManhattanMetric, _> =
+            BkInRamTree::new(ManhattanMetric, &array_alloc);
+        for x in 0..5u32 {
+            for y in 0..5u32 {
+                vecs.add(&[x, y, 1][..]).unwrap();
+                arrays.add(&[x, y, 1]).unwrap();
+            }
+        }
+
+        let mut found = Vec::new();
+        vecs.find_each(&[2, 2, 1][..], 1, |d, k| found.push((d, k.clone())));
+        found.sort();
+        assert_eq!(
+            vec![
+                (0, vec![2, 2, 1]),
+                (1, vec![1, 2, 1]),
+                (1, vec![2, 1, 1]),
+                (1, vec![2, 3, 1]),
+                (1, vec![3, 2, 1]),
+            ],
+            found
+        );
+
+        let mut found_arrays = Vec::new();
+        arrays.find_each(&[2, 2, 1], 1, |d, k| found_arrays.push((d, k.to_vec())));
+        found_arrays.sort();
+        assert_eq!(found, found_arrays);
+    }
+}
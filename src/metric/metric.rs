@@ -2,5 +2,31 @@ use crate::Dist;
 
 pub trait Metric<K: ?Sized> {
     fn distance(&self, k1: &K, k2: &K) -> Dist;
-    fn distance_static(k1: &K, k2: &K) -> Dist;
+
+    /// A cheap lower bound on `distance`, used by searches to skip nodes without computing their
+    /// full distance. The default bound is useless but always correct.
+    fn lower_bound(&self, _k1: &K, _k2: &K) -> Dist {
+        0
+    }
+
+    /// The furthest apart any two keys can be, if there's a limit, e.g. 64 for Hamming distance
+    /// over u64s. Node types with a child slot per distance (see `fixed`) need one.
+    fn max_distance(&self) -> Option<Dist> {
+        None
+    }
+
+    /// The name `dynamic::by_name` knows this metric by, if it has one, e.g. for bkfiles to
+    /// record which metric they were built with.
+    fn registered_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Distance for metrics that need no configuration. Trees call `distance` on their own metric
+    /// instance; this is a convenience for callers without one.
+    fn distance_static(k1: &K, k2: &K) -> Dist
+    where
+        Self: Default,
+    {
+        Self::default().distance(k1, k2)
+    }
 }
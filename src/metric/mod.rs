@@ -1,5 +1,18 @@
+pub mod cached;
+pub mod chebyshev;
+pub mod combinators;
+pub mod counting;
+pub mod dynamic;
 pub mod hamming;
+pub mod jaccard;
+pub mod levenshtein;
+pub mod manhattan;
+#[allow(clippy::module_inception)]
 pub mod metric;
+pub mod plugin;
+pub mod qgram;
 pub mod strlen;
+pub mod weighted_levenshtein;
 
 pub use super::metric::metric::Metric;
+pub use dynamic::{by_name, DynMetric};
@@ -0,0 +1,219 @@
+/*
+ * Metrics loaded from shared libraries, so distances that can't live in this crate can still be
+ * used by its tools.
+ *
+ * A plugin exports one C function, and optionally a second with the same signature:
+ *
+ *   uint32_t distance(const uint8_t *k1, size_t k1_len, const uint8_t *k2, size_t k2_len);
+ *   uint32_t lower_bound(const uint8_t *k1, size_t k1_len, const uint8_t *k2, size_t k2_len);
+ *
+ * Both must be pure and thread safe. Keys are passed as bytes: byte string keys as they are, u64
+ * keys as their 8 little endian bytes. Loading libraries needs the plugins feature.
+ *
+ *   let metric = unsafe { PluginMetric::load("libmydistance.so") }?;
+ *   let mut tree: BkInRamTree<U64Key, PluginMetric, _> = BkInRamTree::new(metric, &U64_ALLOC);
+ */
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+#[cfg(feature = "plugins")]
+use std::path::Path;
+
+#[cfg(feature = "plugins")]
+use libloading::Library;
+
+use crate::metric::dynamic::DynMetric;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// The signature of a plugin's `distance` and `lower_bound` symbols.
+pub type DistanceFn = unsafe extern "C" fn(*const u8, usize, *const u8, usize) -> u32;
+
+/// A metric implemented by a C function.
+pub struct PluginMetric {
+    name: String,
+    distance: DistanceFn,
+    lower_bound: Option<DistanceFn>,
+    /// Keeps the functions above loaded. None for metrics made by `from_fn`.
+    #[cfg(feature = "plugins")]
+    _library: Option<Library>,
+}
+
+impl PluginMetric {
+    /// Load the shared library at `path` and use its `distance` symbol, and `lower_bound` if it
+    /// has one.
+    ///
+    /// # Safety
+    ///
+    /// Loading runs the library's initializers, which may do anything. Its `distance` and
+    /// `lower_bound` symbols must have the signature of `DistanceFn` and be pure and thread safe,
+    /// reading only the `len` bytes at each key pointer.
+    #[cfg(feature = "plugins")]
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let library = Library::new(path)
+            .map_err(|e| format!("Can't load metric plugin {:?}: {}", path, e))?;
+        let distance: DistanceFn = *library
+            .get::<DistanceFn>(b"distance\0")
+            .map_err(|e| format!("Metric plugin {:?} has no distance: {}", path, e))?;
+        let lower_bound = library.get::<DistanceFn>(b"lower_bound\0").ok().map(|f| *f);
+        Ok(PluginMetric {
+            name: Path::new(path)
+                .file_name()
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned(),
+            distance,
+            lower_bound,
+            _library: Some(library),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// As with the plugins feature, though without it this only returns an error.
+    #[cfg(not(feature = "plugins"))]
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Err(format!(
+            "Can't load metric plugin {:?}: loading plugins needs the plugins feature",
+            path.as_ref()
+        )
+        .into())
+    }
+
+    /// A metric from a function already linked in, e.g. to test a plugin's code without building
+    /// it as a library.
+    ///
+    /// # Safety
+    ///
+    /// Every distance the metric measures calls `distance`, which must be pure and thread safe and
+    /// read only the `len` bytes at each key pointer.
+    pub unsafe fn from_fn(name: &str, distance: DistanceFn) -> Self {
+        PluginMetric {
+            name: name.to_string(),
+            distance,
+            lower_bound: None,
+            #[cfg(feature = "plugins")]
+            _library: None,
+        }
+    }
+
+    /// The library's file name, or the name given to `from_fn`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    fn call(f: DistanceFn, k1: &[u8], k2: &[u8]) -> Dist {
+        // `load` and `from_fn` callers vouched for `f`.
+        unsafe { f(k1.as_ptr(), k1.len(), k2.as_ptr(), k2.len()) as Dist }
+    }
+}
+
+impl Debug for PluginMetric {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PluginMetric")
+            .field("name", &self.name)
+            .field("lower_bound", &self.lower_bound.is_some())
+            .finish()
+    }
+}
+
+impl Metric<[u8]> for PluginMetric {
+    #[inline]
+    fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        Self::call(self.distance, k1, k2)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        match self.lower_bound {
+            Some(f) => Self::call(f, k1, k2),
+            None => 0,
+        }
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+impl Metric<u64> for PluginMetric {
+    #[inline]
+    fn distance(&self, k1: &u64, k2: &u64) -> Dist {
+        Self::call(self.distance, &k1.to_le_bytes(), &k2.to_le_bytes())
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &u64, k2: &u64) -> Dist {
+        match self.lower_bound {
+            Some(f) => Self::call(f, &k1.to_le_bytes(), &k2.to_le_bytes()),
+            None => 0,
+        }
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+impl DynMetric for PluginMetric {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn distance(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        Metric::<[u8]>::distance(self, k1, k2)
+    }
+
+    fn lower_bound(&self, k1: &[u8], k2: &[u8]) -> Dist {
+        Metric::<[u8]>::lower_bound(self, k1, k2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamTree, U64_ALLOC};
+    use crate::bkfile;
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::U64Key;
+    use std::slice;
+
+    unsafe extern "C" fn hamming(
+        k1: *const u8,
+        k1_len: usize,
+        k2: *const u8,
+        k2_len: usize,
+    ) -> u32 {
+        let k1 = slice::from_raw_parts(k1, k1_len);
+        let k2 = slice::from_raw_parts(k2, k2_len);
+        k1.iter().zip(k2).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+
+    #[test]
+    fn plugin_functions_measure_trees() {
+        let metric = unsafe { PluginMetric::from_fn("libhamming.so", hamming) };
+        assert_eq!(3, Metric::<u64>::distance(&metric, &1, &(1 << 40 | 7)));
+        let mut tree: BkInRamTree<U64Key, PluginMetric, _> = BkInRamTree::new(metric, &U64_ALLOC);
+        let (keys, expected) = hashed_tree(500);
+        for key in &keys {
+            tree.add(key).unwrap();
+        }
+        let mut found = Vec::new();
+        tree.find_each(&0xff, 24, |d, k| found.push((d, *k)));
+        let mut wanted = Vec::new();
+        expected.find_each(&0xff, 24, |d, k| wanted.push((d, *k)));
+        assert!(!found.is_empty());
+        assert_eq!(wanted, found);
+
+        // Files record the plugin's name, so searches know to load it.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.bk");
+        let descr = bkfile::write_tree(&tree, &path, &Default::default()).unwrap();
+        assert_eq!("libhamming.so", descr.metric);
+
+        assert!(unsafe { PluginMetric::load("/nonexistent/libmetric.so") }.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use crate::metric::Metric;
+use crate::Dist;
+
+/// q-gram distance: the L1 distance between two strings' q-gram (length q substring) count
+/// profiles, counted in chars. Use with `keys::StringKey`.
+///
+/// This is only a pseudometric (distinct strings can share a profile), but it's linear time and a
+/// lower bound on edit distance: one edit changes at most q q-grams, so
+///     qgram(a, b) <= 2q * levenshtein(a, b)
+#[derive(Clone, Copy, Debug)]
+pub struct QGramMetric {
+    q: usize,
+}
+
+impl QGramMetric {
+    pub fn new(q: usize) -> Self {
+        assert!(q >= 1, "q-grams must be at least one char long");
+        QGramMetric { q }
+    }
+
+    pub fn q(&self) -> usize {
+        self.q
+    }
+
+    /// The edit distance lower bound implied by the q-gram distance.
+    pub fn edit_lower_bound(&self, k1: &str, k2: &str) -> Dist {
+        let dist = self.distance(k1, k2);
+        dist.div_ceil(2 * self.q)
+    }
+}
+
+impl Default for QGramMetric {
+    fn default() -> Self {
+        QGramMetric::new(2)
+    }
+}
+
+fn qgram_distance(q: usize, k1: &str, k2: &str) -> Dist {
+    if k1 == k2 {
+        return 0;
+    }
+    let a: Vec<char> = k1.chars().collect();
+    let b: Vec<char> = k2.chars().collect();
+    let mut profile: HashMap<&[char], isize> = HashMap::new();
+    for gram in a.windows(q) {
+        *profile.entry(gram).or_insert(0) += 1;
+    }
+    for gram in b.windows(q) {
+        *profile.entry(gram).or_insert(0) -= 1;
+    }
+    profile
+        .values()
+        .map(|count| count.unsigned_abs() as Dist)
+        .sum()
+}
+
+impl Metric<str> for QGramMetric {
+    #[inline]
+    fn distance(&self, k1: &str, k2: &str) -> Dist {
+        qgram_distance(self.q, k1, k2)
+    }
+}
+
+/// An edit distance metric with a q-gram pre-filter. Searches use the q-gram bound to skip nodes
+/// without running the (quadratic) edit distance on them.
+///
+/// The inner metric must charge at least 1 for every insert, delete and substitution, as
+/// `LevenshteinMetric` and a validated `WeightedLevenshteinMetric` do.
+#[derive(Clone, Debug, Default)]
+pub struct QGramFiltered<M> {
+    pub inner: M,
+    pub qgram: QGramMetric,
+}
+
+impl<M> QGramFiltered<M> {
+    pub fn new(inner: M, q: usize) -> Self {
+        QGramFiltered {
+            inner,
+            qgram: QGramMetric::new(q),
+        }
+    }
+}
+
+impl<M: Metric<str>> Metric<str> for QGramFiltered<M> {
+    #[inline]
+    fn distance(&self, k1: &str, k2: &str) -> Dist {
+        self.inner.distance(k1, k2)
+    }
+
+    #[inline]
+    fn lower_bound(&self, k1: &str, k2: &str) -> Dist {
+        self.qgram
+            .edit_lower_bound(k1, k2)
+            .max(self.inner.lower_bound(k1, k2))
+    }
+
+    fn max_distance(&self) -> Option<Dist> {
+        self.inner.max_distance()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::StringKey;
+    use crate::metric::combinators::ClampedMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    const WORDS: &[&str] = &[
+        "book",
+        "books",
+        "cake",
+        "boo",
+        "cape",
+        "cart",
+        "boon",
+        "cook",
+        "bookkeeper",
+        "bookcase",
+        "casebook",
+        "notebook",
+        "",
+        "b",
+        "kitten",
+        "sitting",
+        "mitten",
+        "smitten",
+        "bitten",
+    ];
+
+    #[test]
+    fn qgram_distance_profiles() {
+        let metric = QGramMetric::new(2);
+        assert_eq!(0, metric.distance("abc", "abc"));
+        assert_eq!(0, metric.distance("a", "b"));
+        // {ab, bc} vs {ab, bd}
+        assert_eq!(2, metric.distance("abc", "abd"));
+        // {ab, ba} vs {ba, ab}: same profile.
+        assert_eq!(0, metric.distance("aba", "bab"));
+        assert_eq!(2, metric.distance("abcd", "ab"));
+    }
+
+    #[test]
+    fn bounds_edit_distance() {
+        let lev = LevenshteinMetric;
+        for q in 1..4 {
+            let filtered = QGramFiltered::new(LevenshteinMetric, q);
+            for a in WORDS {
+                for b in WORDS {
+                    assert!(
+                        filtered.lower_bound(a, b) <= lev.distance(a, b),
+                        "q={} {:?} {:?}",
+                        q,
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+        // The inner metric's limit, if it has one.
+        assert_eq!(None, QGramFiltered::new(lev, 2).max_distance());
+        let clamped = QGramFiltered::new(ClampedMetric::new(lev, 8), 2);
+        assert_eq!(Some(8), clamped.max_distance());
+    }
+
+    #[test]
+    fn filtered_find_matches_unfiltered() {
+        let mut plain: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        let mut filtered: BkInRamTree<StringKey, QGramFiltered<LevenshteinMetric>, _> =
+            BkInRamTree::new(QGramFiltered::new(LevenshteinMetric, 2), &STRING_ALLOC);
+        for word in WORDS {
+            plain.add(*word).unwrap();
+            filtered.add(*word).unwrap();
+        }
+        for needle in &["book", "mittens", "cast", "x"] {
+            for tolerance in 0..4 {
+                let mut expected = Vec::new();
+                plain.find_each(needle, tolerance, |d, k| expected.push((d, k.clone())));
+                expected.sort();
+                let mut found = Vec::new();
+                filtered.find_each(needle, tolerance, |d, k| found.push((d, k.clone())));
+                found.sort();
+                assert_eq!(expected, found, "{:?} within {}", needle, tolerance);
+            }
+        }
+    }
+}
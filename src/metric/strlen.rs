@@ -7,11 +7,15 @@ pub struct StrLenMetric;
 impl Metric<str> for StrLenMetric {
     #[inline]
     fn distance(&self, k1: &str, k2: &str) -> Dist {
-        (k1.len() as i64 - k2.len() as i64).abs() as Dist
+        k1.len().abs_diff(k2.len()) as Dist
     }
 
     #[inline]
     fn distance_static(k1: &str, k2: &str) -> Dist {
-        (k1.len() as i64 - k2.len() as i64).abs() as Dist
+        k1.len().abs_diff(k2.len()) as Dist
+    }
+
+    fn registered_name(&self) -> Option<&str> {
+        Some("strlen")
     }
 }
@@ -0,0 +1,332 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::metric::Metric;
+use crate::Dist;
+
+/// Edit costs per character class.
+///
+/// Class i (for i < classes.len()) is the set of chars in `classes[i]`. Every other char is in
+/// the catch-all class `classes.len()`, so the cost vectors have `classes.len() + 1` entries.
+///
+/// E.g. for OCR, with `classes: vec!["0Oo".into(), "1lI".into()]`, a low `substitute[0][0]`
+/// makes confusing 0 for O cheap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditCosts {
+    pub classes: Vec<String>,
+    /// insert[c]: cost of inserting a char of class c.
+    pub insert: Vec<Dist>,
+    /// delete[c]: cost of deleting a char of class c.
+    pub delete: Vec<Dist>,
+    /// substitute[a][b]: cost of replacing a char of class a with a different char of class b.
+    pub substitute: Vec<Vec<Dist>>,
+}
+
+impl EditCosts {
+    /// Unit costs for everything, i.e. plain Levenshtein distance.
+    pub fn uniform() -> Self {
+        EditCosts {
+            classes: Vec::new(),
+            insert: vec![1],
+            delete: vec![1],
+            substitute: vec![vec![1]],
+        }
+    }
+
+    /// Check that edit distance under these costs is a metric, which the tree's pruning relies
+    /// on.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let n = self.classes.len() + 1;
+        if self.insert.len() != n || self.delete.len() != n || self.substitute.len() != n {
+            return Err(format!("Expected costs for {} character classes", n).into());
+        }
+        if let Some(row) = self.substitute.iter().find(|row| row.len() != n) {
+            return Err(format!(
+                "Expected {} substitution costs per class, got {}",
+                n,
+                row.len()
+            )
+            .into());
+        }
+        let mut seen: HashMap<char, usize> = HashMap::new();
+        for (class, chars) in self.classes.iter().enumerate() {
+            for c in chars.chars() {
+                if let Some(other) = seen.insert(c, class) {
+                    return Err(
+                        format!("{:?} is in both class {} and class {}", c, other, class).into(),
+                    );
+                }
+            }
+        }
+
+        for a in 0..n {
+            // Zero costs would make distinct strings distance 0 apart.
+            if self.insert[a] == 0 || self.delete[a] == 0 {
+                return Err(format!("Insert and delete costs of class {} must be >= 1", a).into());
+            }
+            // Inserting into one string is deleting from the other.
+            if self.insert[a] != self.delete[a] {
+                return Err(format!(
+                    "Insert and delete costs of class {} must be equal for symmetry",
+                    a
+                )
+                .into());
+            }
+            for b in 0..n {
+                let sub = self.substitute[a][b];
+                if sub == 0 {
+                    return Err(format!("Substitution cost {}->{} must be >= 1", a, b).into());
+                }
+                if sub != self.substitute[b][a] {
+                    return Err(format!(
+                        "Substitution costs {}->{} and {}->{} must be equal for symmetry",
+                        a, b, b, a
+                    )
+                    .into());
+                }
+                // The dynamic program edits each char at most once. That's only the cheapest
+                // edit sequence, and so only obeys the triangle inequality, if no single edit
+                // can be done more cheaply in two steps.
+                if sub > self.delete[a] + self.insert[b] {
+                    return Err(format!(
+                        "Substitution cost {}->{} exceeds deleting and then inserting",
+                        a, b
+                    )
+                    .into());
+                }
+                // Likewise inserting a b could be done by inserting an a and substituting it,
+                // and deleting an a by substituting it with a b and deleting that.
+                if self.insert[b] > self.insert[a] + sub {
+                    return Err(format!(
+                        "Insert cost of class {} exceeds inserting class {} and substituting",
+                        b, a
+                    )
+                    .into());
+                }
+                if self.delete[a] > sub + self.delete[b] {
+                    return Err(format!(
+                        "Delete cost of class {} exceeds substituting to class {} and deleting",
+                        a, b
+                    )
+                    .into());
+                }
+                for via in 0..n {
+                    if sub > self.substitute[a][via] + self.substitute[via][b] {
+                        return Err(format!(
+                            "Substitution cost {}->{} exceeds substituting via class {}",
+                            a, b, via
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Edit distance with per character class costs. Use with `keys::StringKey`.
+///
+/// Unlike the other metrics this one carries configuration, so it has no `Default` and can't be
+/// used through `Metric::distance_static`.
+#[derive(Debug, Clone)]
+pub struct WeightedLevenshteinMetric {
+    costs: EditCosts,
+    class_of: HashMap<char, usize>,
+    #[cfg(feature = "unicode")]
+    graphemes: bool,
+}
+
+impl WeightedLevenshteinMetric {
+    pub fn new(costs: EditCosts) -> Result<Self, Box<dyn Error>> {
+        costs.validate()?;
+        let mut class_of = HashMap::new();
+        for (class, chars) in costs.classes.iter().enumerate() {
+            for c in chars.chars() {
+                class_of.insert(c, class);
+            }
+        }
+        Ok(WeightedLevenshteinMetric {
+            costs,
+            class_of,
+            #[cfg(feature = "unicode")]
+            graphemes: false,
+        })
+    }
+
+    pub fn costs(&self) -> &EditCosts {
+        &self.costs
+    }
+
+    fn class(&self, c: char) -> usize {
+        *self.class_of.get(&c).unwrap_or(&self.costs.classes.len())
+    }
+
+    /// Compare by extended grapheme cluster rather than by char. A cluster's class is the class
+    /// of its first (base) char.
+    #[cfg(feature = "unicode")]
+    pub fn with_graphemes(mut self) -> Self {
+        self.graphemes = true;
+        self
+    }
+
+    fn weighted(&self, k1: &str, k2: &str) -> Dist {
+        if k1 == k2 {
+            return 0;
+        }
+        #[cfg(feature = "unicode")]
+        {
+            if self.graphemes {
+                use unicode_segmentation::UnicodeSegmentation;
+                let class = |g: &str| self.class(g.chars().next().unwrap());
+                let a: Vec<(&str, usize)> = k1.graphemes(true).map(|g| (g, class(g))).collect();
+                let b: Vec<(&str, usize)> = k2.graphemes(true).map(|g| (g, class(g))).collect();
+                return self.weighted_units(&a, &b);
+            }
+        }
+        let a: Vec<(char, usize)> = k1.chars().map(|c| (c, self.class(c))).collect();
+        let b: Vec<(char, usize)> = k2.chars().map(|c| (c, self.class(c))).collect();
+        self.weighted_units(&a, &b)
+    }
+
+    /// Same two row dynamic program as levenshtein(), with the unit costs replaced. Units are
+    /// paired with their character class.
+    fn weighted_units<T: PartialEq>(&self, a: &[(T, usize)], b: &[(T, usize)]) -> Dist {
+        let costs = &self.costs;
+        let mut prev: Vec<Dist> = Vec::with_capacity(b.len() + 1);
+        prev.push(0);
+        for (_, cb) in b.iter() {
+            let last = *prev.last().unwrap();
+            prev.push(last + costs.insert[*cb]);
+        }
+        let mut cur: Vec<Dist> = vec![0; b.len() + 1];
+        for (ca, class_a) in a.iter() {
+            cur[0] = prev[0] + costs.delete[*class_a];
+            for (j, (cb, class_b)) in b.iter().enumerate() {
+                let substitute = prev[j]
+                    + if ca == cb {
+                        0
+                    } else {
+                        costs.substitute[*class_a][*class_b]
+                    };
+                let delete = prev[j + 1] + costs.delete[*class_a];
+                let insert = cur[j] + costs.insert[*class_b];
+                cur[j + 1] = min(substitute, min(delete, insert));
+            }
+            std::mem::swap(&mut prev, &mut cur);
+        }
+        prev[b.len()]
+    }
+}
+
+impl Metric<str> for WeightedLevenshteinMetric {
+    #[inline]
+    fn distance(&self, k1: &str, k2: &str) -> Dist {
+        self.weighted(k1, k2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::StringKey;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    fn ocr_costs() -> EditCosts {
+        EditCosts {
+            classes: vec!["0Oo".to_string(), "1lI".to_string()],
+            insert: vec![2, 2, 2],
+            delete: vec![2, 2, 2],
+            substitute: vec![vec![1, 2, 2], vec![2, 1, 2], vec![2, 2, 2]],
+        }
+    }
+
+    #[test]
+    fn uniform_costs_match_levenshtein() {
+        let metric = WeightedLevenshteinMetric::new(EditCosts::uniform()).unwrap();
+        let plain = LevenshteinMetric;
+        for (a, b) in &[
+            ("", "foo"),
+            ("kitten", "sitting"),
+            ("naïve", "naive"),
+            ("ab", "ba"),
+        ] {
+            assert_eq!(plain.distance(a, b), metric.distance(a, b));
+        }
+    }
+
+    #[test]
+    fn class_costs() {
+        let metric = WeightedLevenshteinMetric::new(ocr_costs()).unwrap();
+        assert_eq!(0, metric.distance("B0O", "B0O"));
+        assert_eq!(1, metric.distance("B0O", "BOO"));
+        assert_eq!(3, metric.distance("B0O", "BOl"));
+        assert_eq!(2, metric.distance("B0O", "B0"));
+        assert_eq!(2, metric.distance("B0", "B0O"));
+        assert_eq!(2, metric.distance("cat", "cut"));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn grapheme_mode() {
+        let metric = WeightedLevenshteinMetric::new(ocr_costs())
+            .unwrap()
+            .with_graphemes();
+        // O with a combining diaeresis is still in the O class.
+        assert_eq!(1, metric.distance("B0", "BO\u{308}"));
+        assert_eq!(2, metric.distance("Bx", "BO\u{308}"));
+    }
+
+    #[test]
+    fn rejects_non_metric_costs() {
+        let mut asymmetric = ocr_costs();
+        asymmetric.substitute[0][1] = 1;
+        assert!(WeightedLevenshteinMetric::new(asymmetric).is_err());
+
+        let mut zero = ocr_costs();
+        zero.substitute[0][0] = 0;
+        assert!(WeightedLevenshteinMetric::new(zero).is_err());
+
+        let mut shortcut = ocr_costs();
+        shortcut.substitute[0][1] = 5;
+        shortcut.substitute[1][0] = 5;
+        assert!(WeightedLevenshteinMetric::new(shortcut).is_err());
+
+        // d("", "x") would be 5 but d("", "a") + d("a", "x") only 2.
+        let dear_insert = EditCosts {
+            classes: vec!["abc".to_string()],
+            insert: vec![1, 5],
+            delete: vec![1, 5],
+            substitute: vec![vec![1, 1], vec![1, 1]],
+        };
+        assert!(WeightedLevenshteinMetric::new(dear_insert).is_err());
+
+        let mut overlapping = ocr_costs();
+        overlapping.classes[1].push('O');
+        assert!(WeightedLevenshteinMetric::new(overlapping).is_err());
+
+        let mut short = ocr_costs();
+        short.insert.pop();
+        assert!(WeightedLevenshteinMetric::new(short).is_err());
+    }
+
+    #[test]
+    fn tree_uses_metric_instance() {
+        let metric = WeightedLevenshteinMetric::new(ocr_costs()).unwrap();
+        let mut tree: BkInRamTree<StringKey, WeightedLevenshteinMetric, _> =
+            BkInRamTree::new(metric, &STRING_ALLOC);
+        for word in &["B00K", "BOOK", "LOOK", "BOOKS", "B0OK", "COOK"] {
+            tree.add(*word).unwrap();
+        }
+        let mut results = Vec::new();
+        tree.find_each("BOOK", 1, |d, k| results.push((d, k.clone())));
+        results.sort();
+        assert_eq!(
+            vec![(0, "BOOK".to_string()), (1, "B0OK".to_string())],
+            results
+        );
+    }
+}
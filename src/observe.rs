@@ -0,0 +1,274 @@
+/*
+ * Crate-level observability hooks for serving deployments.
+ *
+ * Install one `Observer` per process and the trees report into it: every query with the number of
+ * nodes it visited, each tree's size as it changes, and metric cache lookups. `Counters` is a
+ * ready made implementation; bridge it (or your own Observer) to prometheus or the metrics crate
+ * by polling `Counters::snapshot()`.
+ *
+ *   let counters = Arc::new(Counters::new());
+ *   observe::install(counters.clone())?;
+ *   ...
+ *   let snap = counters.snapshot();
+ *   gauge!("bk_nodes_visited_p99", snap.nodes_visited_quantile(0.99) as f64);
+ *
+ * With no observer installed the hooks cost one atomic load.
+ */
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Tells apart the trees reporting their sizes, which would otherwise overwrite each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TreeId(u64);
+
+static NEXT_TREE: AtomicU64 = AtomicU64::new(0);
+
+impl TreeId {
+    pub(crate) fn next() -> Self {
+        TreeId(NEXT_TREE.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub trait Observer: Send + Sync {
+    /// A query finished after visiting (computing the distance to) `nodes_visited` nodes.
+    fn query_served(&self, _nodes_visited: u64) {}
+
+    /// A tree's size changed.
+    fn tree_size(&self, _tree: TreeId, _nodes: u64, _tombstones: u64) {}
+
+    /// A tree was dropped, so its size no longer counts.
+    fn tree_dropped(&self, _tree: TreeId) {}
+
+    /// A cached metric looked up a distance.
+    fn cache_lookup(&self, _hit: bool) {}
+}
+
+static OBSERVER: OnceLock<Arc<dyn Observer>> = OnceLock::new();
+
+/// Install the process-wide observer. Can only be done once.
+pub fn install(observer: Arc<dyn Observer>) -> Result<(), Box<dyn Error>> {
+    OBSERVER
+        .set(observer)
+        .map_err(|_| "An observer is already installed".into())
+}
+
+#[inline]
+pub(crate) fn query_served(nodes_visited: u64) {
+    if let Some(observer) = OBSERVER.get() {
+        observer.query_served(nodes_visited);
+    }
+}
+
+#[inline]
+pub(crate) fn tree_size(tree: TreeId, nodes: u64, tombstones: u64) {
+    if let Some(observer) = OBSERVER.get() {
+        observer.tree_size(tree, nodes, tombstones);
+    }
+}
+
+#[inline]
+pub(crate) fn tree_dropped(tree: TreeId) {
+    if let Some(observer) = OBSERVER.get() {
+        observer.tree_dropped(tree);
+    }
+}
+
+#[inline]
+pub(crate) fn cache_lookup(hit: bool) {
+    if let Some(observer) = OBSERVER.get() {
+        observer.cache_lookup(hit);
+    }
+}
+
+/// Visit counts are bucketed by bit length: bucket b holds counts in [2^(b-1), 2^b).
+const BUCKETS: usize = 65;
+
+fn bucket_of(n: u64) -> usize {
+    (64 - n.leading_zeros()) as usize
+}
+
+/// Counters and gauges, suitable for sharing between serving threads. Only tree size changes
+/// take a lock.
+#[derive(Debug)]
+pub struct Counters {
+    queries: AtomicU64,
+    nodes_visited: AtomicU64,
+    visited_buckets: [AtomicU64; BUCKETS],
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// (nodes, tombstones) of each live tree.
+    trees: Mutex<HashMap<TreeId, (u64, u64)>>,
+}
+
+/// A point in time copy of `Counters`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterSnapshot {
+    pub queries: u64,
+    /// Total over all queries.
+    pub nodes_visited: u64,
+    pub visited_buckets: Vec<u64>,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Sizes summed over the live trees.
+    pub nodes: u64,
+    pub tombstones: u64,
+    pub trees: usize,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Counters {
+            queries: AtomicU64::new(0),
+            nodes_visited: AtomicU64::new(0),
+            visited_buckets: [(); BUCKETS].map(|_| AtomicU64::new(0)),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            trees: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn snapshot(&self) -> CounterSnapshot {
+        let trees = self.trees.lock().unwrap();
+        CounterSnapshot {
+            queries: self.queries.load(Ordering::Relaxed),
+            nodes_visited: self.nodes_visited.load(Ordering::Relaxed),
+            visited_buckets: self
+                .visited_buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            nodes: trees.values().map(|(nodes, _)| nodes).sum(),
+            tombstones: trees.values().map(|(_, tombstones)| tombstones).sum(),
+            trees: trees.len(),
+        }
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observer for Counters {
+    fn query_served(&self, nodes_visited: u64) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.nodes_visited
+            .fetch_add(nodes_visited, Ordering::Relaxed);
+        self.visited_buckets[bucket_of(nodes_visited)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn tree_size(&self, tree: TreeId, nodes: u64, tombstones: u64) {
+        self.trees.lock().unwrap().insert(tree, (nodes, tombstones));
+    }
+
+    fn tree_dropped(&self, tree: TreeId) {
+        self.trees.lock().unwrap().remove(&tree);
+    }
+
+    fn cache_lookup(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl CounterSnapshot {
+    /// Upper bound on the number of nodes visited by `fraction` of queries, e.g. 0.99 for p99.
+    /// Exact to within a factor of two.
+    pub fn nodes_visited_quantile(&self, fraction: f64) -> u64 {
+        if self.queries == 0 {
+            return 0;
+        }
+        let fraction = fraction.clamp(0.0, 1.0);
+        let target = ((fraction * self.queries as f64).ceil() as u64).max(1);
+        let mut cumulative: u64 = 0;
+        for (bucket, count) in self.visited_buckets.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                return if bucket == 0 {
+                    0
+                } else {
+                    u64::MAX >> (64 - bucket)
+                };
+            }
+        }
+        u64::MAX
+    }
+
+    /// Fraction of cache lookups that hit, if there were any.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.cache_hits as f64 / lookups as f64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, U64_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd};
+    use crate::keys::U64Key;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn counter_quantiles() {
+        let counters = Counters::new();
+        for visited in 1..=100 {
+            counters.query_served(visited);
+        }
+        counters.cache_lookup(true);
+        counters.cache_lookup(true);
+        counters.cache_lookup(false);
+        counters.cache_lookup(true);
+        let snap = counters.snapshot();
+        assert_eq!(100, snap.queries);
+        assert_eq!(5050, snap.nodes_visited);
+        assert_eq!(63, snap.nodes_visited_quantile(0.5));
+        assert_eq!(127, snap.nodes_visited_quantile(0.99));
+        assert_eq!(Some(0.75), snap.cache_hit_rate());
+    }
+
+    #[test]
+    fn tree_sizes_are_per_tree() {
+        let counters = Counters::new();
+        let (a, b) = (TreeId::next(), TreeId::next());
+        counters.tree_size(a, 10, 1);
+        counters.tree_size(b, 5, 0);
+        counters.tree_size(a, 11, 1);
+        let snap = counters.snapshot();
+        assert_eq!((16, 1, 2), (snap.nodes, snap.tombstones, snap.trees));
+        counters.tree_dropped(b);
+        assert_eq!(11, counters.snapshot().nodes);
+    }
+
+    #[test]
+    fn installed_observer_sees_trees() {
+        // The observer is process-wide and other tests run concurrently, so only check lower
+        // bounds.
+        let counters = Arc::new(Counters::new());
+        install(counters.clone()).unwrap();
+        assert!(install(counters.clone()).is_err());
+
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for k in 0..10u64 {
+            tree.add(&k).unwrap();
+        }
+        tree.find_each(&3, 1, |_, _| {});
+        let snap = counters.snapshot();
+        assert!(snap.queries >= 1);
+        assert!(snap.nodes_visited >= 1);
+        assert!(snap.nodes >= 1);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Searches spread over a rayon thread pool, for wide queries of big trees: the `parallel`
+ * feature.
+ *
+ *   use bkchainsaw::parallel::ParBkTree;
+ *   let found = tree.par_find_all(&needle, 10);
+ *   let nearest = tree.par_find_knn(&needle, 10, 16);
+ *
+ * The root is checked on the calling thread, and each of its children in range is searched as
+ * a task of its own, so the speedup is bounded by how many of those there are and how evenly the
+ * keys are spread among them. Any tree whose nodes, metric and queries can be shared between
+ * threads will do.
+ */
+use rayon::prelude::*;
+
+use crate::bk::BkFind;
+use crate::bknode::BkNode;
+use crate::bktree::{BkTree, Subtree};
+use crate::forest::merge_knn;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+pub trait ParBkTree<Key: Clone>: BkTree<Key> {
+    /// `find_each`, a task per child of the root. The callback is called from the pool's
+    /// threads, in no particular order.
+    fn par_find_each<F>(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        callback: F,
+    ) where
+        F: Fn(Dist, &Key) + Sync;
+
+    /// Every key within `tolerance` of `needle`, in no particular order.
+    fn par_find_all(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+    ) -> Vec<(Dist, Key)>;
+
+    /// `find_knn`, a task per child of the root. Each searches for its own `k` nearest, and
+    /// they're merged, so more nodes are visited than by `find_knn`, but sooner. Ties at the
+    /// k-th distance go to the smaller key.
+    fn par_find_knn(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> Vec<(Dist, Key)>
+    where
+        Key: Ord;
+}
+
+impl<Key, T> ParBkTree<Key> for T
+where
+    Key: Clone + Send + Sync,
+    T: BkTree<Key> + Sync,
+    T::Node: Sync,
+    T::Metric: Sync,
+    <T::KQ as KeyQuery>::Query: Sync,
+{
+    fn par_find_each<F>(&self, needle: &<Self::KQ as KeyQuery>::Query, tolerance: Dist, callback: F)
+    where
+        F: Fn(Dist, &Key) + Sync,
+    {
+        if let Some((dist, root)) = root_distance(self, needle) {
+            if dist <= tolerance && !root.is_tombstone() {
+                callback(dist, root.key());
+            }
+            children_within(root, dist, tolerance)
+                .par_iter()
+                .for_each(|child| {
+                    BkFind::new(0, Some(*child), tolerance, needle)
+                        .each::<T::KQ, T::Metric, _>(self.metric(), |dist, key| {
+                            callback(dist, key)
+                        });
+                });
+        }
+    }
+
+    fn par_find_all(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+    ) -> Vec<(Dist, Key)> {
+        let (dist, root) = match root_distance(self, needle) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let mut found: Vec<(Dist, Key)> = children_within(root, dist, tolerance)
+            .par_iter()
+            .flat_map_iter(|child| {
+                let mut found = Vec::new();
+                BkFind::new(0, Some(*child), tolerance, needle)
+                    .each::<T::KQ, T::Metric, _>(self.metric(), |dist, key| {
+                        found.push((dist, key.clone()))
+                    });
+                found
+            })
+            .collect();
+        if dist <= tolerance && !root.is_tombstone() {
+            found.push((dist, root.key().clone()));
+        }
+        found
+    }
+
+    fn par_find_knn(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+    ) -> Vec<(Dist, Key)>
+    where
+        Key: Ord,
+    {
+        let (dist, root) = match root_distance(self, needle) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let mut found: Vec<Vec<(Dist, Key)>> = children_within(root, dist, max_distance)
+            .par_iter()
+            .map(|child| {
+                Subtree::<T::KQ, _, _>::new(self.metric(), *child).find_knn(needle, k, max_distance)
+            })
+            .collect();
+        if dist <= max_distance && !root.is_tombstone() {
+            found.insert(0, vec![(dist, root.key().clone())]);
+        }
+        merge_knn(found, k)
+    }
+}
+
+fn root_distance<'t, Key, T>(
+    tree: &'t T,
+    needle: &<T::KQ as KeyQuery>::Query,
+) -> Option<(Dist, &'t T::Node)>
+where
+    Key: Clone,
+    T: BkTree<Key>,
+{
+    tree.root().map(|root| {
+        let dist = tree
+            .metric()
+            .distance(T::KQ::to_query_static(root.key()), needle);
+        (dist, root)
+    })
+}
+
+/// The children of a node `dist` from the needle that could hold keys within `radius` of it.
+fn children_within<N: BkNode>(node: &N, dist: Dist, radius: Dist) -> Vec<&N> {
+    node.children_vector()
+        .into_iter()
+        .filter(|(slot, _)| {
+            dist.saturating_sub(radius) <= *slot && *slot <= dist.saturating_add(radius)
+        })
+        .map(|(_, child)| child)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+    use std::sync::Mutex;
+
+    #[test]
+    fn finds_what_a_serial_search_does() {
+        let (keys, tree) = hashed_tree(2000);
+        for needle in &[keys[1234] ^ 0b101, 0] {
+            let expected = tree.find_sorted(needle, 24);
+            let mut found = tree.par_find_all(needle, 24);
+            found.sort();
+            assert_eq!(expected, found);
+            let each = Mutex::new(Vec::new());
+            tree.par_find_each(needle, 24, |dist, key| {
+                each.lock().unwrap().push((dist, *key))
+            });
+            let mut each = each.into_inner().unwrap();
+            each.sort();
+            assert_eq!(expected, each);
+
+            let dists =
+                |found: Vec<(Dist, u64)>| -> Vec<Dist> { found.iter().map(|f| f.0).collect() };
+            assert_eq!(
+                dists(tree.find_knn(needle, 7, 64)),
+                dists(tree.par_find_knn(needle, 7, 64))
+            );
+        }
+    }
+}
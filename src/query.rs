@@ -0,0 +1,141 @@
+/*
+ * Searches configured by `FindOptions`.
+ *
+ *   let nearest = tree.find_with(&needle, &FindOptions::new(8).limit(10));
+ *
+ * Results always come back sorted by distance, closest first, with ties in traversal order.
+ *
+ * With a limit, they're the closest `limit` keys within tolerance, not the first `limit` found:
+ * the search is best first, visiting subtrees in order of how close their keys could be to the
+ * needle, and stops once no subtree left could beat the furthest result kept. So a limit makes
+ * searches cheaper as well as smaller, and truncating the unlimited results to `limit` gives the
+ * same keys (up to ties at the furthest distance).
+ */
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FindOptions {
+    /// Largest distance a match may be from the needle.
+    pub tolerance: Dist,
+    /// Most matches to return: the closest ones. None for all of them.
+    pub limit: Option<usize>,
+}
+
+impl FindOptions {
+    pub fn new(tolerance: Dist) -> Self {
+        FindOptions {
+            tolerance,
+            limit: None,
+        }
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// How far away a key can be and still make the results: the furthest kept, once there are
+/// `limit` of them.
+fn search_radius(best: &BinaryHeap<(Dist, usize)>, limit: usize, tolerance: Dist) -> Dist {
+    match best.peek() {
+        Some((furthest, _)) if best.len() >= limit => *furthest,
+        _ => tolerance,
+    }
+}
+
+/// Searches configured by `FindOptions`. Implemented for every tree.
+pub trait FindWith<K: Clone>: BkTree<K> {
+    /// Matches for `needle`, closest first.
+    fn find_with(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        options: &FindOptions,
+    ) -> Vec<(Dist, K)> {
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut found: Vec<&K> = Vec::new();
+        // Max-heap of (distance, index into found), holding the best `limit` so far.
+        let mut best: BinaryHeap<(Dist, usize)> = BinaryHeap::new();
+        let mut nodes: Vec<&Self::Node> = Vec::new();
+        // Min-heap of (least distance any key in the subtree can have, index into nodes). Every
+        // key under the child in slot `s` of a node `d` from the needle is `s` from that node, so
+        // at least `|d - s|` from the needle.
+        let mut frontier: BinaryHeap<Reverse<(Dist, usize)>> = BinaryHeap::new();
+        if let Some(root) = self.root() {
+            nodes.push(root);
+            frontier.push(Reverse((0, 0)));
+        }
+        while let Some(Reverse((bound, index))) = frontier.pop() {
+            let radius = search_radius(&best, limit, options.tolerance);
+            if limit == 0 || bound > radius {
+                break;
+            }
+            let node = nodes[index];
+            let dist = self
+                .metric()
+                .distance(Self::KQ::to_query_static(node.key()), needle);
+            if dist <= radius && !node.is_tombstone() {
+                best.push((dist, found.len()));
+                found.push(node.key());
+                if best.len() > limit {
+                    best.pop();
+                }
+            }
+
+            let radius = search_radius(&best, limit, options.tolerance);
+            node.each_child(|slot, child| {
+                let bound = slot.abs_diff(dist);
+                if bound <= radius {
+                    frontier.push(Reverse((bound, nodes.len())));
+                    nodes.push(child);
+                }
+            });
+        }
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|(dist, i)| (dist, found[i].clone()))
+            .collect()
+    }
+}
+
+impl<K: Clone, T: BkTree<K>> FindWith<K> for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+
+    #[test]
+    fn limited_results_are_the_closest() {
+        let (keys, tree) = hashed_tree(2000);
+        let needle = keys[7] ^ 0b1011;
+        let mut expected: Vec<(Dist, u64)> = keys
+            .iter()
+            .map(|k| ((k ^ needle).count_ones() as Dist, *k))
+            .filter(|(d, _)| *d <= 22)
+            .collect();
+        expected.sort();
+
+        let all = tree.find_with(&needle, &FindOptions::new(22));
+        assert_eq!(expected.len(), all.len());
+        assert!(all.windows(2).all(|w| w[0].0 <= w[1].0));
+
+        let nearest = tree.find_with(&needle, &FindOptions::new(22).limit(5));
+        assert_eq!(5, nearest.len());
+        assert_eq!((3, keys[7]), nearest[0]);
+        let distances: Vec<Dist> = nearest.iter().map(|(d, _)| *d).collect();
+        let expected: Vec<Dist> = expected.iter().take(5).map(|(d, _)| *d).collect();
+        assert_eq!(expected, distances);
+
+        assert!(tree
+            .find_with(&needle, &FindOptions::new(22).limit(0))
+            .is_empty());
+    }
+}
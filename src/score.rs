@@ -0,0 +1,164 @@
+/*
+ * Similarity scores for query results.
+ *
+ * Raw distances mean different things for different metrics: 3 is close for Hamming over 256-bit
+ * hashes and far for Levenshtein over 4 letter words. A `Normalizer` maps a distance to a
+ * similarity between 0.0 (as different as the metric allows) and 1.0 (identical), so ranking code
+ * downstream of several indexes can compare them:
+ *
+ *   let bits = MaxDistance(64);
+ *   tree.find_each_scored(&needle, 8, &bits, |similarity, dist, key| ...);
+ *   let nearest = words.find_knn_scored("kitten", 5, 3, &LengthNormalizer::default());
+ */
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::Dist;
+
+pub trait Normalizer<Q: ?Sized> {
+    /// Similarity of `key` to `needle`, `dist` apart, from 0.0 to 1.0.
+    fn similarity(&self, dist: Dist, key: &Q, needle: &Q) -> f64;
+}
+
+fn ratio(dist: Dist, max: Dist) -> f64 {
+    if max == 0 {
+        return if dist == 0 { 1.0 } else { 0.0 };
+    }
+    (1.0 - dist as f64 / max as f64).max(0.0)
+}
+
+/// For metrics with a fixed maximum distance, e.g. `MaxDistance(64)` for Hamming over u64s.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxDistance(pub Dist);
+
+impl<Q: ?Sized> Normalizer<Q> for MaxDistance {
+    fn similarity(&self, dist: Dist, _key: &Q, _needle: &Q) -> f64 {
+        ratio(dist, self.0)
+    }
+}
+
+/// For edit distances, whose maximum depends on the length of the longer key: characters for
+/// strings, elements for slices.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthNormalizer {
+    /// The most each unit of length can add to the distance. 1 for unit cost edit distances; the
+    /// largest operation cost for weighted ones.
+    pub per_unit: Dist,
+}
+
+impl Default for LengthNormalizer {
+    fn default() -> Self {
+        LengthNormalizer { per_unit: 1 }
+    }
+}
+
+impl Normalizer<str> for LengthNormalizer {
+    fn similarity(&self, dist: Dist, key: &str, needle: &str) -> f64 {
+        let len = key.chars().count().max(needle.chars().count());
+        ratio(dist, len * self.per_unit)
+    }
+}
+
+impl<T> Normalizer<[T]> for LengthNormalizer {
+    fn similarity(&self, dist: Dist, key: &[T], needle: &[T]) -> f64 {
+        ratio(dist, key.len().max(needle.len()) * self.per_unit)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredMatch<K> {
+    pub similarity: f64,
+    pub distance: Dist,
+    pub key: K,
+}
+
+/// Searches that report a similarity with each result. Implemented for every tree.
+pub trait ScoredFind<K: Clone>: BkTree<K> {
+    /// `find_each`, with the callback getting each match's similarity ahead of its distance.
+    fn find_each_scored<'a, N, F>(
+        &'a self,
+        needle: &'a <Self::KQ as KeyQuery>::Query,
+        tolerance: Dist,
+        normalizer: &N,
+        mut callback: F,
+    ) where
+        N: Normalizer<<Self::KQ as KeyQuery>::Query>,
+        F: FnMut(f64, Dist, &K),
+    {
+        self.find_each(needle, tolerance, |dist, key| {
+            let similarity = normalizer.similarity(dist, Self::KQ::to_query_static(key), needle);
+            callback(similarity, dist, key)
+        })
+    }
+
+    /// `find_knn`, with similarities.
+    fn find_knn_scored<N>(
+        &self,
+        needle: &<Self::KQ as KeyQuery>::Query,
+        k: usize,
+        max_distance: Dist,
+        normalizer: &N,
+    ) -> Vec<ScoredMatch<K>>
+    where
+        N: Normalizer<<Self::KQ as KeyQuery>::Query>,
+    {
+        self.find_knn(needle, k, max_distance)
+            .into_iter()
+            .map(|(distance, key)| ScoredMatch {
+                similarity: normalizer.similarity(
+                    distance,
+                    Self::KQ::to_query_static(&key),
+                    needle,
+                ),
+                distance,
+                key,
+            })
+            .collect()
+    }
+}
+
+impl<K: Clone, T: BkTree<K>> ScoredFind<K> for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{BkInRamTree, STRING_ALLOC, U64_ALLOC};
+    use crate::bktree::BkTreeAdd;
+    use crate::keys::{StringKey, U64Key};
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn similarities() {
+        assert_eq!(1.0, MaxDistance(64).similarity(0, &1u64, &1u64));
+        assert_eq!(0.75, MaxDistance(64).similarity(16, &1u64, &2u64));
+        assert_eq!(0.0, MaxDistance(4).similarity(9, &1u64, &2u64));
+        let length = LengthNormalizer::default();
+        assert_eq!(0.5, length.similarity(2, "book", "back"));
+        assert_eq!(1.0, length.similarity(0, "", ""));
+        assert_eq!(0.5, length.similarity(1, &[1u8][..], &[1u8, 2][..]));
+    }
+
+    #[test]
+    fn scored_results() {
+        let mut words: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        for word in &["kitten", "sitten", "sitting", "mitten"] {
+            words.add(word).unwrap();
+        }
+        let nearest = words.find_knn_scored("kitten", 2, 3, &LengthNormalizer::default());
+        assert_eq!(2, nearest.len());
+        assert_eq!(1.0, nearest[0].similarity);
+        assert_eq!("kitten", nearest[0].key);
+        assert_eq!(1, nearest[1].distance);
+        assert!((nearest[1].similarity - 5.0 / 6.0).abs() < 1e-9);
+
+        let mut hashes: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        hashes.add(&0).unwrap();
+        hashes.add(&0xffff).unwrap();
+        let mut found = Vec::new();
+        hashes.find_each_scored(&0xff, 8, &MaxDistance(64), |s, d, k| found.push((s, d, *k)));
+        found.sort_by_key(|(_, _, k)| *k);
+        assert_eq!(vec![(0.875, 8, 0), (0.875, 8, 0xffff)], found);
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ * Trees with a memory budget, for ingestion jobs that can't be sure their input fits in RAM.
+ *
+ * A `BkSpillTree` keeps an approximate count of the bytes its nodes take. Past its budget, it
+ * cuts the subtrees under the root that have gone longest without a new key out of RAM and
+ * writes each to its own temporary bkfile. Searches cover the nodes left in RAM and the spilled
+ * subtrees, which are read straight from the mapped files, so callers see one tree:
+ *
+ *   let mut tree = BkSpillTree::new(HammingMetric::default(), 512 << 20);
+ *   for key in keys { tree.add(&key)?; }
+ *   tree.find_each(&needle, 4, |dist, key| ...);
+ *
+ * Keys that land under a spilled subtree's slot later start a new subtree in RAM, which may
+ * spill in turn. The temporary files are deleted with the tree.
+ *
+ * Spilled subtrees use the "8 bits distance, 8 bits child" node format, so only u64 keys, and
+ * metrics with distances and child counts under 256 (like hamming), can spill.
+ */
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::mem;
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::Mmap;
+use tempfile::NamedTempFile;
+
+use crate::bk::{BkInRam, BkInRamAllocator, BkInRamTree, U64_ALLOC};
+use crate::bkfile;
+use crate::bknode::BkNode;
+use crate::bktree::{BkTree, BkTreeAdd, Subtree};
+use crate::codec::FixedU64;
+use crate::keys::U64Key;
+use crate::metric::Metric;
+use crate::Dist;
+
+// F64BNode8 uses 8 bytes per node
+const NODE_SIZE: usize = 8;
+
+/// Once over budget, spill until this fraction of it is in use, so the next spill is a while
+/// off.
+const LOW_WATER: f64 = 0.75;
+
+/// A subtree cut from under the root, in a temporary bkfile.
+struct Spill {
+    /// The root child slot it was cut from.
+    slot: Dist,
+    map: Mmap,
+    /// Deleted when the spill is dropped.
+    _file: NamedTempFile,
+    /// Where the node and key arrays start in `map`.
+    nodes: usize,
+    keys: usize,
+    node_count: u64,
+}
+
+impl Spill {
+    /// Write out the subtree at `root`, whose parent had it at `slot`, of a tree whose metric
+    /// is `metric`.
+    fn write<M: Metric<u64>>(
+        slot: Dist,
+        metric: &M,
+        root: &BkInRam<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = NamedTempFile::new()?;
+        // Full size keys, for `key` to read in place.
+        let options = bkfile::WriteOptions {
+            codec: Some(&FixedU64),
+            ..Default::default()
+        };
+        let subtree = Subtree::<U64Key, _, _>::new(metric, root);
+        let descr = bkfile::write_tree(&subtree, file.path(), &options)?;
+        let mut written = file.reopen()?;
+        let data = bkfile::Header::read(&mut written, false)?.data_offset() as usize;
+        Ok(Spill {
+            slot,
+            map: unsafe { Mmap::map(&written)? },
+            _file: file,
+            nodes: data + descr.node_offset as usize,
+            keys: data + descr.key_offset as usize,
+            node_count: descr.node_count,
+        })
+    }
+
+    fn key(&self, offset: usize) -> u64 {
+        let at = self.keys + offset / NODE_SIZE * 8;
+        LittleEndian::read_u64(&self.map[at..at + 8])
+    }
+
+    fn dist(&self, offset: usize) -> Dist {
+        self.map[self.nodes + offset] as Dist
+    }
+
+    /// Offsets of the node's children.
+    fn children(&self, offset: usize) -> impl Iterator<Item = usize> {
+        let at = self.nodes + offset;
+        let count = self.map[at + 1] as usize;
+        let first = LittleEndian::read_u32(&self.map[at + 4..at + 8]) as usize;
+        (0..count).map(move |i| first + NODE_SIZE * i)
+    }
+
+    fn contains<M: Metric<u64>>(&self, metric: &M, key: &u64) -> bool {
+        let mut offset = 0;
+        loop {
+            let dist = metric.distance(&self.key(offset), key);
+            if dist == 0 {
+                return true;
+            }
+            match self
+                .children(offset)
+                .find(|child| self.dist(*child) == dist)
+            {
+                Some(child) => offset = child,
+                None => return false,
+            }
+        }
+    }
+
+    fn find_each<M, F>(&self, metric: &M, needle: &u64, tolerance: Dist, callback: &mut F)
+    where
+        M: Metric<u64>,
+        F: FnMut(Dist, &u64),
+    {
+        let mut stack = vec![0];
+        while let Some(offset) = stack.pop() {
+            let key = self.key(offset);
+            let dist = metric.distance(&key, needle);
+            if dist <= tolerance {
+                callback(dist, &key);
+            }
+            let min = dist.saturating_sub(tolerance);
+            let max = dist.saturating_add(tolerance);
+            stack.extend(self.children(offset).filter(|child| {
+                let slot = self.dist(*child);
+                min <= slot && slot <= max
+            }));
+        }
+    }
+}
+
+type InRamTree<M> = BkInRamTree<'static, U64Key, M, BkInRamAllocator<'static, u64>>;
+
+/// A tree of u64 keys that moves subtrees to disk to stay within a memory budget.
+pub struct BkSpillTree<M: Metric<u64>> {
+    tree: InRamTree<M>,
+    budget: usize,
+    spills: Vec<Spill>,
+    /// Keys added so far, as a clock for which subtrees are cold.
+    adds: u64,
+    /// last_added[slot] is the clock when a key last went under the root's child at `slot`.
+    last_added: Vec<u64>,
+    /// Bytes in use when last measured, and the node count to measure again at.
+    measured: usize,
+    next_check: u64,
+}
+
+/// Heap bytes under `node`, not counting its own slot in its parent's child table.
+fn subtree_bytes(node: &BkInRam<u64>) -> (usize, u64) {
+    let mut bytes = 0;
+    let mut nodes = 0;
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        bytes += node.children_bytes();
+        nodes += 1;
+        node.each_child(|_, child| stack.push(child));
+    }
+    (bytes, nodes)
+}
+
+impl<M: Metric<u64>> BkSpillTree<M> {
+    /// A tree keeping roughly `budget` bytes of nodes in RAM.
+    pub fn new(metric: M, budget: usize) -> Self {
+        BkSpillTree {
+            tree: BkInRamTree::new(metric, &U64_ALLOC),
+            budget,
+            spills: Vec::new(),
+            adds: 0,
+            last_added: Vec::new(),
+            measured: 0,
+            next_check: 0,
+        }
+    }
+
+    pub fn add(&mut self, key: &u64) -> Result<(), Box<dyn Error>> {
+        if let Some(ref root) = self.tree.root {
+            let slot = self.tree.metric().distance(&root.key, key);
+            if slot > 0 {
+                let metric = self.tree.metric();
+                if self
+                    .spills
+                    .iter()
+                    .any(|spill| spill.slot == slot && spill.contains(metric, key))
+                {
+                    return Ok(());
+                }
+                if self.last_added.len() <= slot {
+                    self.last_added.resize(slot + 1, 0);
+                }
+                self.last_added[slot] = self.adds;
+            }
+        }
+        self.tree.add(key)?;
+        self.adds += 1;
+        if self.tree.node_count >= self.next_check {
+            self.enforce_budget()?;
+        }
+        Ok(())
+    }
+
+    /// Measure the nodes in RAM, and spill the coldest subtrees if they're over budget.
+    fn enforce_budget(&mut self) -> Result<(), Box<dyn Error>> {
+        let root = match self.tree.root {
+            Some(ref root) => root,
+            None => return Ok(()),
+        };
+        let mut subtrees: Vec<(Dist, usize, u64)> = Vec::new();
+        let mut bytes = mem::size_of::<BkInRam<u64>>() + root.children_bytes();
+        root.each_child(|slot, child| {
+            let (child_bytes, nodes) = subtree_bytes(child);
+            bytes += child_bytes;
+            subtrees.push((slot, child_bytes, nodes));
+        });
+        if bytes > self.budget {
+            let last_added = &self.last_added;
+            subtrees.sort_by_key(|(slot, _, _)| last_added.get(*slot).cloned().unwrap_or(0));
+            let low_water = (self.budget as f64 * LOW_WATER) as usize;
+            for (slot, child_bytes, nodes) in subtrees {
+                if bytes <= low_water {
+                    break;
+                }
+                // Cut out only once it's safely on disk.
+                let root = self.tree.root.as_ref().expect("root measured above");
+                let child = root.child_at(slot).expect("child measured above");
+                self.spills
+                    .push(Spill::write(slot, self.tree.metric(), child)?);
+                if let Some(root) = self.tree.root.as_mut() {
+                    root.take_child(slot);
+                }
+                bytes -= child_bytes;
+                self.tree.node_count -= nodes;
+            }
+            self.tree.recount_stats();
+        }
+        // Assume nodes keep costing what they do on average until the next measurement.
+        let per_node = bytes / self.tree.node_count.max(1) as usize;
+        self.measured = bytes;
+        self.next_check =
+            self.tree.node_count + (self.budget.saturating_sub(bytes) / per_node.max(1)) as u64 + 1;
+        Ok(())
+    }
+
+    /// Calls `callback` with every key within `tolerance` of `needle`, in RAM or spilled.
+    pub fn find_each<F>(&self, needle: &u64, tolerance: Dist, mut callback: F)
+    where
+        F: FnMut(Dist, &u64),
+    {
+        self.tree.find_each(needle, tolerance, &mut callback);
+        let root = match self.tree.root {
+            Some(ref root) => root,
+            None => return,
+        };
+        let metric = self.tree.metric();
+        let dist = metric.distance(&root.key, needle);
+        for spill in self.spills.iter() {
+            if spill.slot + tolerance >= dist && spill.slot <= dist + tolerance {
+                spill.find_each(metric, needle, tolerance, &mut callback);
+            }
+        }
+    }
+
+    /// As `BkTree::find_sorted`: every match, by distance and then key.
+    pub fn find_sorted(&self, needle: &u64, tolerance: Dist) -> Vec<(Dist, u64)> {
+        let mut found = Vec::new();
+        self.find_each(needle, tolerance, |dist, key| found.push((dist, *key)));
+        found.sort();
+        found
+    }
+
+    /// Keys in the tree, in RAM or spilled.
+    pub fn len(&self) -> u64 {
+        self.tree.node_count + self.spilled_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keys that have been spilled to disk.
+    pub fn spilled_len(&self) -> u64 {
+        self.spills.iter().map(|spill| spill.node_count).sum()
+    }
+
+    /// Subtrees spilled so far, each in its own file.
+    pub fn spill_count(&self) -> usize {
+        self.spills.len()
+    }
+
+    /// Approximate bytes taken by the nodes in RAM, as of the last check against the budget.
+    pub fn ram_bytes(&self) -> usize {
+        self.measured
+    }
+
+    /// The part of the tree still in RAM.
+    pub fn in_ram(&self) -> &InRamTree<M> {
+        &self.tree
+    }
+}
+
+impl<M: Metric<u64>> Debug for BkSpillTree<M> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BkSpillTree")
+            .field("budget", &self.budget)
+            .field("ram_bytes", &self.measured)
+            .field("node_count", &self.tree.node_count)
+            .field("spilled", &self.spilled_len())
+            .field("spill_count", &self.spills.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+    use crate::metric::hamming::HammingMetric;
+
+    #[test]
+    fn spilled_trees_find_what_in_ram_trees_find() {
+        let mut tree = BkSpillTree::new(HammingMetric::default(), 64 << 10);
+        let (keys, expected) = hashed_tree(20000);
+        for key in &keys {
+            tree.add(key).unwrap();
+        }
+        // Duplicates are caught whether or not they've been spilled.
+        for key in &keys {
+            tree.add(key).unwrap();
+        }
+        assert!(tree.spill_count() > 0);
+        assert!(tree.ram_bytes() <= 64 << 10);
+        assert_eq!(20000, tree.len());
+
+        for needle in &[0u64, keys[1] ^ 0b101, 0x3c6ef372fe94f82a] {
+            let mut wanted = Vec::new();
+            expected.find_each(needle, 20, |d, k| wanted.push((d, *k)));
+            let mut found = Vec::new();
+            tree.find_each(needle, 20, |d, k| found.push((d, *k)));
+            wanted.sort();
+            found.sort();
+            assert!(!found.is_empty());
+            assert_eq!(wanted, found);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * Tree shape statistics: how many nodes sit at each depth, and how many children nodes have.
+ *
+ * `TreeStats::from_tree` (or `BkTree::stats`) walks the whole tree, and prints as a report:
+ *
+ *   println!("{}", tree.stats());
+ *
+ * `TreeStats::from_tree` walks the whole tree. `BkInRamTree` keeps its own copy up to date as keys
+ * are added, so dashboards polling `quick_stats()` don't pay for a traversal each time.
+ *
+ * `QueryStats` are what one search cost; see `BkTree::find_each_with_stats`.
+ *
+ * `Fragmentation` measures the space a `BkInRamTree` holds but doesn't use, which builds up as
+ * keys are removed. `compact()` reports it from before and after the rewrite:
+ *
+ *   let report = tree.compact()?;
+ *   println!("reclaimed {} bytes", report.bytes_reclaimed());
+ */
+use std::fmt;
+use std::vec::Vec;
+
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    pub nodes: u64,
+    /// Nodes whose keys have been removed. They're still in the counts below.
+    pub tombstones: u64,
+    /// nodes_per_depth[d] is the number of nodes d levels below the root.
+    pub nodes_per_depth: Vec<u64>,
+    /// child_counts[c] is the number of nodes with c children.
+    pub child_counts: Vec<u64>,
+}
+
+fn bump(counts: &mut Vec<u64>, index: usize) {
+    if counts.len() <= index {
+        counts.resize(index + 1, 0);
+    }
+    counts[index] += 1;
+}
+
+impl TreeStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Compute the statistics with a full traversal.
+    pub fn from_tree<K: Clone, T: BkTree<K>>(tree: &T) -> Self {
+        let mut stats = Self::new();
+        let mut stack: Vec<(usize, &T::Node)> = tree.root().into_iter().map(|r| (0, r)).collect();
+        while let Some((depth, node)) = stack.pop() {
+            let mut children = 0;
+            node.each_child(|_, child| {
+                children += 1;
+                stack.push((depth + 1, child));
+            });
+            stats.nodes += 1;
+            if node.is_tombstone() {
+                stats.tombstones += 1;
+            }
+            bump(&mut stats.nodes_per_depth, depth);
+            bump(&mut stats.child_counts, children);
+        }
+        stats
+    }
+
+    /// Account for a node inserted `depth` levels down, under a parent that had
+    /// `parent_children` children before (None for a new root).
+    pub fn record_insert(&mut self, depth: usize, parent_children: Option<usize>) {
+        self.nodes += 1;
+        bump(&mut self.nodes_per_depth, depth);
+        bump(&mut self.child_counts, 0);
+        if let Some(before) = parent_children {
+            self.child_counts[before] -= 1;
+            bump(&mut self.child_counts, before + 1);
+        }
+    }
+
+    /// Depth of the deepest node, or None for an empty tree.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.nodes_per_depth.len().checked_sub(1)
+    }
+
+    /// Mean number of children among nodes that have any.
+    pub fn mean_branching(&self) -> f64 {
+        let (parents, children) = self
+            .child_counts
+            .iter()
+            .enumerate()
+            .skip(1)
+            .fold((0, 0), |(p, c), (n, count)| {
+                (p + count, c + n as u64 * count)
+            });
+        if parents == 0 {
+            0.0
+        } else {
+            children as f64 / parents as f64
+        }
+    }
+}
+
+impl fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "nodes\t{}", self.nodes)?;
+        writeln!(f, "tombstones\t{}", self.tombstones)?;
+        match self.max_depth() {
+            Some(depth) => writeln!(f, "max depth\t{}", depth)?,
+            None => writeln!(f, "max depth\t-")?,
+        }
+        writeln!(f, "mean branching\t{:.2}", self.mean_branching())?;
+        for (depth, nodes) in self.nodes_per_depth.iter().enumerate() {
+            writeln!(f, "depth {}\t{}", depth, nodes)?;
+        }
+        for (children, nodes) in self.child_counts.iter().enumerate() {
+            if *nodes > 0 {
+                writeln!(f, "{} children\t{}", children, nodes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a search cost, for tuning tolerances, tree shapes and file layouts by experiment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Nodes whose children were considered.
+    pub nodes_visited: usize,
+    /// Calls to `Metric::distance`. More than `nodes_visited` when the search stops early.
+    pub distance_computations: usize,
+    /// Most nodes waiting to be visited at once, counting the one being visited.
+    pub max_stack_depth: usize,
+}
+
+/// Space a tree holds without using it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fragmentation {
+    pub nodes: u64,
+    /// Nodes whose keys have been removed, still taking their space.
+    pub tombstones: u64,
+    /// Empty slots below the furthest child in dense child tables.
+    pub dead_slots: u64,
+    /// Slots allocated past the end of child tables, waiting for children that may never come.
+    pub spare_slots: u64,
+    /// Heap bytes of the child tables, the nodes in them included.
+    pub bytes: usize,
+    /// The part of `bytes` taken by the three above.
+    pub wasted_bytes: usize,
+}
+
+impl Fragmentation {
+    /// Share of `bytes` wasted, from 0 to 1.
+    pub fn waste(&self) -> f64 {
+        if self.bytes == 0 {
+            0.0
+        } else {
+            self.wasted_bytes as f64 / self.bytes as f64
+        }
+    }
+}
+
+/// What `BkInRamTree::compact` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionReport {
+    pub before: Fragmentation,
+    pub after: Fragmentation,
+}
+
+impl CompactionReport {
+    pub fn bytes_reclaimed(&self) -> usize {
+        self.before.bytes.saturating_sub(self.after.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::{hashed_tree, BkInRamTree, STRING_ALLOC, U64_ALLOC};
+    use crate::bktree::{BkTree, BkTreeAdd, BkTreeRemove};
+    use crate::keys::{StringKey, U64Key};
+    use crate::metric::hamming::HammingMetric;
+    use crate::metric::levenshtein::LevenshteinMetric;
+
+    #[test]
+    fn quick_stats_match_traversal() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        assert_eq!(TreeStats::from_tree(&tree), tree.quick_stats());
+        for i in 0..500u64 {
+            tree.add(&i.wrapping_mul(0x9e3779b97f4a7c15)).unwrap();
+            tree.add(&i).unwrap();
+        }
+        tree.remove(&3u64);
+        let stats = tree.quick_stats();
+        assert_eq!(TreeStats::from_tree(&tree), stats);
+        assert_eq!(tree.node_count, stats.nodes);
+        assert_eq!(1, stats.tombstones);
+        assert_eq!(1, stats.nodes_per_depth[0]);
+        assert!(stats.mean_branching() > 1.0);
+
+        tree.compact().unwrap();
+        assert_eq!(TreeStats::from_tree(&tree), tree.quick_stats());
+    }
+
+    #[test]
+    fn reports_shape_and_size() {
+        let mut tree: BkInRamTree<U64Key, HammingMetric<u64>, _> =
+            BkInRamTree::new(Default::default(), &U64_ALLOC);
+        for i in 0..100u64 {
+            tree.add(&i).unwrap();
+        }
+        let stats = tree.stats();
+        assert_eq!(100, stats.nodes);
+        let report = stats.to_string();
+        assert!(report.starts_with("nodes\t100\ntombstones\t0\n"));
+        assert!(report.contains("depth 0\t1\n"));
+    }
+
+    #[test]
+    fn heap_estimate_counts_tables_and_keys() {
+        let mut tree: BkInRamTree<StringKey, LevenshteinMetric, _> =
+            BkInRamTree::new(LevenshteinMetric, &STRING_ALLOC);
+        assert_eq!(0, tree.approx_heap_bytes());
+        let words: Vec<String> = (0..300).map(|i| format!("word{}", i * 7)).collect();
+        for word in &words {
+            tree.add(word.as_str()).unwrap();
+        }
+        let key_bytes: usize = words.iter().map(|w| w.len()).sum();
+        let tables = tree.fragmentation().bytes;
+        assert!(tree.approx_heap_bytes() >= tables + key_bytes);
+        assert!(tree.approx_heap_bytes() < tables + 2 * key_bytes);
+    }
+
+    #[test]
+    fn shrinking_drops_slack_but_keeps_keys() {
+        let (_, mut tree) = hashed_tree(2000);
+        let before = tree.approx_heap_bytes();
+        let found = tree.find_sorted(&0, 20);
+        tree.shrink_to_fit();
+        assert_eq!(0, tree.fragmentation().spare_slots);
+        assert!(tree.approx_heap_bytes() < before / 2);
+        assert_eq!(found, tree.find_sorted(&0, 20));
+        tree.add(&1).unwrap();
+        assert_eq!(Ok(()), tree.validate());
+    }
+
+    #[test]
+    fn compaction_reclaims_fragmented_space() {
+        assert_eq!(Fragmentation::default(), hashed_tree(0).1.fragmentation());
+        let (keys, mut tree) = hashed_tree(2000);
+        for key in &keys[..1000] {
+            tree.remove(key);
+        }
+        let before = tree.fragmentation();
+        assert_eq!((2000, 1000), (before.nodes, before.tombstones));
+        assert!(before.spare_slots > 0);
+        assert!(before.waste() > 0.5);
+
+        let report = tree.compact().unwrap();
+        assert_eq!(before, report.before);
+        assert_eq!(report.after, tree.fragmentation());
+        assert_eq!(
+            (1000, 0, 0),
+            (
+                report.after.nodes,
+                report.after.tombstones,
+                report.after.spare_slots
+            )
+        );
+        assert!(report.bytes_reclaimed() > report.after.bytes);
+        assert!(report.after.waste() < before.waste());
+    }
+}
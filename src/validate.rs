@@ -0,0 +1,184 @@
+/*
+ * Checking a tree's structure, for metrics under development and trees of unknown provenance.
+ *
+ * A BK tree is only searchable if every key under a node's child at slot d really is d from that
+ * node. A "metric" that breaks the triangle inequality, isn't symmetric, or isn't deterministic
+ * builds trees that silently lose matches; `BkTree::validate` finds where.
+ *
+ *   tree.validate()?;
+ *
+ * Validation measures every key against each of its ancestors, so costs the node count times
+ * the depth in distance computations.
+ */
+use std::error::Error;
+use std::fmt;
+
+use crate::bknode::BkNode;
+use crate::bktree::BkTree;
+use crate::keyquery::KeyQuery;
+use crate::metric::Metric;
+use crate::Dist;
+
+/// The first thing `BkTree::validate` found wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantError {
+    /// The node at `path` (the slots leading to it from the root) is `actual` from its ancestor
+    /// `ancestor_depth` levels below the root, which filed it under slot `expected`.
+    WrongDistance {
+        path: Vec<Dist>,
+        ancestor_depth: usize,
+        expected: Dist,
+        actual: Dist,
+    },
+    /// The tree says it has `recorded` nodes, tombstones included, but holds `actual`.
+    NodeCount { recorded: u64, actual: u64 },
+    /// The tree says it's `recorded` deep, but it goes down to depth `actual`.
+    MaxDepth { recorded: usize, actual: usize },
+    /// The node at `path` has a child table its node type can't search properly, e.g. one
+    /// loaded from a corrupt file. See `BkNode::children_well_formed`.
+    MalformedChildren { path: Vec<Dist> },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvariantError::WrongDistance {
+                path,
+                ancestor_depth,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Node at {:?} is {} from its ancestor at depth {}, not {}",
+                path, actual, ancestor_depth, expected
+            ),
+            InvariantError::NodeCount { recorded, actual } => {
+                write!(f, "Tree records {} nodes but has {}", recorded, actual)
+            }
+            InvariantError::MaxDepth { recorded, actual } => {
+                write!(f, "Tree records depth {} but is {} deep", recorded, actual)
+            }
+            InvariantError::MalformedChildren { path } => {
+                write!(f, "Node at {:?} has a malformed child table", path)
+            }
+        }
+    }
+}
+
+impl Error for InvariantError {}
+
+/// The body of `BkTree::validate`.
+pub(crate) fn validate<K, T>(tree: &T) -> Result<(), InvariantError>
+where
+    K: Clone,
+    T: BkTree<K> + ?Sized,
+{
+    let distance = |a: &T::Node, b: &T::Node| {
+        tree.metric().distance(
+            T::KQ::to_query_static(a.key()),
+            T::KQ::to_query_static(b.key()),
+        )
+    };
+    let mut nodes: u64 = 0;
+    let mut depth = 0;
+    // The nodes above the one being checked, and the slots leading down from each.
+    let mut ancestors: Vec<&T::Node> = Vec::new();
+    let mut path: Vec<Dist> = Vec::new();
+    let mut stack: Vec<(usize, Dist, &T::Node)> =
+        tree.root().into_iter().map(|r| (0, 0, r)).collect();
+    while let Some((level, slot, node)) = stack.pop() {
+        nodes += 1;
+        depth = depth.max(level);
+        ancestors.truncate(level);
+        path.truncate(level.saturating_sub(1));
+        if level > 0 {
+            path.push(slot);
+        }
+        for (ancestor_depth, ancestor) in ancestors.iter().enumerate() {
+            let actual = distance(ancestor, node);
+            if actual != path[ancestor_depth] {
+                return Err(InvariantError::WrongDistance {
+                    path: path.clone(),
+                    ancestor_depth,
+                    expected: path[ancestor_depth],
+                    actual,
+                });
+            }
+        }
+        if !node.children_well_formed() {
+            return Err(InvariantError::MalformedChildren { path: path.clone() });
+        }
+        ancestors.push(node);
+        node.each_child(|slot, child| stack.push((level + 1, slot, child)));
+    }
+
+    if let Some((recorded, recorded_depth)) = tree.recorded_counts() {
+        if recorded != nodes {
+            return Err(InvariantError::NodeCount {
+                recorded,
+                actual: nodes,
+            });
+        }
+        // Only a hint for sizing search stacks, so too deep is fine: detaching subtrees leaves
+        // it behind.
+        if recorded_depth < depth {
+            return Err(InvariantError::MaxDepth {
+                recorded: recorded_depth,
+                actual: depth,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bk::hashed_tree;
+    use crate::bknode::BkNodeMut;
+    use crate::bktree::BkTreeRemove;
+
+    #[test]
+    fn finds_misfiled_nodes_and_bad_counts() {
+        assert_eq!(Ok(()), hashed_tree(0).1.validate());
+        let (keys, mut tree) = hashed_tree(2000);
+        tree.remove(&keys[1]);
+        assert_eq!(Ok(()), tree.validate());
+
+        tree.node_count += 1;
+        assert_eq!(
+            Err(InvariantError::NodeCount {
+                recorded: 2001,
+                actual: 2000
+            }),
+            tree.validate()
+        );
+        tree.node_count -= 1;
+        tree.max_depth -= 1;
+        assert!(matches!(
+            tree.validate(),
+            Err(InvariantError::MaxDepth { .. })
+        ));
+        tree.max_depth += 1;
+
+        // A grandchild of the root, moved to a slot it doesn't belong in.
+        let root = tree.root.as_mut().unwrap();
+        let slot = root.max_child_dist().unwrap();
+        let child = root.child_at_mut(slot).unwrap();
+        let (grandchild_slot, _) = child.children_vector()[0];
+        let grandchild = child.take_child(grandchild_slot).unwrap();
+        child.set_child_node(63, grandchild);
+        match tree.validate() {
+            Err(InvariantError::WrongDistance {
+                path,
+                ancestor_depth,
+                expected,
+                ..
+            }) => {
+                assert_eq!(vec![slot, 63], path);
+                assert_eq!((1, 63), (ancestor_depth, expected));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}